@@ -0,0 +1,51 @@
+/* C interface to huge_allocator. Build the library with:
+ *   cargo rustc --release --features ffi --crate-type cdylib
+ */
+
+#ifndef HUGE_ALLOCATOR_H
+#define HUGE_ALLOCATOR_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct huge_allocator huge_allocator_t;
+
+typedef struct huge_stats {
+    size_t alloc;
+    size_t mapped;
+    size_t segments;
+    size_t huge_mapped;
+    size_t huge_segments;
+    size_t default_mapped;
+    size_t default_segments;
+    size_t missed_allocs;
+    size_t remaps_failed;
+    size_t unmaps_failed;
+} huge_stats_t;
+
+/* Creates an allocator trying huge pages for allocations of at least threshold_pct percent of a huge page */
+huge_allocator_t *huge_allocator_new(size_t threshold_pct);
+
+/* Frees an allocator, unmapping any allocations still live */
+void huge_allocator_free(huge_allocator_t *allocator);
+
+/* Allocates zeroed memory. Returns NULL on failure */
+void *huge_alloc(const huge_allocator_t *allocator, size_t size, size_t align);
+
+/* Resizes an allocation keeping its alignment. Returns NULL on failure leaving ptr untouched */
+void *huge_realloc(const huge_allocator_t *allocator, void *ptr, size_t size);
+
+/* Frees an allocation. NULL is ignored */
+void huge_free(const huge_allocator_t *allocator, void *ptr);
+
+/* Fills in statistics. Returns 0 on success */
+int huge_stats(const huge_allocator_t *allocator, huge_stats_t *stats);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
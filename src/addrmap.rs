@@ -0,0 +1,209 @@
+use std::io::{self, Write};
+
+use crate::mmap::{MMap, PageSize};
+
+/// Width of the SVG address space bar in pixels
+const SVG_WIDTH: f64 = 1024.0;
+
+/// Height of the SVG address space bar in pixels
+const SVG_HEIGHT: f64 = 32.0;
+
+/// What a mapped range of the address space is holding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RangeState {
+    /// A live allocation
+    Live,
+    /// A freed segment kept in the segment cache
+    Cached,
+    /// A freed segment waiting for the unmap epoch to end
+    Epoch,
+}
+
+impl RangeState {
+    fn name(self) -> &'static str {
+        match self {
+            RangeState::Live => "live",
+            RangeState::Cached => "cached",
+            RangeState::Epoch => "epoch",
+        }
+    }
+
+    /// Fill colour of the range in the SVG rendering
+    fn colour(self, page_size: PageSize) -> &'static str {
+        match (self, page_size) {
+            (RangeState::Live, PageSize::SizeDefault) => "#4878d0",
+            (RangeState::Live, _) => "#d65f5f",
+            (RangeState::Cached, _) => "#6acc64",
+            (RangeState::Epoch, _) => "#ee854a",
+        }
+    }
+}
+
+/// A range of the address space mapped by the allocator
+#[derive(Debug, Clone)]
+pub(crate) struct MappedRange {
+    pub(crate) start: usize,
+    /// Mapped bytes
+    pub(crate) len: usize,
+    /// Bytes in use by a live allocation
+    pub(crate) used: usize,
+    pub(crate) page_size: PageSize,
+    pub(crate) state: RangeState,
+}
+
+impl MappedRange {
+    pub(crate) fn new(mmap: &MMap, state: RangeState) -> Self {
+        Self {
+            start: mmap.as_ptr() as usize,
+            len: mmap.alloc_size(),
+            used: if state == RangeState::Live { mmap.size() } else { 0 },
+            page_size: mmap.page_size(),
+            state,
+        }
+    }
+
+    fn end(&self) -> usize {
+        self.start + self.len
+    }
+}
+
+/// Returns the unmapped bytes between each range and the next. Ranges must be sorted by address
+fn gaps(ranges: &[MappedRange]) -> impl Iterator<Item = usize> + '_ {
+    ranges.windows(2).map(|pair| pair[1].start.saturating_sub(pair[0].end()))
+}
+
+/// Writes the ranges as a table sorted by address, showing the gap after each range. A segment can only
+/// grow in place when the gap after it is large enough
+pub(crate) fn write_text(ranges: &[MappedRange], out: &mut impl Write) -> io::Result<()> {
+    let (Some(first), Some(last)) = (ranges.first(), ranges.last()) else {
+        return writeln!(out, "0 segments mapped");
+    };
+
+    let mapped: usize = ranges.iter().map(|range| range.len).sum();
+    let span = last.end() - first.start;
+
+    writeln!(
+        out,
+        "{} segments mapping {} bytes between {:#x} and {:#x}",
+        ranges.len(),
+        mapped,
+        first.start,
+        last.end()
+    )?;
+    writeln!(
+        out,
+        "{:<18} {:<18} {:>12} {:>12} {:>5} {:<6} {:>14}",
+        "start", "end", "mapped", "used", "page", "state", "gap after"
+    )?;
+
+    let mut gaps_after = gaps(ranges);
+
+    for range in ranges {
+        let gap = match gaps_after.next() {
+            Some(0) => "adjoins".to_string(),
+            Some(gap) => gap.to_string(),
+            None => "-".to_string(),
+        };
+
+        writeln!(
+            out,
+            "{:#018x} {:#018x} {:>12} {:>12} {:>5} {:<6} {:>14}",
+            range.start,
+            range.end(),
+            range.len,
+            range.used,
+            range.page_size.short_name(),
+            range.state.name(),
+            gap
+        )?;
+    }
+
+    let (count, largest) = gaps(ranges)
+        .filter(|&gap| gap > 0)
+        .fold((0, 0), |(count, largest), gap| (count + 1, largest.max(gap)));
+
+    writeln!(
+        out,
+        "{} gaps, largest {} bytes, {:.1}% of the span mapped",
+        count,
+        largest,
+        mapped as f64 * 100.0 / span as f64
+    )
+}
+
+/// Writes the ranges as an SVG bar across the address space. Each range is drawn in proportion to its size
+/// and coloured by state, with live huge and default page segments told apart. Gaps are drawn in grey, no
+/// wider than the largest range so distant mappings don't squeeze everything else out of view. Hovering
+/// over a range shows its details
+pub(crate) fn write_svg(ranges: &[MappedRange], out: &mut impl Write) -> io::Result<()> {
+    let largest = ranges.iter().map(|range| range.len).max().unwrap_or(0);
+
+    let gap_widths: Vec<usize> = gaps(ranges).map(|gap| gap.min(largest)).collect();
+
+    let units = ranges.iter().map(|range| range.len).sum::<usize>() + gap_widths.iter().sum::<usize>();
+    let scale = if units == 0 { 0.0 } else { SVG_WIDTH / units as f64 };
+
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="12">"#,
+        SVG_WIDTH,
+        SVG_HEIGHT + 20.0
+    )?;
+
+    let mut x = 0.0;
+
+    for (i, range) in ranges.iter().enumerate() {
+        let width = range.len as f64 * scale;
+
+        writeln!(
+            out,
+            r#"<rect x="{:.2}" y="0" width="{:.2}" height="{}" fill="{}" stroke="white" stroke-width="0.5"><title>{:#x}-{:#x} {} bytes {} {}</title></rect>"#,
+            x,
+            width,
+            SVG_HEIGHT,
+            range.state.colour(range.page_size),
+            range.start,
+            range.end(),
+            range.len,
+            range.page_size.short_name(),
+            range.state.name()
+        )?;
+
+        x += width;
+
+        if let Some(&gap_width) = gap_widths.get(i) {
+            let width = gap_width as f64 * scale;
+
+            if width > 0.0 {
+                writeln!(
+                    out,
+                    r##"<rect x="{:.2}" y="0" width="{:.2}" height="{}" fill="#dddddd"><title>gap {} bytes</title></rect>"##,
+                    x,
+                    width,
+                    SVG_HEIGHT,
+                    ranges[i + 1].start.saturating_sub(range.end())
+                )?;
+            }
+
+            x += width;
+        }
+    }
+
+    if let (Some(first), Some(last)) = (ranges.first(), ranges.last()) {
+        writeln!(
+            out,
+            r#"<text x="0" y="{}">{:#x}</text>"#,
+            SVG_HEIGHT + 15.0,
+            first.start
+        )?;
+        writeln!(
+            out,
+            r#"<text x="{}" y="{}" text-anchor="end">{:#x}</text>"#,
+            SVG_WIDTH,
+            SVG_HEIGHT + 15.0,
+            last.end()
+        )?;
+    }
+
+    writeln!(out, "</svg>")
+}
@@ -0,0 +1,100 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+use crate::HugeAllocator;
+
+/// A reference counted [`HugeAllocator`] handle which implements the Allocator trait, so collections can
+/// own their allocator rather than borrow it. The trait can't be implemented on `Arc<HugeAllocator>`
+/// directly as both the trait and Arc are foreign to this crate.
+///
+/// [`HugeAllocator`] is itself a cheap cloneable handle so can be used by value in the same way - this
+/// type is for code which already holds an `Arc<HugeAllocator>`
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::{ArcHugeAllocator, HugeAllocator};
+///
+/// struct Cache {
+///     data: Vec<u8, ArcHugeAllocator>,
+/// }
+///
+/// let allocator = ArcHugeAllocator::from(HugeAllocator::new(50));
+///
+/// let cache = Cache {
+///     data: Vec::with_capacity_in(4 * 1024 * 1024, allocator.clone()),
+/// };
+///
+/// assert_eq!(1, allocator.stats().unwrap().segments);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ArcHugeAllocator(Arc<HugeAllocator>);
+
+impl ArcHugeAllocator {
+    /// Creates a new reference counted allocator with a given threshold percentage
+    pub fn new(threshold_pct: usize) -> Self {
+        Self::from(HugeAllocator::new(threshold_pct))
+    }
+}
+
+impl From<HugeAllocator> for ArcHugeAllocator {
+    fn from(allocator: HugeAllocator) -> Self {
+        Self(Arc::new(allocator))
+    }
+}
+
+impl From<Arc<HugeAllocator>> for ArcHugeAllocator {
+    fn from(allocator: Arc<HugeAllocator>) -> Self {
+        Self(allocator)
+    }
+}
+
+impl Deref for ArcHugeAllocator {
+    type Target = HugeAllocator;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+unsafe impl Allocator for ArcHugeAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.deallocate(ptr, layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.allocate_zeroed(layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.grow(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.0.shrink(ptr, old_layout, new_layout)
+    }
+}
@@ -0,0 +1,98 @@
+use std::cell::RefCell;
+use std::mem::size_of;
+
+use crate::HugeAllocator;
+
+/// Size of the first chunk of an arena in bytes
+const FIRST_CHUNK_BYTES: usize = 2 * 1024 * 1024;
+
+/// A typed arena allocating objects of a single type from huge page segments. Objects live as long as
+/// the arena and are all dropped together when the arena is dropped
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::{HugeAllocator, TypedHugeArena};
+///
+/// struct Node<'a> {
+///     value: u32,
+///     next: Option<&'a Node<'a>>,
+/// }
+///
+/// let allocator = HugeAllocator::new(50);
+/// let arena = TypedHugeArena::new(&allocator);
+///
+/// let first = arena.alloc(Node { value: 1, next: None });
+/// let second = arena.alloc(Node { value: 2, next: Some(first) });
+///
+/// assert_eq!(1, second.next.unwrap().value);
+/// ```
+pub struct TypedHugeArena<'a, T> {
+    allocator: &'a HugeAllocator,
+    /// Fixed capacity chunks - these never reallocate so references to their contents remain valid
+    chunks: RefCell<Vec<Vec<T, &'a HugeAllocator>>>,
+}
+
+impl<'a, T> TypedHugeArena<'a, T> {
+    /// Creates a new empty arena. No memory is allocated until the first object is added
+    pub fn new(allocator: &'a HugeAllocator) -> Self {
+        Self {
+            allocator,
+            chunks: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Moves an object in to the arena, returning a mutable reference to it
+    ///
+    /// # Panics
+    ///
+    /// Panics if a new segment can't be allocated
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc(&self, value: T) -> &mut T {
+        match self.try_alloc(value) {
+            Ok(r) => r,
+            Err(_) => panic!("{}: arena allocation failed", self.allocator.mapper.ident()),
+        }
+    }
+
+    /// Moves an object in to the arena, returning a mutable reference to it or the value back if a new
+    /// segment can't be allocated
+    #[allow(clippy::mut_from_ref)]
+    pub fn try_alloc(&self, value: T) -> Result<&mut T, T> {
+        let mut chunks = self.chunks.borrow_mut();
+
+        let full = match chunks.last() {
+            Some(chunk) => chunk.len() == chunk.capacity(),
+            None => true,
+        };
+
+        if full {
+            // Allocate a new chunk double the size of the last
+            let capacity = match chunks.last() {
+                Some(chunk) => chunk.capacity() * 2,
+                None => (FIRST_CHUNK_BYTES / size_of::<T>().max(1)).max(1),
+            };
+
+            match Vec::try_with_capacity_in(capacity, self.allocator) {
+                Ok(chunk) => chunks.push(chunk),
+                Err(_) => return Err(value),
+            }
+        }
+
+        let chunk = chunks.last_mut().unwrap();
+
+        chunk.push(value);
+
+        // The chunk never reallocates and is never shrunk while the arena is alive
+        Ok(unsafe { &mut *chunk.as_mut_ptr().add(chunk.len() - 1) })
+    }
+
+    /// Returns the number of objects in the arena
+    pub fn len(&self) -> usize {
+        self.chunks.borrow().iter().map(|chunk| chunk.len()).sum()
+    }
+
+    /// Returns true if the arena holds no objects
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,124 @@
+use std::alloc::Layout;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::slice;
+
+use crate::{AllocError, AllocOptions, HugeAllocator};
+
+/// Alignment and size multiple guaranteed for buffers returned by [`HugeAllocator::alloc_direct_io_buffer`].
+/// This covers the logical block size of all common block devices
+pub const DIRECT_IO_ALIGN: usize = 4096;
+
+/// A zero-initialised byte buffer allocated from a [`HugeAllocator`]. The buffer is released when dropped.
+/// This needs no allocator traits so works on a stable toolchain
+///
+/// ```rust
+/// use huge_allocator::HugeAllocator;
+///
+/// let allocator = HugeAllocator::new(50);
+///
+/// let mut buf = allocator.alloc_raw(4 * 1024 * 1024).unwrap();
+/// buf[0] = 1;
+///
+/// assert_eq!(4 * 1024 * 1024, buf.len());
+/// ```
+pub struct HugeBuf<'a> {
+    allocator: &'a HugeAllocator,
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl<'a> HugeBuf<'a> {
+    /// Allocates a new buffer of the given size
+    pub(crate) fn new(allocator: &'a HugeAllocator, size: usize) -> Result<Self, AllocError> {
+        let layout = Layout::from_size_align(size, 1).map_err(|_| AllocError)?;
+
+        Self::with_layout(allocator, layout)
+    }
+
+    /// Allocates a new buffer suitable for O_DIRECT I/O. The size is rounded up to a multiple of
+    /// [`DIRECT_IO_ALIGN`]
+    pub(crate) fn new_direct_io(allocator: &'a HugeAllocator, size: usize) -> Result<Self, AllocError> {
+        let size = size.checked_next_multiple_of(DIRECT_IO_ALIGN).ok_or(AllocError)?;
+        let layout = Layout::from_size_align(size, DIRECT_IO_ALIGN).map_err(|_| AllocError)?;
+
+        Self::with_layout(allocator, layout)
+    }
+
+    /// Allocates a new buffer with the given layout
+    fn with_layout(allocator: &'a HugeAllocator, layout: Layout) -> Result<Self, AllocError> {
+        Self::with_options(allocator, layout, &allocator.mapper.default_options())
+    }
+
+    /// Allocates a new buffer with the given layout and mapping options
+    pub(crate) fn with_options(
+        allocator: &'a HugeAllocator,
+        layout: Layout,
+        options: &AllocOptions,
+    ) -> Result<Self, AllocError> {
+        let ptr = allocator.mapper.alloc_with(layout, options)?;
+
+        Ok(Self {
+            allocator,
+            ptr: ptr.cast(),
+            layout,
+        })
+    }
+
+    /// Returns a raw pointer to the buffer
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// Returns a raw mutable pointer to the buffer
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+}
+
+unsafe impl Send for HugeBuf<'_> {}
+unsafe impl Sync for HugeBuf<'_> {}
+
+impl Deref for HugeBuf<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for HugeBuf<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl AsRef<[u8]> for HugeBuf<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for HugeBuf<'_> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl fmt::Debug for HugeBuf<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HugeBuf")
+            .field("ptr", &self.ptr)
+            .field("len", &self.layout.size())
+            .finish()
+    }
+}
+
+impl Drop for HugeBuf<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.allocator.mapper.dealloc(self.ptr, self.layout) {
+            panic!("{}: failed to release HugeBuf ({})", self.allocator.mapper.ident(), e);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::checkpoint;
+use crate::mmapper::MMapper;
+use crate::options::{AllocOptions, NumaPolicy, ShrinkPolicy};
+use crate::quota::Quota;
+use crate::registry;
+use crate::trace::TraceRecorder;
+use crate::HugeAllocator;
+
+/// Callback receiving diagnostic messages from the allocator
+pub type LogSink = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Callback receiving the address and mapped length of a segment
+pub type SegmentHook = Arc<dyn Fn(*mut u8, usize) + Send + Sync>;
+
+/// Allocator configuration
+#[derive(Clone)]
+pub(crate) struct Config {
+    /// Allocator instance name
+    pub(crate) name: Option<String>,
+    /// Threshold percentage to try and use huge pages.
+    /// For example a threshold percentage of 50 will try and allocate a 2mb page for allocations >= 1mb
+    pub(crate) threshold_pct: usize,
+    /// Panic if a segment fails to unmap
+    pub(crate) strict_unmap: bool,
+    /// Wipe memory before it is unmapped or released by a shrink
+    pub(crate) zero_on_free: bool,
+    /// Unmap freed segments on a background thread
+    pub(crate) deferred_unmap: bool,
+    /// Number of freed segments collected before they are unmapped together, 0 to unmap each as it is freed
+    pub(crate) unmap_epoch: usize,
+    /// Options applied to allocations made through the Allocator trait
+    pub(crate) default_options: AllocOptions,
+    /// Name mappings in /proc/<pid>/maps
+    pub(crate) name_vmas: bool,
+    /// Label used to name mappings instead of one derived from the allocator name
+    pub(crate) vma_label: Option<String>,
+    /// Destination for diagnostic messages
+    pub(crate) log_sink: Option<LogSink>,
+    /// Called after a segment is mapped
+    pub(crate) on_map: Option<SegmentHook>,
+    /// Called before a segment is unmapped
+    pub(crate) on_unmap: Option<SegmentHook>,
+    /// Maximum bytes of freed segments kept mapped for reuse
+    pub(crate) segment_cache: usize,
+    /// NUMA placement of segments without a node in their options
+    pub(crate) numa_policy: NumaPolicy,
+    /// Handling of huge page segments shrunk below the threshold
+    pub(crate) shrink_policy: ShrinkPolicy,
+    /// Fail allocations at or above the threshold rather than falling back to default pages
+    pub(crate) no_fallback: bool,
+    /// Advise transparent huge pages on segments which fell back to the default page size
+    pub(crate) thp_fallback: bool,
+    /// Map segments which fell back to the default page size 2mb aligned and in 2mb multiples
+    pub(crate) align_fallback: bool,
+    /// Count huge page allocations served from surplus huge pages
+    pub(crate) track_surplus: bool,
+    /// Minimum time between repeated warnings about the same condition
+    pub(crate) warn_interval: Duration,
+    /// Base address new mappings are placed upwards from, if any
+    pub(crate) address_hint: Option<usize>,
+    /// Number of mappings at which to warn and trim the segment cache, or None for 90% of vm.max_map_count
+    pub(crate) map_count_limit: Option<usize>,
+    /// Recorder for every allocation, free and resize
+    pub(crate) trace_recorder: Option<Arc<TraceRecorder>>,
+    /// Capture the call stack of one in this many allocations, 0 to disable
+    pub(crate) sample_every: usize,
+    /// Map allocations directly without recording them in the pointer map
+    pub(crate) untracked: bool,
+    /// Maximum bytes of live allocations, including those of child allocators, or None if unlimited
+    pub(crate) quota: Option<usize>,
+    /// Budget of the parent allocator for child allocators
+    pub(crate) parent_quota: Option<Arc<Quota>>,
+    /// File to write the final statistics to when the allocator is dropped or the process exits, if any
+    pub(crate) exit_stats_path: Option<PathBuf>,
+    /// Include the leak report in the exit checkpoint
+    pub(crate) exit_leak_report: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            name: None,
+            threshold_pct: 50,
+            strict_unmap: false,
+            zero_on_free: false,
+            deferred_unmap: false,
+            unmap_epoch: 0,
+            default_options: AllocOptions::default(),
+            name_vmas: false,
+            vma_label: None,
+            log_sink: None,
+            on_map: None,
+            on_unmap: None,
+            segment_cache: 0,
+            numa_policy: NumaPolicy::FirstTouch,
+            shrink_policy: ShrinkPolicy::Demote,
+            no_fallback: false,
+            thp_fallback: true,
+            align_fallback: true,
+            track_surplus: false,
+            warn_interval: Duration::from_secs(60),
+            address_hint: None,
+            map_count_limit: None,
+            trace_recorder: None,
+            sample_every: 0,
+            untracked: false,
+            quota: None,
+            parent_quota: None,
+            exit_stats_path: None,
+            exit_leak_report: false,
+        }
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("name", &self.name)
+            .field("threshold_pct", &self.threshold_pct)
+            .field("strict_unmap", &self.strict_unmap)
+            .field("zero_on_free", &self.zero_on_free)
+            .field("deferred_unmap", &self.deferred_unmap)
+            .field("unmap_epoch", &self.unmap_epoch)
+            .field("default_options", &self.default_options)
+            .field("name_vmas", &self.name_vmas)
+            .field("vma_label", &self.vma_label)
+            .field("log_sink", &self.log_sink.is_some())
+            .field("on_map", &self.on_map.is_some())
+            .field("on_unmap", &self.on_unmap.is_some())
+            .field("segment_cache", &self.segment_cache)
+            .field("numa_policy", &self.numa_policy)
+            .field("shrink_policy", &self.shrink_policy)
+            .field("no_fallback", &self.no_fallback)
+            .field("thp_fallback", &self.thp_fallback)
+            .field("align_fallback", &self.align_fallback)
+            .field("track_surplus", &self.track_surplus)
+            .field("warn_interval", &self.warn_interval)
+            .field("address_hint", &self.address_hint.map(|addr| format!("{addr:#x}")))
+            .field("map_count_limit", &self.map_count_limit)
+            .field("trace_recorder", &self.trace_recorder)
+            .field("sample_every", &self.sample_every)
+            .field("untracked", &self.untracked)
+            .field("quota", &self.quota)
+            .field("parent_quota", &self.parent_quota.is_some())
+            .field("exit_stats_path", &self.exit_stats_path)
+            .field("exit_leak_report", &self.exit_leak_report)
+            .finish()
+    }
+}
+
+/// Builder for a [`HugeAllocator`] with non-default options
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::HugeAllocator;
+///
+/// let allocator = HugeAllocator::builder()
+///     .threshold_pct(75)
+///     .log_sink(|msg| eprintln!("{}", msg))
+///     .build();
+///
+/// let vec: Vec<u8, _> = Vec::with_capacity_in(2 * 1024 * 1024, &allocator);
+/// ```
+#[derive(Debug, Default)]
+pub struct HugeAllocatorBuilder {
+    config: Config,
+}
+
+impl HugeAllocatorBuilder {
+    /// Creates a new builder with default options (threshold percentage of 50)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the threshold percentage of a huge page at which huge pages are tried
+    pub fn threshold_pct(mut self, threshold_pct: usize) -> Self {
+        self.config.threshold_pct = threshold_pct;
+        self
+    }
+
+    /// Panic if a segment fails to unmap instead of counting the failure and continuing
+    pub fn strict_unmap(mut self, strict: bool) -> Self {
+        self.config.strict_unmap = strict;
+        self
+    }
+
+    /// Wipe memory with explicit volatile writes before it is unmapped or released by a shrink.
+    /// Use this when allocations hold sensitive data such as key material
+    pub fn zero_on_free(mut self, zero: bool) -> Self {
+        self.config.zero_on_free = zero;
+        self
+    }
+
+    /// Unmaps freed segments on a background thread so the thread freeing memory doesn't wait for munmap.
+    /// Segments waiting to be unmapped are reported in the `deferred_segments` and `deferred_bytes`
+    /// statistics, and the queue is drained when the allocator is dropped. Unmap failures on the background
+    /// thread are counted and logged. In strict mode they panic on the next thread to free memory or wait for
+    /// the queue, rather than on the background thread. Defaults to false
+    pub fn deferred_unmap(mut self, deferred: bool) -> Self {
+        self.config.deferred_unmap = deferred;
+        self
+    }
+
+    /// Collects freed segments into epochs of `segments` segments which are unmapped together, so a burst of
+    /// frees takes the lock once per epoch and adjacent segments are released with a single munmap. Freed
+    /// memory stays mapped until its epoch fills, [`HugeAllocator::flush`] is called or the allocator is
+    /// dropped. Combine with [`deferred_unmap`](Self::deferred_unmap) to hand whole epochs to the background
+    /// thread. Defaults to 0 (unmap each segment as it is freed)
+    pub fn unmap_epoch(mut self, segments: usize) -> Self {
+        self.config.unmap_epoch = segments;
+        self
+    }
+
+    /// Sets the mapping options applied to allocations made through the Allocator trait
+    pub fn default_options(mut self, options: AllocOptions) -> Self {
+        self.config.default_options = options;
+        self
+    }
+
+    /// Names the allocator instance. The name appears in statistics, log messages, panics and mapping names
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.config.name = Some(name.into());
+        self
+    }
+
+    /// Names each mapping "huge_allocator:<page size>" (e.g. "huge_allocator:2m"), or
+    /// "huge_allocator:<name>:<page size>" for a named allocator, so they can be identified in
+    /// /proc/<pid>/maps and smaps. Requires Linux 5.17 or later - naming failures are ignored
+    pub fn name_vmas(mut self, name: bool) -> Self {
+        self.config.name_vmas = name;
+        self
+    }
+
+    /// Names each mapping "<label>:<page size>" in /proc/<pid>/maps and smaps. Requires Linux 5.17 or
+    /// later - naming failures are ignored
+    pub fn vma_label(mut self, label: impl Into<String>) -> Self {
+        self.config.name_vmas = true;
+        self.config.vma_label = Some(label.into());
+        self
+    }
+
+    /// Sets a callback to receive diagnostic messages
+    pub fn log_sink<F>(mut self, sink: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.config.log_sink = Some(Arc::new(sink));
+        self
+    }
+
+    /// Sets a callback run with the address and mapped length of each segment after it is mapped, for
+    /// example to register it with `cudaHostRegister`. Segments which move on reallocation are reported
+    /// to the unmap callback at their old address and then to this callback at their new address
+    pub fn on_map<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(*mut u8, usize) + Send + Sync + 'static,
+    {
+        self.config.on_map = Some(Arc::new(hook));
+        self
+    }
+
+    /// Sets a callback run with the address and mapped length of each segment before it is unmapped, for
+    /// example to unregister it with `cudaHostUnregister`
+    pub fn on_unmap<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(*mut u8, usize) + Send + Sync + 'static,
+    {
+        self.config.on_unmap = Some(Arc::new(hook));
+        self
+    }
+
+    /// Keeps up to the given number of bytes of freed segments mapped so later allocations needing the same
+    /// number of pages or fewer can reuse them without a system call. Adjacent cached segments are merged so
+    /// they can satisfy larger allocations too. Cached segments are released by
+    /// [`HugeAllocator::trim`] or a [`PressureMonitor`](crate::PressureMonitor). Defaults to 0 (disabled)
+    pub fn segment_cache(mut self, max_bytes: usize) -> Self {
+        self.config.segment_cache = max_bytes;
+        self
+    }
+
+    /// Sets the NUMA placement of segments which don't have a node set in their options. Defaults to
+    /// [`NumaPolicy::FirstTouch`]
+    pub fn numa_policy(mut self, policy: NumaPolicy) -> Self {
+        self.config.numa_policy = policy;
+        self
+    }
+
+    /// Sets what happens to huge page allocations reallocated below the huge page threshold. Defaults to
+    /// [`ShrinkPolicy::Demote`]
+    pub fn shrink_policy(mut self, policy: ShrinkPolicy) -> Self {
+        self.config.shrink_policy = policy;
+        self
+    }
+
+    /// Fails allocations at or above the huge page threshold with `AllocError` when huge pages can't be
+    /// mapped, instead of silently falling back to the default page size. Use this for benchmarking and
+    /// latency critical deployments where degraded performance is worse than failure
+    pub fn no_fallback(mut self, no_fallback: bool) -> Self {
+        self.config.no_fallback = no_fallback;
+        self
+    }
+
+    /// Offers allocations which fall back to the default page size to transparent huge pages, advising
+    /// MADV_HUGEPAGE where the host's THP settings call for it (see [`ThpFallback`](crate::ThpFallback)). Only
+    /// segments of at least 2mb are considered. Eligible segments are counted in the `thp_eligible` statistic.
+    /// Defaults to true
+    pub fn thp_fallback(mut self, thp: bool) -> Self {
+        self.config.thp_fallback = thp;
+        self
+    }
+
+    /// Maps allocations which fall back to the default page size starting on a 2mb boundary and in whole
+    /// 2mb units, so transparent huge pages can back (or later collapse) the whole segment. The padding is
+    /// included in the mapped statistics. Defaults to true
+    pub fn align_fallback(mut self, align: bool) -> Self {
+        self.config.align_fallback = align;
+        self
+    }
+
+    /// Counts huge page allocations served from surplus (overcommitted) huge pages in the `surplus_allocs` and
+    /// `surplus_bytes` statistics. The system wide surplus counter is read before and after each huge page
+    /// mapping, which costs two sysfs reads per mapping, and growth caused by other processes in between is
+    /// attributed to this allocator, so the figures are best-effort. Defaults to false
+    pub fn track_surplus(mut self, track: bool) -> Self {
+        self.config.track_surplus = track;
+        self
+    }
+
+    /// Sets the minimum time between warnings sent to the log sink about the same condition (huge page
+    /// fallbacks, remap failures and object pool exhaustion). Occurrences in between are counted and reported
+    /// with the next warning. Defaults to 60 seconds
+    pub fn warn_interval(mut self, interval: Duration) -> Self {
+        self.config.warn_interval = interval;
+        self
+    }
+
+    /// Places new mappings contiguously upwards from the given base address (rounded up to a huge page), so
+    /// the allocator's memory occupies one range of the address space. Mappings which would overlap an
+    /// existing mapping are placed anywhere instead. Freed ranges are not reused, and once a mapping can't be
+    /// placed in the region for another reason (such as reaching the top of the address space) hinting stops.
+    /// See [`HugeAllocator::hint_region`]
+    pub fn address_hint(mut self, base: usize) -> Self {
+        self.config.address_hint = Some(base);
+        self
+    }
+
+    /// Places new mappings contiguously from a base address derived from the seed, so repeated runs making
+    /// the same allocations get the same addresses as far as the kernel allows. Different seeds give
+    /// different 1gb aligned bases between 16tb and 80tb. Overrides [`address_hint`](Self::address_hint)
+    pub fn address_seed(mut self, seed: u64) -> Self {
+        self.config.address_hint = Some(seeded_base(seed));
+        self
+    }
+
+    /// Sets the number of mappings (live and cached segments) at which the allocator trims its segment cache
+    /// and warns that it is approaching vm.max_map_count. Every allocation is a separate mapping, and mmap
+    /// fails with ENOMEM once the process reaches the limit. Defaults to 90% of vm.max_map_count. 0 disables
+    /// the check
+    pub fn map_count_limit(mut self, mappings: usize) -> Self {
+        self.config.map_count_limit = Some(mappings);
+        self
+    }
+
+    /// Records every allocation, free and resize in the given [`TraceRecorder`]
+    pub fn trace_recorder(mut self, recorder: Arc<TraceRecorder>) -> Self {
+        self.config.trace_recorder = Some(recorder);
+        self
+    }
+
+    /// Captures the call stack of one in every `every` allocations and attributes the bytes they hold to it
+    /// until they are freed. The live bytes by call stack are reported by [`HugeAllocator::profile`]. Defaults
+    /// to 0 (disabled)
+    pub fn sample_backtraces(mut self, every: usize) -> Self {
+        self.config.sample_every = every;
+        self
+    }
+
+    /// Maps allocations directly without recording them in the pointer map, so allocating and freeing take no
+    /// locks. The mapped length is worked out again from the layout, as with [`RawHugeAlloc`](crate::RawHugeAlloc),
+    /// so deallocations must pass the exact layout and the returned slice is only as long as the layout.
+    /// Untracked allocations don't appear in statistics or any other introspection, ignore mapping options,
+    /// hooks and the segment cache, and are not unmapped when the allocator is dropped. Tagged allocations
+    /// are refused as stale handles can't be detected
+    pub fn untracked(mut self, untracked: bool) -> Self {
+        self.config.untracked = untracked;
+        self
+    }
+
+    /// Limits the bytes of live allocations to `bytes`, including those made by [child
+    /// allocators](HugeAllocator::child). Allocations which would exceed the quota fail. Untracked allocations
+    /// are not counted
+    pub fn quota(mut self, bytes: usize) -> Self {
+        self.config.quota = Some(bytes);
+        self
+    }
+
+    /// Writes a final snapshot of the statistics to `path` when the allocator is dropped, or at process exit
+    /// if it is still alive then, so short-lived jobs leave a record behind. The file is replaced each run.
+    /// Failures to write it are sent to the log sink. Not inherited by child allocators
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let path = std::env::temp_dir().join("huge_allocator_exit_stats_doc.txt");
+    ///
+    /// let allocator = HugeAllocator::builder()
+    ///     .name("batch")
+    ///     .exit_stats(&path)
+    ///     .exit_leak_report(true)
+    ///     .build();
+    ///
+    /// let vec: Vec<u8, _> = Vec::with_capacity_in(4096, &allocator);
+    /// drop(vec);
+    /// drop(allocator);
+    ///
+    /// let checkpoint = std::fs::read_to_string(&path).unwrap();
+    /// assert!(checkpoint.contains("batch: no allocations leaked"));
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn exit_stats(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.exit_stats_path = Some(path.into());
+        self
+    }
+
+    /// Appends a list of the allocations still live to the exit checkpoint configured with
+    /// [`exit_stats`](Self::exit_stats). Defaults to false
+    pub fn exit_leak_report(mut self, leak_report: bool) -> Self {
+        self.config.exit_leak_report = leak_report;
+        self
+    }
+
+    /// Creates a builder for a child allocator with the parent's options, drawing from the parent's quota.
+    /// Options tied to the parent instance (address hint, trace recorder, sampling profiler, unmap thread and
+    /// exit checkpoint) are not inherited
+    pub(crate) fn child_of(parent: &MMapper, name: String, quota: usize) -> Self {
+        let mut config = parent.config().clone();
+
+        config.name = Some(name);
+        config.vma_label = None;
+        config.quota = Some(quota);
+        config.parent_quota = Some(parent.quota().clone());
+        config.exit_stats_path = None;
+
+        // The child places its own mappings and records its own activity
+        config.address_hint = None;
+        config.trace_recorder = None;
+        config.sample_every = 0;
+        config.deferred_unmap = false;
+
+        Self { config }
+    }
+
+    /// Builds the allocator
+    pub fn build(self) -> HugeAllocator {
+        let mapper = Arc::new(MMapper::new(self.config));
+
+        registry::register(&mapper);
+
+        if mapper.config().exit_stats_path.is_some() {
+            checkpoint::register(&mapper);
+        }
+
+        HugeAllocator { mapper }
+    }
+}
+
+/// Derives a base address from a seed with the splitmix64 mixer
+fn seeded_base(seed: u64) -> usize {
+    const BASE: usize = 0x1000_0000_0000;
+    const SLOTS: u64 = 64 * 1024;
+    const SLOT_SIZE: usize = 1024 * 1024 * 1024;
+
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+
+    BASE + (z % SLOTS) as usize * SLOT_SIZE
+}
@@ -0,0 +1,181 @@
+//! Hugetlb cgroup (v2) limit discovery
+//!
+//! Containers commonly limit the number of huge pages a cgroup may fault in with `hugetlb.<size>.max`.
+//! Faulting past the limit kills the process with SIGBUS rather than failing the mapping, so the allocator
+//! checks the headroom left in the process's cgroup and its ancestors before mapping huge pages. The
+//! allocators re-read the limit at most every 100ms, deducting the huge pages they map in the meantime
+//!
+//! ```rust
+//! use huge_allocator::cgroup;
+//! use huge_allocator::PageSize;
+//!
+//! match cgroup::hugetlb_limit(PageSize::Size2m) {
+//!     Some(limit) => println!("{} bytes of huge pages left", limit.headroom()),
+//!     None => println!("huge pages not limited"),
+//! }
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::mmap::PageSize;
+
+/// How long the allocators reuse a 2mb hugetlb limit before reading the cgroup files again
+const LIMIT_TTL: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    /// The cgroup v2 directory of the process, if the unified hierarchy is mounted
+    static ref CGROUP_DIR: Option<PathBuf> = cgroup_dir();
+}
+
+/// The 2mb hugetlb limit as last read for the allocators
+static HUGE_LIMIT: LimitCache = LimitCache::new(LIMIT_TTL);
+
+/// Hugetlb limit and usage of a cgroup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HugetlbLimit {
+    /// Maximum bytes of huge pages which may be faulted in
+    pub max: usize,
+    /// Bytes of huge pages currently faulted in
+    pub current: usize,
+}
+
+impl HugetlbLimit {
+    /// Returns the number of bytes of huge pages which can still be faulted in
+    pub fn headroom(&self) -> usize {
+        self.max.saturating_sub(self.current)
+    }
+}
+
+/// Returns the tightest hugetlb limit applying to the process for the given page size, checking its cgroup
+/// and every ancestor. Returns None if there is no limit or cgroup v2 is not in use
+pub fn hugetlb_limit(page_size: PageSize) -> Option<HugetlbLimit> {
+    let dir = CGROUP_DIR.as_ref()?;
+
+    hugetlb_limit_in(dir, page_size)
+}
+
+/// Returns the 2mb hugetlb limit applying to the process, read at most once per TTL
+pub(crate) fn cached_huge_limit() -> Option<HugetlbLimit> {
+    HUGE_LIMIT.get(|| hugetlb_limit(PageSize::Size2m))
+}
+
+/// Takes bytes of 2mb huge page headroom for a mapping, returning false if they would exceed the hugetlb
+/// limit. The limit is read at most once per TTL
+pub(crate) fn reserve_huge(bytes: usize) -> bool {
+    HUGE_LIMIT.try_reserve(bytes, || hugetlb_limit(PageSize::Size2m))
+}
+
+/// A hugetlb limit which is re-read once it is older than the TTL. Bytes reserved in between are added to
+/// the usage so mappings made before the next read can't overrun the limit
+pub(crate) struct LimitCache {
+    ttl: Duration,
+    /// Time of the last read and the limit read, if any
+    state: Mutex<Option<(Instant, Option<HugetlbLimit>)>>,
+}
+
+impl LimitCache {
+    /// Creates an empty cache reading the limit again after the TTL
+    pub(crate) const fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(None),
+        }
+    }
+
+    /// Returns the limit, reading it if the cached value has expired
+    pub(crate) fn get(&self, read: impl FnOnce() -> Option<HugetlbLimit>) -> Option<HugetlbLimit> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        Self::current(&mut state, self.ttl, read)
+    }
+
+    /// Adds bytes to the usage if they fit in the limit, reading it if the cached value has expired
+    pub(crate) fn try_reserve(&self, bytes: usize, read: impl FnOnce() -> Option<HugetlbLimit>) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        Self::current(&mut state, self.ttl, read);
+
+        match &mut *state {
+            Some((_, Some(limit))) if bytes > limit.headroom() => false,
+            Some((_, Some(limit))) => {
+                limit.current += bytes;
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// Refreshes an expired cached value, returning the limit
+    fn current(
+        state: &mut Option<(Instant, Option<HugetlbLimit>)>,
+        ttl: Duration,
+        read: impl FnOnce() -> Option<HugetlbLimit>,
+    ) -> Option<HugetlbLimit> {
+        match state {
+            Some((read_at, limit)) if read_at.elapsed() < ttl => *limit,
+            _ => state.insert((Instant::now(), read())).1,
+        }
+    }
+}
+
+/// Returns the tightest hugetlb limit for the cgroup directory and its ancestors
+pub(crate) fn hugetlb_limit_in(dir: &Path, page_size: PageSize) -> Option<HugetlbLimit> {
+    if page_size == PageSize::SizeDefault {
+        return None;
+    }
+
+    hugetlb_limit_named(dir, &format!("{}B", page_size.short_name().to_uppercase()))
+}
+
+/// Returns the tightest hugetlb limit applying to the process for a page size named as in the cgroup
+/// interface files (e.g. "2MB" or "1GB")
+pub(crate) fn hugetlb_limit_for(name: &str) -> Option<HugetlbLimit> {
+    hugetlb_limit_named(CGROUP_DIR.as_ref()?, name)
+}
+
+/// Returns the process's cgroup v2 directory, if the unified hierarchy is mounted
+pub(crate) fn dir() -> Option<&'static Path> {
+    CGROUP_DIR.as_deref()
+}
+
+/// Returns the tightest hugetlb limit for a named page size in the cgroup directory and its ancestors
+fn hugetlb_limit_named(dir: &Path, name: &str) -> Option<HugetlbLimit> {
+    let prefix = format!("hugetlb.{}", name);
+
+    dir.ancestors()
+        .filter_map(|dir| {
+            let max = read_value(&dir.join(format!("{}.max", prefix)))?;
+            let current = read_value(&dir.join(format!("{}.current", prefix)))?;
+
+            Some(HugetlbLimit { max, current })
+        })
+        .min_by_key(HugetlbLimit::headroom)
+}
+
+/// Reads a numeric cgroup value. "max" (unlimited) and unreadable files give None
+fn read_value(path: &Path) -> Option<usize> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Locates the cgroup v2 directory of the process from the mount table and /proc/self/cgroup
+fn cgroup_dir() -> Option<PathBuf> {
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+
+    let mount = mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mount = fields.nth(1)?;
+
+        (fields.next()? == "cgroup2").then(|| PathBuf::from(mount))
+    })?;
+
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+
+    Some(mount.join(path.trim_start_matches('/')))
+}
@@ -0,0 +1,92 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Once, PoisonError, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mmapper::MMapper;
+use crate::{AllocationInfo, HugeAllocatorStats};
+
+/// Installs the exit handler the first time an allocator with an exit checkpoint is built
+static INSTALL: Once = Once::new();
+
+/// Allocators which write a checkpoint at process exit if they are still alive
+static PENDING: Mutex<Vec<Weak<MMapper>>> = Mutex::new(Vec::new());
+
+/// Arranges for an allocator's checkpoint to be written at process exit if it hasn't been dropped by then
+pub(crate) fn register(mapper: &Arc<MMapper>) {
+    INSTALL.call_once(|| unsafe {
+        libc::atexit(at_exit);
+    });
+
+    let mut pending = PENDING.lock().unwrap_or_else(PoisonError::into_inner);
+
+    pending.retain(|mapper| mapper.strong_count() > 0);
+    pending.push(Arc::downgrade(mapper));
+}
+
+/// Writes the checkpoint of every allocator still alive
+extern "C" fn at_exit() {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap_or_else(PoisonError::into_inner));
+
+    for mapper in pending.iter().filter_map(Weak::upgrade) {
+        mapper.checkpoint("process exit");
+    }
+}
+
+/// Writes the checkpoint file, replacing any previous contents: a header saying what triggered it, the
+/// statistics and, if requested, the leak report
+pub(crate) fn write(
+    path: &Path,
+    ident: &str,
+    trigger: &str,
+    stats: &HugeAllocatorStats,
+    leaks: Option<String>,
+) -> io::Result<()> {
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+
+    let mut contents = format!("{} checkpoint at {} (unix ms {})\n{:#?}\n", ident, trigger, timestamp_ms, stats);
+
+    if let Some(leaks) = leaks {
+        let _ = writeln!(contents, "{}", leaks);
+    }
+
+    fs::write(path, contents)
+}
+
+/// Describes the given live allocations, or returns None if there are none
+pub(crate) fn leak_report(name: &str, mut leaks: Vec<AllocationInfo>) -> Option<String> {
+    if leaks.is_empty() {
+        return None;
+    }
+
+    leaks.sort_by_key(|info| info.ptr);
+
+    let bytes: usize = leaks.iter().map(|info| info.layout.size()).sum();
+
+    let mut report = format!(
+        "{}: {} allocation{} leaked ({} bytes)",
+        name,
+        leaks.len(),
+        if leaks.len() == 1 { "" } else { "s" },
+        bytes
+    );
+
+    for info in &leaks {
+        let _ = write!(
+            report,
+            "\n  {:#x}: {} bytes aligned to {} ({} bytes mapped, {:?}, generation {})",
+            info.ptr,
+            info.layout.size(),
+            info.layout.align(),
+            info.mapped,
+            info.page_size,
+            info.generation
+        );
+    }
+
+    Some(report)
+}
@@ -0,0 +1,119 @@
+//! Helpers to construct standard collections on an allocator without the unstable `*_in` constructors
+//!
+//! ```rust
+//! #![feature(allocator_api)]
+//! use huge_allocator::prelude::*;
+//!
+//! let allocator = HugeAllocator::new(50);
+//!
+//! let mut vec = huge_vec_with_capacity::<u64, _>(1024 * 1024, &allocator);
+//! vec.push(1);
+//!
+//! let boxed = huge_box([0u8; 4096], &allocator);
+//! ```
+
+use std::alloc::{AllocError, Allocator};
+use std::collections::VecDeque;
+#[cfg(feature = "hashbrown")]
+use std::hash::Hash;
+
+/// Creates an empty vector using the allocator
+pub fn huge_vec<T, A: Allocator>(alloc: A) -> Vec<T, A> {
+    Vec::new_in(alloc)
+}
+
+/// Creates a vector with room for at least `capacity` elements using the allocator
+pub fn huge_vec_with_capacity<T, A: Allocator>(capacity: usize, alloc: A) -> Vec<T, A> {
+    Vec::with_capacity_in(capacity, alloc)
+}
+
+/// Creates a vector with room for at least `capacity` elements using the allocator, returning an error
+/// instead of aborting if the memory can't be allocated
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::collections::try_huge_vec_with_capacity;
+/// use huge_allocator::HugeAllocator;
+///
+/// let allocator = HugeAllocator::new(50);
+///
+/// assert!(try_huge_vec_with_capacity::<u64, _>(1 << 60, &allocator).is_err());
+/// ```
+pub fn try_huge_vec_with_capacity<T, A: Allocator>(capacity: usize, alloc: A) -> Result<Vec<T, A>, AllocError> {
+    let mut vec = Vec::new_in(alloc);
+
+    vec.try_reserve_exact(capacity).map_err(|_| AllocError)?;
+
+    Ok(vec)
+}
+
+/// Creates a double ended queue with room for at least `capacity` elements using the allocator
+pub fn huge_vec_deque_with_capacity<T, A: Allocator>(capacity: usize, alloc: A) -> VecDeque<T, A> {
+    VecDeque::with_capacity_in(capacity, alloc)
+}
+
+/// Creates a double ended queue with room for at least `capacity` elements using the allocator, returning
+/// an error instead of aborting if the memory can't be allocated
+pub fn try_huge_vec_deque_with_capacity<T, A: Allocator>(
+    capacity: usize,
+    alloc: A,
+) -> Result<VecDeque<T, A>, AllocError> {
+    let mut deque = VecDeque::new_in(alloc);
+
+    deque.try_reserve_exact(capacity).map_err(|_| AllocError)?;
+
+    Ok(deque)
+}
+
+/// Moves a value in to a box using the allocator
+pub fn huge_box<T, A: Allocator>(value: T, alloc: A) -> Box<T, A> {
+    Box::new_in(value, alloc)
+}
+
+/// Moves a value in to a box using the allocator, returning an error instead of aborting if the memory
+/// can't be allocated
+pub fn try_huge_box<T, A: Allocator>(value: T, alloc: A) -> Result<Box<T, A>, AllocError> {
+    Box::try_new_in(value, alloc)
+}
+
+/// A hash map using the allocator for its table
+#[cfg(feature = "hashbrown")]
+pub type HugeHashMap<K, V, A> = hashbrown::HashMap<K, V, hashbrown::DefaultHashBuilder, A>;
+
+/// A hash set using the allocator for its table
+#[cfg(feature = "hashbrown")]
+pub type HugeHashSet<T, A> = hashbrown::HashSet<T, hashbrown::DefaultHashBuilder, A>;
+
+/// Creates an empty hash map using the allocator
+#[cfg(feature = "hashbrown")]
+pub fn huge_hashmap<K, V, A: allocator_api2::alloc::Allocator>(alloc: A) -> HugeHashMap<K, V, A> {
+    hashbrown::HashMap::new_in(alloc)
+}
+
+/// Creates a hash map with room for at least `capacity` entries using the allocator
+#[cfg(feature = "hashbrown")]
+pub fn huge_hashmap_with_capacity<K, V, A: allocator_api2::alloc::Allocator>(
+    capacity: usize,
+    alloc: A,
+) -> HugeHashMap<K, V, A> {
+    hashbrown::HashMap::with_capacity_in(capacity, alloc)
+}
+
+/// Creates a hash map with room for at least `capacity` entries using the allocator, returning an error
+/// instead of aborting if the memory can't be allocated
+#[cfg(feature = "hashbrown")]
+pub fn try_huge_hashmap_with_capacity<K: Hash + Eq, V, A: allocator_api2::alloc::Allocator>(
+    capacity: usize,
+    alloc: A,
+) -> Result<HugeHashMap<K, V, A>, AllocError> {
+    let mut map = hashbrown::HashMap::new_in(alloc);
+
+    map.try_reserve(capacity).map_err(|_| AllocError)?;
+
+    Ok(map)
+}
+
+/// Creates an empty hash set using the allocator
+#[cfg(feature = "hashbrown")]
+pub fn huge_hashset<T: Hash + Eq, A: allocator_api2::alloc::Allocator>(alloc: A) -> HugeHashSet<T, A> {
+    hashbrown::HashSet::new_in(alloc)
+}
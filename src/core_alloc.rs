@@ -0,0 +1,233 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+use crate::raw::{page_size, RawHugeAlloc, HUGE_PAGE};
+use crate::sync::Mutex;
+use crate::table::{Segment, SegmentTable};
+
+/// A global allocator which tries huge pages for big allocations like [`HugeAllocator`](crate::HugeAllocator)
+/// without needing std. Allocations which can't get huge pages fall back to default pages, and every
+/// segment is recorded with its mapped length and page size so it is freed correctly and counted in the
+/// statistics. Without std the lock is a spin lock and the segment table maps its own memory, so this can
+/// be the global allocator in environments which only have mmap
+///
+/// ```rust
+/// use huge_allocator::CoreHugeAlloc;
+///
+/// #[global_allocator]
+/// static GLOBAL: CoreHugeAlloc = CoreHugeAlloc::new(50);
+///
+/// let vec: Vec<u8> = Vec::with_capacity(2 * 1024 * 1024);
+///
+/// assert!(GLOBAL.stats().segments >= 1);
+/// ```
+pub struct CoreHugeAlloc {
+    /// Threshold percentage of a huge page at which huge pages are tried
+    threshold_pct: usize,
+    state: Mutex<State>,
+}
+
+/// Segments and statistics, updated together
+struct State {
+    table: SegmentTable,
+    stats: CoreHugeStats,
+}
+
+/// Statistics for a [`CoreHugeAlloc`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CoreHugeStats {
+    /// Number of live segments
+    pub segments: usize,
+    /// Number of live segments mapped with huge pages
+    pub huge_segments: usize,
+    /// Bytes mapped for live segments
+    pub mapped: usize,
+    /// Bytes mapped with huge pages for live segments
+    pub huge_mapped: usize,
+    /// Number of huge page mappings which fell back to default pages
+    pub fallbacks: usize,
+}
+
+impl CoreHugeAlloc {
+    /// Creates a new allocator with a given threshold percentage
+    pub const fn new(threshold_pct: usize) -> Self {
+        Self {
+            threshold_pct,
+            state: Mutex::new(State {
+                table: SegmentTable::new(),
+                stats: CoreHugeStats {
+                    segments: 0,
+                    huge_segments: 0,
+                    mapped: 0,
+                    huge_mapped: 0,
+                    fallbacks: 0,
+                },
+            }),
+        }
+    }
+
+    /// Returns the current statistics
+    pub fn stats(&self) -> CoreHugeStats {
+        self.state.lock().stats
+    }
+
+    /// Returns true if huge pages are tried for an allocation of the given size
+    fn wants_huge(&self, size: usize) -> bool {
+        size.saturating_mul(100) / HUGE_PAGE >= self.threshold_pct
+    }
+
+    /// Maps a segment for a layout, returning null on failure and whether huge pages fell back
+    unsafe fn map(&self, layout: Layout) -> (*mut u8, Segment, bool) {
+        let size = layout.size().max(1);
+        let mut fell_back = false;
+
+        // Huge page mappings are only aligned to the huge page size
+        if self.wants_huge(size) && layout.align() <= HUGE_PAGE {
+            let len = size.next_multiple_of(HUGE_PAGE);
+            let ptr = RawHugeAlloc::map(len, libc::MAP_HUGETLB | libc::MAP_HUGE_2MB);
+
+            if !ptr.is_null() {
+                return (ptr, Segment { len, huge: true }, false);
+            }
+
+            fell_back = true;
+        }
+
+        let page = page_size();
+        let len = size.next_multiple_of(page);
+        let segment = Segment { len, huge: false };
+
+        if layout.align() <= page {
+            return (RawHugeAlloc::map(len, 0), segment, fell_back);
+        }
+
+        // Map enough to find an aligned start, then unmap either side
+        let over = len + layout.align() - page;
+        let base = RawHugeAlloc::map(over, 0);
+
+        if base.is_null() {
+            return (base, segment, fell_back);
+        }
+
+        let head = (base as usize).next_multiple_of(layout.align()) - base as usize;
+        let tail = over - head - len;
+
+        if head > 0 {
+            libc::munmap(base as *mut libc::c_void, head);
+        }
+
+        if tail > 0 {
+            libc::munmap(base.add(head + len) as *mut libc::c_void, tail);
+        }
+
+        (base.add(head), segment, fell_back)
+    }
+
+    /// Adds a mapped segment to the table and statistics, unmapping it if the table can't grow
+    unsafe fn track(&self, ptr: *mut u8, segment: Segment, fell_back: bool) -> *mut u8 {
+        let mut state = self.state.lock();
+
+        if !state.table.insert(ptr as usize, segment) {
+            drop(state);
+            libc::munmap(ptr as *mut libc::c_void, segment.len);
+            return null_mut();
+        }
+
+        let stats = &mut state.stats;
+
+        stats.segments += 1;
+        stats.mapped += segment.len;
+
+        if segment.huge {
+            stats.huge_segments += 1;
+            stats.huge_mapped += segment.len;
+        }
+
+        if fell_back {
+            stats.fallbacks += 1;
+        }
+
+        ptr
+    }
+
+    /// Removes a segment from the table and statistics
+    fn untrack(&self, ptr: *mut u8) -> Option<Segment> {
+        let mut state = self.state.lock();
+
+        let segment = state.table.remove(ptr as usize)?;
+        let stats = &mut state.stats;
+
+        stats.segments -= 1;
+        stats.mapped -= segment.len;
+
+        if segment.huge {
+            stats.huge_segments -= 1;
+            stats.huge_mapped -= segment.len;
+        }
+
+        Some(segment)
+    }
+}
+
+unsafe impl GlobalAlloc for CoreHugeAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (ptr, segment, fell_back) = self.map(layout);
+
+        if ptr.is_null() {
+            return ptr;
+        }
+
+        self.track(ptr, segment, fell_back)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        if let Some(segment) = self.untrack(ptr) {
+            libc::munmap(ptr as *mut libc::c_void, segment.len);
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // Anonymous mappings are always zeroed
+        self.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let Some(segment) = self.state.lock().table.get(ptr as usize) else {
+            return null_mut();
+        };
+
+        let unit = if segment.huge { HUGE_PAGE } else { page_size() };
+        let new_len = new_size.max(1).next_multiple_of(unit);
+
+        if new_len == segment.len {
+            // Still needs the same pages
+            return ptr;
+        }
+
+        if !segment.huge && !self.wants_huge(new_size) && layout.align() <= unit {
+            let new_ptr = libc::mremap(ptr as *mut libc::c_void, segment.len, new_len, libc::MREMAP_MAYMOVE);
+
+            if new_ptr != libc::MAP_FAILED {
+                let mut state = self.state.lock();
+
+                // Inserting straight after the remove never grows the table, so can't fail
+                state.table.remove(ptr as usize);
+                state.table.insert(new_ptr as usize, Segment { len: new_len, huge: false });
+
+                state.stats.mapped = state.stats.mapped - segment.len + new_len;
+
+                return new_ptr as *mut u8;
+            }
+        }
+
+        // Move to a new segment
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+
+        if !new_ptr.is_null() {
+            copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+
+        new_ptr
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+
+use crate::builder::LogSink;
+use crate::latency::{LatencyRecorder, Syscall};
+use crate::mmap::MMap;
+
+/// Background thread unmapping freed segments so munmap latency stays off the thread freeing them
+pub(crate) struct DeferredUnmap {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+/// State shared with the worker thread
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when segments are queued or the worker should exit
+    wake: Condvar,
+    /// Signalled when the queue has been drained
+    idle: Condvar,
+    /// Number of segments which failed to unmap
+    failed: AtomicUsize,
+    /// Report unmap failures with a panic on the allocator's threads
+    strict: bool,
+    /// Time spent in munmap
+    latency: Arc<LatencyRecorder>,
+}
+
+/// Segments waiting to be unmapped
+#[derive(Default)]
+struct State {
+    queue: VecDeque<MMap>,
+    /// Segments queued or being unmapped
+    segments: usize,
+    /// Mapped bytes of the segments queued or being unmapped
+    bytes: usize,
+    /// First unmap failure not yet reported in strict mode
+    failure: Option<String>,
+    shutdown: bool,
+}
+
+impl DeferredUnmap {
+    /// Starts the worker thread. Unmap failures are sent to the log sink prefixed with the allocator ident. In
+    /// strict mode a failure also panics on the next thread to queue segments or wait for the queue to drain
+    pub(crate) fn new(
+        ident: String,
+        log_sink: Option<LogSink>,
+        latency: Arc<LatencyRecorder>,
+        strict: bool,
+    ) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            wake: Condvar::new(),
+            idle: Condvar::new(),
+            failed: AtomicUsize::new(0),
+            strict,
+            latency,
+        });
+
+        let worker_shared = shared.clone();
+
+        let worker = std::thread::Builder::new()
+            .name("huge_allocator-unmap".to_string())
+            .spawn(move || worker_shared.run(&ident, log_sink.as_ref()))?;
+
+        Ok(Self {
+            shared,
+            worker: Some(worker),
+        })
+    }
+
+    /// Queues a segment to be unmapped
+    pub(crate) fn push(&self, mmap: MMap) {
+        self.push_all(vec![mmap]);
+    }
+
+    /// Queues a batch of segments to be unmapped
+    pub(crate) fn push_all(&self, mmaps: Vec<MMap>) {
+        let mut state = self.shared.lock();
+
+        for mmap in mmaps {
+            state.segments += 1;
+            state.bytes += mmap.alloc_size();
+            state.queue.push_back(mmap);
+        }
+
+        let failure = state.failure.take();
+
+        drop(state);
+
+        self.shared.wake.notify_one();
+
+        Self::report(failure);
+    }
+
+    /// Waits until every queued segment has been unmapped
+    pub(crate) fn wait_idle(&self) {
+        let state = self.shared.lock();
+
+        let mut state = self
+            .shared
+            .idle
+            .wait_while(state, |state| state.segments > 0)
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let failure = state.failure.take();
+
+        drop(state);
+
+        Self::report(failure);
+    }
+
+    /// Panics with an unmap failure from the worker thread in strict mode
+    fn report(failure: Option<String>) {
+        if let Some(failure) = failure {
+            panic!("{}", failure);
+        }
+    }
+
+    /// Returns the number of segments and mapped bytes waiting to be unmapped
+    pub(crate) fn pending(&self) -> (usize, usize) {
+        let state = self.shared.lock();
+
+        (state.segments, state.bytes)
+    }
+
+    /// Returns the number of segments which failed to unmap
+    pub(crate) fn failed(&self) -> usize {
+        self.shared.failed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for DeferredUnmap {
+    /// Unmaps everything still queued and stops the worker
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.wake.notify_one();
+
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Shared {
+    /// Unmaps queued segments until shut down with an empty queue
+    fn run(&self, ident: &str, log_sink: Option<&LogSink>) {
+        let mut state = self.lock();
+
+        loop {
+            match state.queue.pop_front() {
+                Some(mmap) => {
+                    drop(state);
+
+                    let ptr = mmap.as_ptr();
+                    let alloc_size = mmap.alloc_size();
+                    let page_size = mmap.page_size();
+
+                    let mut failure = None;
+
+                    if let Err(e) = self.latency.time(Syscall::Munmap, page_size, || mmap.unmap()) {
+                        self.failed.fetch_add(1, Ordering::Relaxed);
+
+                        let msg = format!("{}: failed to unmap {:?} ({} bytes) ({})", ident, ptr, alloc_size, e);
+
+                        if let Some(sink) = log_sink {
+                            sink(&msg);
+                        }
+
+                        if self.strict {
+                            failure = Some(msg);
+                        }
+                    }
+
+                    state = self.lock();
+
+                    if state.failure.is_none() {
+                        state.failure = failure;
+                    }
+
+                    state.segments -= 1;
+                    state.bytes -= alloc_size;
+
+                    if state.segments == 0 {
+                        self.idle.notify_all();
+                    }
+                }
+                None if state.shutdown => return,
+                None => state = self.wake.wait(state).unwrap_or_else(PoisonError::into_inner),
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,243 @@
+//! System huge page diagnostics
+//!
+//! [`system_report`] gathers everything affecting huge page allocation on the host in one place: the huge
+//! page pools for every supported size, the transparent huge page settings, the memory lock limits and the
+//! hugetlb cgroup limits. Print it at service startup or attach it to bug reports
+//!
+//! ```rust
+//! use huge_allocator::diagnostics;
+//!
+//! let report = diagnostics::system_report();
+//!
+//! println!("{}", report);
+//!
+//! if let Some(pool) = report.pool(2 * 1024 * 1024) {
+//!     println!("{} free 2mb huge pages", pool.free);
+//! }
+//! ```
+
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::cgroup::{self, HugetlbLimit};
+use crate::mmap::PageSize;
+use crate::probe::{read_max_map_count, read_thp, read_thp_defrag, ThpFallback, ThpMode};
+
+/// Sysfs directory containing a directory per huge page size
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+
+/// Sysfs directory of the transparent huge page settings
+const THP_DIR: &str = "/sys/kernel/mm/transparent_hugepage";
+
+/// Huge page pool for one page size
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HugePagePool {
+    /// Size of the pages in the pool in bytes
+    pub page_size: usize,
+    /// Number of pages in the persistent pool (nr_hugepages)
+    pub total: usize,
+    /// Number of pages not yet faulted in
+    pub free: usize,
+    /// Number of free pages reserved by mappings but not yet faulted in
+    pub reserved: usize,
+    /// Number of pages the pool may grow by on demand
+    pub overcommit: usize,
+    /// Number of pages currently allocated beyond the persistent pool through overcommit
+    pub surplus: usize,
+    /// Hugetlb cgroup limit on pages of this size, if one applies
+    pub cgroup_limit: Option<HugetlbLimit>,
+}
+
+/// Summary of the huge page configuration of the host and process, returned by [`system_report`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemReport {
+    /// Kernel release (uname -r)
+    pub kernel: String,
+    /// The default page size in bytes
+    pub default_page_size: usize,
+    /// Huge page pools in ascending order of page size
+    pub pools: Vec<HugePagePool>,
+    /// Transparent huge page setting
+    pub thp: ThpMode,
+    /// Transparent huge page defrag setting (e.g. "madvise"), if it can be read
+    pub thp_defrag: Option<String>,
+    /// Transparent huge page setting for shared memory (e.g. "never"), if it can be read
+    pub thp_shmem: Option<String>,
+    /// How allocations which fall back to the default page size are offered to transparent huge pages
+    pub thp_fallback: ThpFallback,
+    /// Soft limit on the bytes the process may lock in memory, or None if unlimited
+    pub memlock_soft: Option<u64>,
+    /// Hard limit on the bytes the process may lock in memory, or None if unlimited
+    pub memlock_hard: Option<u64>,
+    /// The cgroup v2 directory of the process, if the unified hierarchy is mounted
+    pub cgroup: Option<PathBuf>,
+    /// Maximum number of mappings a process may have (vm.max_map_count), or 0 if it can't be read
+    pub max_map_count: usize,
+}
+
+impl SystemReport {
+    /// Returns the huge page pool for the given page size in bytes
+    pub fn pool(&self, page_size: usize) -> Option<&HugePagePool> {
+        self.pools.iter().find(|pool| pool.page_size == page_size)
+    }
+}
+
+/// Gathers the huge page configuration of the host and process
+pub fn system_report() -> SystemReport {
+    let (memlock_soft, memlock_hard) = read_memlock_limits();
+
+    SystemReport {
+        kernel: read_kernel_release(),
+        default_page_size: PageSize::SizeDefault.bytes(),
+        pools: read_pools(),
+        thp: read_thp(),
+        thp_defrag: read_thp_setting("defrag"),
+        thp_shmem: read_thp_setting("shmem_enabled"),
+        thp_fallback: ThpFallback::decide(read_thp(), read_thp_defrag()),
+        memlock_soft,
+        memlock_hard,
+        cgroup: cgroup::dir().map(PathBuf::from),
+        max_map_count: read_max_map_count(),
+    }
+}
+
+impl fmt::Display for SystemReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "kernel: {}", self.kernel)?;
+        writeln!(f, "default page size: {} bytes", self.default_page_size)?;
+
+        if self.pools.is_empty() {
+            writeln!(f, "huge page pools: none")?;
+        }
+
+        for pool in &self.pools {
+            write!(
+                f,
+                "huge page pool {}: {} total, {} free, {} reserved, {} overcommit, {} surplus",
+                size_name(pool.page_size),
+                pool.total,
+                pool.free,
+                pool.reserved,
+                pool.overcommit,
+                pool.surplus
+            )?;
+
+            match pool.cgroup_limit {
+                Some(limit) => writeln!(f, ", cgroup limit {} bytes ({} used)", limit.max, limit.current)?,
+                None => writeln!(f)?,
+            }
+        }
+
+        writeln!(
+            f,
+            "transparent huge pages: {:?} (defrag: {}, shmem: {}, fallback: {:?})",
+            self.thp,
+            self.thp_defrag.as_deref().unwrap_or("unknown"),
+            self.thp_shmem.as_deref().unwrap_or("unknown"),
+            self.thp_fallback
+        )?;
+
+        writeln!(f, "memlock limit: {} soft, {} hard", limit_name(self.memlock_soft), limit_name(self.memlock_hard))?;
+
+        match &self.cgroup {
+            Some(dir) => writeln!(f, "cgroup: {}", dir.display())?,
+            None => writeln!(f, "cgroup: v2 not mounted")?,
+        }
+
+        write!(f, "max map count: {}", self.max_map_count)
+    }
+}
+
+/// Reads the pool for every huge page size the kernel supports
+fn read_pools() -> Vec<HugePagePool> {
+    let Ok(dirs) = fs::read_dir(HUGEPAGES_DIR) else {
+        return Vec::new();
+    };
+
+    let mut pools: Vec<HugePagePool> = dirs
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name();
+            let kb: usize = name.to_str()?.strip_prefix("hugepages-")?.strip_suffix("kB")?.parse().ok()?;
+
+            let read = |counter: &str| {
+                fs::read_to_string(entry.path().join(counter))
+                    .ok()
+                    .and_then(|value| value.trim().parse().ok())
+                    .unwrap_or(0)
+            };
+
+            Some(HugePagePool {
+                page_size: kb * 1024,
+                total: read("nr_hugepages"),
+                free: read("free_hugepages"),
+                reserved: read("resv_hugepages"),
+                overcommit: read("nr_overcommit_hugepages"),
+                surplus: read("surplus_hugepages"),
+                cgroup_limit: cgroup::hugetlb_limit_for(&format!("{}B", size_name(kb * 1024).to_uppercase())),
+            })
+        })
+        .collect();
+
+    pools.sort_by_key(|pool| pool.page_size);
+
+    pools
+}
+
+/// Reads the active value of a transparent huge page setting, which is in brackets (e.g. "always [madvise]")
+fn read_thp_setting(name: &str) -> Option<String> {
+    let setting = fs::read_to_string(format!("{}/{}", THP_DIR, name)).ok()?;
+
+    setting
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix('[')?.strip_suffix(']'))
+        .map(String::from)
+}
+
+/// Reads the soft and hard RLIMIT_MEMLOCK limits
+fn read_memlock_limits() -> (Option<u64>, Option<u64>) {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return (None, None);
+    }
+
+    let value = |limit| (limit != libc::RLIM_INFINITY).then_some(limit);
+
+    (value(limit.rlim_cur), value(limit.rlim_max))
+}
+
+/// Reads the kernel release, or "unknown" if uname fails
+fn read_kernel_release() -> String {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return "unknown".to_string();
+    }
+
+    unsafe { CStr::from_ptr(uts.release.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+/// Formats a page size as in the sysfs and cgroup names (e.g. "2m" or "1g")
+fn size_name(bytes: usize) -> String {
+    const KB: usize = 1024;
+
+    match bytes {
+        bytes if bytes.is_multiple_of(KB * KB * KB) => format!("{}g", bytes / (KB * KB * KB)),
+        bytes if bytes.is_multiple_of(KB * KB) => format!("{}m", bytes / (KB * KB)),
+        bytes => format!("{}k", bytes / KB),
+    }
+}
+
+/// Formats a resource limit
+fn limit_name(limit: Option<u64>) -> String {
+    match limit {
+        Some(bytes) => format!("{} bytes", bytes),
+        None => "unlimited".to_string(),
+    }
+}
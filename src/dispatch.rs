@@ -0,0 +1,185 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::collections::HashSet;
+use std::ptr::{copy_nonoverlapping, NonNull};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// An allocator which routes each allocation to one of two inner allocators using a predicate on the
+/// allocation layout. Allocations served by the matching allocator are tracked so that deallocations are
+/// routed back to their owner, and reallocations which cross the predicate move between allocators.
+/// Dispatch allocators can be nested to route between more than two allocators
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use std::alloc::System;
+/// use huge_allocator::{DispatchAllocator, HugeAllocator};
+///
+/// let huge = HugeAllocator::new(50);
+/// let allocator = DispatchAllocator::new(|layout| layout.size() >= 1024 * 1024, huge.clone(), System);
+///
+/// let small: Vec<u8, _> = Vec::with_capacity_in(1024, &allocator);
+/// let large: Vec<u8, _> = Vec::with_capacity_in(2 * 1024 * 1024, &allocator);
+///
+/// assert_eq!(1, huge.stats().unwrap().segments);
+/// ```
+pub struct DispatchAllocator<P, A, B> {
+    predicate: P,
+    matched: A,
+    other: B,
+    /// Addresses of allocations owned by the matching allocator
+    matched_ptrs: Mutex<HashSet<usize>>,
+}
+
+impl<P, A, B> DispatchAllocator<P, A, B>
+where
+    P: Fn(Layout) -> bool,
+    A: Allocator,
+    B: Allocator,
+{
+    /// Creates a new dispatch allocator. Layouts for which the predicate returns true are allocated from
+    /// `matched`, all others from `other`
+    pub fn new(predicate: P, matched: A, other: B) -> Self {
+        Self {
+            predicate,
+            matched,
+            other,
+            matched_ptrs: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Returns the allocator serving layouts matching the predicate
+    pub fn matched(&self) -> &A {
+        &self.matched
+    }
+
+    /// Returns the allocator serving all other layouts
+    pub fn other(&self) -> &B {
+        &self.other
+    }
+
+    /// Returns the number of live allocations served by the matching allocator
+    pub fn matched_allocations(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Locks the matched pointer set
+    fn lock(&self) -> MutexGuard<'_, HashSet<usize>> {
+        self.matched_ptrs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Allocates from the allocator selected by the predicate
+    fn alloc(&self, layout: Layout, zeroed: bool) -> Result<NonNull<[u8]>, AllocError> {
+        if (self.predicate)(layout) {
+            let ptr = if zeroed {
+                self.matched.allocate_zeroed(layout)?
+            } else {
+                self.matched.allocate(layout)?
+            };
+
+            self.lock().insert(ptr.cast::<u8>().as_ptr() as usize);
+
+            Ok(ptr)
+        } else if zeroed {
+            self.other.allocate_zeroed(layout)
+        } else {
+            self.other.allocate(layout)
+        }
+    }
+
+    /// Reallocates an allocation, moving it between allocators if the new layout is routed elsewhere
+    unsafe fn realloc(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let grow = new_layout.size() >= old_layout.size();
+        let old_matched = self.lock().contains(&(ptr.as_ptr() as usize));
+        let new_matched = (self.predicate)(new_layout);
+
+        match (old_matched, new_matched) {
+            (true, true) => {
+                let new_ptr = match (grow, zeroed) {
+                    (true, true) => self.matched.grow_zeroed(ptr, old_layout, new_layout)?,
+                    (true, false) => self.matched.grow(ptr, old_layout, new_layout)?,
+                    _ => self.matched.shrink(ptr, old_layout, new_layout)?,
+                };
+
+                let mut ptrs = self.lock();
+                ptrs.remove(&(ptr.as_ptr() as usize));
+                ptrs.insert(new_ptr.cast::<u8>().as_ptr() as usize);
+
+                Ok(new_ptr)
+            }
+            (false, false) => match (grow, zeroed) {
+                (true, true) => self.other.grow_zeroed(ptr, old_layout, new_layout),
+                (true, false) => self.other.grow(ptr, old_layout, new_layout),
+                _ => self.other.shrink(ptr, old_layout, new_layout),
+            },
+            _ => {
+                // Moving between allocators
+                let new_ptr = self.alloc(new_layout, zeroed)?;
+
+                copy_nonoverlapping(
+                    ptr.as_ptr(),
+                    new_ptr.cast::<u8>().as_ptr(),
+                    old_layout.size().min(new_layout.size()),
+                );
+
+                self.deallocate(ptr, old_layout);
+
+                Ok(new_ptr)
+            }
+        }
+    }
+}
+
+unsafe impl<P, A, B> Allocator for DispatchAllocator<P, A, B>
+where
+    P: Fn(Layout) -> bool,
+    A: Allocator,
+    B: Allocator,
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc(layout, false)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc(layout, true)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if self.lock().remove(&(ptr.as_ptr() as usize)) {
+            self.matched.deallocate(ptr, layout)
+        } else {
+            self.other.deallocate(ptr, layout)
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout, false)
+    }
+}
@@ -0,0 +1,173 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::collections::HashSet;
+use std::ptr::{copy_nonoverlapping, NonNull};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// An allocator which tries a primary allocator and falls back to a secondary allocator when the primary
+/// fails. Pointers served by the secondary are tracked so that deallocations and reallocations are routed
+/// back to the allocator which owns them
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use std::alloc::System;
+/// use huge_allocator::{FallbackAllocator, HugeAllocator};
+///
+/// let allocator = FallbackAllocator::new(HugeAllocator::new(50), System);
+///
+/// let vec: Vec<u8, _> = Vec::with_capacity_in(4 * 1024 * 1024, &allocator);
+/// ```
+pub struct FallbackAllocator<A, B> {
+    primary: A,
+    secondary: B,
+    /// Addresses of allocations owned by the secondary allocator
+    secondary_ptrs: Mutex<HashSet<usize>>,
+}
+
+impl<A: Allocator, B: Allocator> FallbackAllocator<A, B> {
+    /// Creates a new fallback allocator
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self {
+            primary,
+            secondary,
+            secondary_ptrs: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Returns the primary allocator
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    /// Returns the secondary allocator
+    pub fn secondary(&self) -> &B {
+        &self.secondary
+    }
+
+    /// Returns the number of live allocations served by the secondary allocator
+    pub fn secondary_allocations(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns true if the pointer is owned by the secondary allocator
+    fn is_secondary(&self, ptr: NonNull<u8>) -> bool {
+        self.lock().contains(&(ptr.as_ptr() as usize))
+    }
+
+    /// Locks the secondary pointer set
+    fn lock(&self) -> MutexGuard<'_, HashSet<usize>> {
+        self.secondary_ptrs.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Allocates from the secondary allocator and records ownership
+    fn secondary_allocate(&self, layout: Layout, zeroed: bool) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = if zeroed {
+            self.secondary.allocate_zeroed(layout)?
+        } else {
+            self.secondary.allocate(layout)?
+        };
+
+        self.lock().insert(ptr.cast::<u8>().as_ptr() as usize);
+
+        Ok(ptr)
+    }
+
+    /// Reallocates an allocation, moving it from the primary to the secondary allocator if the primary
+    /// can't satisfy the request
+    unsafe fn realloc(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zeroed: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let grow = new_layout.size() >= old_layout.size();
+
+        if self.is_secondary(ptr) {
+            let new_ptr = match (grow, zeroed) {
+                (true, true) => self.secondary.grow_zeroed(ptr, old_layout, new_layout)?,
+                (true, false) => self.secondary.grow(ptr, old_layout, new_layout)?,
+                _ => self.secondary.shrink(ptr, old_layout, new_layout)?,
+            };
+
+            let mut ptrs = self.lock();
+            ptrs.remove(&(ptr.as_ptr() as usize));
+            ptrs.insert(new_ptr.cast::<u8>().as_ptr() as usize);
+
+            return Ok(new_ptr);
+        }
+
+        let res = match (grow, zeroed) {
+            (true, true) => self.primary.grow_zeroed(ptr, old_layout, new_layout),
+            (true, false) => self.primary.grow(ptr, old_layout, new_layout),
+            _ => self.primary.shrink(ptr, old_layout, new_layout),
+        };
+
+        if res.is_ok() {
+            return res;
+        }
+
+        // Primary failed - move to the secondary
+        let new_ptr = self.secondary_allocate(new_layout, zeroed)?;
+
+        copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            old_layout.size().min(new_layout.size()),
+        );
+
+        self.primary.deallocate(ptr, old_layout);
+
+        Ok(new_ptr)
+    }
+}
+
+unsafe impl<A: Allocator, B: Allocator> Allocator for FallbackAllocator<A, B> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self.primary.allocate(layout) {
+            Ok(ptr) => Ok(ptr),
+            Err(_) => self.secondary_allocate(layout, false),
+        }
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self.primary.allocate_zeroed(layout) {
+            Ok(ptr) => Ok(ptr),
+            Err(_) => self.secondary_allocate(layout, true),
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if self.lock().remove(&(ptr.as_ptr() as usize)) {
+            self.secondary.deallocate(ptr, layout)
+        } else {
+            self.primary.deallocate(ptr, layout)
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout, false)
+    }
+}
@@ -0,0 +1,165 @@
+//! C interface to a [`HugeAllocator`], enabled with the `ffi` feature. Build a shared library with
+//! `cargo rustc --release --features ffi --crate-type cdylib` and include `include/huge_allocator.h`
+//!
+//! ```c
+//! huge_allocator_t *allocator = huge_allocator_new(50);
+//!
+//! void *buf = huge_alloc(allocator, 4 << 20, 64);
+//! buf = huge_realloc(allocator, buf, 8 << 20);
+//! huge_free(allocator, buf);
+//!
+//! huge_stats_t stats;
+//! huge_stats(allocator, &stats);
+//!
+//! huge_allocator_free(allocator);
+//! ```
+
+use std::alloc::Layout;
+use std::ffi::c_void;
+use std::ptr::{null_mut, NonNull};
+
+use crate::mmapper::MMapper;
+use crate::HugeAllocator;
+
+/// Allocator statistics for C callers. A subset of [`HugeAllocatorStats`](crate::HugeAllocatorStats)
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HugeStats {
+    /// Total amount of memory allocated in bytes
+    pub alloc: usize,
+    /// Total amount of memory mapped in bytes
+    pub mapped: usize,
+    /// Total number of segments mapped
+    pub segments: usize,
+    /// Amount of memory mapped in huge pages in bytes
+    pub huge_mapped: usize,
+    /// Number of huge page segments mapped
+    pub huge_segments: usize,
+    /// Amount of memory mapped in default page size pages in bytes
+    pub default_mapped: usize,
+    /// Number of default page size segments mapped
+    pub default_segments: usize,
+    /// Number of allocations missed due to lack of huge pages
+    pub missed_allocs: usize,
+    /// Number of failed remaps
+    pub remaps_failed: usize,
+    /// Number of failed unmaps
+    pub unmaps_failed: usize,
+}
+
+/// Creates a new allocator with the given threshold percentage. Free it with `huge_allocator_free`
+#[no_mangle]
+pub extern "C" fn huge_allocator_new(threshold_pct: usize) -> *mut HugeAllocator {
+    Box::into_raw(Box::new(HugeAllocator::new(threshold_pct)))
+}
+
+/// Frees an allocator, unmapping any allocations still live
+///
+/// # Safety
+///
+/// `allocator` must have come from `huge_allocator_new` and not already been freed, or be null
+#[no_mangle]
+pub unsafe extern "C" fn huge_allocator_free(allocator: *mut HugeAllocator) {
+    if !allocator.is_null() {
+        drop(Box::from_raw(allocator));
+    }
+}
+
+/// Allocates memory with the given size and alignment. Returns null on failure
+///
+/// # Safety
+///
+/// `allocator` must be a live allocator from `huge_allocator_new`
+#[no_mangle]
+pub unsafe extern "C" fn huge_alloc(allocator: *const HugeAllocator, size: usize, align: usize) -> *mut c_void {
+    let (Some(allocator), Ok(layout)) = (allocator.as_ref(), Layout::from_size_align(size, align.max(1))) else {
+        return null_mut();
+    };
+
+    match allocator.mapper.alloc(layout) {
+        Ok(ptr) => ptr.as_ptr() as *mut c_void,
+        Err(_) => null_mut(),
+    }
+}
+
+/// Resizes an allocation, keeping its alignment. The allocation may move. Returns null on failure, in which
+/// case the original allocation is untouched. A null `ptr` allocates with the default alignment
+///
+/// # Safety
+///
+/// `allocator` must be a live allocator from `huge_allocator_new` and `ptr` a live allocation from it
+#[no_mangle]
+pub unsafe extern "C" fn huge_realloc(allocator: *const HugeAllocator, ptr: *mut c_void, size: usize) -> *mut c_void {
+    let Some(allocator) = allocator.as_ref() else {
+        return null_mut();
+    };
+
+    let Some(ptr) = NonNull::new(ptr as *mut u8) else {
+        return huge_alloc(allocator, size, 16);
+    };
+
+    let Some(old_layout) = layout_of(allocator, ptr) else {
+        return null_mut();
+    };
+
+    let Ok(new_layout) = Layout::from_size_align(size, old_layout.align()) else {
+        return null_mut();
+    };
+
+    match allocator.mapper.realloc(ptr, old_layout, new_layout) {
+        Ok(ptr) => ptr.as_ptr() as *mut c_void,
+        Err(_) => null_mut(),
+    }
+}
+
+/// Frees an allocation. Null pointers and pointers not owned by the allocator are ignored
+///
+/// # Safety
+///
+/// `allocator` must be a live allocator from `huge_allocator_new` and `ptr` must not be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn huge_free(allocator: *const HugeAllocator, ptr: *mut c_void) {
+    let (Some(allocator), Some(ptr)) = (allocator.as_ref(), NonNull::new(ptr as *mut u8)) else {
+        return;
+    };
+
+    if let Some(layout) = layout_of(allocator, ptr) {
+        let _ = allocator.mapper.dealloc(ptr, layout);
+    }
+}
+
+/// Fills in allocator statistics. Returns 0 on success or -1 if either pointer is null
+///
+/// # Safety
+///
+/// `allocator` must be a live allocator from `huge_allocator_new` and `stats` must be writable
+#[no_mangle]
+pub unsafe extern "C" fn huge_stats(allocator: *const HugeAllocator, stats: *mut HugeStats) -> i32 {
+    let (Some(allocator), Some(out)) = (allocator.as_ref(), stats.as_mut()) else {
+        return -1;
+    };
+
+    let stats = allocator.mapper.stats();
+
+    *out = HugeStats {
+        alloc: stats.alloc,
+        mapped: stats.mapped,
+        segments: stats.segments,
+        huge_mapped: stats.huge_mapped,
+        huge_segments: stats.huge_segments,
+        default_mapped: stats.default_mapped,
+        default_segments: stats.default_segments,
+        missed_allocs: stats.missed_allocs,
+        remaps_failed: stats.remaps_failed,
+        unmaps_failed: stats.unmaps_failed,
+    };
+
+    0
+}
+
+/// Returns the layout of a live allocation
+fn layout_of(allocator: &HugeAllocator, ptr: NonNull<u8>) -> Option<Layout> {
+    allocator
+        .mapper
+        .with_map(|ptr_map| ptr_map.get(&MMapper::key(ptr)).map(|mmap| mmap.layout()))
+}
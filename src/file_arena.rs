@@ -0,0 +1,323 @@
+use std::alloc::Layout;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr::{copy_nonoverlapping, null_mut, NonNull};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::mmap::PageSize;
+use crate::sys;
+use crate::AllocError;
+
+/// Filesystem type of hugetlbfs mounts reported by statfs
+const HUGETLBFS_MAGIC: u32 = 0x9584_58f6;
+
+/// Allocations are rounded up to a multiple of this many bytes, and aligned to at least it
+const GRANULE: usize = 64;
+
+/// An allocator whose entire capacity comes from one pre-created file on a 2mb hugetlbfs mount. The whole
+/// file is mapped shared when the arena is opened, which reserves its huge pages up front, so every
+/// allocation is guaranteed to be huge page backed and the arena can never use more memory than the file
+/// holds. Allocations which don't fit fail rather than falling back to the default page size
+///
+/// ```rust,no_run
+/// #![feature(allocator_api)]
+/// use huge_allocator::HugeFileArena;
+///
+/// // Created by the deployment with e.g. fallocate -l 1G /dev/hugepages/myservice
+/// let arena = HugeFileArena::open("/dev/hugepages/myservice").unwrap();
+///
+/// let mut vec: Vec<u8, _> = Vec::with_capacity_in(64 * 1024 * 1024, &arena);
+/// vec.extend_from_slice(b"bounded");
+///
+/// assert!(arena.used() >= 64 * 1024 * 1024);
+/// assert!(arena.used() <= arena.capacity());
+/// ```
+pub struct HugeFileArena {
+    path: PathBuf,
+    ptr: NonNull<u8>,
+    len: usize,
+    inner: Mutex<ArenaInner>,
+}
+
+/// Mutable arena state
+struct ArenaInner {
+    /// Free ranges of the mapping as offset to length, with adjacent ranges merged
+    free: BTreeMap<usize, usize>,
+    /// Bytes handed out
+    used: usize,
+}
+
+impl HugeFileArena {
+    /// Opens an existing file on a 2mb hugetlbfs mount and maps all of it. The file's size is the arena's
+    /// capacity. Fails if the file is empty or not on hugetlbfs, or if there aren't enough huge pages to back
+    /// the parts of the file which have not been allocated yet
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut stat = MaybeUninit::<libc::statfs>::uninit();
+
+        if unsafe { libc::fstatfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+            Err(io::Error::last_os_error())?;
+        }
+
+        let stat = unsafe { stat.assume_init() };
+
+        if stat.f_type as u32 != HUGETLBFS_MAGIC {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "arena file is not on hugetlbfs"))?;
+        }
+
+        if stat.f_bsize as usize != PageSize::Size2m.bytes() {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "arena file is not on a 2mb huge page mount"))?;
+        }
+
+        let len = usize::try_from(file.metadata()?.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+
+        if len == 0 {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "arena file is empty"))?;
+        }
+
+        // Huge page reservations are made here so this fails if there aren't enough huge pages
+        let ptr = unsafe {
+            sys::mmap(
+                null_mut::<c_void>(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        }?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            ptr: NonNull::new(ptr as *mut u8).ok_or(io::ErrorKind::Other)?,
+            len,
+            inner: Mutex::new(ArenaInner {
+                free: BTreeMap::from([(0, len)]),
+                used: 0,
+            }),
+        })
+    }
+
+    /// Returns the path of the backing file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the size of the backing file, which bounds the memory the arena can hand out
+    pub fn capacity(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the number of bytes allocated, including rounding
+    pub fn used(&self) -> usize {
+        self.lock().used
+    }
+
+    /// Returns the size of the largest allocation which would currently succeed with minimal alignment
+    pub fn largest_free(&self) -> usize {
+        self.lock().free.values().copied().max().unwrap_or(0)
+    }
+
+    /// Allocates memory from the file. Fails if there is no free range large enough
+    pub fn alloc(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let size = Self::granules(layout.size());
+
+        let offset = self.lock().take(self.ptr.as_ptr() as usize, size, layout.align().max(GRANULE))?;
+
+        Ok(self.slice(offset, size))
+    }
+
+    /// Returns memory to the arena
+    pub fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        let offset = self.offset(ptr);
+
+        self.lock().give(offset, Self::granules(layout.size()));
+    }
+
+    /// Resizes an allocation, in place if the alignment allows and, when growing, the memory following it
+    /// is free
+    pub fn realloc(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let offset = self.offset(ptr);
+        let old_size = Self::granules(old_layout.size());
+        let new_size = Self::granules(new_layout.size());
+
+        if (ptr.as_ptr() as usize).is_multiple_of(new_layout.align()) {
+            let mut inner = self.lock();
+
+            if new_size <= old_size {
+                // Give back the tail
+                inner.give(offset + new_size, old_size - new_size);
+
+                return Ok(self.slice(offset, new_size));
+            }
+
+            if inner.extend(offset + old_size, new_size - old_size) {
+                return Ok(self.slice(offset, new_size));
+            }
+        }
+
+        let new_ptr = self.alloc(new_layout)?;
+
+        unsafe {
+            copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast::<u8>().as_ptr(), old_layout.size().min(new_layout.size()));
+        }
+
+        self.dealloc(ptr, old_layout);
+
+        Ok(new_ptr)
+    }
+
+    /// Rounds a size up to whole granules. Zero sized allocations take one granule so each has its own address
+    fn granules(size: usize) -> usize {
+        size.max(1).next_multiple_of(GRANULE)
+    }
+
+    /// Returns the offset of a pointer in to the mapping
+    fn offset(&self, ptr: NonNull<u8>) -> usize {
+        let offset = (ptr.as_ptr() as usize).wrapping_sub(self.ptr.as_ptr() as usize);
+
+        assert!(offset < self.len, "{:?} is not from the arena backed by {}", ptr, self.path.display());
+
+        offset
+    }
+
+    fn slice(&self, offset: usize, size: usize) -> NonNull<[u8]> {
+        let ptr = unsafe { NonNull::new_unchecked(self.ptr.as_ptr().add(offset)) };
+
+        NonNull::slice_from_raw_parts(ptr, size)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ArenaInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ArenaInner {
+    /// Takes the first free range which can hold an aligned allocation, returning its offset
+    fn take(&mut self, base: usize, size: usize, align: usize) -> Result<usize, AllocError> {
+        let (start, offset, len) = self
+            .free
+            .iter()
+            .find_map(|(&start, &len)| {
+                let offset = (base + start).checked_next_multiple_of(align)? - base;
+
+                (offset.checked_add(size)? <= start + len).then_some((start, offset, len))
+            })
+            .ok_or(AllocError)?;
+
+        self.free.remove(&start);
+
+        if offset > start {
+            self.free.insert(start, offset - start);
+        }
+
+        if offset + size < start + len {
+            self.free.insert(offset + size, start + len - offset - size);
+        }
+
+        self.used += size;
+
+        Ok(offset)
+    }
+
+    /// Takes the start of the free range at the given offset if it is long enough
+    fn extend(&mut self, offset: usize, size: usize) -> bool {
+        match self.free.get(&offset) {
+            Some(&len) if len >= size => {
+                self.free.remove(&offset);
+
+                if len > size {
+                    self.free.insert(offset + size, len - size);
+                }
+
+                self.used += size;
+
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns a range to the free list, merging it with free neighbours
+    fn give(&mut self, mut offset: usize, mut size: usize) {
+        if size == 0 {
+            return;
+        }
+
+        self.used -= size;
+
+        if let Some((&prev, &prev_len)) = self.free.range(..offset).next_back() {
+            if prev + prev_len == offset {
+                self.free.remove(&prev);
+
+                offset = prev;
+                size += prev_len;
+            }
+        }
+
+        if let Some(next_len) = self.free.remove(&(offset + size)) {
+            size += next_len;
+        }
+
+        self.free.insert(offset, size);
+    }
+}
+
+unsafe impl Send for HugeFileArena {}
+unsafe impl Sync for HugeFileArena {}
+
+impl fmt::Debug for HugeFileArena {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HugeFileArena")
+            .field("path", &self.path)
+            .field("ptr", &self.ptr)
+            .field("capacity", &self.len)
+            .field("used", &self.used())
+            .finish()
+    }
+}
+
+impl Drop for HugeFileArena {
+    /// Unmaps the file. Its huge pages stay allocated to the file until it is truncated or removed
+    fn drop(&mut self) {
+        let _ = unsafe { sys::munmap(self.ptr.as_ptr() as *mut c_void, self.len) };
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl std::alloc::Allocator for HugeFileArena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.dealloc(ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout)
+    }
+}
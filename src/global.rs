@@ -0,0 +1,159 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ptr::NonNull;
+
+use lazy_static::lazy_static;
+
+use crate::HugeAllocator;
+
+lazy_static! {
+    /// The process wide shared allocator
+    static ref GLOBAL_HUGE: HugeAllocator = HugeAllocator::new(50);
+}
+
+/// Returns the process wide shared allocator (threshold percentage of 50) used by [`GlobalHuge`]
+pub fn global_allocator() -> &'static HugeAllocator {
+    &GLOBAL_HUGE
+}
+
+/// Zero sized handle to the process wide shared [`HugeAllocator`]. Collections using it don't need to
+/// borrow an allocator so can be stored in structs freely
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GlobalHuge;
+
+/// A Vec allocated from the process wide shared allocator
+pub type HugeVec<T> = Vec<T, GlobalHuge>;
+
+/// A Box allocated from the process wide shared allocator
+pub type HugeBox<T> = Box<T, GlobalHuge>;
+
+/// Constructors for [`HugeVec`]
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::{HugeVec, HugeVecExt};
+///
+/// struct Table {
+///     rows: HugeVec<u64>,
+/// }
+///
+/// let mut table = Table {
+///     rows: HugeVec::with_capacity(1024 * 1024),
+/// };
+///
+/// table.rows.push(1);
+/// ```
+pub trait HugeVecExt<T> {
+    /// Creates an empty vector
+    fn new() -> Self;
+
+    /// Creates an empty vector with space for at least `capacity` elements
+    fn with_capacity(capacity: usize) -> Self;
+
+    /// Creates an empty vector with space for at least `capacity` elements, returning an error instead of
+    /// aborting if the memory can't be allocated
+    fn try_with_capacity(capacity: usize) -> Result<Self, AllocError>
+    where
+        Self: Sized;
+}
+
+impl<T> HugeVecExt<T> for HugeVec<T> {
+    fn new() -> Self {
+        Vec::new_in(GlobalHuge)
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        Vec::with_capacity_in(capacity, GlobalHuge)
+    }
+
+    fn try_with_capacity(capacity: usize) -> Result<Self, AllocError> {
+        crate::collections::try_huge_vec_with_capacity(capacity, GlobalHuge)
+    }
+}
+
+/// Constructors for [`HugeBox`]
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::{HugeBox, HugeBoxExt};
+///
+/// let table: HugeBox<[u64; 1024 * 1024]> = HugeBox::new_zeroed_huge();
+/// ```
+pub trait HugeBoxExt<T> {
+    /// Moves a value in to a new box
+    fn new(x: T) -> Self;
+
+    /// Creates a box holding a zeroed value without constructing it on the stack first. This is only
+    /// valid for types where all zero bytes is a valid value
+    fn new_zeroed_huge() -> Self;
+
+    /// Moves a value in to a new box, returning an error instead of aborting if the memory can't be
+    /// allocated
+    fn try_new(x: T) -> Result<Self, AllocError>
+    where
+        Self: Sized;
+
+    /// Creates a box holding a zeroed value as [`HugeBoxExt::new_zeroed_huge`], returning an error instead
+    /// of aborting if the memory can't be allocated
+    fn try_new_zeroed_huge() -> Result<Self, AllocError>
+    where
+        Self: Sized;
+}
+
+impl<T> HugeBoxExt<T> for HugeBox<T> {
+    fn new(x: T) -> Self {
+        Box::new_in(x, GlobalHuge)
+    }
+
+    fn new_zeroed_huge() -> Self {
+        unsafe { Box::new_zeroed_in(GlobalHuge).assume_init() }
+    }
+
+    fn try_new(x: T) -> Result<Self, AllocError> {
+        Box::try_new_in(x, GlobalHuge)
+    }
+
+    fn try_new_zeroed_huge() -> Result<Self, AllocError> {
+        Ok(unsafe { Box::try_new_zeroed_in(GlobalHuge)?.assume_init() })
+    }
+}
+
+unsafe impl Allocator for GlobalHuge {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        GLOBAL_HUGE.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        GLOBAL_HUGE.deallocate(ptr, layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        GLOBAL_HUGE.allocate_zeroed(layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        GLOBAL_HUGE.grow(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        GLOBAL_HUGE.grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        GLOBAL_HUGE.shrink(ptr, old_layout, new_layout)
+    }
+}
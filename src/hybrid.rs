@@ -0,0 +1,155 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::cmp::min;
+use std::ptr::{copy_nonoverlapping, null_mut, NonNull};
+use std::sync::OnceLock;
+
+use crate::HugeAllocator;
+
+thread_local! {
+    /// Set while the current thread is inside the huge page allocator so that any allocations the
+    /// allocator makes itself are routed to the system allocator
+    static IN_HUGE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A global allocator which forwards allocations below the huge page threshold to the system allocator
+/// and those above it to a [`HugeAllocator`]
+///
+/// ```rust
+/// use huge_allocator::HybridGlobalAlloc;
+///
+/// #[global_allocator]
+/// static GLOBAL: HybridGlobalAlloc = HybridGlobalAlloc::new(50);
+///
+/// let vec: Vec<u8> = Vec::with_capacity(2 * 1024 * 1024);
+///
+/// assert_eq!(1, GLOBAL.allocator().stats().unwrap().segments);
+/// ```
+pub struct HybridGlobalAlloc {
+    /// Threshold percentage of a huge page at which allocations are routed to the huge page allocator
+    threshold_pct: usize,
+    /// Huge page allocator, created on first use
+    huge: OnceLock<HugeAllocator>,
+}
+
+impl HybridGlobalAlloc {
+    /// Creates a new hybrid allocator with a given threshold percentage
+    pub const fn new(threshold_pct: usize) -> Self {
+        Self {
+            threshold_pct,
+            huge: OnceLock::new(),
+        }
+    }
+
+    /// Returns the huge page allocator serving allocations above the threshold
+    pub fn allocator(&self) -> &HugeAllocator {
+        self.huge.get_or_init(|| Self::guarded(|| HugeAllocator::new(self.threshold_pct)))
+    }
+
+    /// Returns true if an allocation of this size should be served by the huge page allocator
+    fn is_huge(&self, size: usize) -> bool {
+        (size * 100) / (2 * 1024 * 1024) >= self.threshold_pct && !IN_HUGE.try_with(Cell::get).unwrap_or(true)
+    }
+
+    /// Returns true if the pointer was allocated by the huge page allocator
+    fn owns(&self, ptr: *mut u8, size: usize) -> bool {
+        // Small allocations are never huge so avoid locking the registry
+        if (size * 100) / (2 * 1024 * 1024) < self.threshold_pct {
+            return false;
+        }
+
+        // Nothing allocated inside the huge page allocator is huge, and the registry may already be locked
+        // by this thread (when its own table is resized)
+        if IN_HUGE.try_with(Cell::get).unwrap_or(true) {
+            return false;
+        }
+
+        match NonNull::new(ptr) {
+            Some(ptr) => Self::guarded(|| self.allocator().generation_of(ptr).is_some()),
+            None => false,
+        }
+    }
+
+    /// Runs a closure with allocations on this thread routed to the system allocator
+    fn guarded<R>(f: impl FnOnce() -> R) -> R {
+        let prev = IN_HUGE.try_with(|flag| flag.replace(true)).unwrap_or(true);
+
+        let res = f();
+
+        let _ = IN_HUGE.try_with(|flag| flag.set(prev));
+
+        res
+    }
+
+    /// Allocates from the huge page allocator
+    fn huge_alloc(&self, layout: Layout) -> *mut u8 {
+        Self::guarded(|| match self.allocator().mapper.alloc(layout) {
+            Ok(ptr) => ptr.cast::<u8>().as_ptr(),
+            Err(_) => null_mut(),
+        })
+    }
+
+    /// Frees to the huge page allocator
+    fn huge_dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            Self::guarded(|| {
+                let _ = self.allocator().mapper.dealloc(ptr, layout);
+            })
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for HybridGlobalAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.is_huge(layout.size()) {
+            self.huge_alloc(layout)
+        } else {
+            System.alloc(layout)
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if self.is_huge(layout.size()) {
+            // Mapped pages are zeroed by default
+            self.huge_alloc(layout)
+        } else {
+            System.alloc_zeroed(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.owns(ptr, layout.size()) {
+            self.huge_dealloc(ptr, layout)
+        } else {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        let old_huge = self.owns(ptr, layout.size());
+        let new_huge = self.is_huge(new_size);
+
+        match (old_huge, new_huge) {
+            (false, false) => System.realloc(ptr, layout, new_size),
+            (true, true) => Self::guarded(|| {
+                match self.allocator().mapper.realloc(NonNull::new_unchecked(ptr), layout, new_layout) {
+                    Ok(new_ptr) => new_ptr.cast::<u8>().as_ptr(),
+                    Err(_) => null_mut(),
+                }
+            }),
+            _ => {
+                // Moving between allocators
+                let new_ptr = self.alloc(new_layout);
+
+                if !new_ptr.is_null() {
+                    copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+                    self.dealloc(ptr, layout);
+                }
+
+                new_ptr
+            }
+        }
+    }
+}
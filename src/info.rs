@@ -0,0 +1,41 @@
+use std::alloc::Layout;
+
+use crate::mmap::{MMap, PageSize, Protection};
+use crate::AllocOptions;
+
+/// Read-only description of a live allocation, passed to
+/// [`HugeAllocator::for_each_allocation`](crate::HugeAllocator::for_each_allocation)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationInfo {
+    /// Address of the allocation
+    pub ptr: usize,
+    /// Layout the allocation was made with
+    pub layout: Layout,
+    /// Mapped length of the segment in bytes
+    pub mapped: usize,
+    /// Page size backing the segment
+    pub page_size: PageSize,
+    /// Generation of the allocation
+    pub generation: u64,
+    /// Mapping options of the segment
+    pub options: AllocOptions,
+    /// Current access protection
+    pub protection: Protection,
+    /// True if the segment has been sealed
+    pub sealed: bool,
+}
+
+impl From<&MMap> for AllocationInfo {
+    fn from(mmap: &MMap) -> Self {
+        Self {
+            ptr: mmap.as_ptr() as usize,
+            layout: mmap.layout(),
+            mapped: mmap.alloc_size(),
+            page_size: mmap.page_size(),
+            generation: mmap.generation(),
+            options: *mmap.options(),
+            protection: mmap.protection(),
+            sealed: mmap.is_sealed(),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::alloc::Layout;
+use std::ptr::NonNull;
+use std::slice;
+
+use crate::{AllocError, HugeAllocator};
+
+/// A fixed set of equally sized buffers for registration with `io_uring_register_buffers`. Each buffer is
+/// a separate segment which is never moved or resized, so the iovecs stay valid until the set is dropped.
+/// The set holds a handle to its allocator so it can be kept alongside the ring for the ring's lifetime
+///
+/// ```rust
+/// use huge_allocator::HugeAllocator;
+///
+/// let allocator = HugeAllocator::new(50);
+///
+/// let mut buffers = allocator.io_buffers(4, 2 * 1024 * 1024).unwrap();
+///
+/// buffers.buffer_mut(0)[0] = 1;
+///
+/// // Pass buffers.iovecs() to io_uring_register_buffers
+/// assert_eq!(4, buffers.iovecs().len());
+/// assert_eq!(2 * 1024 * 1024, buffers.iovecs()[0].iov_len);
+/// ```
+pub struct IoBuffers {
+    allocator: HugeAllocator,
+    layout: Layout,
+    iovecs: Vec<libc::iovec>,
+}
+
+impl IoBuffers {
+    /// Allocates a set of buffers
+    pub(crate) fn new(allocator: &HugeAllocator, count: usize, size: usize) -> Result<Self, AllocError> {
+        let layout = Layout::from_size_align(size, 1).map_err(|_| AllocError)?;
+
+        let mut buffers = Self {
+            allocator: allocator.clone(),
+            layout,
+            iovecs: Vec::with_capacity(count),
+        };
+
+        for _ in 0..count {
+            // Any buffers already allocated are freed on drop if this fails
+            let ptr = buffers.allocator.mapper.alloc(layout)?;
+
+            buffers.iovecs.push(libc::iovec {
+                iov_base: ptr.cast::<libc::c_void>().as_ptr(),
+                iov_len: size,
+            });
+        }
+
+        Ok(buffers)
+    }
+
+    /// Returns the iovecs describing the buffers, in buffer index order
+    pub fn iovecs(&self) -> &[libc::iovec] {
+        &self.iovecs
+    }
+
+    /// Returns the number of buffers
+    pub fn len(&self) -> usize {
+        self.iovecs.len()
+    }
+
+    /// Returns true if the set has no buffers
+    pub fn is_empty(&self) -> bool {
+        self.iovecs.is_empty()
+    }
+
+    /// Returns the buffer at the given index
+    pub fn buffer(&self, index: usize) -> &[u8] {
+        let iovec = &self.iovecs[index];
+
+        unsafe { slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len) }
+    }
+
+    /// Returns the buffer at the given index mutably
+    pub fn buffer_mut(&mut self, index: usize) -> &mut [u8] {
+        let iovec = &self.iovecs[index];
+
+        unsafe { slice::from_raw_parts_mut(iovec.iov_base as *mut u8, iovec.iov_len) }
+    }
+}
+
+unsafe impl Send for IoBuffers {}
+unsafe impl Sync for IoBuffers {}
+
+impl Drop for IoBuffers {
+    fn drop(&mut self) {
+        for iovec in self.iovecs.drain(..) {
+            if let Some(ptr) = NonNull::new(iovec.iov_base as *mut u8) {
+                let _ = self.allocator.mapper.dealloc(ptr, self.layout);
+            }
+        }
+    }
+}
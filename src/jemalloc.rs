@@ -0,0 +1,247 @@
+//! jemalloc extent hooks backed by huge page mappings, enabled with the `jemalloc` feature
+//!
+//! Install the hooks on an arena with jemalloc's `arena.<i>.extent_hooks` mallctl, for example through
+//! `tikv_jemalloc_sys::mallctl`, and jemalloc will carve its allocations out of huge page extents. Extents
+//! are never returned to the system while the arena lives - jemalloc keeps them for reuse - as huge page
+//! mappings can't be partially unmapped or purged
+//!
+//! ```rust,ignore
+//! use huge_allocator::jemalloc::JemallocExtentHooks;
+//!
+//! let hooks = JemallocExtentHooks::new(50);
+//! let mut ptr = hooks.as_ptr();
+//!
+//! let name = c"arena.0.extent_hooks";
+//! unsafe {
+//!     tikv_jemalloc_sys::mallctl(
+//!         name.as_ptr(),
+//!         std::ptr::null_mut(),
+//!         std::ptr::null_mut(),
+//!         &mut ptr as *mut _ as *mut _,
+//!         std::mem::size_of_val(&ptr),
+//!     )
+//! };
+//! ```
+
+use std::alloc::Layout;
+use std::ffi::{c_uint, c_void};
+use std::mem::ManuallyDrop;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use nix::errno::Errno;
+
+use crate::mmap::{MMap, PageSize};
+use crate::options::AllocOptions;
+
+/// `extent_alloc_t`
+pub type ExtentAlloc =
+    unsafe extern "C" fn(*mut ExtentHooks, *mut c_void, usize, usize, *mut bool, *mut bool, c_uint) -> *mut c_void;
+/// `extent_dalloc_t`
+pub type ExtentDalloc = unsafe extern "C" fn(*mut ExtentHooks, *mut c_void, usize, bool, c_uint) -> bool;
+/// `extent_destroy_t`
+pub type ExtentDestroy = unsafe extern "C" fn(*mut ExtentHooks, *mut c_void, usize, bool, c_uint);
+/// `extent_commit_t`, `extent_decommit_t` and `extent_purge_t`
+pub type ExtentRange = unsafe extern "C" fn(*mut ExtentHooks, *mut c_void, usize, usize, usize, c_uint) -> bool;
+/// `extent_split_t`
+pub type ExtentSplit = unsafe extern "C" fn(*mut ExtentHooks, *mut c_void, usize, usize, usize, bool, c_uint) -> bool;
+/// `extent_merge_t`
+pub type ExtentMerge =
+    unsafe extern "C" fn(*mut ExtentHooks, *mut c_void, usize, *mut c_void, usize, bool, c_uint) -> bool;
+
+/// jemalloc's `extent_hooks_t`. Null entries opt out of the operation
+#[repr(C)]
+#[derive(Debug)]
+pub struct ExtentHooks {
+    /// Maps a new extent
+    pub alloc: Option<ExtentAlloc>,
+    /// Returns an extent to the system. Returning true opts out and jemalloc retains it
+    pub dalloc: Option<ExtentDalloc>,
+    /// Unconditionally destroys an extent
+    pub destroy: Option<ExtentDestroy>,
+    /// Commits pages within an extent
+    pub commit: Option<ExtentRange>,
+    /// Decommits pages within an extent
+    pub decommit: Option<ExtentRange>,
+    /// Lazily purges pages within an extent
+    pub purge_lazy: Option<ExtentRange>,
+    /// Purges pages within an extent
+    pub purge_forced: Option<ExtentRange>,
+    /// Splits an extent in two
+    pub split: Option<ExtentSplit>,
+    /// Merges two adjacent extents
+    pub merge: Option<ExtentMerge>,
+}
+
+/// Extent hooks mapping jemalloc extents with huge pages where the extent is at least the threshold
+/// percentage of a huge page and a whole number of huge pages, falling back to the default page size
+#[repr(C)]
+#[derive(Debug)]
+pub struct JemallocExtentHooks {
+    /// Must be first so jemalloc's hooks pointer can be cast back
+    hooks: ExtentHooks,
+    threshold_pct: usize,
+    mapped: AtomicUsize,
+    huge_mapped: AtomicUsize,
+}
+
+impl JemallocExtentHooks {
+    /// Creates the hooks with the given threshold percentage. They are leaked as jemalloc may use them
+    /// for the rest of the process
+    pub fn new(threshold_pct: usize) -> &'static Self {
+        Box::leak(Box::new(Self {
+            hooks: ExtentHooks {
+                alloc: Some(extent_alloc),
+                dalloc: Some(extent_dalloc),
+                destroy: Some(extent_destroy),
+                commit: None,
+                decommit: None,
+                purge_lazy: None,
+                purge_forced: None,
+                split: Some(extent_split),
+                merge: Some(extent_merge),
+            },
+            threshold_pct,
+            mapped: AtomicUsize::new(0),
+            huge_mapped: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Returns the `extent_hooks_t` pointer to install with mallctl
+    pub fn as_ptr(&'static self) -> *mut ExtentHooks {
+        &self.hooks as *const ExtentHooks as *mut ExtentHooks
+    }
+
+    /// Returns the bytes currently mapped for extents
+    pub fn mapped(&self) -> usize {
+        self.mapped.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total bytes of extents which were mapped on huge pages, including destroyed extents
+    pub fn huge_mapped(&self) -> usize {
+        self.huge_mapped.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if an extent of the given size is mapped on huge pages. Only whole huge pages can be, so
+    /// the mapping is exactly the extent
+    fn wants_huge(&self, size: usize) -> bool {
+        (size * 100) / PageSize::Size2m.bytes() >= self.threshold_pct && size.is_multiple_of(PageSize::Size2m.bytes())
+    }
+
+    /// Maps an extent, optionally at a fixed address
+    fn map(&self, addr: *mut c_void, size: usize, align: usize) -> *mut c_void {
+        let Ok(layout) = Layout::from_size_align(size, align) else {
+            return null_mut();
+        };
+
+        let addr = (!addr.is_null()).then_some(addr as usize);
+
+        let mmap = if self.wants_huge(size) {
+            match Self::map_aligned(addr, layout, &PageSize::Size2m) {
+                // Try default pages unless the range is in use
+                Err(e) if e != Errno::EEXIST => Self::map_aligned(addr, layout, &PageSize::SizeDefault),
+                mmap => mmap,
+            }
+        } else {
+            Self::map_aligned(addr, layout, &PageSize::SizeDefault)
+        };
+
+        let Ok(mut mmap) = mmap else {
+            return null_mut();
+        };
+
+        if mmap.alloc_size() > size {
+            // Mapped in whole units of the alignment - unmap the excess
+            drop(mmap.split_off(size));
+        }
+
+        self.mapped.fetch_add(size, Ordering::Relaxed);
+
+        if mmap.page_size() != PageSize::SizeDefault {
+            self.huge_mapped.fetch_add(size, Ordering::Relaxed);
+        }
+
+        ManuallyDrop::new(mmap).as_ptr() as *mut c_void
+    }
+
+    /// Maps an extent on the given page size aligned to the layout, optionally at a fixed address
+    fn map_aligned(addr: Option<usize>, layout: Layout, page_size: &PageSize) -> nix::Result<MMap> {
+        let align = layout.align().max(page_size.bytes());
+
+        MMap::new_aligned(addr, layout, page_size, align, &AllocOptions::default())
+    }
+}
+
+/// Recovers the hooks from jemalloc's hooks pointer
+unsafe fn hooks<'a>(extent_hooks: *mut ExtentHooks) -> &'a JemallocExtentHooks {
+    &*(extent_hooks as *const JemallocExtentHooks)
+}
+
+unsafe extern "C" fn extent_alloc(
+    extent_hooks: *mut ExtentHooks,
+    new_addr: *mut c_void,
+    size: usize,
+    alignment: usize,
+    zero: *mut bool,
+    commit: *mut bool,
+    _arena_ind: c_uint,
+) -> *mut c_void {
+    let ptr = hooks(extent_hooks).map(new_addr, size, alignment);
+
+    if !ptr.is_null() {
+        // Fresh mappings are zeroed and always committed
+        *zero = true;
+        *commit = true;
+    }
+
+    ptr
+}
+
+unsafe extern "C" fn extent_dalloc(
+    _extent_hooks: *mut ExtentHooks,
+    _addr: *mut c_void,
+    _size: usize,
+    _committed: bool,
+    _arena_ind: c_uint,
+) -> bool {
+    // Opt out - huge page extents may have been split at addresses which can't be unmapped
+    true
+}
+
+unsafe extern "C" fn extent_destroy(
+    extent_hooks: *mut ExtentHooks,
+    addr: *mut c_void,
+    size: usize,
+    _committed: bool,
+    _arena_ind: c_uint,
+) {
+    if libc::munmap(addr, size) == 0 {
+        hooks(extent_hooks).mapped.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe extern "C" fn extent_split(
+    extent_hooks: *mut ExtentHooks,
+    _addr: *mut c_void,
+    size: usize,
+    size_a: usize,
+    _size_b: usize,
+    _committed: bool,
+    _arena_ind: c_uint,
+) -> bool {
+    // Huge page extents can only be unmapped in whole huge pages, so refuse splits inside a huge page
+    hooks(extent_hooks).wants_huge(size) && !size_a.is_multiple_of(PageSize::Size2m.bytes())
+}
+
+unsafe extern "C" fn extent_merge(
+    _extent_hooks: *mut ExtentHooks,
+    _addr_a: *mut c_void,
+    _size_a: usize,
+    _addr_b: *mut c_void,
+    _size_b: usize,
+    _committed: bool,
+    _arena_ind: c_uint,
+) -> bool {
+    // Adjacent extents are contiguous address space so merging needs no work
+    false
+}
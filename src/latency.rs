@@ -0,0 +1,110 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::mmap::PageSize;
+
+/// Time spent in one kind of system call
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallLatency {
+    /// Number of calls timed
+    pub calls: usize,
+    /// Shortest call
+    pub min: Duration,
+    /// Mean call duration
+    pub mean: Duration,
+    /// Longest call
+    pub max: Duration,
+}
+
+impl SyscallLatency {
+    /// Combines the timings of another set of calls into this one
+    pub fn merge(&mut self, other: &SyscallLatency) {
+        if other.calls == 0 {
+            return;
+        }
+
+        if self.calls == 0 {
+            *self = *other;
+            return;
+        }
+
+        let calls = self.calls + other.calls;
+        let total = self.mean.as_nanos() * self.calls as u128 + other.mean.as_nanos() * other.calls as u128;
+
+        self.calls = calls;
+        self.min = self.min.min(other.min);
+        self.mean = Duration::from_nanos((total / calls as u128) as u64);
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// System calls which are timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Syscall {
+    Mmap,
+    Mremap,
+    Munmap,
+}
+
+/// Accumulated timings of a system call on one page size
+#[derive(Default, Clone, Copy)]
+struct Timing {
+    calls: usize,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+/// Records how long mmap, mremap and munmap take on each page size
+#[derive(Default)]
+pub(crate) struct LatencyRecorder {
+    /// Timings indexed by system call then page size (default, huge)
+    timings: Mutex<[[Timing; 2]; 3]>,
+}
+
+impl LatencyRecorder {
+    /// Runs a system call, recording how long it took
+    pub(crate) fn time<R>(&self, syscall: Syscall, page_size: PageSize, f: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+
+        let result = f();
+
+        self.record(syscall, page_size, start.elapsed());
+
+        result
+    }
+
+    /// Records a system call which took the given time
+    pub(crate) fn record(&self, syscall: Syscall, page_size: PageSize, elapsed: Duration) {
+        let mut timings = self.timings.lock().unwrap_or_else(PoisonError::into_inner);
+        let timing = &mut timings[syscall as usize][Self::page_index(page_size)];
+
+        if timing.calls == 0 || elapsed < timing.min {
+            timing.min = elapsed;
+        }
+
+        timing.max = timing.max.max(elapsed);
+        timing.total += elapsed;
+        timing.calls += 1;
+    }
+
+    /// Returns the timings of a system call on a page size
+    pub(crate) fn latency(&self, syscall: Syscall, page_size: PageSize) -> SyscallLatency {
+        let timings = self.timings.lock().unwrap_or_else(PoisonError::into_inner);
+        let timing = timings[syscall as usize][Self::page_index(page_size)];
+
+        SyscallLatency {
+            calls: timing.calls,
+            min: timing.min,
+            mean: Duration::from_nanos(timing.total.as_nanos().checked_div(timing.calls as u128).unwrap_or(0) as u64),
+            max: timing.max,
+        }
+    }
+
+    fn page_index(page_size: PageSize) -> usize {
+        match page_size {
+            PageSize::SizeDefault => 0,
+            _ => 1,
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::alloc::Layout;
+use std::fmt;
+use std::io;
+use std::mem::size_of;
+use std::ops::Deref;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr::NonNull;
+use std::slice;
+use std::thread::JoinHandle;
+
+use crate::{AllocOptions, HugeAllocator};
+
+/// userfaultfd API version
+const UFFD_API: u64 = 0xaa;
+/// Only handle faults from user mode (allowed without privilege since Linux 5.11)
+const UFFD_USER_MODE_ONLY: libc::c_int = 1;
+/// Page fault event type
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+/// Register for faults on missing pages
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+
+/// Builds a userfaultfd ioctl request number
+const fn uffd_ioctl(dir: u64, nr: u64, size: usize) -> u64 {
+    (dir << 30) | ((size as u64) << 16) | (0xaa << 8) | nr
+}
+
+const UFFDIO_API: u64 = uffd_ioctl(3, 0x3f, size_of::<UffdioApi>());
+const UFFDIO_REGISTER: u64 = uffd_ioctl(3, 0x00, size_of::<UffdioRegister>());
+const UFFDIO_UNREGISTER: u64 = uffd_ioctl(2, 0x01, size_of::<UffdioRange>());
+const UFFDIO_COPY: u64 = uffd_ioctl(3, 0x03, size_of::<UffdioCopy>());
+
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+/// Page fill callback. Receives the offset of the page in the segment and a zeroed page sized buffer
+pub type FillFn = Box<dyn FnMut(usize, &mut [u8]) + Send>;
+
+/// A segment whose pages are materialised on first touch by a fill callback, using userfaultfd. The
+/// callback runs on a dedicated handler thread while the faulting thread waits. The segment is never moved
+/// or resized and is released when dropped
+///
+/// ```rust,no_run
+/// use std::alloc::Layout;
+/// use huge_allocator::HugeAllocator;
+///
+/// let allocator = HugeAllocator::new(50);
+/// let layout = Layout::from_size_align(64 * 1024 * 1024, 1).unwrap();
+///
+/// let dataset = allocator
+///     .allocate_lazy(layout, |offset, page| page.fill((offset / page.len()) as u8))
+///     .unwrap();
+///
+/// // Only the pages touched are generated
+/// assert_eq!(3, dataset[3 * 2 * 1024 * 1024]);
+/// ```
+pub struct LazySegment {
+    allocator: HugeAllocator,
+    ptr: NonNull<u8>,
+    layout: Layout,
+    /// Mapped length registered with userfaultfd
+    mapped: usize,
+    uffd: OwnedFd,
+    /// Wakes the handler thread to shut it down
+    event: OwnedFd,
+    handler: Option<JoinHandle<()>>,
+}
+
+impl LazySegment {
+    /// Allocates a segment and registers it for lazy filling
+    pub(crate) fn new(allocator: &HugeAllocator, layout: Layout, fill: FillFn) -> io::Result<Self> {
+        let options = AllocOptions {
+            pinned: true,
+            ..allocator.mapper.default_options()
+        };
+
+        // Open the descriptors first so the segment is released by drop on any later failure
+        let uffd = open_uffd()?;
+        let event = open_eventfd()?;
+
+        let ptr = allocator
+            .mapper
+            .alloc_with(layout, &options)
+            .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+
+        let mut segment = Self {
+            allocator: allocator.clone(),
+            ptr: ptr.cast(),
+            layout,
+            mapped: ptr.len(),
+            uffd,
+            event,
+            handler: None,
+        };
+
+        // Handle faults on the whole mapping in units of the mapping page size
+        let page_size = allocator
+            .mapper
+            .with_map(|ptr_map| ptr_map.get(&(segment.ptr.as_ptr() as usize)).map(|mmap| mmap.page_size().bytes()))
+            .ok_or(io::ErrorKind::NotFound)?;
+
+        let mut register = UffdioRegister {
+            range: UffdioRange {
+                start: segment.ptr.as_ptr() as u64,
+                len: segment.mapped as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+
+        uffd_call(&segment.uffd, UFFDIO_REGISTER, &mut register)?;
+
+        let handler = Handler {
+            uffd: segment.uffd.as_raw_fd(),
+            event: segment.event.as_raw_fd(),
+            base: segment.ptr.as_ptr() as usize,
+            page_size,
+            fill,
+        };
+
+        segment.handler = Some(
+            std::thread::Builder::new()
+                .name("huge_allocator-uffd".to_string())
+                .spawn(move || handler.run())?,
+        );
+
+        Ok(segment)
+    }
+
+    /// Returns a raw pointer to the segment
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+}
+
+impl Deref for LazySegment {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl fmt::Debug for LazySegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazySegment")
+            .field("ptr", &self.ptr)
+            .field("len", &self.layout.size())
+            .finish()
+    }
+}
+
+unsafe impl Send for LazySegment {}
+unsafe impl Sync for LazySegment {}
+
+impl Drop for LazySegment {
+    fn drop(&mut self) {
+        if let Some(handler) = self.handler.take() {
+            // Wake the handler thread and wait for it to exit
+            let one: u64 = 1;
+            let _ = unsafe { libc::write(self.event.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+            let _ = handler.join();
+        }
+
+        let mut range = UffdioRange {
+            start: self.ptr.as_ptr() as u64,
+            len: self.mapped as u64,
+        };
+
+        // Unregistering is best effort as closing the userfaultfd also unregisters
+        let _ = uffd_call(&self.uffd, UFFDIO_UNREGISTER, &mut range);
+
+        let _ = self.allocator.mapper.dealloc(self.ptr, self.layout);
+    }
+}
+
+/// Page fault handler thread state
+struct Handler {
+    uffd: libc::c_int,
+    event: libc::c_int,
+    base: usize,
+    page_size: usize,
+    fill: FillFn,
+}
+
+impl Handler {
+    /// Services page faults until woken by the shutdown event
+    fn run(mut self) {
+        let mut page = vec![0u8; self.page_size];
+
+        loop {
+            let mut fds = [
+                libc::pollfd {
+                    fd: self.uffd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.event,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+
+            if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                return;
+            }
+
+            if fds[1].revents != 0 {
+                return;
+            }
+
+            // Read a uffd_msg
+            let mut msg = [0u8; 32];
+
+            let len = unsafe { libc::read(self.uffd, msg.as_mut_ptr() as *mut libc::c_void, msg.len()) };
+
+            if len != msg.len() as isize || msg[0] != UFFD_EVENT_PAGEFAULT {
+                continue;
+            }
+
+            let addr = u64::from_ne_bytes(msg[16..24].try_into().unwrap()) as usize;
+            let offset = (addr - self.base) / self.page_size * self.page_size;
+
+            page.fill(0);
+            (self.fill)(offset, &mut page);
+
+            let mut copy = UffdioCopy {
+                dst: (self.base + offset) as u64,
+                src: page.as_ptr() as u64,
+                len: self.page_size as u64,
+                mode: 0,
+                copy: 0,
+            };
+
+            // EEXIST means another fault on the same page was already serviced
+            let _ = unsafe { libc::ioctl(self.uffd, UFFDIO_COPY as _, &mut copy) };
+        }
+    }
+}
+
+/// Opens a userfaultfd and negotiates the API
+fn open_uffd() -> io::Result<OwnedFd> {
+    let flags = libc::O_CLOEXEC | libc::O_NONBLOCK;
+
+    let mut fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags | UFFD_USER_MODE_ONLY) };
+
+    if fd < 0 {
+        // Kernels before 5.11 don't support user mode only
+        fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+    }
+
+    if fd < 0 {
+        Err(io::Error::last_os_error())?;
+    }
+
+    let uffd = unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) };
+
+    let mut api = UffdioApi {
+        api: UFFD_API,
+        features: 0,
+        ioctls: 0,
+    };
+
+    uffd_call(&uffd, UFFDIO_API, &mut api)?;
+
+    Ok(uffd)
+}
+
+/// Opens an eventfd
+pub(crate) fn open_eventfd() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+
+    if fd < 0 {
+        Err(io::Error::last_os_error())?;
+    }
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Calls a userfaultfd ioctl
+fn uffd_call<T>(uffd: &OwnedFd, request: u64, arg: &mut T) -> io::Result<()> {
+    if unsafe { libc::ioctl(uffd.as_raw_fd(), request as _, arg as *mut T) } < 0 {
+        Err(io::Error::last_os_error())?;
+    }
+
+    Ok(())
+}
@@ -1,23 +1,224 @@
-#![feature(allocator_api)]
-#![feature(slice_ptr_get)]
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
+#![cfg_attr(all(test, feature = "nightly"), feature(slice_ptr_get))]
 
 #![warn(missing_docs)]
 
 //! A memory allocator which tries to use huge pages for big allocations
+//!
+//! Without the default `std` feature the crate is `no_std` and provides [`CoreHugeAlloc`], which tracks its
+//! segments with a spin lock and a self mapped table in place of `Mutex` and `HashMap`, and the registry
+//! free [`RawHugeAlloc`]
 
-mod mmap;
+#[cfg(feature = "std")]
+mod addrmap;
+#[cfg(feature = "nightly")]
+mod arc;
+#[cfg(feature = "nightly")]
+mod arena;
+#[cfg(feature = "std")]
+mod buf;
+#[cfg(feature = "std")]
+mod builder;
+#[cfg(feature = "std")]
+pub mod cgroup;
+#[cfg(feature = "std")]
+mod checkpoint;
+mod core_alloc;
+#[cfg(feature = "nightly")]
+pub mod collections;
+#[cfg(feature = "std")]
+mod deferred;
+#[cfg(feature = "std")]
+pub mod diagnostics;
+#[cfg(feature = "nightly")]
+mod dispatch;
+#[cfg(feature = "nightly")]
+mod fallback;
+#[cfg(feature = "std")]
+mod file_arena;
+#[cfg(feature = "nightly")]
+mod global;
+#[cfg(feature = "std")]
+mod hybrid;
+#[cfg(feature = "std")]
+mod info;
+#[cfg(feature = "std")]
+mod iobuf;
+#[cfg(feature = "std")]
+mod latency;
+#[cfg(feature = "std")]
+mod lazy;
+#[cfg(feature = "nightly")]
+pub mod load;
+#[cfg(feature = "std")]
+pub mod mmap;
+#[cfg(feature = "std")]
 mod mmapper;
+#[cfg(feature = "std")]
+pub mod mte;
+#[cfg(feature = "std")]
+mod options;
+#[cfg(feature = "std")]
+mod pinned;
+#[cfg(feature = "nightly")]
+pub mod prelude;
+#[cfg(feature = "std")]
+mod pool;
+#[cfg(feature = "std")]
+mod probe;
+#[cfg(feature = "std")]
+mod pressure;
+#[cfg(feature = "std")]
+mod profiler;
+#[cfg(feature = "std")]
+mod quota;
+mod raw;
+#[cfg(feature = "std")]
+mod region;
+#[cfg(feature = "std")]
+mod registry;
+#[cfg(feature = "std")]
+mod request;
+#[cfg(feature = "std")]
+mod secure;
+#[cfg(feature = "std")]
+pub mod segment;
+#[cfg(feature = "std")]
+pub mod shared;
+#[cfg(feature = "std")]
+mod snapshot;
+#[cfg(feature = "std")]
+mod stats_csv;
+mod sync;
+#[cfg(feature = "std")]
+mod sys;
+mod table;
+#[cfg(feature = "std")]
+mod tagged;
+#[cfg(feature = "std")]
+mod trace;
+#[cfg(feature = "std")]
+mod watermark;
 
-use std::alloc::{AllocError, Allocator, Layout};
+#[cfg(feature = "allocator-api2")]
+mod api2;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "jemalloc")]
+pub mod jemalloc;
+#[cfg(feature = "mimalloc")]
+pub mod mimalloc;
+#[cfg(feature = "opentelemetry")]
+pub mod otel;
+
+#[cfg(feature = "stress")]
+pub mod stress;
+#[cfg(all(feature = "std", any(test, feature = "testing")))]
+pub mod testing;
+
+use core::fmt;
+
+#[cfg(feature = "nightly")]
+use std::alloc::Allocator;
+#[cfg(feature = "std")]
+use std::alloc::Layout;
+#[cfg(feature = "std")]
+use std::any::Any;
+#[cfg(feature = "std")]
+use std::collections::BTreeMap;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "std")]
+use std::ops::Range;
+#[cfg(feature = "std")]
+use std::path::Path;
+#[cfg(feature = "std")]
 use std::ptr::NonNull;
+#[cfg(feature = "std")]
+use std::sync::Arc;
+#[cfg(feature = "std")]
+use std::time::Duration;
 
+#[cfg(feature = "std")]
 use mmapper::MMapper;
 
-/// Huge page allocator
+#[cfg(feature = "nightly")]
+pub use std::alloc::AllocError;
+
+#[cfg(feature = "nightly")]
+pub use arc::ArcHugeAllocator;
+#[cfg(feature = "nightly")]
+pub use arena::TypedHugeArena;
+#[cfg(feature = "std")]
+pub use buf::{HugeBuf, DIRECT_IO_ALIGN};
+#[cfg(feature = "std")]
+pub use builder::{HugeAllocatorBuilder, LogSink, SegmentHook};
+#[cfg(feature = "std")]
+pub use cgroup::HugetlbLimit;
+pub use core_alloc::{CoreHugeAlloc, CoreHugeStats};
+#[cfg(feature = "nightly")]
+pub use dispatch::DispatchAllocator;
+#[cfg(feature = "nightly")]
+pub use fallback::FallbackAllocator;
+#[cfg(feature = "std")]
+pub use file_arena::HugeFileArena;
+#[cfg(feature = "nightly")]
+pub use global::{global_allocator, GlobalHuge, HugeBox, HugeBoxExt, HugeVec, HugeVecExt};
+#[cfg(feature = "std")]
+pub use hybrid::HybridGlobalAlloc;
+#[cfg(feature = "std")]
+pub use info::AllocationInfo;
+#[cfg(feature = "std")]
+pub use iobuf::IoBuffers;
+#[cfg(feature = "std")]
+pub use mmap::{Advice, PageSize};
+#[cfg(feature = "std")]
+pub use latency::SyscallLatency;
+#[cfg(feature = "std")]
+pub use lazy::{FillFn, LazySegment};
+#[cfg(feature = "std")]
+pub use options::{AllocOptions, NumaPolicy, ShrinkPolicy};
+#[cfg(feature = "std")]
+pub use pinned::PinnedBuf;
+#[cfg(feature = "std")]
+pub use pool::{ObjectPool, Pooled};
+#[cfg(feature = "std")]
+pub use pressure::PressureMonitor;
+#[cfg(feature = "std")]
+pub use probe::{CapabilityReport, PoolRecommendation, ThpDefrag, ThpFallback, ThpMode};
+#[cfg(feature = "std")]
+pub use profiler::{ProfileReport, SampledCallsite};
+pub use raw::RawHugeAlloc;
+#[cfg(feature = "std")]
+pub use region::Region;
+#[cfg(feature = "std")]
+pub use registry::{all_stats, enable_registry, AllStats};
+#[cfg(feature = "std")]
+pub use request::{RequestArena, RequestId};
+#[cfg(feature = "std")]
+pub use secure::SecureHugeAllocator;
+#[cfg(feature = "std")]
+pub use snapshot::{RestoredSegment, SegmentSnapshot};
+#[cfg(feature = "std")]
+pub use stats_csv::{CsvRotation, StatsCsvWriter};
+#[cfg(feature = "std")]
+pub use tagged::{StaleHandle, TaggedPtr};
+#[cfg(feature = "std")]
+pub use trace::{read_trace, ReplayReport, TraceEvent, TraceOp, TraceRecorder};
+#[cfg(feature = "std")]
+pub use watermark::WatermarkMonitor;
+
+#[cfg(feature = "std")]
+/// Huge page allocator. This is a cheap handle - clones share the same segments and statistics, and the
+/// segments are released when the last clone is dropped
+#[derive(Clone)]
 pub struct HugeAllocator {
-    mapper: MMapper,
+    mapper: Arc<MMapper>,
 }
 
+#[cfg(feature = "std")]
 impl HugeAllocator {
     /// Creates a new huge page allocator with a given threshold percentage.
     /// As an example a threshold percentage of 50 will try and allocate a 2mb page for allocations >= 1mb
@@ -35,9 +236,731 @@ impl HugeAllocator {
     /// # assert_eq!(2, stats.segments, "Segments allocated should be 2");
     /// ```
     pub fn new(threshold_pct: usize) -> Self {
-        Self {
-            mapper: MMapper::new(threshold_pct),
+        Self::builder().threshold_pct(threshold_pct).build()
+    }
+
+    /// Returns a builder to create an allocator with non-default options
+    pub fn builder() -> HugeAllocatorBuilder {
+        HugeAllocatorBuilder::new()
+    }
+
+    /// Creates a child allocator with its own name and a quota of `quota` bytes of live allocations. The
+    /// child has the same options as this allocator and maps its own segments, but its allocations are also
+    /// charged to this allocator's quota and those of its ancestors, so a child can't allocate more than its
+    /// parent has left. Each level's [`quota_used`](HugeAllocatorStats::quota_used) includes its descendants.
+    /// The address hint, trace recorder, sampling profiler, background unmap thread and exit checkpoint
+    /// belong to this allocator alone and are not inherited
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let server = HugeAllocator::builder().quota(64 * 1024 * 1024).build();
+    ///
+    /// let tenant_a = server.child("tenant a", 48 * 1024 * 1024);
+    /// let tenant_b = server.child("tenant b", 48 * 1024 * 1024);
+    ///
+    /// let vec_a: Vec<u8, _> = Vec::with_capacity_in(40 * 1024 * 1024, &tenant_a);
+    ///
+    /// // Within tenant b's quota, but not what is left of the server's
+    /// assert!(Vec::<u8, _>::try_with_capacity_in(40 * 1024 * 1024, &tenant_b).is_err());
+    ///
+    /// assert_eq!(40 * 1024 * 1024, server.stats().unwrap().quota_used);
+    /// ```
+    pub fn child(&self, name: impl Into<String>, quota: usize) -> HugeAllocator {
+        HugeAllocatorBuilder::child_of(&self.mapper, name.into(), quota).build()
+    }
+
+    /// Checks the huge page capabilities of the host and process: the 2mb huge page pool, the transparent
+    /// huge page setting, the memory lock limit and any hugetlb cgroup limit. Call this at startup to fail
+    /// fast or log a warning rather than finding out from missed allocation statistics later
+    /// ```rust
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// println!("{}", HugeAllocator::probe());
+    /// ```
+    pub fn probe() -> CapabilityReport {
+        CapabilityReport::probe()
+    }
+
+    /// Allocates memory with mapping options which override the allocator defaults. The memory is
+    /// released with [`Allocator::deallocate`] as normal and keeps its options when reallocated
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::alloc::{Allocator, Layout};
+    /// use huge_allocator::{AllocOptions, HugeAllocator};
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    /// let options = AllocOptions {
+    ///     wipe_on_fork: true,
+    ///     ..Default::default()
+    /// };
+    ///
+    /// let layout = Layout::from_size_align(4096, 8).unwrap();
+    /// let ptr = allocator.allocate_with(layout, &options).unwrap();
+    ///
+    /// unsafe { allocator.deallocate(ptr.cast(), layout) };
+    /// ```
+    pub fn allocate_with(&self, layout: Layout, options: &AllocOptions) -> Result<NonNull<[u8]>, AllocError> {
+        self.mapper.alloc_with(layout, options)
+    }
+
+    /// Allocates memory bound to the given NUMA node. The binding is applied before any page is faulted in,
+    /// so the memory is only ever placed on that node. Fails if the node doesn't exist
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::alloc::{Allocator, Layout};
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    /// let layout = Layout::from_size_align(2 * 1024 * 1024, 8).unwrap();
+    ///
+    /// let ptr = allocator.allocate_on_node(layout, 0).unwrap();
+    ///
+    /// assert_eq!(Some(0), allocator.node_of(ptr.cast()));
+    ///
+    /// unsafe { allocator.deallocate(ptr.cast(), layout) };
+    /// ```
+    pub fn allocate_on_node(&self, layout: Layout, node: usize) -> Result<NonNull<[u8]>, AllocError> {
+        let options = AllocOptions {
+            node: Some(node),
+            ..self.mapper.default_options()
+        };
+
+        self.mapper.alloc_with(layout, &options)
+    }
+
+    /// Attaches a value to the allocation at the given address, returning any value previously attached. The
+    /// value moves with the allocation when it is reallocated and is dropped when the allocation is freed.
+    /// Fails if the address is not a live allocation
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::alloc::{Allocator, Layout};
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    /// let layout = Layout::from_size_align(4096, 8).unwrap();
+    ///
+    /// let ptr = allocator.allocate(layout).unwrap().cast();
+    /// allocator.set_userdata(ptr, 42u64).unwrap();
+    ///
+    /// assert_eq!(Some(42u64), allocator.get_userdata(ptr));
+    ///
+    /// unsafe { allocator.deallocate(ptr, layout) };
+    /// ```
+    pub fn set_userdata<T>(&self, ptr: NonNull<u8>, value: T) -> Result<Option<Box<dyn Any + Send + Sync>>, AllocError>
+    where
+        T: Any + Send + Sync,
+    {
+        self.mapper.set_userdata(ptr, Box::new(value))
+    }
+
+    /// Returns a copy of the value of type `T` attached to the allocation at the given address, if any
+    pub fn get_userdata<T>(&self, ptr: NonNull<u8>) -> Option<T>
+    where
+        T: Any + Clone,
+    {
+        self.mapper.with_userdata(ptr, |userdata| userdata?.downcast_ref::<T>().cloned())
+    }
+
+    /// Detaches and returns the value attached to the allocation at the given address, if any
+    pub fn take_userdata(&self, ptr: NonNull<u8>) -> Option<Box<dyn Any + Send + Sync>> {
+        self.mapper.take_userdata(ptr)
+    }
+
+    /// Returns the NUMA node the allocation at the given address is bound to, if any
+    pub fn node_of(&self, ptr: NonNull<u8>) -> Option<usize> {
+        self.mapper.with_map(|ptr_map| ptr_map.get(&MMapper::key(ptr)).and_then(|mmap| mmap.options().node))
+    }
+
+    /// Allocates memory at a fixed address, for example to rebuild an address space layout after restoring a
+    /// snapshot. The address must be aligned to the page size chosen for the allocation (2mb for huge page
+    /// sized allocations, falling back to the default page size). Fails if any part of the range is already
+    /// mapped. The memory is tracked and released as for any other allocation
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::alloc::{Allocator, Layout};
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    /// let layout = Layout::from_size_align(4 * 1024 * 1024, 8).unwrap();
+    ///
+    /// let ptr = allocator.allocate(layout).unwrap();
+    /// let addr = ptr.cast::<u8>().as_ptr() as usize;
+    ///
+    /// unsafe { allocator.deallocate(ptr.cast(), layout) };
+    ///
+    /// let ptr = allocator.allocate_at(addr, layout).unwrap();
+    /// assert_eq!(addr, ptr.cast::<u8>().as_ptr() as usize);
+    /// # unsafe { allocator.deallocate(ptr.cast(), layout) };
+    /// ```
+    pub fn allocate_at(&self, addr: usize, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.mapper
+            .alloc_at(addr, layout, &self.mapper.default_options())
+            .map_err(|_| AllocError)
+    }
+
+    /// Allocates a zero-initialised byte buffer of the given size which is released when dropped
+    pub fn alloc_raw(&self, size: usize) -> Result<HugeBuf<'_>, AllocError> {
+        HugeBuf::new(self, size)
+    }
+
+    /// Allocates a set of `count` buffers of `size` bytes each for registration with an io_uring instance.
+    /// The buffers are never moved and are released when the set is dropped
+    pub fn io_buffers(&self, count: usize, size: usize) -> Result<IoBuffers, AllocError> {
+        IoBuffers::new(self, count, size)
+    }
+
+    /// Allocates a zero-initialised buffer for O_DIRECT reads and writes. The buffer is aligned to
+    /// [`DIRECT_IO_ALIGN`] and its length is `len` rounded up to a multiple of it. Large buffers are backed
+    /// by huge pages as for any other allocation
+    /// ```rust
+    /// use huge_allocator::{HugeAllocator, DIRECT_IO_ALIGN};
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let buf = allocator.alloc_direct_io_buffer(1_000_000).unwrap();
+    ///
+    /// assert_eq!(0, buf.as_ptr() as usize % DIRECT_IO_ALIGN);
+    /// assert_eq!(0, buf.len() % DIRECT_IO_ALIGN);
+    /// ```
+    pub fn alloc_direct_io_buffer(&self, len: usize) -> Result<HugeBuf<'_>, AllocError> {
+        HugeBuf::new_direct_io(self, len)
+    }
+
+    /// Allocates a zero-initialised buffer which is locked in memory and never moved or resized, for
+    /// registration with hardware which accesses the memory directly
+    pub fn alloc_pinned(&self, size: usize) -> Result<PinnedBuf<'_>, AllocError> {
+        PinnedBuf::new(self, size)
+    }
+
+    /// Allocates a segment whose pages are filled on first touch by a callback, using userfaultfd. The
+    /// callback receives the offset of each page in the segment and a zeroed page sized buffer to fill.
+    /// Requires permission to use userfaultfd (see `vm.unprivileged_userfaultfd`)
+    pub fn allocate_lazy<F>(&self, layout: Layout, fill: F) -> io::Result<LazySegment>
+    where
+        F: FnMut(usize, &mut [u8]) + Send + 'static,
+    {
+        LazySegment::new(self, layout, Box::new(fill))
+    }
+
+    /// Allocates memory and returns it tagged with its generation. Free it with
+    /// [`HugeAllocator::deallocate_tagged`] to detect stale handles. Fails for untracked allocators, which
+    /// have no registry to check handles against
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::alloc::Layout;
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    /// let layout = Layout::from_size_align(4096, 8).unwrap();
+    ///
+    /// let tagged = allocator.allocate_tagged(layout).unwrap();
+    ///
+    /// unsafe {
+    ///     assert!(allocator.deallocate_tagged(tagged, layout).is_ok());
+    ///     assert!(allocator.deallocate_tagged(tagged, layout).is_err());
+    /// }
+    /// ```
+    pub fn allocate_tagged(&self, layout: Layout) -> Result<TaggedPtr, AllocError> {
+        if self.mapper.config().untracked {
+            Err(AllocError)?;
         }
+
+        let (ptr, generation) = self.mapper.alloc_tagged(layout, &self.mapper.default_options())?;
+
+        Ok(TaggedPtr { ptr, generation })
+    }
+
+    /// Deallocates memory allocated with [`HugeAllocator::allocate_tagged`]. If the allocation has already
+    /// been freed, or the address has since been reused by another allocation, nothing is freed and an
+    /// error is returned
+    ///
+    /// # Safety
+    ///
+    /// The layout must fit the allocation as for [`Allocator::deallocate`]
+    pub unsafe fn deallocate_tagged(&self, tagged: TaggedPtr, layout: Layout) -> Result<(), StaleHandle> {
+        self.mapper.dealloc_tagged(tagged.ptr.cast(), layout, tagged.generation)
+    }
+
+    /// Writes a snapshot of the allocation at the given address, which can be restored with
+    /// [`HugeAllocator::restore`]
+    pub fn save_segment(&self, ptr: NonNull<u8>, out: &mut impl Write) -> io::Result<()> {
+        snapshot::save_segment(self, ptr, out)
+    }
+
+    /// Writes a snapshot of every live allocation, returning the number of segments written. The allocator
+    /// stays usable, including from the writer, but segments freed while the snapshot is written stay mapped
+    /// until it is finished and reallocations copy rather than remap
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let mut vec: Vec<u8, _> = Vec::with_capacity_in(2 * 1024 * 1024, &allocator);
+    /// vec.extend_from_slice(b"warm start");
+    ///
+    /// let mut file = Vec::new();
+    /// assert_eq!(1, allocator.save_all(&mut file).unwrap());
+    ///
+    /// let restored = HugeAllocator::new(50);
+    /// let segments = restored.restore(&mut file.as_slice(), false).unwrap();
+    ///
+    /// assert_eq!(b"warm start", unsafe { &segments[0].ptr.as_ref()[..10] });
+    /// ```
+    pub fn save_all(&self, out: &mut impl Write) -> io::Result<usize> {
+        snapshot::save_all(self, out)
+    }
+
+    /// Writes a map of the address space the allocator has mapped, one line per segment sorted by address.
+    /// Live segments, freed segments held in the segment cache or unmap epoch, and the gap after each segment
+    /// are shown, so fragmentation and segments which can't grow in place can be spotted
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let vec: Vec<u8, _> = Vec::with_capacity_in(2 * 1024 * 1024, &allocator);
+    ///
+    /// let mut map = Vec::new();
+    /// allocator.dump_layout(&mut map).unwrap();
+    ///
+    /// let map = String::from_utf8(map).unwrap();
+    /// assert!(map.starts_with("1 segments mapping"));
+    /// assert!(map.contains(&format!("{:#018x}", vec.as_ptr() as usize)));
+    /// ```
+    pub fn dump_layout(&self, out: &mut impl Write) -> io::Result<()> {
+        addrmap::write_text(&self.mapper.mapped_ranges(), out)
+    }
+
+    /// Writes the address space map as an SVG image, with each segment drawn in proportion to its size and
+    /// coloured by page size and state
+    pub fn dump_layout_svg(&self, out: &mut impl Write) -> io::Result<()> {
+        addrmap::write_svg(&self.mapper.mapped_ranges(), out)
+    }
+
+    /// Replays the allocations, frees and resizes of a recorded trace against this allocator as fast as
+    /// possible, for evaluating configuration changes against a real workload. Allocations still live at the
+    /// end of the trace are freed before returning
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::sync::Arc;
+    /// use huge_allocator::{HugeAllocator, TraceRecorder};
+    ///
+    /// let recorder = Arc::new(TraceRecorder::memory(1024));
+    /// let recorded = HugeAllocator::builder().trace_recorder(recorder.clone()).build();
+    ///
+    /// let mut vec: Vec<u8, _> = Vec::new_in(&recorded);
+    /// vec.resize(4 * 1024 * 1024, 0);
+    /// drop(vec);
+    ///
+    /// let candidate = HugeAllocator::builder().threshold_pct(10).segment_cache(16 * 1024 * 1024).build();
+    /// let report = candidate.replay(&recorder.events());
+    ///
+    /// assert_eq!(0, report.failed);
+    /// assert_eq!(0, report.live);
+    /// ```
+    pub fn replay(&self, events: &[TraceEvent]) -> ReplayReport {
+        trace::replay(self, events)
+    }
+
+    /// Copies the allocation at the given address in to a new read-only mapping. This is a full copy taken
+    /// without blocking the allocator, not copy on write. The allocation stays mapped if it is freed while it
+    /// is copied, but writes through existing pointers are not blocked, so writers must be paused for a
+    /// consistent copy
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let mut vec: Vec<u8, _> = Vec::with_capacity_in(2 * 1024 * 1024, &allocator);
+    /// vec.extend_from_slice(b"checkpoint");
+    ///
+    /// let snapshot = allocator.snapshot(std::ptr::NonNull::new(vec.as_mut_ptr()).unwrap()).unwrap();
+    ///
+    /// vec[0] = b'C';
+    ///
+    /// assert_eq!(b"checkpoint", &snapshot[..10]);
+    /// ```
+    pub fn snapshot(&self, ptr: NonNull<u8>) -> Result<SegmentSnapshot, AllocError> {
+        snapshot::snapshot(self, ptr)
+    }
+
+    /// Restores the segments in a snapshot in to new allocations with the same sizes and alignments. If
+    /// `preserve_addresses` is set each segment is mapped at its original address, failing if that range
+    /// is in use
+    pub fn restore(&self, input: &mut impl Read, preserve_addresses: bool) -> io::Result<Vec<RestoredSegment>> {
+        snapshot::restore(self, input, preserve_addresses)
+    }
+
+    /// Write protects the allocation at the given address so it can only be read. Writes through existing
+    /// pointers fault, and growing or shrinking the allocation fails. The allocation can still be freed
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let mut table: Vec<u64, _> = Vec::with_capacity_in(1024 * 1024, &allocator);
+    /// table.extend(0..1024 * 1024);
+    ///
+    /// allocator.seal(std::ptr::NonNull::new(table.as_mut_ptr()).unwrap().cast()).unwrap();
+    ///
+    /// assert_eq!(1000, table[1000]);
+    /// ```
+    pub fn seal(&self, ptr: NonNull<u8>) -> Result<(), AllocError> {
+        self.mapper.seal(ptr)
+    }
+
+    /// Makes the allocation at the given address read execute so generated code can be run. The memory is
+    /// never writable and executable at the same time - use [`HugeAllocator::make_writable`] to switch back.
+    /// Executable allocations can't be grown or shrunk. Fails for sealed allocations
+    pub fn make_executable(&self, ptr: NonNull<u8>) -> Result<(), AllocError> {
+        self.mapper.set_executable(ptr, true)
+    }
+
+    /// Makes the allocation at the given address read write again after
+    /// [`HugeAllocator::make_executable`]. Fails for sealed allocations
+    pub fn make_writable(&self, ptr: NonNull<u8>) -> Result<(), AllocError> {
+        self.mapper.set_executable(ptr, false)
+    }
+
+    /// Returns the generation of the live allocation at the given address
+    pub fn generation_of(&self, ptr: NonNull<u8>) -> Option<u64> {
+        self.mapper.generation_of(ptr)
+    }
+
+    /// Returns the page size backing the live allocation at the given address, or None if the address is not
+    /// the start of an allocation from this allocator
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::{HugeAllocator, PageSize};
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let mut vec: Vec<u8, _> = Vec::with_capacity_in(2 * 1024 * 1024, &allocator);
+    ///
+    /// let chunk = match allocator.page_size_of(std::ptr::NonNull::new(vec.as_mut_ptr()).unwrap()) {
+    ///     Some(PageSize::Size2m) => 2 * 1024 * 1024,
+    ///     _ => 64 * 1024,
+    /// };
+    /// ```
+    pub fn page_size_of(&self, ptr: NonNull<u8>) -> Option<PageSize> {
+        self.mapper.page_size_of(ptr)
+    }
+
+    /// Retries huge page backing for an allocation which fell back to the default page size, for example once
+    /// huge pages have been freed after a warm up phase. On success the contents are moved to a new huge
+    /// page segment and the new pointer is returned - the old pointer must no longer be used. Allocations
+    /// already on huge pages are returned unchanged. On failure the allocation is left untouched
+    ///
+    /// # Safety
+    ///
+    /// The allocation may move, so no other references to it may be in use
+    pub unsafe fn promote(&self, ptr: NonNull<u8>) -> Result<NonNull<[u8]>, AllocError> {
+        self.mapper.promote(ptr)
+    }
+
+    /// Moves a huge page allocation on to the default page size, returning its huge pages to the system pool
+    /// for another process which needs them. The allocation keeps its address and contents. Returns the
+    /// number of huge page bytes released, which is 0 for allocations already on the default page size.
+    /// Pinned, sealed, protected and memory tagged allocations can't be demoted
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::ptr::NonNull;
+    /// use huge_allocator::{HugeAllocator, PageSize};
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let mut vec: Vec<u8, _> = Vec::with_capacity_in(4 * 1024 * 1024, &allocator);
+    /// vec.extend_from_slice(b"kept");
+    ///
+    /// let ptr = NonNull::new(vec.as_mut_ptr()).unwrap();
+    ///
+    /// unsafe { allocator.demote(ptr) }.unwrap();
+    ///
+    /// assert_eq!(Some(PageSize::SizeDefault), allocator.page_size_of(ptr));
+    /// assert_eq!(b"kept", &vec[..]);
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// The contents are copied to the new pages, so the allocation must not be written while it is demoted
+    pub unsafe fn demote(&self, ptr: NonNull<u8>) -> Result<usize, AllocError> {
+        self.mapper.demote(ptr)
+    }
+
+    /// Returns at least the given number of bytes of huge pages to the system pool where possible, first
+    /// unmapping cached huge page segments and then demoting live allocations which were allocated or last
+    /// grew longest ago (see [`HugeAllocator::demote`]). Returns the number of huge page bytes released
+    ///
+    /// # Safety
+    ///
+    /// The contents of demoted allocations are copied, so no allocation may be written while this runs
+    pub unsafe fn demote_coldest(&self, bytes: usize) -> usize {
+        self.mapper.demote_coldest(bytes)
+    }
+
+    /// Hands a live allocation to another allocator without copying, for example moving finished buffers
+    /// from a short lived loader to a long lived cache. The allocation keeps its address and contents and
+    /// moves between the two allocators' statistics
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::alloc::{Allocator, Layout};
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let loader = HugeAllocator::new(50);
+    /// let cache = HugeAllocator::new(50);
+    ///
+    /// let layout = Layout::from_size_align(4 * 1024 * 1024, 8).unwrap();
+    /// let ptr = loader.allocate(layout).unwrap().cast::<u8>();
+    ///
+    /// unsafe { loader.transfer(ptr, &cache) }.unwrap();
+    ///
+    /// assert_eq!(0, loader.stats().unwrap().segments);
+    /// assert_eq!(1, cache.stats().unwrap().segments);
+    ///
+    /// unsafe { cache.deallocate(ptr, layout) };
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// The allocation must afterwards be freed or resized through `to`, so nothing owning it may still free it
+    /// through this allocator
+    pub unsafe fn transfer(&self, ptr: NonNull<u8>, to: &HugeAllocator) -> Result<(), AllocError> {
+        self.mapper.transfer(ptr, &to.mapper)
+    }
+
+    /// Takes ownership of a mapping created elsewhere, for example by a capture library or by mapping a
+    /// hugetlbfs file, so it appears in the statistics and is unmapped when freed. The returned allocation
+    /// has a layout of `len` bytes aligned to the page size. It is pinned, so it can't be reallocated
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use std::alloc::{Allocator, Layout};
+    /// use std::ptr::NonNull;
+    /// use huge_allocator::{HugeAllocator, PageSize};
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let len = 64 * 1024;
+    /// let addr = unsafe {
+    ///     libc::mmap(
+    ///         std::ptr::null_mut(),
+    ///         len,
+    ///         libc::PROT_READ | libc::PROT_WRITE,
+    ///         libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+    ///         -1,
+    ///         0,
+    ///     )
+    /// };
+    /// let ptr = NonNull::new(addr.cast::<u8>()).unwrap();
+    ///
+    /// unsafe { allocator.adopt(ptr, len, PageSize::SizeDefault) }.unwrap();
+    ///
+    /// assert_eq!(1, allocator.stats().unwrap().segments);
+    ///
+    /// unsafe { allocator.deallocate(ptr, Layout::from_size_align(len, 4096).unwrap()) };
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// The range must be a whole read write mapping backed by the given page size, which nothing else will
+    /// unmap
+    pub unsafe fn adopt(&self, ptr: NonNull<u8>, len: usize, page_size: PageSize) -> Result<NonNull<[u8]>, AllocError> {
+        unsafe { self.mapper.adopt(ptr, len, page_size) }
+    }
+
+    /// Gives the kernel access pattern advice for the live allocation at the given address. Only whole
+    /// allocations owned by this allocator can be advised
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::{Advice, HugeAllocator};
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let mut scan: Vec<u8, _> = Vec::with_capacity_in(8 * 1024 * 1024, &allocator);
+    ///
+    /// allocator.advise(std::ptr::NonNull::new(scan.as_mut_ptr()).unwrap(), Advice::Sequential).unwrap();
+    /// ```
+    pub fn advise(&self, ptr: NonNull<u8>, advice: Advice) -> io::Result<()> {
+        self.mapper.advise(ptr, advice)
+    }
+
+    /// Frees every live allocation at once, returning the number of segments released. This suits arena
+    /// style lifecycles where a whole phase's allocations are discarded together
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// for _ in 0..4 {
+    ///     std::mem::forget(Vec::<u8, _>::with_capacity_in(1024 * 1024, &allocator));
+    /// }
+    ///
+    /// assert_eq!(4, unsafe { allocator.reset() });
+    /// assert_eq!(0, allocator.stats().unwrap().segments);
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// Nothing may reference any memory allocated from this allocator, and no collection owning such memory
+    /// may be dropped or otherwise free it afterwards
+    pub unsafe fn reset(&self) -> usize {
+        self.mapper.reset()
+    }
+
+    /// Releases all freed segments held in the segment cache (see [`HugeAllocatorBuilder::segment_cache`]),
+    /// returning the number of bytes unmapped. Call this when the process is asked to give memory back
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::builder().segment_cache(64 * 1024 * 1024).build();
+    ///
+    /// drop(Vec::<u8, _>::with_capacity_in(2 * 1024 * 1024, &allocator));
+    ///
+    /// assert_eq!(2 * 1024 * 1024, allocator.trim());
+    /// ```
+    pub fn trim(&self) -> usize {
+        self.mapper.trim()
+    }
+
+    /// Unmaps the freed segments collected in the current [unmap epoch](HugeAllocatorBuilder::unmap_epoch)
+    /// and waits for any [deferred unmaps](HugeAllocatorBuilder::deferred_unmap) to finish, returning the
+    /// number of bytes in the epoch
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::builder().unmap_epoch(1024).build();
+    ///
+    /// drop(Vec::<u8, _>::with_capacity_in(4096, &allocator));
+    ///
+    /// assert_eq!(1, allocator.stats().unwrap().epoch_segments);
+    /// assert_eq!(4096, allocator.flush());
+    /// assert_eq!(0, allocator.stats().unwrap().epoch_segments);
+    /// ```
+    pub fn flush(&self) -> usize {
+        self.mapper.flush()
+    }
+
+    /// Starts a [`PressureMonitor`] which trims the segment cache whenever tasks stall waiting for memory for
+    /// longer than `stall` in any `window`. Fails if pressure stall information is not available
+    pub fn watch_pressure(&self, stall: Duration, window: Duration) -> io::Result<PressureMonitor> {
+        PressureMonitor::new(self, stall, window)
+    }
+
+    /// Starts a [`WatermarkMonitor`] which checks the free 2mb huge pages on the host every `interval` and,
+    /// whenever fewer than `watermark` are free, gives huge pages back by releasing cached segments and
+    /// demoting the coldest live allocations. Fails if the huge page pool can't be read
+    ///
+    /// # Safety
+    ///
+    /// Demoted allocations are copied (see [`HugeAllocator::demote`]), so allocations must not be written
+    /// while the pool is below the watermark
+    pub unsafe fn watch_watermark(&self, watermark: usize, interval: Duration) -> io::Result<WatermarkMonitor> {
+        WatermarkMonitor::new(self, watermark, interval)
+    }
+
+    /// Starts a [`StatsCsvWriter`] which appends the allocator's statistics to a CSV file every `interval`,
+    /// rotating the file when it reaches the size in `rotation`. Fails if the file can't be opened
+    pub fn write_stats_csv(&self, path: impl AsRef<Path>, interval: Duration, rotation: CsvRotation) -> io::Result<StatsCsvWriter> {
+        StatsCsvWriter::new(self, path.as_ref(), interval, rotation)
+    }
+
+    /// Calls a closure with a description of each live allocation. The registry is locked while walking, so
+    /// the closure must not allocate or free through this allocator. Allocations are visited in no particular
+    /// order
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let vec: Vec<u8, _> = Vec::with_capacity_in(4 * 1024 * 1024, &allocator);
+    ///
+    /// let mut total = 0;
+    /// allocator.for_each_allocation(|info| total += info.layout.size());
+    ///
+    /// assert_eq!(4 * 1024 * 1024, total);
+    /// ```
+    pub fn for_each_allocation<F>(&self, mut f: F)
+    where
+        F: FnMut(&AllocationInfo),
+    {
+        self.mapper.with_map(|ptr_map| {
+            for mmap in ptr_map.values() {
+                f(&AllocationInfo::from(mmap));
+            }
+        })
+    }
+
+    /// Returns the range of the address space reserved for mappings so far when an address hint is configured
+    /// with [`HugeAllocatorBuilder::address_hint`]. Pointers outside the range are not from this allocator,
+    /// although mappings which couldn't be placed in the range live elsewhere
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::builder().address_hint(0x5000_0000_0000).build();
+    ///
+    /// let vec: Vec<u8, _> = Vec::with_capacity_in(4 * 1024 * 1024, &allocator);
+    ///
+    /// assert!(allocator.hint_region().unwrap().contains(&(vec.as_ptr() as usize)));
+    /// ```
+    pub fn hint_region(&self) -> Option<Range<usize>> {
+        self.mapper.hint_region()
+    }
+
+    /// Returns the allocator instance name if one was given with [`HugeAllocatorBuilder::name`]
+    pub fn name(&self) -> Option<&str> {
+        self.mapper.name()
+    }
+
+    /// Recommends how many 2mb huge pages to reserve for this allocator, based on the peak huge page demand of
+    /// live allocations at or above the threshold. Allocations which fell back to the default page size count
+    /// towards the demand, so the recommendation covers what the workload wanted rather than what it got
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// drop(Vec::<u8, _>::with_capacity_in(20 * 1024 * 1024, &allocator));
+    ///
+    /// let recommendation = allocator.recommend_pool_size();
+    /// println!("{}", recommendation);
+    ///
+    /// assert_eq!(11, recommendation.huge_pages);
+    /// ```
+    pub fn recommend_pool_size(&self) -> PoolRecommendation {
+        PoolRecommendation::new(self.mapper.peak_huge_demand(), self.mapper.stats().missed_allocs)
+    }
+
+    /// Returns the live bytes held by allocations sampled with
+    /// [`sample_backtraces`](HugeAllocatorBuilder::sample_backtraces), grouped by the call stack which made
+    /// them. Returns None if sampling is disabled
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::builder().sample_backtraces(1).build();
+    ///
+    /// let vec: Vec<u8, _> = Vec::with_capacity_in(4096, &allocator);
+    ///
+    /// let report = allocator.profile().unwrap();
+    /// println!("{}", report);
+    ///
+    /// assert_eq!(4096, report.callsites[0].live_bytes);
+    /// ```
+    pub fn profile(&self) -> Option<ProfileReport> {
+        self.mapper.profile()
     }
 
     /// Returns allocator statistics
@@ -53,23 +976,69 @@ impl HugeAllocator {
     /// assert_eq!(1, stats.segments, "Segments allocated should be 1");
     /// ```
     pub fn stats(&self) -> Result<HugeAllocatorStats, AllocError> {
-        self.mapper.stats()
+        Ok(self.mapper.stats())
+    }
+
+    /// Validates the allocator's internal bookkeeping. Checks that no segments (live or freed but still
+    /// mapped) overlap, that no allocation exceeds its mapping and that the statistics are consistent with
+    /// the registry. Build with the `validate` feature to run these checks after every allocation, free and
+    /// resize.
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::HugeAllocator;
+    /// let allocator = HugeAllocator::new(50);
+    ///
+    /// let vec: Vec<u8, _> = Vec::with_capacity_in(2 * 1024 * 1024, &allocator);
+    ///
+    /// assert!(allocator.check_integrity().is_ok());
+    /// ```
+    pub fn check_integrity(&self) -> Result<(), IntegrityError> {
+        self.mapper.check_integrity()
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for HugeAllocator {
+    /// Summarises the configuration and current usage
+    /// ```rust
+    /// use huge_allocator::HugeAllocator;
+    ///
+    /// let allocator = HugeAllocator::builder().name("cache").build();
+    ///
+    /// assert_eq!(
+    ///     "HugeAllocator { name: Some(\"cache\"), threshold_pct: 50, segments: 0, huge_segments: 0, \
+    ///      mapped: 0, missed_allocs: 0 }",
+    ///     format!("{:?}", allocator)
+    /// );
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = self.mapper.stats();
+
+        f.debug_struct("HugeAllocator")
+            .field("name", &self.name())
+            .field("threshold_pct", &self.mapper.threshold_pct())
+            .field("segments", &stats.segments)
+            .field("huge_segments", &stats.huge_segments)
+            .field("mapped", &stats.mapped)
+            .field("missed_allocs", &stats.missed_allocs)
+            .finish()
     }
 }
 
+#[cfg(feature = "nightly")]
 unsafe impl Allocator for HugeAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         self.mapper.alloc(layout)
     }
 
-    unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, _layout: Layout) {
-        match self.mapper.dealloc(ptr) {
+    unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: Layout) {
+        match self.mapper.dealloc(ptr, layout) {
             Ok(p) => p,
-            Err(e) => panic!("HugeAllocator::deallocate: Failed to dealloc ({})", e),
+            Err(e) => panic!("{}::deallocate: Failed to dealloc ({})", self.mapper.ident(), e),
         }
     }
 
-    fn allocate_zeroed(&self, layout: Layout) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
+    fn allocate_zeroed(&self, layout: Layout) -> Result<std::ptr::NonNull<[u8]>, AllocError> {
         // Mapped pages are zeroed by default so just revert to allocate()
         self.allocate(layout)
     }
@@ -113,9 +1082,29 @@ unsafe impl Allocator for HugeAllocator {
     }
 }
 
+/// The error type for allocation failure. This is `std::alloc::AllocError` when the `nightly` feature is
+/// enabled
+#[cfg(not(feature = "nightly"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+#[cfg(not(feature = "nightly"))]
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("memory allocation failed")
+    }
+}
+
+#[cfg(all(feature = "std", not(feature = "nightly")))]
+impl std::error::Error for AllocError {}
+
+#[cfg(feature = "std")]
 /// Allocator performance statistics
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct HugeAllocatorStats {
+    /// Allocator instance name
+    pub name: Option<String>,
+
     /// Total amount of memory allocated in bytes
     pub alloc: usize,
     /// Total amount of memory mapped in bytes
@@ -141,11 +1130,262 @@ pub struct HugeAllocatorStats {
     pub missed_allocs: usize,
     /// Allocations missed due to lack of huge pages in total megabytes
     pub missed_mb: f64,
+    /// Number of allocations which fell back to the default page size and were advised to use transparent
+    /// huge pages
+    pub thp_eligible: usize,
+    /// Number of huge page segments moved on to the default page size by [`HugeAllocator::demote`] and
+    /// [`HugeAllocator::demote_coldest`]
+    pub demoted_segments: usize,
+    /// Amount of memory returned to the huge page pool by demoting segments in bytes
+    pub demoted_bytes: usize,
     /// Number of failed remaps
     pub remaps_failed: usize,
+    /// Number of failed unmaps
+    pub unmaps_failed: usize,
+    /// Number of huge page allocations served at least partly from surplus (overcommitted) huge pages. Only
+    /// counted with [`track_surplus`](HugeAllocatorBuilder::track_surplus)
+    pub surplus_allocs: usize,
+    /// Amount of memory mapped from surplus huge pages in bytes. Only counted with
+    /// [`track_surplus`](HugeAllocatorBuilder::track_surplus)
+    pub surplus_bytes: usize,
+    /// Number of huge page allocations asking to be mergeable, which KSM ignores
+    pub mergeable_ignored: usize,
+    /// Number of [`RequestArena`]s which have finished
+    pub requests: usize,
+    /// Bytes allocated by all finished request arenas
+    pub request_bytes: usize,
+    /// Most bytes allocated by a single finished request arena
+    pub peak_request_bytes: usize,
+    /// Number of freed segments held in the segment cache
+    pub cached_segments: usize,
+    /// Amount of memory held in the segment cache in bytes
+    pub cached_bytes: usize,
+    /// Number of freed segments waiting to be unmapped by the background thread
+    pub deferred_segments: usize,
+    /// Amount of memory waiting to be unmapped by the background thread in bytes
+    pub deferred_bytes: usize,
+    /// Number of freed segments collected in the current unmap epoch
+    pub epoch_segments: usize,
+    /// Amount of memory collected in the current unmap epoch in bytes
+    pub epoch_bytes: usize,
+    /// Time spent in mmap mapping default page segments
+    pub default_mmap_latency: SyscallLatency,
+    /// Time spent in mmap mapping huge page segments, including failed attempts
+    pub huge_mmap_latency: SyscallLatency,
+    /// Time spent in mremap resizing default page segments
+    pub default_mremap_latency: SyscallLatency,
+    /// Time spent in mremap resizing huge page segments
+    pub huge_mremap_latency: SyscallLatency,
+    /// Time spent in munmap releasing default page segments
+    pub default_munmap_latency: SyscallLatency,
+    /// Time spent in munmap releasing huge page segments
+    pub huge_munmap_latency: SyscallLatency,
+    /// Amount of memory mapped bound to each NUMA node in bytes
+    pub node_mapped: BTreeMap<usize, usize>,
+    /// Tightest hugetlb cgroup limit on 2mb huge pages, if one applies
+    pub cgroup_limit: Option<HugetlbLimit>,
+    /// Number of mappings (live and cached segments) at which the allocator warns about vm.max_map_count,
+    /// or 0 if the check is disabled
+    pub map_count_limit: usize,
+    /// Maximum bytes of live allocations by the allocator and its children, or None if unlimited
+    pub quota: Option<usize>,
+    /// Bytes of live allocations charged to the quota, including those of child allocators
+    pub quota_used: usize,
     /// Percentage of mapped memory used by allocations
     pub efficiency: usize,
 }
 
-#[cfg(test)]
+#[cfg(feature = "std")]
+impl HugeAllocatorStats {
+    /// Adds the statistics of another allocator to these, for a rollup of several allocators. Counts and
+    /// sizes are summed (peaks take the maximum) and the efficiency recalculated. The name is kept only if
+    /// both are the same, the tightest cgroup limit and map count limit are kept, and quotas are summed
+    /// unless either is unlimited
+    ///
+    /// ```rust
+    /// #![feature(allocator_api)]
+    /// use huge_allocator::{HugeAllocator, HugeAllocatorStats};
+    ///
+    /// let requests = HugeAllocator::builder().name("requests").build();
+    /// let cache = HugeAllocator::builder().name("cache").build();
+    ///
+    /// let vec1: Vec<u8, _> = Vec::with_capacity_in(4096, &requests);
+    /// let vec2: Vec<u8, _> = Vec::with_capacity_in(4096, &cache);
+    ///
+    /// let mut total = HugeAllocatorStats::default();
+    ///
+    /// total.merge(&requests.stats().unwrap());
+    /// total.merge(&cache.stats().unwrap());
+    ///
+    /// assert_eq!(2 * 4096, total.alloc);
+    /// assert_eq!(None, total.name);
+    /// ```
+    pub fn merge(&mut self, other: &HugeAllocatorStats) {
+        if self.name != other.name {
+            self.name = None;
+        }
+
+        self.alloc += other.alloc;
+        self.mapped += other.mapped;
+        self.segments += other.segments;
+
+        self.default_alloc += other.default_alloc;
+        self.default_mapped += other.default_mapped;
+        self.default_segments += other.default_segments;
+
+        self.huge_alloc += other.huge_alloc;
+        self.huge_mapped += other.huge_mapped;
+        self.huge_segments += other.huge_segments;
+
+        self.missed_allocs += other.missed_allocs;
+        self.missed_mb += other.missed_mb;
+        self.thp_eligible += other.thp_eligible;
+        self.demoted_segments += other.demoted_segments;
+        self.demoted_bytes += other.demoted_bytes;
+        self.remaps_failed += other.remaps_failed;
+        self.unmaps_failed += other.unmaps_failed;
+        self.surplus_allocs += other.surplus_allocs;
+        self.surplus_bytes += other.surplus_bytes;
+        self.mergeable_ignored += other.mergeable_ignored;
+        self.requests += other.requests;
+        self.request_bytes += other.request_bytes;
+        self.peak_request_bytes = self.peak_request_bytes.max(other.peak_request_bytes);
+        self.cached_segments += other.cached_segments;
+        self.cached_bytes += other.cached_bytes;
+        self.deferred_segments += other.deferred_segments;
+        self.deferred_bytes += other.deferred_bytes;
+        self.epoch_segments += other.epoch_segments;
+        self.epoch_bytes += other.epoch_bytes;
+        self.default_mmap_latency.merge(&other.default_mmap_latency);
+        self.huge_mmap_latency.merge(&other.huge_mmap_latency);
+        self.default_mremap_latency.merge(&other.default_mremap_latency);
+        self.huge_mremap_latency.merge(&other.huge_mremap_latency);
+        self.default_munmap_latency.merge(&other.default_munmap_latency);
+        self.huge_munmap_latency.merge(&other.huge_munmap_latency);
+
+        for (node, mapped) in &other.node_mapped {
+            *self.node_mapped.entry(*node).or_default() += mapped;
+        }
+
+        self.cgroup_limit = match (self.cgroup_limit, other.cgroup_limit) {
+            (Some(a), Some(b)) => Some(if b.headroom() < a.headroom() { b } else { a }),
+            (a, b) => a.or(b),
+        };
+
+        self.quota = match (self.quota, other.quota) {
+            (Some(a), Some(b)) => Some(a + b),
+            _ => None,
+        };
+        self.quota_used += other.quota_used;
+
+        self.map_count_limit = match (self.map_count_limit, other.map_count_limit) {
+            (0, b) => b,
+            (a, 0) => a,
+            (a, b) => a.min(b),
+        };
+
+        self.efficiency = (self.alloc * 100).checked_div(self.mapped).unwrap_or(100);
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::ops::AddAssign<&HugeAllocatorStats> for HugeAllocatorStats {
+    fn add_assign(&mut self, other: &HugeAllocatorStats) {
+        self.merge(other);
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::ops::AddAssign for HugeAllocatorStats {
+    fn add_assign(&mut self, other: HugeAllocatorStats) {
+        self.merge(&other);
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::ops::Add for HugeAllocatorStats {
+    type Output = HugeAllocatorStats;
+
+    fn add(mut self, other: HugeAllocatorStats) -> HugeAllocatorStats {
+        self.merge(&other);
+        self
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::iter::Sum for HugeAllocatorStats {
+    fn sum<I: Iterator<Item = HugeAllocatorStats>>(iter: I) -> HugeAllocatorStats {
+        iter.fold(HugeAllocatorStats::default(), |total, stats| total + stats)
+    }
+}
+
+#[cfg(feature = "std")]
+/// Allocator bookkeeping inconsistency found by [`HugeAllocator::check_integrity`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// A registry key does not match the segment address it refers to
+    KeyMismatch {
+        /// Registry key
+        key: usize,
+        /// Segment address
+        ptr: usize,
+    },
+    /// An allocation is bigger than its mapped segment
+    SizeExceedsMapping {
+        /// Segment address
+        ptr: usize,
+        /// Allocation size in bytes
+        size: usize,
+        /// Mapped size in bytes
+        alloc_size: usize,
+    },
+    /// A segment is not aligned to, or not a whole number of, its pages
+    PageMisaligned {
+        /// Segment address
+        ptr: usize,
+        /// Mapped size in bytes
+        alloc_size: usize,
+        /// Page size in bytes
+        page_size: usize,
+    },
+    /// Two segments overlap
+    Overlap {
+        /// Address of the lower segment
+        first: usize,
+        /// Address of the higher segment
+        second: usize,
+    },
+    /// Statistics counters are inconsistent
+    StatsMismatch(&'static str),
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::KeyMismatch { key, ptr } => {
+                write!(f, "registry key {:#x} refers to segment at {:#x}", key, ptr)
+            }
+            IntegrityError::SizeExceedsMapping { ptr, size, alloc_size } => write!(
+                f,
+                "segment at {:#x} has allocation size {} greater than mapped size {}",
+                ptr, size, alloc_size
+            ),
+            IntegrityError::PageMisaligned { ptr, alloc_size, page_size } => write!(
+                f,
+                "segment at {:#x} (mapped size {}) is not aligned to page size {}",
+                ptr, alloc_size, page_size
+            ),
+            IntegrityError::Overlap { first, second } => {
+                write!(f, "segment at {:#x} overlaps segment at {:#x}", first, second)
+            }
+            IntegrityError::StatsMismatch(desc) => write!(f, "statistics mismatch ({})", desc),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for IntegrityError {}
+
+#[cfg(all(test, feature = "nightly"))]
 mod tests;
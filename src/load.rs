@@ -0,0 +1,187 @@
+//! Loading files and streams in to allocator backed buffers
+//!
+//! ```rust
+//! #![feature(allocator_api)]
+//! use huge_allocator::load::{load_file, ReadHugeExt};
+//! use huge_allocator::HugeAllocator;
+//!
+//! let allocator = HugeAllocator::new(50);
+//!
+//! let data = load_file("Cargo.toml", &allocator).unwrap();
+//!
+//! assert!(data.starts_with(b"[package]"));
+//!
+//! let mut stream: &[u8] = b"streamed";
+//! let data = stream.read_to_huge_vec(&allocator).unwrap();
+//!
+//! assert_eq!(b"streamed", &data[..]);
+//! ```
+
+use std::alloc::Allocator;
+use std::ffi::c_void;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::ptr::{copy_nonoverlapping, null_mut};
+use std::slice;
+
+
+use crate::collections::try_huge_vec_with_capacity;
+use crate::sys;
+
+/// Size of each read when loading a file
+const READ_CHUNK: usize = 16 * 1024 * 1024;
+
+/// Capacity growth unit when draining a reader (one huge page)
+const GROWTH_UNIT: usize = 2 * 1024 * 1024;
+
+/// Drains readers in to allocator backed vectors. Capacity doubles in whole huge pages so a large stream
+/// needs few reallocations
+pub trait ReadHugeExt: Read {
+    /// Reads all bytes until EOF, appending them to `buf`. Returns the number of bytes read
+    fn read_to_huge_end<A: Allocator>(&mut self, buf: &mut Vec<u8, A>) -> io::Result<usize>;
+
+    /// Reads all bytes until EOF in to a new vector from the allocator
+    fn read_to_huge_vec<A: Allocator>(&mut self, alloc: A) -> io::Result<Vec<u8, A>> {
+        let mut buf = Vec::new_in(alloc);
+
+        self.read_to_huge_end(&mut buf)?;
+
+        Ok(buf)
+    }
+}
+
+impl<R: Read + ?Sized> ReadHugeExt for R {
+    fn read_to_huge_end<A: Allocator>(&mut self, buf: &mut Vec<u8, A>) -> io::Result<usize> {
+        let start = buf.len();
+
+        // Spare capacity below this offset has been zeroed
+        let mut initialized = buf.len();
+
+        loop {
+            if buf.len() == buf.capacity() {
+                // Double in whole huge pages
+                let target = (buf.capacity() * 2).max(GROWTH_UNIT).next_multiple_of(GROWTH_UNIT);
+
+                buf.try_reserve_exact(target - buf.len())
+                    .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+            }
+
+            let len = buf.len();
+            let capacity = buf.capacity();
+
+            if initialized < capacity {
+                unsafe { buf.as_mut_ptr().add(initialized).write_bytes(0, capacity - initialized) };
+                initialized = capacity;
+            }
+
+            let spare = unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr().add(len), capacity - len) };
+
+            match self.read(spare) {
+                Ok(0) => return Ok(len - start),
+                Ok(n) => unsafe { buf.set_len(len + n) },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Reads a whole file in to a buffer from the allocator. The buffer is sized from the file length up
+/// front, prefaulted, and filled with large reads
+pub fn load_file<A: Allocator>(path: impl AsRef<Path>, alloc: A) -> io::Result<Vec<u8, A>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len() as usize;
+
+    let mut vec = allocate(len, alloc)?;
+
+    while vec.len() < len {
+        let want = (len - vec.len()).min(READ_CHUNK);
+
+        // Read straight in to the spare capacity
+        let res = unsafe {
+            libc::read(
+                file.as_raw_fd(),
+                vec.as_mut_ptr().add(vec.len()) as *mut c_void,
+                want,
+            )
+        };
+
+        if res < 0 {
+            let e = io::Error::last_os_error();
+
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            Err(e)?;
+        }
+
+        if res == 0 {
+            // The file was truncated while reading
+            break;
+        }
+
+        unsafe { vec.set_len(vec.len() + res as usize) };
+    }
+
+    Ok(vec)
+}
+
+/// Reads a whole file in to a buffer from the allocator by mapping the file and copying from the mapping.
+/// This avoids read syscalls and can be faster for files already in the page cache
+pub fn load_file_mmap<A: Allocator>(path: impl AsRef<Path>, alloc: A) -> io::Result<Vec<u8, A>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len() as usize;
+
+    let mut vec = allocate(len, alloc)?;
+
+    if len == 0 {
+        return Ok(vec);
+    }
+
+    let src = unsafe {
+        sys::mmap(
+            null_mut::<c_void>(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE | libc::MAP_POPULATE,
+            file.as_raw_fd(),
+            0,
+        )
+    }?;
+
+    unsafe {
+        libc::madvise(src, len, libc::MADV_SEQUENTIAL);
+
+        copy_nonoverlapping(src as *const u8, vec.as_mut_ptr(), len);
+        vec.set_len(len);
+
+        let _ = sys::munmap(src, len);
+    }
+
+    Ok(vec)
+}
+
+/// Allocates an empty buffer with capacity for `len` bytes and prefaults it
+fn allocate<A: Allocator>(len: usize, alloc: A) -> io::Result<Vec<u8, A>> {
+    let mut vec =
+        try_huge_vec_with_capacity(len, alloc).map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+
+    prefault(vec.as_mut_ptr(), vec.capacity());
+
+    Ok(vec)
+}
+
+/// Populates the page tables for a buffer up front (Linux 5.14 or later) so the copy in doesn't take a page
+/// fault per page. Only whole pages inside the buffer are populated and failure is ignored
+fn prefault(ptr: *mut u8, len: usize) {
+    let page = 4096;
+    let start = (ptr as usize).next_multiple_of(page);
+    let end = (ptr as usize + len) / page * page;
+
+    if end > start {
+        unsafe { libc::madvise(start as *mut c_void, end - start, libc::MADV_POPULATE_WRITE) };
+    }
+}
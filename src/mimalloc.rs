@@ -0,0 +1,85 @@
+//! Huge page arenas for mimalloc, enabled with the `mimalloc` feature
+//!
+//! [`MimallocArena::register`] maps a region from a [`HugeAllocator`] and hands it to mimalloc with
+//! `mi_manage_os_memory`, so mimalloc's heaps are carved out of huge pages. The region stays registered with the
+//! allocator, so it shows up in [`HugeAllocator::stats`] and is covered by [`HugeAllocator::check_integrity`].
+//! Pass the registration function from whichever mimalloc bindings are in use:
+//!
+//! ```rust,ignore
+//! use huge_allocator::{mimalloc::MimallocArena, HugeAllocator};
+//!
+//! static ALLOCATOR: std::sync::LazyLock<HugeAllocator> = std::sync::LazyLock::new(|| HugeAllocator::new(50));
+//!
+//! let arena = MimallocArena::register(&ALLOCATOR, 256 << 20, libmimalloc_sys::mi_manage_os_memory).unwrap();
+//! ```
+
+use std::alloc::Layout;
+use std::ffi::{c_int, c_void};
+use std::ptr::NonNull;
+
+use crate::mmap::PageSize;
+use crate::{AllocError, HugeAllocator};
+
+/// Signature of mimalloc's `mi_manage_os_memory(start, size, is_committed, is_large, is_zero, numa_node)`
+pub type ManageOsMemory = unsafe extern "C" fn(*mut c_void, usize, bool, bool, bool, c_int) -> bool;
+
+/// A region of huge page memory handed to mimalloc as an arena
+#[derive(Debug)]
+pub struct MimallocArena {
+    ptr: NonNull<u8>,
+    len: usize,
+    page_size: PageSize,
+}
+
+impl MimallocArena {
+    /// Maps `size` bytes (rounded up to a huge page) from the allocator and registers the region with mimalloc
+    /// through `manage`. The allocator must outlive mimalloc as the region can never be given back, hence
+    /// `'static`. Fails if the region can't be mapped or mimalloc rejects it
+    pub fn register(
+        allocator: &'static HugeAllocator,
+        size: usize,
+        manage: ManageOsMemory,
+    ) -> Result<Self, AllocError> {
+        let huge = PageSize::Size2m.bytes();
+        let len = size.div_ceil(huge).max(1) * huge;
+        let layout = Layout::from_size_align(len, huge).map_err(|_| AllocError)?;
+
+        let ptr = allocator.allocate_with(layout, &allocator.mapper.default_options())?.cast::<u8>();
+        let page_size = allocator.page_size_of(ptr).unwrap_or(PageSize::SizeDefault);
+        let node = allocator.node_of(ptr).map_or(-1, |node| node as c_int);
+
+        let large = page_size != PageSize::SizeDefault;
+
+        // Fresh mappings are committed and zeroed
+        if !unsafe { manage(ptr.as_ptr() as *mut c_void, len, true, large, true, node) } {
+            allocator.mapper.dealloc(ptr, layout)?;
+            Err(AllocError)?
+        }
+
+        Ok(Self { ptr, len, page_size })
+    }
+
+    /// Returns the start of the arena
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// Returns the length of the arena in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the arena is empty. Arenas are never empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the page size backing the arena
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+}
+
+// The arena is only a description of memory owned by mimalloc
+unsafe impl Send for MimallocArena {}
+unsafe impl Sync for MimallocArena {}
@@ -1,22 +1,74 @@
+//! Low level anonymous memory mapped segments
+//!
+//! [`MMap`] owns a single anonymous mapping backed by pages of a chosen [`PageSize`]. The segment is
+//! unmapped when dropped. Unlike the allocators in this crate no fallback is attempted - mapping huge
+//! pages fails if none are available
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use huge_allocator::mmap::{MMap, PageSize};
+//! use huge_allocator::AllocOptions;
+//!
+//! let layout = Layout::from_size_align(3 * 1024 * 1024, 8).unwrap();
+//!
+//! let mmap = MMap::new(layout, &PageSize::Size2m, &AllocOptions::default())
+//!     .or_else(|_| MMap::new(layout, &PageSize::SizeDefault, &AllocOptions::default()))
+//!     .unwrap();
+//!
+//! assert_eq!(layout.size(), mmap.size());
+//! assert!(mmap.alloc_size() >= mmap.size());
+//! assert_eq!(0, mmap.alloc_size() % mmap.page_size().bytes());
+//! ```
+
 use std::alloc::Layout;
-use std::ffi::c_void;
-use std::ptr::{null_mut, slice_from_raw_parts_mut, NonNull};
+use std::any::Any;
+use std::cell::Cell;
+use std::ffi::{c_void, CStr};
+use std::mem::{size_of, ManuallyDrop};
+use std::ptr::{null_mut, slice_from_raw_parts_mut, write_volatile, NonNull};
+use std::sync::atomic::{compiler_fence, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
 use lazy_static::lazy_static;
 
-use nix::{
-    sys::mman::{mmap, mremap, munmap, MRemapFlags, MapFlags, ProtFlags},
-    unistd::{sysconf, SysconfVar},
-};
+use nix::errno::Errno;
+
+use crate::mte;
+use crate::options::AllocOptions;
+use crate::sys;
+
+pub use nix::sys::mman::ProtFlags as Protection;
+
+/// mbind mode restricting allocation to the nodes in the mask
+const MPOL_BIND: libc::c_int = 2;
+/// mbind mode spreading pages across the nodes in the mask
+const MPOL_INTERLEAVE: libc::c_int = 3;
+/// End of the address range MAP_32BIT segments are placed in
+const MAP_32BIT_LIMIT: usize = 1 << 31;
+
+/// Number of segments which failed to unmap when dropped
+static DROP_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// Panic when a dropped segment fails to unmap
+static DROP_STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Called when a dropped segment fails to unmap
+static DROP_HOOK: RwLock<Option<DropFailureHook>> = RwLock::new(None);
+
+/// Function called with the address, mapped length and error when a dropped segment fails to unmap
+pub type DropFailureHook = fn(*mut u8, usize, Errno);
+
+thread_local! {
+    /// How long the last mmap call made by this thread took
+    static MAP_TIME: Cell<Option<Duration>> = const { Cell::new(None) };
+}
 
 lazy_static! {
     /// The default page size for the platform
     static ref DEFAULT_PAGE_SIZE: usize = {
-        match sysconf(SysconfVar::PAGE_SIZE) {
-            Ok(val) => match val {
-                Some(val) => val as usize,
-                None => panic!("sysconf PAGE_SIZE returned no value")
-            }
+        match sys::page_size() {
+            Ok(val) => val,
             Err(e) => panic!("sysconf PAGE_SIZE failed ({})", e)
         }
     };
@@ -25,11 +77,14 @@ lazy_static! {
 /// Available page sizes
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PageSize {
+    /// The default page size for the platform
     SizeDefault = 0,
+    /// 2mb huge pages
     Size2m = 2 * 1024 * 1024
 }
 
 impl PageSize {
+    /// Returns the page size in bytes
     pub fn bytes(&self) -> usize {
         match self {
             PageSize::SizeDefault => *DEFAULT_PAGE_SIZE,
@@ -37,14 +92,78 @@ impl PageSize {
         }
     }
 
-    fn map_flags(&self) -> MapFlags {
+    /// Returns a short description of the page size (e.g. "4k" or "2m")
+    pub fn short_name(&self) -> String {
+        let bytes = self.bytes();
+
+        if bytes.is_multiple_of(1024 * 1024) {
+            format!("{}m", bytes / (1024 * 1024))
+        } else {
+            format!("{}k", bytes / 1024)
+        }
+    }
+
+    fn map_flags(&self) -> libc::c_int {
         match self {
-            PageSize::SizeDefault => MapFlags::empty(),
-            PageSize::Size2m => MapFlags::MAP_HUGETLB | MapFlags::MAP_HUGE_2MB,
+            PageSize::SizeDefault => 0,
+            PageSize::Size2m => libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
         }
     }
 }
 
+/// Returns the NUMA node of the CPU the calling thread is running on
+pub(crate) fn current_node() -> Option<usize> {
+    let mut cpu: libc::c_uint = 0;
+    let mut node: libc::c_uint = 0;
+
+    let res = unsafe { libc::syscall(libc::SYS_getcpu, &mut cpu, &mut node, null_mut::<c_void>()) };
+
+    (res == 0).then_some(node as usize)
+}
+
+/// Access pattern advice for a segment, applied with madvise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    /// No special treatment (MADV_NORMAL)
+    Normal,
+    /// Expect sequential access - read ahead aggressively and free pages soon after access
+    /// (MADV_SEQUENTIAL)
+    Sequential,
+    /// Expect random access - disable read ahead (MADV_RANDOM)
+    Random,
+    /// Expect access soon - start paging in (MADV_WILLNEED)
+    WillNeed,
+    /// Rarely used - move the pages to the inactive list so they are reclaimed first under memory
+    /// pressure, keeping their contents (MADV_COLD, Linux 5.4 or later). Default page size segments only
+    Cold,
+    /// Not needed for a while - reclaim the pages now, writing them to swap, keeping their contents
+    /// (MADV_PAGEOUT, Linux 5.4 or later). Default page size segments only
+    PageOut,
+    /// Back the segment with transparent huge pages where possible (MADV_HUGEPAGE). Default page size
+    /// segments only
+    HugePage,
+}
+
+impl Advice {
+    /// Returns the madvise advice value
+    fn value(&self) -> libc::c_int {
+        match self {
+            Advice::Normal => libc::MADV_NORMAL,
+            Advice::Sequential => libc::MADV_SEQUENTIAL,
+            Advice::Random => libc::MADV_RANDOM,
+            Advice::WillNeed => libc::MADV_WILLNEED,
+            Advice::Cold => libc::MADV_COLD,
+            Advice::PageOut => libc::MADV_PAGEOUT,
+            Advice::HugePage => libc::MADV_HUGEPAGE,
+        }
+    }
+
+    /// Returns true if the advice only applies to default page size memory
+    fn default_pages_only(&self) -> bool {
+        matches!(self, Advice::Cold | Advice::PageOut | Advice::HugePage)
+    }
+}
+
 /// Descriptor for anonymous memory mapped segments
 #[derive(Debug)]
 pub struct MMap {
@@ -56,13 +175,155 @@ pub struct MMap {
     alloc_size: usize,
     /// Page size
     page_size: PageSize,
+    /// Mapping options
+    options: AllocOptions,
+    /// Allocation generation
+    generation: u64,
+    /// Current access protection
+    protection: Protection,
+    /// Write protected and not resizable
+    sealed: bool,
+    /// When the segment was first shrunk below the huge page threshold while keeping its huge pages
+    shrunk_at: Option<Instant>,
+    /// When the segment was mapped or last grew
+    grown_at: Instant,
+    /// Value attached by the user
+    userdata: Option<Userdata>,
+    /// Memory tag carried in the top byte of pointers in to the segment
+    tag: usize,
 }
 
+/// A value attached to an allocation
+pub(crate) type Userdata = Box<dyn Any + Send + Sync>;
+
 impl MMap {
-    /// Creates a new anonymous memory mapped segment. A huge page allocation is tried initially if the
-    /// size is above the threshold percentage. If that fails a default page size allocation is tried.
-    pub fn new(layout: Layout, page_size: &PageSize) -> nix::Result<MMap> {
-        Self::map(layout, page_size)
+    /// Creates a new anonymous read write memory mapped segment with the given page size, rounding the
+    /// mapping up to a whole number of pages. The mapping options are applied before returning
+    pub fn new(layout: Layout, page_size: &PageSize, options: &AllocOptions) -> nix::Result<MMap> {
+        let mmap = Self::map(None, layout, page_size, options)?;
+
+        // Apply the mapping options. The segment is unmapped on drop if this fails
+        mmap.apply_options()?;
+
+        Ok(mmap)
+    }
+
+    /// Creates a new anonymous read write memory mapped segment at the given address. Fails with EEXIST
+    /// if any part of the range is already mapped, or EINVAL if the address is not aligned to the page size
+    pub fn new_at(addr: usize, layout: Layout, page_size: &PageSize, options: &AllocOptions) -> nix::Result<MMap> {
+        if !addr.is_multiple_of(page_size.bytes()) {
+            Err(Errno::EINVAL)?;
+        }
+
+        let mmap = Self::map(Some(addr), layout, page_size, options)?;
+
+        if mmap.ptr != addr {
+            // Kernels before 4.17 treat MAP_FIXED_NOREPLACE as a hint
+            Err(Errno::EEXIST)?;
+        }
+
+        // Apply the mapping options. The segment is unmapped on drop if this fails
+        mmap.apply_options()?;
+
+        Ok(mmap)
+    }
+
+    /// Creates a new anonymous read write memory mapped segment with the given page size which starts on a
+    /// multiple of `align` and is a whole number of `align` bytes long. Without an address the mapping is
+    /// over-sized and the excess either side unmapped. With an address it must be aligned, and fails with
+    /// EEXIST if any part of the range is already mapped. The mapping options are applied before returning
+    pub fn new_aligned(
+        addr: Option<usize>,
+        layout: Layout,
+        page_size: &PageSize,
+        align: usize,
+        options: &AllocOptions,
+    ) -> nix::Result<MMap> {
+        if !align.is_power_of_two() || align < page_size.bytes() || addr.is_some_and(|addr| !addr.is_multiple_of(align)) {
+            Err(Errno::EINVAL)?;
+        }
+
+        let size = layout.size().checked_next_multiple_of(align).ok_or(Errno::ENOMEM)?;
+
+        let mut mmap = match addr {
+            Some(addr) => {
+                let padded = Layout::from_size_align(size, layout.align()).map_err(|_| Errno::ENOMEM)?;
+
+                let mmap = Self::map(Some(addr), padded, page_size, options)?;
+
+                if mmap.ptr != addr {
+                    // Kernels before 4.17 treat MAP_FIXED_NOREPLACE as a hint
+                    Err(Errno::EEXIST)?;
+                }
+
+                mmap
+            }
+            None => {
+                let over = size.checked_add(align - page_size.bytes()).ok_or(Errno::ENOMEM)?;
+                let padded = Layout::from_size_align(over, layout.align()).map_err(|_| Errno::ENOMEM)?;
+
+                let mut mmap = Self::map(None, padded, page_size, options)?;
+
+                let start = mmap.ptr.next_multiple_of(align);
+                let head = start - mmap.ptr;
+
+                if head > 0 {
+                    // Unmap the excess before the aligned start
+                    unsafe { sys::munmap(mmap.ptr as *mut c_void, head) }?;
+
+                    mmap.ptr = start;
+                    mmap.alloc_size -= head;
+                }
+
+                if mmap.alloc_size > size {
+                    // Unmap the excess after the aligned end
+                    unsafe { sys::munmap((start + size) as *mut c_void, mmap.alloc_size - size) }?;
+
+                    mmap.alloc_size = size;
+                }
+
+                mmap
+            }
+        };
+
+        mmap.layout = layout;
+
+        // Apply the mapping options. The segment is unmapped on drop if this fails
+        mmap.apply_options()?;
+
+        Ok(mmap)
+    }
+
+    /// Takes ownership of an existing read write mapping of `len` bytes backed by the given page size. The
+    /// segment is pinned, as the way it was mapped is unknown, and is unmapped on drop
+    ///
+    /// # Safety
+    ///
+    /// The range must be a whole mapping which nothing else will unmap
+    pub unsafe fn from_raw(ptr: usize, len: usize, page_size: &PageSize) -> nix::Result<MMap> {
+        if ptr == 0 || len == 0 || !ptr.is_multiple_of(page_size.bytes()) {
+            Err(Errno::EINVAL)?;
+        }
+
+        let layout = Layout::from_size_align(len, page_size.bytes()).map_err(|_| Errno::EINVAL)?;
+
+        Ok(MMap {
+            ptr,
+            layout,
+            alloc_size: Self::calc_alloc_size(len, page_size),
+            page_size: *page_size,
+            options: AllocOptions {
+                pinned: true,
+                ..Default::default()
+            },
+            generation: 0,
+            protection: Protection::PROT_READ | Protection::PROT_WRITE,
+            sealed: false,
+            shrunk_at: None,
+            grown_at: Instant::now(),
+            userdata: None,
+            tag: 0,
+        })
     }
 
     /// Returns the fat pointer
@@ -77,14 +338,39 @@ impl MMap {
 
     /// Returns the raw pointer to the memory mapped segment
     pub fn as_fat_ptr(&self) -> *mut [u8] {
-        slice_from_raw_parts_mut(self.as_ptr(), self.alloc_size)
+        slice_from_raw_parts_mut(self.data_ptr(), self.alloc_size)
     }
-    
-    /// Returns the allocation size of the segment
+
+    /// Returns the pointer to use for accessing the segment's memory, carrying its memory tag if it has one
+    pub(crate) fn data_ptr(&self) -> *mut u8 {
+        (self.ptr | self.tag) as *mut u8
+    }
+
+    /// Gives a memory tagged segment a new tag, so pointers carrying the old tag fault
+    pub(crate) fn retag(&mut self) {
+        if self.options.mte && self.alloc_size > 0 {
+            let tagged = unsafe { mte::retag(self.as_ptr(), self.alloc_size) } as usize;
+
+            self.tag = tagged - mte::untag(tagged);
+        }
+    }
+
+    /// Returns the requested size of the segment
     pub fn size(&self) -> usize {
         self.layout.size()
     }
 
+    /// Returns the layout the segment was allocated with
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Returns true if the layout fits this segment. The alignment must match and the size must be
+    /// between the requested size and the mapped size
+    pub fn fits(&self, layout: Layout) -> bool {
+        layout.align() == self.layout.align() && layout.size() >= self.layout.size() && layout.size() <= self.alloc_size
+    }
+
     /// Returns the total mapped size of the segment
     pub fn alloc_size(&self) -> usize {
         self.alloc_size
@@ -95,24 +381,267 @@ impl MMap {
         self.page_size
     }
 
-    /// Remaps a memory section
+    /// Returns the allocation generation
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Sets the allocation generation
+    pub(crate) fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    /// Sets the requested layout of a segment being reused. The layout must fit in the segment
+    pub(crate) fn set_layout(&mut self, layout: Layout) {
+        debug_assert!(Self::calc_alloc_size(layout.size(), &self.page_size) <= self.alloc_size);
+
+        self.layout = layout;
+    }
+
+    /// Returns true if the other segment is directly before or after this one with the same page size, options
+    /// and protection, so the two can be merged
+    pub(crate) fn adjoins(&self, other: &MMap) -> bool {
+        (self.ptr + self.alloc_size == other.ptr || other.ptr + other.alloc_size == self.ptr)
+            && self.page_size == other.page_size
+            && self.options == other.options
+            && self.protection == other.protection
+            && !self.sealed
+            && !other.sealed
+    }
+
+    /// Merges two adjoining segments into one covering both. The requested layout becomes the whole mapping
+    pub(crate) fn merge(self, other: MMap) -> MMap {
+        debug_assert!(self.adjoins(&other));
+
+        let (mut lower, upper) = if self.ptr < other.ptr { (self, other) } else { (other, self) };
+        let upper = ManuallyDrop::new(upper);
+
+        lower.alloc_size += upper.alloc_size;
+        lower.layout = Layout::from_size_align(lower.alloc_size, lower.layout.align()).unwrap();
+
+        lower
+    }
+
+    /// Splits the segment at the given offset, which must be a whole number of pages, returning the tail. The
+    /// requested layouts become the whole of each mapping
+    pub(crate) fn split_off(&mut self, at: usize) -> MMap {
+        debug_assert!(at > 0 && at < self.alloc_size && at.is_multiple_of(self.page_size.bytes()));
+
+        let tail = MMap {
+            ptr: self.ptr + at,
+            layout: Layout::from_size_align(self.alloc_size - at, self.layout.align()).unwrap(),
+            alloc_size: self.alloc_size - at,
+            page_size: self.page_size,
+            options: self.options,
+            generation: 0,
+            protection: self.protection,
+            sealed: false,
+            shrunk_at: None,
+            grown_at: self.grown_at,
+            userdata: None,
+            tag: 0,
+        };
+
+        self.alloc_size = at;
+        self.layout = Layout::from_size_align(at, self.layout.align()).unwrap();
+
+        tail
+    }
+
+    /// Returns the value attached by the user
+    pub(crate) fn userdata(&self) -> Option<&Userdata> {
+        self.userdata.as_ref()
+    }
+
+    /// Attaches a value, returning any previously attached
+    pub(crate) fn set_userdata(&mut self, userdata: Option<Userdata>) -> Option<Userdata> {
+        std::mem::replace(&mut self.userdata, userdata)
+    }
+
+    /// Returns when the segment was first shrunk below the huge page threshold while keeping its huge pages
+    pub(crate) fn shrunk_at(&self) -> Option<Instant> {
+        self.shrunk_at
+    }
+
+    /// Sets when the segment was first shrunk below the huge page threshold
+    pub(crate) fn set_shrunk_at(&mut self, shrunk_at: Option<Instant>) {
+        self.shrunk_at = shrunk_at;
+    }
+
+    /// Returns when the segment was mapped or last grew
+    pub(crate) fn grown_at(&self) -> Instant {
+        self.grown_at
+    }
+
+    /// Resets when the segment was mapped, for segments reused from the cache
+    pub(crate) fn reset_grown_at(&mut self) {
+        self.grown_at = Instant::now();
+    }
+
+    /// Returns the mapping options
+    pub fn options(&self) -> &AllocOptions {
+        &self.options
+    }
+
+    /// Applies the mapping options to the whole segment with madvise and mlock
+    pub fn apply_options(&self) -> nix::Result<()> {
+        if self.alloc_size == 0 {
+            return Ok(());
+        }
+
+        if self.options.wipe_on_fork {
+            self.madvise(libc::MADV_WIPEONFORK)?;
+        }
+
+        if self.options.dont_dump {
+            self.madvise(libc::MADV_DONTDUMP)?;
+        }
+
+        if self.options.dont_fork {
+            self.madvise(libc::MADV_DONTFORK)?;
+        }
+
+        if self.options.mergeable && self.page_size == PageSize::SizeDefault {
+            // Fails if the kernel is built without KSM, which just leaves the pages unmerged
+            let _ = self.madvise(libc::MADV_MERGEABLE);
+        }
+
+        if self.options.lock {
+            unsafe { sys::mlock(self.ptr as *const c_void, self.alloc_size) }?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies the NUMA placement options. Bound and interleaved segments are populated straight away so
+    /// placement is decided at allocation time rather than by whichever thread first touches each page
+    fn place(&self) -> nix::Result<()> {
+        if self.alloc_size == 0 {
+            return Ok(());
+        }
+
+        let bits = libc::c_ulong::BITS as usize;
+
+        let (mode, mask) = match (self.options.node, self.options.interleave) {
+            (Some(node), _) => {
+                let mut mask = vec![0 as libc::c_ulong; node / bits + 1];
+                mask[node / bits] |= 1 << (node % bits);
+
+                (MPOL_BIND, mask)
+            }
+            (None, Some(nodes)) => (MPOL_INTERLEAVE, vec![nodes as libc::c_ulong]),
+            (None, None) => return Ok(()),
+        };
+
+        // The kernel ignores the last bit of maxnode
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                self.ptr,
+                self.alloc_size,
+                mode,
+                mask.as_ptr(),
+                mask.len() * bits + 1,
+                0,
+            )
+        };
+
+        Errno::result(res)?;
+
+        // Populating is best effort as it needs Linux 5.14 or later
+        let _ = self.madvise(libc::MADV_POPULATE_WRITE);
+
+        Ok(())
+    }
+
+    /// Names the anonymous mapping so it can be identified in /proc/<pid>/maps
+    pub fn set_name(&self, name: &CStr) -> nix::Result<()> {
+        if self.alloc_size == 0 {
+            return Ok(());
+        }
+
+        let res = unsafe {
+            libc::prctl(
+                libc::PR_SET_VMA,
+                libc::PR_SET_VMA_ANON_NAME,
+                self.ptr as libc::c_ulong,
+                self.alloc_size as libc::c_ulong,
+                name.as_ptr() as libc::c_ulong,
+            )
+        };
+
+        Errno::result(res).map(drop)
+    }
+
+    /// Changes the access protection of the whole segment with mprotect
+    pub fn protect(&mut self, prot: Protection) -> nix::Result<()> {
+        if self.alloc_size > 0 {
+            let bits = prot.bits() | Self::mte_prot(&self.options);
+
+            unsafe { sys::mprotect(self.ptr as *mut c_void, self.alloc_size, bits) }?;
+        }
+
+        self.protection = prot;
+
+        Ok(())
+    }
+
+    /// Returns the current access protection of the segment
+    pub fn protection(&self) -> Protection {
+        self.protection
+    }
+
+    /// Returns true if the segment is mapped executable
+    pub fn is_executable(&self) -> bool {
+        self.protection.contains(Protection::PROT_EXEC)
+    }
+
+    /// Write protects the segment. A sealed segment is never resized by the allocators
+    pub fn seal(&mut self) -> nix::Result<()> {
+        self.protect(Protection::PROT_READ)?;
+        self.sealed = true;
+
+        Ok(())
+    }
+
+    /// Returns true if the segment has been sealed
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// Gives the kernel access pattern advice for the whole segment
+    pub fn advise(&self, advice: Advice) -> nix::Result<()> {
+        if advice.default_pages_only() && self.page_size != PageSize::SizeDefault {
+            // Huge pages are never reclaimed or backed by transparent huge pages
+            Err(Errno::EINVAL)?;
+        }
+
+        self.madvise(advice.value())
+    }
+
+    /// Calls madvise on the whole segment
+    fn madvise(&self, advice: libc::c_int) -> nix::Result<()> {
+        unsafe { sys::madvise(self.ptr as *mut c_void, self.alloc_size, advice) }
+    }
+
+    /// Resizes the segment to fit a new layout with mremap, keeping its page size. The segment may move,
+    /// unless it was mapped with MAP_32BIT as mremap could move it above 2GB. Returns false if the remap
+    /// failed, in which case the segment is unchanged
     pub fn remap(&mut self, new_layout: Layout) -> bool {
         let new_size = new_layout.size();
         let new_alloc_size = Self::calc_alloc_size(new_size, &self.page_size);
 
+        let flags = if self.options.map_32bit { 0 } else { libc::MREMAP_MAYMOVE };
+
         let ok = if self.alloc_size != new_alloc_size {
             // Try and remap
-            match unsafe {
-                mremap(
-                    self.ptr as *mut c_void,
-                    self.alloc_size,
-                    new_alloc_size,
-                    MRemapFlags::MREMAP_MAYMOVE,
-                    None,
-                )
-            } {
+            match unsafe { sys::mremap(self.ptr as *mut c_void, self.alloc_size, new_alloc_size, flags) } {
                 Ok(ptr) => {
                     // Success
+                    if new_alloc_size > self.alloc_size {
+                        self.grown_at = Instant::now();
+                    }
+
                     self.ptr = ptr as usize;
                     self.alloc_size = new_alloc_size;
 
@@ -134,37 +663,171 @@ impl MMap {
         ok
     }
 
-    /// Tries to map an anonymous read write segment with given page size.
-    /// Reverts to default page size on failure
-    fn map(layout: Layout, page_size: &PageSize) -> nix::Result<MMap> {
+    /// Zeroes the mapped segment from the given offset to the end using volatile writes which can't be
+    /// optimised away
+    pub fn wipe(&mut self, from: usize) {
+        if from >= self.alloc_size {
+            return;
+        }
+
+        let word = size_of::<usize>();
+
+        // Zero up to a word boundary
+        let mut offset = from;
+
+        while offset < self.alloc_size && !offset.is_multiple_of(word) {
+            unsafe { write_volatile(self.data_ptr().add(offset), 0) };
+            offset += 1;
+        }
+
+        // Zero whole words (the mapped size is always a whole number of pages)
+        while offset < self.alloc_size {
+            unsafe { write_volatile(self.data_ptr().add(offset) as *mut usize, 0) };
+            offset += word;
+        }
+
+        compiler_fence(Ordering::SeqCst);
+    }
+
+    /// Moves the segment on top of another segment of the same length with mremap, replacing the other
+    /// segment's pages at its address. On success the other segment's mapping no longer exists, so it must be
+    /// discarded with [`MMap::forget`]
+    pub(crate) fn move_over(&mut self, other: &MMap) -> nix::Result<()> {
+        debug_assert_eq!(self.alloc_size, other.alloc_size);
+
+        unsafe { sys::mremap_fixed(self.ptr as *mut c_void, self.alloc_size, other.ptr as *mut c_void) }?;
+
+        self.ptr = other.ptr;
+
+        Ok(())
+    }
+
+    /// Discards a segment whose mapping has been replaced without unmapping it
+    pub(crate) fn forget(self) {
+        let mut this = ManuallyDrop::new(self);
+
+        drop(this.userdata.take());
+    }
+
+    /// Returns the number of segments in the process which have failed to unmap when dropped
+    pub fn drop_failures() -> usize {
+        DROP_FAILURES.load(Ordering::Relaxed)
+    }
+
+    /// Sets a function to call when a dropped segment fails to unmap, replacing any previous one
+    pub fn set_drop_failure_hook(hook: Option<DropFailureHook>) {
+        *DROP_HOOK.write().unwrap_or_else(PoisonError::into_inner) = hook;
+    }
+
+    /// Panic when a dropped segment fails to unmap, unless the thread is already panicking. Applies to every
+    /// segment in the process. Defaults to false
+    pub fn set_strict_drop(strict: bool) {
+        DROP_STRICT.store(strict, Ordering::Relaxed);
+    }
+
+    /// Unmaps the segment, returning any error from munmap
+    pub fn unmap(self) -> nix::Result<()> {
+        let this = ManuallyDrop::new(self);
+
+        unsafe { sys::munmap(this.ptr as *mut c_void, this.alloc_size) }
+    }
+
+    /// Returns the extra protection flags for memory tagged segments
+    fn mte_prot(options: &AllocOptions) -> libc::c_int {
+        #[cfg(target_arch = "aarch64")]
+        if options.mte {
+            return mte::PROT_MTE;
+        }
+
+        let _ = options;
+
+        0
+    }
+
+    /// Returns the extra mmap flags for segments which must be in the first 2GB of the address space
+    fn map_32bit_flags(options: &AllocOptions) -> libc::c_int {
+        #[cfg(target_arch = "x86_64")]
+        if options.map_32bit {
+            return libc::MAP_32BIT;
+        }
+
+        let _ = options;
+
+        0
+    }
+
+    /// Maps an anonymous read write segment with given page size, optionally at a fixed address
+    fn map(addr: Option<usize>, layout: Layout, page_size: &PageSize, options: &AllocOptions) -> nix::Result<MMap> {
+        if options.mte && !mte::supported() {
+            Err(Errno::ENOTSUP)?;
+        }
+
+        if options.map_32bit && !cfg!(target_arch = "x86_64") {
+            Err(Errno::ENOTSUP)?;
+        }
+
         // Calculate mmap flags for this page size
-        let map_flags = page_size.map_flags();
+        let mut map_flags = page_size.map_flags() | Self::map_32bit_flags(options);
 
         // Calculate size of mapped area
         let alloc_size = Self::calc_alloc_size(layout.size(), page_size);
 
+        if let Some(addr) = addr {
+            // MAP_32BIT is ignored for fixed addresses
+            if options.map_32bit && addr.checked_add(alloc_size).is_none_or(|end| end > MAP_32BIT_LIMIT) {
+                Err(Errno::EINVAL)?;
+            }
+
+            map_flags |= libc::MAP_FIXED_NOREPLACE;
+        }
+
         // Try and map the memory
+        let start = Instant::now();
+
         let ptr = unsafe {
-            mmap(
-                null_mut::<c_void>(),
+            sys::mmap(
+                addr.map_or(null_mut::<c_void>(), |addr| addr as *mut c_void),
                 alloc_size,
-                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_ANON | MapFlags::MAP_PRIVATE | map_flags,
-                0,
+                libc::PROT_READ | libc::PROT_WRITE | Self::mte_prot(options),
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | map_flags,
+                -1,
                 0,
             )
-        }?;
+        };
 
-        Ok(MMap {
+        MAP_TIME.set(Some(start.elapsed()));
+
+        let ptr = ptr?;
+
+        let mmap = MMap {
             ptr: ptr as usize,
             layout,
             alloc_size,
             page_size: *page_size,
-        })
+            options: *options,
+            generation: 0,
+            protection: Protection::PROT_READ | Protection::PROT_WRITE,
+            sealed: false,
+            shrunk_at: None,
+            grown_at: Instant::now(),
+            userdata: None,
+            tag: 0,
+        };
+
+        // Place the segment before anything faults pages in. The segment is unmapped on drop if this fails
+        mmap.place()?;
+
+        Ok(mmap)
+    }
+
+    /// Returns how long the last mmap call made by this thread took, excluding the mapping options applied
+    /// afterwards, or None if it hasn't made one since the last call
+    pub(crate) fn take_map_time() -> Option<Duration> {
+        MAP_TIME.take()
     }
 
     /// Calculates the allocation size (whole pages) required for the size required
-    fn calc_alloc_size(size: usize, page_size: &PageSize) -> usize {
+    pub(crate) fn calc_alloc_size(size: usize, page_size: &PageSize) -> usize {
         if size > 0 {
             let page_bytes = page_size.bytes();
 
@@ -176,12 +839,22 @@ impl MMap {
 }
 
 impl Drop for MMap {
-    /// Unmaps the anonymous memory mapped segment on drop
+    /// Unmaps the anonymous memory mapped segment on drop. Failures are counted in
+    /// [`MMap::drop_failures`] and passed to any [drop failure hook](MMap::set_drop_failure_hook), or cause
+    /// a panic in [strict mode](MMap::set_strict_drop). Use [`MMap::unmap`] to handle the failure directly
     fn drop(&mut self) {
         let size = self.alloc_size();
 
-        if unsafe { munmap(self.ptr as *mut c_void, size) }.is_err() {
-            panic!("MMap::drop: failed to unmap ({:?})", self.layout);
+        if let Err(e) = unsafe { sys::munmap(self.ptr as *mut c_void, size) } {
+            DROP_FAILURES.fetch_add(1, Ordering::Relaxed);
+
+            if let Some(hook) = *DROP_HOOK.read().unwrap_or_else(PoisonError::into_inner) {
+                hook(self.ptr as *mut u8, size, e);
+            }
+
+            if DROP_STRICT.load(Ordering::Relaxed) && !std::thread::panicking() {
+                panic!("failed to unmap {:?} ({} bytes) on drop ({})", self.ptr as *mut u8, size, e);
+            }
         }
     }
 }
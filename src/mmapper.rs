@@ -1,97 +1,654 @@
 use std::{
-    alloc::{AllocError, Layout},
+    alloc::{GlobalAlloc, Layout},
     cmp::min,
     collections::HashMap,
+    ffi::CString,
+    fmt, io,
+    ops::Range,
     ptr::{copy_nonoverlapping, NonNull},
-    sync::{Mutex, MutexGuard},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::Instant,
 };
 
-use crate::mmap::{MMap, PageSize};
-use crate::HugeAllocatorStats;
+use nix::errno::Errno;
+
+use crate::addrmap::{MappedRange, RangeState};
+use crate::builder::{Config, SegmentHook};
+use crate::cgroup;
+use crate::checkpoint;
+use crate::deferred::DeferredUnmap;
+use crate::latency::{LatencyRecorder, Syscall};
+use crate::mmap::{self, Advice, MMap, PageSize, Protection, Userdata};
+use crate::mte;
+use crate::options::{AllocOptions, NumaPolicy, ShrinkPolicy};
+use crate::probe::{self, ThpFallback};
+use crate::profiler::{ProfileReport, Profiler};
+use crate::quota::Quota;
+use crate::raw::RawHugeAlloc;
+use crate::tagged::StaleHandle;
+use crate::trace::{TraceEvent, TraceOp};
+use crate::{AllocError, AllocationInfo, HugeAllocatorStats, IntegrityError};
 
 /// A collection of tracked memory mapped segments
 pub struct MMapper {
-    /// Threshold percentage to try and use huge pages.
-    /// For example a threshold percentage of 50 will try and allocate a 2mb page for allocations >= 1mb
-    threshold_pct: usize,
+    /// Allocator configuration
+    config: Config,
     ptr_map: Mutex<HashMap<usize, MMap>>,
     stats: Mutex<MMapperStats>,
+    /// Generation to give the next allocation
+    next_generation: AtomicU64,
+    /// Freed segments kept mapped for reuse
+    cache: Mutex<Vec<MMap>>,
+    /// Rate limiting state for each warning
+    warnings: Mutex<[WarningState; Warning::COUNT]>,
+    /// Next address to place a mapping at when an address hint is configured
+    hint_cursor: AtomicUsize,
+    /// Set once the hint region has proved unusable
+    hint_stopped: AtomicBool,
+    /// Number of mappings at which to warn about vm.max_map_count, 0 to disable
+    map_count_limit: usize,
+    /// Registry free mapper used in untracked mode
+    raw: RawHugeAlloc,
+    /// Byte budget shared with the parent allocator, if any
+    quota: Arc<Quota>,
+    /// Background unmapping of freed segments, if enabled
+    deferred: Option<DeferredUnmap>,
+    /// Freed segments waiting for the current unmap epoch to fill
+    epoch: Mutex<Vec<MMap>>,
+    /// Freed segments kept mapped while they are read without the registry lock
+    held: Mutex<Held>,
+    /// Time spent in mmap, mremap and munmap, shared with the unmap thread
+    latency: Arc<LatencyRecorder>,
+    /// Treatment of segments which fall back to the default page size, decided from the THP settings
+    thp_fallback: ThpFallback,
+    /// Sampling profiler attributing live bytes to call stacks, if enabled
+    profiler: Option<Profiler>,
+    /// Set once the exit checkpoint has been written
+    checkpointed: AtomicBool,
 }
 
 impl MMapper {
     /// Create a new memory mappings container
-    pub fn new(threshold_pct: usize) -> Self {
+    pub fn new(config: Config) -> Self {
+        let hint_base = config.address_hint.map_or(0, |base| base.next_multiple_of(PageSize::Size2m.bytes()));
+
+        let map_count_limit = config.map_count_limit.unwrap_or_else(|| probe::read_max_map_count() / 10 * 9);
+        let raw = RawHugeAlloc::new(config.threshold_pct);
+        let quota = Arc::new(Quota::new(config.quota, config.parent_quota.clone()));
+
+        let thp_fallback = if config.thp_fallback {
+            ThpFallback::decide(probe::read_thp(), probe::read_thp_defrag())
+        } else {
+            ThpFallback::Disabled
+        };
 
-        Self {
-            threshold_pct,
+        let mut mapper = Self {
+            config,
             ptr_map: Mutex::new(HashMap::new()),
             stats: Mutex::new(MMapperStats::default()),
+            next_generation: AtomicU64::new(1),
+            cache: Mutex::new(Vec::new()),
+            warnings: Mutex::new(Default::default()),
+            hint_cursor: AtomicUsize::new(hint_base),
+            hint_stopped: AtomicBool::new(false),
+            map_count_limit,
+            raw,
+            quota,
+            deferred: None,
+            epoch: Mutex::new(Vec::new()),
+            held: Mutex::new(Held::default()),
+            latency: Arc::new(LatencyRecorder::default()),
+            thp_fallback,
+            profiler: None,
+            checkpointed: AtomicBool::new(false),
+        };
+
+        if mapper.config.sample_every > 0 {
+            mapper.profiler = Some(Profiler::new(mapper.config.sample_every));
+        }
+
+        if mapper.config.deferred_unmap {
+            let log_sink = mapper.config.log_sink.clone();
+
+            match DeferredUnmap::new(mapper.ident(), log_sink, mapper.latency.clone(), mapper.config.strict_unmap) {
+                Ok(deferred) => mapper.deferred = Some(deferred),
+                Err(e) => mapper.log(format_args!("failed to start unmap thread, unmapping synchronously ({})", e)),
+            }
         }
+
+        mapper
     }
 
-    /// Allocates an anonymous memory mapped segment
+    /// Allocates an anonymous memory mapped segment with the default options
     pub fn alloc(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_with(layout, &self.config.default_options)
+    }
+
+    /// Allocates an anonymous memory mapped segment with the given options
+    pub fn alloc_with(&self, layout: Layout, options: &AllocOptions) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc_tagged(layout, options).map(|(ptr, _)| ptr)
+    }
+
+    /// Allocates an anonymous memory mapped segment with the given options, returning the pointer and the
+    /// generation of the allocation
+    pub fn alloc_tagged(&self, layout: Layout, options: &AllocOptions) -> Result<(NonNull<[u8]>, u64), AllocError> {
+        if self.config.untracked {
+            return self.raw_alloc(layout).map(|ptr| (ptr, 0));
+        }
+
+        let result = self.alloc_untraced(layout, options);
+
+        let addr = result.as_ref().map_or(0, |(ptr, _)| ptr.as_ptr() as *mut u8 as usize);
+        self.trace(TraceOp::Map, addr, 0, layout, None);
+        self.validate("allocate", addr, layout);
+
+        result
+    }
+
+    /// Allocates a segment without recording it in the trace
+    fn alloc_untraced(&self, layout: Layout, options: &AllocOptions) -> Result<(NonNull<[u8]>, u64), AllocError> {
+        // Charge the quota up front so concurrent allocations can't overrun it
+        if !self.quota.try_charge(layout.size()) {
+            return Err(AllocError);
+        }
+
+        self.alloc_charged(layout, options).inspect_err(|_| self.quota.release(layout.size()))
+    }
+
+    /// Allocates a segment which has already been charged to the quota
+    fn alloc_charged(&self, layout: Layout, options: &AllocOptions) -> Result<(NonNull<[u8]>, u64), AllocError> {
         let size = layout.size();
+        let options = &self.placement(options);
 
         // Calculate page size for this allocation
         let page_size = self.target_page_size(size);
 
-        // Create the anon memory map with the desired page size
-        let mmap = match MMap::new(layout, &page_size) {
-            Ok(m) => m,
-            _ => {
-                // Failed - try default page size
-                if page_size == PageSize::SizeDefault {
-                    Err(AllocError)?
-                } else {
-                    match MMap::new(layout, &PageSize::SizeDefault) {
-                        Ok(m) => m,
-                        _ => Err(AllocError)?
-                    }
+        // Reuse a cached segment or create the anon memory map with the desired page size
+        let mmap = match self.cache_take(layout, &page_size, options) {
+            Some(mmap) => mmap,
+            None => self.map_new(None, layout, &page_size, options).map_err(|e| {
+                if e == Errno::ENOMEM && self.map_count_limit > 0 && self.map_count() >= self.map_count_limit {
+                    self.log(format_args!(
+                        "mmap failed with ENOMEM holding {} mappings, vm.max_map_count has probably been reached",
+                        self.map_count()
+                    ));
+                }
+
+                AllocError
+            })?,
+        };
+
+        self.register(mmap)
+    }
+
+    /// Allocates an anonymous memory mapped segment at a fixed address with the given options. Fails if
+    /// any part of the range is already mapped
+    pub fn alloc_at(&self, addr: usize, layout: Layout, options: &AllocOptions) -> nix::Result<NonNull<[u8]>> {
+        // Calculate page size for this allocation
+        let page_size = self.target_page_size(layout.size());
+        let options = &self.placement(options);
+
+        if !self.quota.try_charge(layout.size()) {
+            return Err(Errno::ENOMEM);
+        }
+
+        // Create the anon memory map at the address with the desired page size
+        let mmap = self.map_new(Some(addr), layout, &page_size, options).inspect_err(|_| self.quota.release(layout.size()))?;
+
+        self.register(mmap).map(|(ptr, _)| ptr).map_err(|_| {
+            self.quota.release(layout.size());
+            Errno::ENOMEM
+        })
+    }
+
+    /// Maps a new segment, optionally at a fixed address, falling back to the default page size unless that
+    /// is disallowed for the allocation size. Huge pages allocated from the surplus pool are counted if enabled
+    fn map_new(
+        &self,
+        addr: Option<usize>,
+        layout: Layout,
+        page_size: &PageSize,
+        options: &AllocOptions,
+    ) -> nix::Result<MMap> {
+        // Surplus huge pages are allocated when a mapping reserves more than the persistent pool has free
+        let surplus = (self.config.track_surplus && *page_size != PageSize::SizeDefault)
+            .then(|| probe::read_hugepages("surplus_hugepages"));
+
+        let mmap = self.map_placed(addr, layout, page_size, options)?;
+
+        if let Some(before) = surplus {
+            if mmap.page_size() != PageSize::SizeDefault {
+                let grown = probe::read_hugepages("surplus_hugepages").saturating_sub(before);
+
+                if grown > 0 {
+                    self.add_surplus(min(grown * PageSize::Size2m.bytes(), mmap.alloc_size()));
                 }
             }
+        }
+
+        Ok(mmap)
+    }
+
+    /// Maps a new segment in the hint region if there is one, otherwise where requested
+    fn map_placed(
+        &self,
+        addr: Option<usize>,
+        layout: Layout,
+        page_size: &PageSize,
+        options: &AllocOptions,
+    ) -> nix::Result<MMap> {
+        // Segments in the first 2GB are placed by the kernel, as the hint region lies above it
+        if addr.is_none() && !options.map_32bit {
+            if let Some(hint) = self.next_hint(layout, page_size) {
+                return match self.map_at(Some(hint), layout, page_size, options) {
+                    // Something else is mapped in the way - place it anywhere
+                    Err(Errno::EEXIST) => self.map_at(None, layout, page_size, options),
+                    Err(e) => {
+                        let mmap = self.map_at(None, layout, page_size, options);
+
+                        if mmap.is_ok() && !self.hint_stopped.swap(true, Ordering::Relaxed) {
+                            // The hint address is unusable (e.g. past the top of the address space) but
+                            // mapping anywhere works, so stop hinting
+                            self.log(format_args!("address hint {:#x} unusable ({}), no longer hinting", hint, e));
+                        }
+
+                        mmap
+                    }
+                    mmap => mmap,
+                };
+            }
+        }
+
+        self.map_at(addr, layout, page_size, options)
+    }
+
+    /// Maps a new segment, optionally at a fixed address, falling back to the default page size unless that
+    /// is disallowed for the allocation size
+    fn map_at(
+        &self,
+        addr: Option<usize>,
+        layout: Layout,
+        page_size: &PageSize,
+        options: &AllocOptions,
+    ) -> nix::Result<MMap> {
+        if !self.fallback_allowed(layout.size()) {
+            let mmap = match page_size {
+                // The hugetlb cgroup limit has been reached
+                PageSize::SizeDefault => Err(Errno::ENOMEM),
+                _ => self.map_timed(addr, layout, page_size, options),
+            };
+
+            if let Err(e) = mmap {
+                self.log(format_args!("no huge pages for a {} byte allocation ({})", layout.size(), e));
+            }
+
+            return mmap;
+        }
+
+        let mmap = match self.map_timed(addr, layout, page_size, options) {
+            // Try the default page size unless the range is in use
+            Err(e) if *page_size != PageSize::SizeDefault && e != Errno::EEXIST => self.map_fallback(addr, layout, options),
+            mmap => mmap,
+        }?;
+
+        if mmap.page_size() != *page_size {
+            self.warn(
+                Warning::Fallback,
+                format_args!("no huge pages for a {} byte allocation, using the default page size", layout.size()),
+            );
+
+            self.advise_thp(&mmap);
+        }
+
+        Ok(mmap)
+    }
+
+    /// Offers a segment which fell back to the default page size to transparent huge pages
+    fn advise_thp(&self, mmap: &MMap) {
+        if mmap.alloc_size() < PageSize::Size2m.bytes() {
+            return;
+        }
+
+        match self.thp_fallback {
+            ThpFallback::Advise => match mmap.advise(Advice::HugePage) {
+                Ok(()) => self.lock_stats().thp_eligible += 1,
+                Err(e) => self.log(format_args!("failed to advise transparent huge pages ({})", e)),
+            },
+            ThpFallback::AlreadyEligible => self.lock_stats().thp_eligible += 1,
+            ThpFallback::AvoidDefragStall | ThpFallback::Disabled => (),
+        }
+    }
+
+    /// Maps a segment on the default page size for an allocation which couldn't get huge pages, 2mb aligned
+    /// if configured
+    fn map_fallback(&self, addr: Option<usize>, layout: Layout, options: &AllocOptions) -> nix::Result<MMap> {
+        if !self.config.align_fallback {
+            return self.map_timed(addr, layout, &PageSize::SizeDefault, options);
+        }
+
+        let align = layout.align().max(PageSize::Size2m.bytes());
+
+        self.time_mmap(PageSize::SizeDefault, || MMap::new_aligned(addr, layout, &PageSize::SizeDefault, align, options))
+    }
+
+    /// Maps a segment with the given page size, optionally at a fixed address, recording how long mmap took.
+    /// Layouts aligned beyond the page size are mapped in whole units of the alignment
+    fn map_timed(
+        &self,
+        addr: Option<usize>,
+        layout: Layout,
+        page_size: &PageSize,
+        options: &AllocOptions,
+    ) -> nix::Result<MMap> {
+        self.time_mmap(*page_size, || {
+            if layout.align() > page_size.bytes() {
+                return MMap::new_aligned(addr, layout, page_size, layout.align(), options);
+            }
+
+            match addr {
+                Some(addr) => MMap::new_at(addr, layout, page_size, options),
+                None => MMap::new(layout, page_size, options),
+            }
+        })
+    }
+
+    /// Creates a segment, recording how long the mmap call itself took. Placement and the mapping options
+    /// applied afterwards aren't counted
+    fn time_mmap(&self, page_size: PageSize, map: impl FnOnce() -> nix::Result<MMap>) -> nix::Result<MMap> {
+        MMap::take_map_time();
+
+        let mmap = map();
+
+        if let Some(elapsed) = MMap::take_map_time() {
+            self.latency.record(Syscall::Mmap, page_size, elapsed);
+        }
+
+        mmap
+    }
+
+    /// Reserves the next range of the hint region for a mapping of the given layout and page size, aligned to
+    /// the page size. Returns None once hinting has stopped
+    fn next_hint(&self, layout: Layout, page_size: &PageSize) -> Option<usize> {
+        if self.hint_stopped.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let align = layout.align().max(page_size.bytes());
+
+        // Huge page allocations which fall back may be mapped in whole huge pages
+        let len = match page_size {
+            PageSize::SizeDefault => MMap::calc_alloc_size(layout.size(), page_size),
+            _ => MMap::calc_alloc_size(layout.size(), &PageSize::Size2m),
         };
 
+        self.hint_cursor
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |cursor| {
+                (cursor != 0).then(|| cursor.next_multiple_of(align).checked_add(len)).flatten()
+            })
+            .ok()
+            .map(|cursor| cursor.next_multiple_of(align))
+    }
+
+    /// Returns the range of addresses reserved from the hint region so far, if an address hint is configured
+    pub fn hint_region(&self) -> Option<Range<usize>> {
+        let base = self.config.address_hint?.next_multiple_of(PageSize::Size2m.bytes());
+
+        Some(base..self.hint_cursor.load(Ordering::Relaxed))
+    }
+
+    /// Returns false if an allocation of the given size must be on huge pages
+    fn fallback_allowed(&self, size: usize) -> bool {
+        !self.config.no_fallback || !self.above_threshold(size)
+    }
+
+    /// Records a newly mapped segment in the pointer map, returning the pointer and the generation given
+    /// to it
+    fn register(&self, mut mmap: MMap) -> Result<(NonNull<[u8]>, u64), AllocError> {
+        let size = mmap.size();
+
         if mmap.page_size() == PageSize::SizeDefault {
             // Log missed allocation
-            self.add_missed(size)?;
+            self.add_missed(size);
+        } else if mmap.options().mergeable {
+            // KSM doesn't merge huge pages
+            self.lock_stats().mergeable_ignored += 1;
         }
 
+        // Name the mapping
+        self.name_mmap(&mmap);
+
+        self.run_hook(&self.config.on_map, &mmap);
+
+        // Tag with a new generation
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        mmap.set_generation(generation);
+
+        // Give memory tagged segments a fresh tag
+        mmap.retag();
+
         // Get raw pointer
         let ptr = mmap.fat_ptr();
 
         // Insert in to hash map
         self.map_add(mmap)?;
 
-        Ok(ptr)
+        self.check_map_count();
+
+        Ok((ptr, generation))
+    }
+
+    /// Returns the number of mappings held by the allocator, live and cached
+    fn map_count(&self) -> usize {
+        self.lock_map().len() + self.lock_cache().len()
+    }
+
+    /// Trims the segment cache and warns when the allocator's mappings approach vm.max_map_count
+    fn check_map_count(&self) {
+        if self.map_count_limit == 0 {
+            return;
+        }
+
+        let mappings = self.map_count();
+
+        if mappings < self.map_count_limit {
+            return;
+        }
+
+        // Cached segments hold mappings too
+        let trimmed = self.trim();
+
+        self.warn(
+            Warning::MapCount,
+            format_args!(
+                "{} mappings is approaching vm.max_map_count, trimmed {} cached bytes - consider pooling small \
+                allocations",
+                mappings, trimmed
+            ),
+        );
     }
 
     /// Deallocates an anonymous memory mapped segment
-    pub fn dealloc(&self, ptr: NonNull<u8>) -> Result<(), AllocError> {
+    pub fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) -> Result<(), AllocError> {
+        if self.config.untracked {
+            unsafe { self.raw.dealloc(ptr.as_ptr(), layout) };
+            return Ok(());
+        }
+
+        // Validate the layout
+        self.check_layout(ptr, layout, "deallocate");
+
         // Remove from the map
-        self.map_remove(ptr)?;
+        let page_size = self.map_remove(ptr).map(|mmap| {
+            let page_size = mmap.page_size();
+            self.quota.release(mmap.size());
+            self.retire(mmap);
+            page_size
+        });
+
+        self.trace(TraceOp::Unmap, ptr.as_ptr() as usize, 0, layout, page_size);
+        self.validate("deallocate", ptr.as_ptr() as usize, layout);
+
+        Ok(())
+    }
+
+    /// Deallocates an anonymous memory mapped segment only if it is still the allocation with the given
+    /// generation
+    pub fn dealloc_tagged(&self, ptr: NonNull<u8>, layout: Layout, generation: u64) -> Result<(), StaleHandle> {
+        if self.config.untracked {
+            // Stale handles can't be detected without the registry, so nothing is freed
+            Err(StaleHandle {
+                ptr: Self::key(ptr),
+                generation,
+                current: None,
+            })?;
+        }
+
+        let addr = Self::key(ptr);
+
+        // Validate the layout
+        self.check_layout(ptr, layout, "deallocate");
+
+        // Remove from the map if the generation matches
+        let mut ptr_map = self.lock_map();
+
+        let current = ptr_map.get(&addr).map(|mmap| mmap.generation());
+
+        if current != Some(generation) {
+            Err(StaleHandle {
+                ptr: addr,
+                generation,
+                current,
+            })?;
+        }
+
+        let mmap = ptr_map.remove(&addr);
+
+        if let Some(mmap) = &mmap {
+            self.sub_demand(mmap);
+        }
+
+        drop(ptr_map);
+
+        let page_size = mmap.map(|mmap| {
+            let page_size = mmap.page_size();
+            self.quota.release(mmap.size());
+            self.retire(mmap);
+            page_size
+        });
+
+        self.trace(TraceOp::Unmap, addr, 0, layout, page_size);
+        self.validate("deallocate", addr, layout);
 
         Ok(())
     }
 
+    /// Returns the generation of the allocation at the given address
+    pub fn generation_of(&self, ptr: NonNull<u8>) -> Option<u64> {
+        self.lock_map().get(&Self::key(ptr)).map(|mmap| mmap.generation())
+    }
+
+    /// Returns the page size backing the allocation at the given address
+    pub fn page_size_of(&self, ptr: NonNull<u8>) -> Option<PageSize> {
+        self.lock_map().get(&Self::key(ptr)).map(|mmap| mmap.page_size())
+    }
+
     /// Reallocates an anonymous memory mapped segment
     pub fn realloc(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if self.config.untracked {
+            return self.raw_realloc(ptr, old_layout, new_layout);
+        }
+
+        let result = self.realloc_untraced(ptr, old_layout, new_layout);
+
+        let new_addr = result.as_ref().map_or(0, |new_ptr| new_ptr.as_ptr() as *mut u8 as usize);
+        self.trace(TraceOp::Remap, ptr.as_ptr() as usize, new_addr, new_layout, None);
+        self.validate("reallocate", ptr.as_ptr() as usize, new_layout);
+
+        result
+    }
+
+    /// Reallocates a segment without recording it in the trace
+    fn realloc_untraced(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
         let old_size = old_layout.size();
         let new_size = new_layout.size();
 
+        // Validate the layout
+        self.check_layout(ptr, old_layout, if new_size >= old_size { "grow" } else { "shrink" });
+
         // Remove existing map entry
-        let mmap = self.map_remove(ptr)?;
+        let mmap = self.map_remove(ptr);
 
         let mut mmap = match mmap {
             Some(m) => m,
             _ => Err(AllocError)?,
         };
 
+        if mmap.options().pinned || mmap.is_sealed() || mmap.is_executable() {
+            // Pinned, sealed and executable segments must never move or change size
+            self.map_add(mmap)?;
+            return Err(AllocError);
+        }
+
+        // Charge any growth to the quota before resizing. Both are measured from the recorded size, as that is
+        // what was charged, not the caller's layout
+        let growth = new_size.saturating_sub(mmap.size());
+        let shrinkage = mmap.size().saturating_sub(new_size);
+
+        if !self.quota.try_charge(growth) {
+            self.map_add(mmap)?;
+            return Err(AllocError);
+        }
+
         let was_default = mmap.page_size() == PageSize::SizeDefault;
+        let target = self.target_page_size(new_size);
+
+        if target != PageSize::SizeDefault {
+            mmap.set_shrunk_at(None);
+        }
+
+        // Held segments must stay where they are, so are copied
+        if Self::remap_keeps_align(&mmap, new_layout)
+            && !self.holding()
+            && (mmap.page_size() == target || (!was_default && self.keep_huge(&mut mmap)))
+        {
+            // Try and do a reallocate. The segment may move so is reported as unmapped and mapped again
+            self.run_hook(&self.config.on_unmap, &mmap);
+
+            if self.config.zero_on_free && new_size < old_size {
+                let keep = MMap::calc_alloc_size(new_size, &mmap.page_size());
+
+                if keep > 0 && keep < mmap.alloc_size() {
+                    // Split off the pages being released and free them like a segment so they are wiped before
+                    // being unmapped. Unlike mremap this can't fail, so nothing is wiped unless the shrink succeeds
+                    self.discard(mmap.split_off(keep));
+                }
+            }
+
+            let remapped = if MMap::calc_alloc_size(new_size, &mmap.page_size()) == mmap.alloc_size() {
+                mmap.remap(new_layout)
+            } else {
+                self.latency.time(Syscall::Mremap, mmap.page_size(), || mmap.remap(new_layout))
+            };
+
+            self.run_hook(&self.config.on_map, &mmap);
+
+            if remapped {
+                if self.config.zero_on_free && new_size < old_size {
+                    // Wipe the rest of the last page now the shrink has succeeded
+                    mmap.wipe(new_size);
+                }
+
+                // A moved segment loses its tags
+                mmap.retag();
 
-        if mmap.page_size() == self.target_page_size(new_size) {
-            // Try and do a reallocate
-            if mmap.remap(new_layout) {
                 // Get raw pointer
                 let ptr = mmap.fat_ptr();
 
@@ -99,148 +656,1410 @@ impl MMapper {
                     // Was default
                     if new_size > old_size {
                         // Add extra space as missed
-                        self.add_missed(new_size - old_size)?;
+                        self.add_missed(new_size - old_size);
                     }
                 } else if mmap.page_size() == PageSize::SizeDefault {
                     // Was huge and is now not
-                    self.add_missed(new_size)?;
+                    self.add_missed(new_size);
                 }
 
+                // Return any shrinkage to the quota
+                self.quota.release(shrinkage);
+
                 // Insert it back in to the hash map
                 self.map_add(mmap)?;
 
                 return Ok(ptr);
             } else {
                 // Failed to remap
-                let mut stats = self.lock_stats()?;
+                self.lock_stats().remaps_failed += 1;
 
-                stats.remaps_failed += 1;
+                self.warn(
+                    Warning::RemapFailed,
+                    format_args!("failed to remap {:?} from {} to {} bytes, copying", ptr, old_size, new_size),
+                );
             }
         }
 
-        // Allocate new segment
-        let new_ptr = self.alloc(new_layout)?;
+        // Allocate new segment with the same options, covered by the old segment's charge and the growth
+        let new_ptr = match self.alloc_charged(new_layout, mmap.options()) {
+            Ok((p, _)) => p,
+            Err(e) => {
+                // Failed - put the original segment back as it must remain valid
+                self.quota.release(growth);
+                self.map_add(mmap)?;
+                return Err(e);
+            }
+        };
 
         // Copy data from old segment to new
         unsafe {
-            copy_nonoverlapping(mmap.as_ptr(), new_ptr.as_mut_ptr(), min(old_size, new_size));
+            copy_nonoverlapping(mmap.data_ptr(), new_ptr.cast::<u8>().as_ptr(), min(old_size, new_size));
         }
 
+        // Move any user data across
+        if let Some(userdata) = mmap.set_userdata(None) {
+            self.set_userdata(new_ptr.cast(), userdata)?;
+        }
+
+        // Unmap or cache the old segment
+        self.quota.release(shrinkage);
+        self.retire(mmap);
+
         Ok(new_ptr)
     }
 
-    /// Returns the target page size for a given allocation size (or 0 for default)
-    fn target_page_size(&self, size: usize) -> PageSize {
-        // Test for 2mb page size
-        if (size * 100) / (2 * 1024 * 1024) >= self.threshold_pct {
-            return PageSize::Size2m;
-        }
+    /// Maps an untracked allocation
+    fn raw_alloc(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = NonNull::new(unsafe { self.raw.alloc(layout) }).ok_or(AllocError)?;
 
-        PageSize::SizeDefault
+        // The mapped length may round to a different page size, so only the exact size can be freed
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
     }
-    
-    /// Returns statistics for the mapper
-    pub(crate) fn stats(&self) -> Result<HugeAllocatorStats, AllocError> {
-        let mut out_stats = HugeAllocatorStats::default();
-
-        // Lock the ptr_map
-        let ptr_map = self.lock_map()?;
 
-        for mmap in ptr_map.values() {
-            out_stats.alloc += mmap.size();
-            out_stats.mapped += mmap.alloc_size();
-            out_stats.segments += 1;
+    /// Resizes an untracked allocation, moving it if the alignment changes
+    fn raw_realloc(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if new_layout.align() == old_layout.align() {
+            let new_ptr = unsafe { self.raw.realloc(ptr.as_ptr(), old_layout, new_layout.size()) };
+            let new_ptr = NonNull::new(new_ptr).ok_or(AllocError)?;
 
-            if mmap.page_size() == PageSize::SizeDefault {
-                out_stats.default_alloc += mmap.size();
-                out_stats.default_mapped += mmap.alloc_size();
-                out_stats.default_segments += 1;
-            } else {
-                out_stats.huge_alloc += mmap.size();
-                out_stats.huge_mapped += mmap.alloc_size();
-                out_stats.huge_segments += 1;
-            }
+            return Ok(NonNull::slice_from_raw_parts(new_ptr, new_layout.size()));
         }
 
-        let stats = self.lock_stats()?;
-
-        out_stats.missed_allocs = stats.missed_allocs;
-        out_stats.missed_mb = stats.missed_mb as f64 + (stats.missed_bytes as f64 / (1024 * 1024) as f64);
-        out_stats.remaps_failed = stats.remaps_failed;
+        let new_ptr = self.raw_alloc(new_layout)?;
 
-        drop(stats);
+        unsafe {
+            let len = min(old_layout.size(), new_layout.size());
 
-        if out_stats.mapped == 0 {
-            out_stats.efficiency = 100;
-        } else {
-            out_stats.efficiency = (out_stats.alloc * 100) / out_stats.mapped;
+            copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast::<u8>().as_ptr(), len);
+            self.raw.dealloc(ptr.as_ptr(), old_layout);
         }
 
-        Ok(out_stats)
+        Ok(new_ptr)
     }
 
-    /// Removes an entry from the pointer map
-    fn map_remove(&self, ptr: NonNull<u8>) -> Result<Option<MMap>, AllocError> {
-        // Lock the ptr_map
-        let mut ptr_map = self.lock_map()?;
+    /// Returns true if resizing a segment with mremap leaves it aligned for the new layout. The segment must
+    /// already be aligned, and a segment needing more pages may move to any address aligned to its page size
+    fn remap_keeps_align(mmap: &MMap, new_layout: Layout) -> bool {
+        let align = new_layout.align();
 
-        // Remove map entry
-        Ok(ptr_map.remove(&(ptr.as_ptr() as usize)))
+        (mmap.as_ptr() as usize).is_multiple_of(align)
+            && (align <= mmap.page_size().bytes()
+                || MMap::calc_alloc_size(new_layout.size(), &mmap.page_size()) <= mmap.alloc_size())
     }
 
-    /// Adds an entry from the pointer map
-    fn map_add(&self, mmap: MMap) -> Result<(), AllocError> {
-        // Lock the ptr_map
-        let mut ptr_map = self.lock_map()?;
+    /// Returns true if a huge page segment reallocated below the threshold should keep its huge pages
+    fn keep_huge(&self, mmap: &mut MMap) -> bool {
+        match self.config.shrink_policy {
+            ShrinkPolicy::Demote => false,
+            ShrinkPolicy::Keep => true,
+            ShrinkPolicy::Lazy(grace) => {
+                let shrunk_at = *mmap.shrunk_at().get_or_insert_with(Instant::now);
 
-        // Add map entry
-        if ptr_map.insert(mmap.as_ptr() as usize, mmap).is_some() {
-            Err(AllocError)?;
-        }
+                mmap.set_shrunk_at(Some(shrunk_at));
 
-        Ok(())
+                shrunk_at.elapsed() < grace
+            }
+        }
     }
 
-    /// Locks the ptr_map for removal
-    fn lock_map(&self) -> Result<MutexGuard<HashMap<usize, MMap>>, AllocError> {
-        // Lock the ptr_map
-        match self.ptr_map.lock() {
-            Ok(ptr_map) => Ok(ptr_map),
-            _ => Err(AllocError),
+    /// Moves a default page size segment on to huge pages, returning the new pointer. Segments already on huge
+    /// pages are left in place
+    pub fn promote(&self, ptr: NonNull<u8>) -> Result<NonNull<[u8]>, AllocError> {
+        // Remove existing map entry
+        let mut mmap = match self.map_remove(ptr) {
+            Some(m) => m,
+            _ => Err(AllocError)?,
+        };
+
+        if mmap.page_size() != PageSize::SizeDefault {
+            // Already huge
+            let ptr = mmap.fat_ptr();
+            self.map_add(mmap)?;
+
+            return Ok(ptr);
         }
-    }
 
-    /// Locks statistics
-    fn lock_stats(&self) -> Result<MutexGuard<MMapperStats>, AllocError> {
-        // Lock stats
-        match self.stats.lock() {
-            Ok(stats) => Ok(stats),
-            _ => Err(AllocError),
+        if mmap.options().pinned || mmap.is_sealed() || mmap.is_executable() || !Self::huge_fits(mmap.size()) {
+            // Pinned, sealed and executable segments must never move, and the cgroup must have room
+            self.map_add(mmap)?;
+            return Err(AllocError);
         }
+
+        // Try and map huge pages
+        let mut huge = match self.map_timed(None, mmap.layout(), &PageSize::Size2m, mmap.options()) {
+            Ok(m) => m,
+            Err(_) => {
+                // Failed - put the original segment back
+                self.map_add(mmap)?;
+                return Err(AllocError);
+            }
+        };
+
+        // Copy data from old segment to new
+        huge.retag();
+
+        unsafe { copy_nonoverlapping(mmap.data_ptr(), huge.data_ptr(), mmap.size()) };
+
+        huge.set_userdata(mmap.set_userdata(None));
+
+        let (new_ptr, _) = self.register(huge)?;
+
+        // Unmap the old segment
+        self.release(mmap);
+
+        Ok(new_ptr)
     }
 
-    /// Add statistics about missed huge allocations
-    fn add_missed(&self, bytes: usize) -> Result<(), AllocError> {
-        let mut stats = self.lock_stats()?;
+    /// Moves a huge page segment on to the default page size in place, returning its huge pages to the system
+    /// pool. Returns the number of huge page bytes released, which is 0 if the segment is already on the
+    /// default page size
+    pub fn demote(&self, ptr: NonNull<u8>) -> Result<usize, AllocError> {
+        // Remove existing map entry
+        let mmap = match self.map_remove(ptr) {
+            Some(m) => m,
+            _ => Err(AllocError)?,
+        };
 
-        stats.missed_allocs += 1;
+        self.demote_segment(mmap)
+    }
 
-        stats.missed_bytes += bytes;
+    /// Returns huge pages to the system pool until at least the given number of bytes have been released.
+    /// Cached huge page segments are unmapped first, then live segments are demoted starting with the one
+    /// which was mapped or last grew longest ago. Returns the number of huge page bytes released
+    pub fn demote_coldest(&self, bytes: usize) -> usize {
+        let mut released = 0;
 
-        if stats.missed_bytes > (1024 * 1024) {
-            let mb = stats.missed_bytes / (1024 * 1024);
-            stats.missed_bytes -= mb * (1024 * 1024);
-            stats.missed_mb += mb;
+        // Cached segments need no copying
+        let mut cache = self.lock_cache();
+        let mut batch = Vec::new();
+
+        while released < bytes {
+            match cache.iter().position(|mmap| mmap.page_size() != PageSize::SizeDefault) {
+                Some(pos) => {
+                    let mmap = cache.swap_remove(pos);
+
+                    released += mmap.alloc_size();
+                    batch.push(mmap);
+                }
+                None => break,
+            }
         }
 
-        Ok(())
-    }
-}
+        drop(cache);
 
-#[derive(Default)]
+        self.unmap_batch(batch);
+
+        let mut candidates: Vec<(Instant, u64, usize)> = self
+            .lock_map()
+            .values()
+            .filter(|mmap| mmap.page_size() != PageSize::SizeDefault && Self::demotable(mmap))
+            .map(|mmap| (mmap.grown_at(), mmap.generation(), mmap.as_ptr() as usize))
+            .collect();
+
+        candidates.sort_unstable();
+
+        for (_, generation, addr) in candidates {
+            if released >= bytes {
+                break;
+            }
+
+            let Some(mmap) = NonNull::new(addr as *mut u8).and_then(|ptr| self.map_remove(ptr)) else {
+                continue;
+            };
+
+            if mmap.generation() != generation {
+                // Freed and reallocated since the candidates were collected
+                if self.map_add(mmap).is_err() {
+                    break;
+                }
+
+                continue;
+            }
+
+            if let Ok(demoted) = self.demote_segment(mmap) {
+                released += demoted;
+            }
+        }
+
+        released
+    }
+
+    /// Returns true if a huge page segment can be demoted. Pinned segments must keep their pages, sealed,
+    /// executable and protected segments can't be rewritten, and memory tagged pointers would change
+    fn demotable(mmap: &MMap) -> bool {
+        let options = mmap.options();
+
+        !options.pinned
+            && !options.mte
+            && !mmap.is_sealed()
+            && mmap.protection() == Protection::PROT_READ | Protection::PROT_WRITE
+    }
+
+    /// Copies a segment which has been removed from the pointer map on to default pages and moves them over
+    /// the huge pages, keeping its address. The segment is returned to the pointer map either way
+    fn demote_segment(&self, mut mmap: MMap) -> Result<usize, AllocError> {
+        if mmap.page_size() == PageSize::SizeDefault {
+            self.map_add(mmap)?;
+            return Ok(0);
+        }
+
+        if !Self::demotable(&mmap) {
+            self.map_add(mmap)?;
+            return Err(AllocError);
+        }
+
+        let alloc_size = mmap.alloc_size();
+
+        // Cover the whole huge page mapping so nothing is left behind
+        let mut default = match Layout::from_size_align(alloc_size, 1)
+            .map_err(|_| Errno::ENOMEM)
+            .and_then(|layout| self.map_timed(None, layout, &PageSize::SizeDefault, mmap.options()))
+        {
+            Ok(m) => m,
+            Err(e) => {
+                self.log(format_args!("failed to map default pages to demote {:?} ({})", mmap.as_ptr(), e));
+                self.map_add(mmap)?;
+                return Err(AllocError);
+            }
+        };
+
+        // Copy data from old segment to new
+        unsafe { copy_nonoverlapping(mmap.data_ptr(), default.data_ptr(), mmap.size()) };
+
+        if self.config.zero_on_free {
+            // Wipe the memory before returning it to the system
+            mmap.wipe(0);
+        }
+
+        let layout = mmap.layout();
+        let generation = mmap.generation();
+        let userdata = mmap.set_userdata(None);
+
+        // The segment changes page size so is reported as unmapped and mapped again
+        self.run_hook(&self.config.on_unmap, &mmap);
+
+        if let Err(e) = self.latency.time(Syscall::Mremap, PageSize::SizeDefault, || default.move_over(&mmap)) {
+            self.log(format_args!("failed to move default pages over {:?} ({})", mmap.as_ptr(), e));
+
+            if self.config.zero_on_free {
+                // Put the wiped contents back
+                unsafe { copy_nonoverlapping(default.data_ptr(), mmap.data_ptr(), mmap.size()) };
+            }
+
+            mmap.set_userdata(userdata);
+
+            self.run_hook(&self.config.on_map, &mmap);
+            self.map_add(mmap)?;
+
+            return Err(AllocError);
+        }
+
+        // The huge pages were unmapped by the move
+        mmap.forget();
+
+        default.set_layout(layout);
+        default.set_generation(generation);
+        default.set_userdata(userdata);
+
+        self.name_mmap(&default);
+        self.run_hook(&self.config.on_map, &default);
+
+        let mut stats = self.lock_stats();
+
+        stats.demoted_segments += 1;
+        stats.demoted_bytes += alloc_size;
+
+        drop(stats);
+
+        self.map_add(default)?;
+
+        Ok(alloc_size)
+    }
+
+    /// Moves a live segment to another allocator without copying. The segment is given a new generation by
+    /// the receiving allocator and keeps its address, options and user data
+    pub fn transfer(&self, ptr: NonNull<u8>, to: &MMapper) -> Result<(), AllocError> {
+        if std::ptr::eq(self, to) {
+            // Already owned
+            return self.generation_of(ptr).map(|_| ()).ok_or(AllocError);
+        }
+
+        if self.config.untracked || to.config.untracked {
+            // Untracked allocators have no registry entries to move
+            Err(AllocError)?;
+        }
+
+        let mut mmap = self.map_remove(ptr).ok_or(AllocError)?;
+
+        if !to.quota.try_charge(mmap.size()) {
+            // No room in the receiving allocator's quota
+            self.map_add(mmap)?;
+            return Err(AllocError);
+        }
+
+        self.quota.release(mmap.size());
+
+        let addr = ptr.as_ptr() as usize;
+        let layout = mmap.layout();
+
+        // The segment leaves this allocator
+        self.run_hook(&self.config.on_unmap, &mmap);
+        self.trace(TraceOp::Unmap, addr, 0, layout, Some(mmap.page_size()));
+
+        // And joins the other
+        to.name_mmap(&mmap);
+        to.run_hook(&to.config.on_map, &mmap);
+
+        mmap.set_generation(to.next_generation.fetch_add(1, Ordering::Relaxed));
+
+        to.map_add(mmap)?;
+        to.check_map_count();
+
+        to.trace(TraceOp::Map, addr, 0, layout, None);
+
+        Ok(())
+    }
+
+    /// Takes ownership of a mapping made elsewhere so it is tracked, counted in the statistics and unmapped
+    /// when freed
+    ///
+    /// # Safety
+    ///
+    /// See [`MMap::from_raw`]
+    pub unsafe fn adopt(&self, ptr: NonNull<u8>, len: usize, page_size: PageSize) -> Result<NonNull<[u8]>, AllocError> {
+        if self.config.untracked || self.lock_map().contains_key(&Self::key(ptr)) {
+            // Nowhere to track it, or already ours
+            Err(AllocError)?;
+        }
+
+        let mut mmap = unsafe { MMap::from_raw(ptr.as_ptr() as usize, len, &page_size) }.map_err(|_| AllocError)?;
+
+        if !self.quota.try_charge(mmap.size()) {
+            // Leave the mapping with the caller
+            std::mem::forget(mmap);
+            return Err(AllocError);
+        }
+
+        self.run_hook(&self.config.on_map, &mmap);
+
+        mmap.set_generation(self.next_generation.fetch_add(1, Ordering::Relaxed));
+
+        let layout = mmap.layout();
+        let fat_ptr = mmap.fat_ptr();
+
+        self.map_add(mmap).inspect_err(|_| self.quota.release(layout.size()))?;
+        self.check_map_count();
+
+        self.trace(TraceOp::Map, ptr.as_ptr() as usize, 0, layout, None);
+
+        Ok(fat_ptr)
+    }
+
+    /// Unmaps every live segment and clears the pointer map, returning the number of segments released
+    pub fn reset(&self) -> usize {
+        let mut ptr_map = self.lock_map();
+
+        let mmaps: Vec<MMap> = ptr_map.drain().map(|(_, mmap)| mmap).collect();
+
+        self.lock_stats().huge_demand = 0;
+
+        drop(ptr_map);
+        self.quota.release(mmaps.iter().map(MMap::size).sum());
+
+        if let Some(profiler) = &self.profiler {
+            profiler.clear();
+        }
+
+        let count = mmaps.len();
+
+        for mmap in mmaps {
+            self.retire(mmap);
+        }
+
+        count
+    }
+
+    /// Unmaps all cached segments, returning the number of bytes released
+    pub fn trim(&self) -> usize {
+        let cached: Vec<MMap> = self.lock_cache().drain(..).collect();
+
+        let mut released = 0;
+
+        for mmap in cached {
+            released += mmap.alloc_size();
+            self.unmap(mmap);
+        }
+
+        released
+    }
+
+    /// Unmaps the segments collected in the current epoch and waits for deferred unmaps to finish, returning
+    /// the number of bytes in the epoch
+    pub fn flush(&self) -> usize {
+        let epoch = std::mem::take(&mut *self.lock_epoch());
+
+        let flushed = epoch.iter().map(MMap::alloc_size).sum();
+
+        self.unmap_batch(epoch);
+
+        if let Some(deferred) = &self.deferred {
+            deferred.wait_idle();
+        }
+
+        flushed
+    }
+
+    /// Returns the target page size for a given allocation size (or 0 for default). Huge pages are not
+    /// targeted if the hugetlb cgroup limit would be exceeded
+    fn target_page_size(&self, size: usize) -> PageSize {
+        // Test for 2mb page size
+        if self.above_threshold(size) && Self::huge_fits(size) {
+            return PageSize::Size2m;
+        }
+
+        PageSize::SizeDefault
+    }
+    
+    /// Applies the NUMA policy to mapping options which don't have a placement set
+    fn placement(&self, options: &AllocOptions) -> AllocOptions {
+        if options.node.is_some() || options.interleave.is_some() {
+            return *options;
+        }
+
+        match self.config.numa_policy {
+            NumaPolicy::FirstTouch => *options,
+            NumaPolicy::BindLocal => AllocOptions {
+                node: mmap::current_node(),
+                ..*options
+            },
+            NumaPolicy::BindNode(node) => AllocOptions {
+                node: Some(node),
+                ..*options
+            },
+            NumaPolicy::Interleave(nodes) => AllocOptions {
+                interleave: Some(nodes),
+                ..*options
+            },
+        }
+    }
+
+    /// Returns true if an allocation of the given size is at or above the huge page threshold
+    fn above_threshold(&self, size: usize) -> bool {
+        (size * 100) / (2 * 1024 * 1024) >= self.config.threshold_pct
+    }
+
+    /// Returns true if a huge page segment of the given size would fit in the hugetlb cgroup limit, taking
+    /// the headroom for it
+    fn huge_fits(size: usize) -> bool {
+        cgroup::reserve_huge(MMap::calc_alloc_size(size, &PageSize::Size2m))
+    }
+
+    /// Returns statistics for the mapper
+    pub(crate) fn stats(&self) -> HugeAllocatorStats {
+        let mut out_stats = HugeAllocatorStats {
+            name: self.config.name.clone(),
+            ..Default::default()
+        };
+
+        // Lock the ptr_map
+        let ptr_map = self.lock_map();
+
+        for mmap in ptr_map.values() {
+            out_stats.alloc += mmap.size();
+            out_stats.mapped += mmap.alloc_size();
+            out_stats.segments += 1;
+
+            if mmap.page_size() == PageSize::SizeDefault {
+                out_stats.default_alloc += mmap.size();
+                out_stats.default_mapped += mmap.alloc_size();
+                out_stats.default_segments += 1;
+            } else {
+                out_stats.huge_alloc += mmap.size();
+                out_stats.huge_mapped += mmap.alloc_size();
+                out_stats.huge_segments += 1;
+            }
+
+            if let Some(node) = mmap.options().node {
+                *out_stats.node_mapped.entry(node).or_default() += mmap.alloc_size();
+            }
+        }
+
+        let stats = self.lock_stats();
+
+        out_stats.missed_allocs = stats.missed_allocs;
+        out_stats.missed_mb = stats.missed_mb as f64 + (stats.missed_bytes as f64 / (1024 * 1024) as f64);
+        out_stats.thp_eligible = stats.thp_eligible;
+        out_stats.demoted_segments = stats.demoted_segments;
+        out_stats.demoted_bytes = stats.demoted_bytes;
+        out_stats.remaps_failed = stats.remaps_failed;
+        out_stats.unmaps_failed = stats.unmaps_failed + self.deferred.as_ref().map_or(0, DeferredUnmap::failed);
+        out_stats.surplus_allocs = stats.surplus_allocs;
+        out_stats.surplus_bytes = stats.surplus_bytes;
+        out_stats.mergeable_ignored = stats.mergeable_ignored;
+        out_stats.requests = stats.requests;
+        out_stats.request_bytes = stats.request_bytes;
+        out_stats.peak_request_bytes = stats.peak_request_bytes;
+
+        drop(stats);
+
+        let cache = self.lock_cache();
+
+        out_stats.cached_segments = cache.len();
+        out_stats.cached_bytes = cache.iter().map(|mmap| mmap.alloc_size()).sum();
+
+        drop(cache);
+
+        let epoch = self.lock_epoch();
+
+        out_stats.epoch_segments = epoch.len();
+        out_stats.epoch_bytes = epoch.iter().map(MMap::alloc_size).sum();
+
+        drop(epoch);
+
+        if let Some(deferred) = &self.deferred {
+            (out_stats.deferred_segments, out_stats.deferred_bytes) = deferred.pending();
+        }
+
+        out_stats.default_mmap_latency = self.latency.latency(Syscall::Mmap, PageSize::SizeDefault);
+        out_stats.huge_mmap_latency = self.latency.latency(Syscall::Mmap, PageSize::Size2m);
+        out_stats.default_mremap_latency = self.latency.latency(Syscall::Mremap, PageSize::SizeDefault);
+        out_stats.huge_mremap_latency = self.latency.latency(Syscall::Mremap, PageSize::Size2m);
+        out_stats.default_munmap_latency = self.latency.latency(Syscall::Munmap, PageSize::SizeDefault);
+        out_stats.huge_munmap_latency = self.latency.latency(Syscall::Munmap, PageSize::Size2m);
+
+        out_stats.cgroup_limit = cgroup::cached_huge_limit();
+        out_stats.map_count_limit = self.map_count_limit;
+        out_stats.quota = self.quota.limit();
+        out_stats.quota_used = self.quota.used();
+
+        out_stats.efficiency = (out_stats.alloc * 100).checked_div(out_stats.mapped).unwrap_or(100);
+
+        out_stats
+    }
+
+    /// Validates the internal bookkeeping of the mapper
+    pub(crate) fn check_integrity(&self) -> Result<(), IntegrityError> {
+        // Lock the ptr_map
+        let ptr_map = self.lock_map();
+
+        // Check each segment in isolation
+        for (&key, mmap) in ptr_map.iter() {
+            let ptr = mmap.as_ptr() as usize;
+
+            if key != ptr {
+                Err(IntegrityError::KeyMismatch { key, ptr })?;
+            }
+
+            if mmap.size() > mmap.alloc_size() {
+                Err(IntegrityError::SizeExceedsMapping {
+                    ptr,
+                    size: mmap.size(),
+                    alloc_size: mmap.alloc_size(),
+                })?;
+            }
+
+            let page_bytes = mmap.page_size().bytes();
+
+            if !ptr.is_multiple_of(page_bytes) || !mmap.alloc_size().is_multiple_of(page_bytes) {
+                Err(IntegrityError::PageMisaligned {
+                    ptr,
+                    alloc_size: mmap.alloc_size(),
+                    page_size: page_bytes,
+                })?;
+            }
+        }
+
+        // Check for overlapping segments, including freed segments which are still mapped
+        let mut ranges: Vec<(usize, usize)> = ptr_map
+            .values()
+            .chain(self.lock_cache().iter())
+            .chain(self.lock_epoch().iter())
+            .map(|mmap| (mmap.as_ptr() as usize, mmap.alloc_size()))
+            .collect();
+
+        ranges.sort_unstable();
+
+        for pair in ranges.windows(2) {
+            let (first, first_size) = pair[0];
+            let (second, _) = pair[1];
+
+            if first + first_size > second {
+                Err(IntegrityError::Overlap { first, second })?;
+            }
+        }
+
+        // Check the counters maintained alongside the map agree with it. They are updated under the map lock
+        let demand: usize = ptr_map.values().map(|mmap| self.demand(mmap)).sum();
+        let alloc: usize = ptr_map.values().map(MMap::size).sum();
+
+        let stats = self.lock_stats();
+
+        if stats.huge_demand != demand {
+            Err(IntegrityError::StatsMismatch("huge page demand"))?;
+        }
+
+        if stats.missed_bytes > (1024 * 1024) {
+            Err(IntegrityError::StatsMismatch("missed bytes not normalised"))?;
+        }
+
+        drop(stats);
+
+        // Quota is charged before a segment is added and released after it is removed, and includes children
+        if self.quota.used() < alloc {
+            Err(IntegrityError::StatsMismatch("quota used below allocated bytes"))?;
+        }
+
+        if self.quota.limit().is_some_and(|limit| self.quota.used() > limit) {
+            Err(IntegrityError::StatsMismatch("quota used exceeds limit"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Attaches a value to a segment, returning any previously attached
+    pub fn set_userdata(&self, ptr: NonNull<u8>, userdata: Userdata) -> Result<Option<Userdata>, AllocError> {
+        let mut ptr_map = self.lock_map();
+
+        let mmap = ptr_map.get_mut(&Self::key(ptr)).ok_or(AllocError)?;
+
+        Ok(mmap.set_userdata(Some(userdata)))
+    }
+
+    /// Detaches the value attached to a segment
+    pub fn take_userdata(&self, ptr: NonNull<u8>) -> Option<Userdata> {
+        self.lock_map().get_mut(&Self::key(ptr))?.set_userdata(None)
+    }
+
+    /// Calls a closure with the value attached to a segment, if any
+    pub fn with_userdata<R>(&self, ptr: NonNull<u8>, f: impl FnOnce(Option<&Userdata>) -> R) -> R {
+        let ptr_map = self.lock_map();
+
+        f(ptr_map.get(&Self::key(ptr)).and_then(|mmap| mmap.userdata()))
+    }
+
+    /// Write protects a segment and marks it sealed
+    pub fn seal(&self, ptr: NonNull<u8>) -> Result<(), AllocError> {
+        let mut ptr_map = self.lock_map();
+
+        let mmap = ptr_map.get_mut(&Self::key(ptr)).ok_or(AllocError)?;
+
+        mmap.seal().map_err(|_| AllocError)
+    }
+
+    /// Switches a segment between read write and read execute. Sealed segments can't be switched
+    pub fn set_executable(&self, ptr: NonNull<u8>, executable: bool) -> Result<(), AllocError> {
+        let mut ptr_map = self.lock_map();
+
+        let mmap = ptr_map.get_mut(&Self::key(ptr)).ok_or(AllocError)?;
+
+        if mmap.is_sealed() {
+            Err(AllocError)?;
+        }
+
+        let prot = if executable {
+            Protection::PROT_READ | Protection::PROT_EXEC
+        } else {
+            Protection::PROT_READ | Protection::PROT_WRITE
+        };
+
+        mmap.protect(prot).map_err(|_| AllocError)
+    }
+
+    /// Gives the kernel access pattern advice for a segment
+    pub fn advise(&self, ptr: NonNull<u8>, advice: Advice) -> io::Result<()> {
+        let ptr_map = self.lock_map();
+
+        let mmap = ptr_map
+            .get(&Self::key(ptr))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not a live allocation"))?;
+
+        Ok(mmap.advise(advice)?)
+    }
+
+    /// Returns every range the allocator has mapped, including freed segments which are still mapped, sorted
+    /// by address
+    pub(crate) fn mapped_ranges(&self) -> Vec<MappedRange> {
+        let ptr_map = self.lock_map();
+
+        let mut ranges: Vec<MappedRange> = ptr_map
+            .values()
+            .map(|mmap| MappedRange::new(mmap, RangeState::Live))
+            .chain(self.lock_cache().iter().map(|mmap| MappedRange::new(mmap, RangeState::Cached)))
+            .chain(self.lock_epoch().iter().map(|mmap| MappedRange::new(mmap, RangeState::Epoch)))
+            .collect();
+
+        drop(ptr_map);
+
+        ranges.sort_unstable_by_key(|range| range.start);
+
+        ranges
+    }
+
+    /// Runs a closure with the pointer map locked
+    pub(crate) fn with_map<R>(&self, f: impl FnOnce(&HashMap<usize, MMap>) -> R) -> R {
+        f(&self.lock_map())
+    }
+
+    /// Keeps segments mapped at their current addresses until the guard is dropped, so segments looked up
+    /// under the registry lock can be read after it is released. Segments freed meanwhile are unmapped when
+    /// the last guard is dropped, and reallocations copy instead of remapping
+    pub(crate) fn hold(&self) -> HoldGuard<'_> {
+        self.lock_held().readers += 1;
+
+        HoldGuard { mapper: self }
+    }
+
+    /// Returns true if any segments are being held
+    fn holding(&self) -> bool {
+        self.lock_held().readers > 0
+    }
+
+    /// Describes the live allocations, or returns None if there are none
+    pub(crate) fn leak_report(&self) -> Option<String> {
+        let leaks = self.with_map(|ptr_map| ptr_map.values().map(AllocationInfo::from).collect());
+
+        checkpoint::leak_report(self.name().unwrap_or("HugeAllocator"), leaks)
+    }
+
+    /// Writes the statistics, and the leak report if enabled, to the exit checkpoint file if one is
+    /// configured. Only the first call writes anything
+    pub(crate) fn checkpoint(&self, trigger: &str) {
+        let Some(path) = &self.config.exit_stats_path else {
+            return;
+        };
+
+        if self.checkpointed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let leaks = self.config.exit_leak_report.then(|| {
+            self.leak_report()
+                .unwrap_or_else(|| format!("{}: no allocations leaked", self.name().unwrap_or("HugeAllocator")))
+        });
+
+        if let Err(e) = checkpoint::write(path, &self.ident(), trigger, &self.stats(), leaks) {
+            self.log(format_args!("failed to write exit checkpoint to {} ({})", path.display(), e));
+        }
+    }
+
+    /// Returns the pointer map key for a pointer, which may carry a memory tag
+    pub(crate) fn key(ptr: NonNull<u8>) -> usize {
+        mte::untag(ptr.as_ptr() as usize)
+    }
+
+    /// Removes an entry from the pointer map
+    fn map_remove(&self, ptr: NonNull<u8>) -> Option<MMap> {
+        // Lock the ptr_map
+        let mut ptr_map = self.lock_map();
+
+        // Remove map entry
+        let mmap = ptr_map.remove(&Self::key(ptr));
+
+        // Update the demand under the map lock so the integrity check sees both change together
+        if let Some(mmap) = &mmap {
+            self.sub_demand(mmap);
+        }
+
+        drop(ptr_map);
+
+        mmap
+    }
+
+    /// Adds an entry from the pointer map
+    fn map_add(&self, mmap: MMap) -> Result<(), AllocError> {
+        // Lock the ptr_map
+        let mut ptr_map = self.lock_map();
+
+        let demand = self.demand(&mmap);
+
+        // Add map entry
+        if ptr_map.insert(mmap.as_ptr() as usize, mmap).is_some() {
+            Err(AllocError)?;
+        }
+
+        // Update the demand under the map lock so the integrity check sees both change together
+        let mut stats = self.lock_stats();
+
+        stats.huge_demand += demand;
+        stats.peak_huge_demand = stats.peak_huge_demand.max(stats.huge_demand);
+
+        Ok(())
+    }
+
+    /// Returns the bytes of huge pages a segment wants. Segments below the threshold want none
+    fn demand(&self, mmap: &MMap) -> usize {
+        if self.above_threshold(mmap.size()) {
+            MMap::calc_alloc_size(mmap.size(), &PageSize::Size2m)
+        } else {
+            0
+        }
+    }
+
+    /// Removes a segment leaving the pointer map from the huge page demand
+    fn sub_demand(&self, mmap: &MMap) {
+        let demand = self.demand(mmap);
+
+        if demand > 0 {
+            self.lock_stats().huge_demand -= demand;
+        }
+    }
+
+    /// Returns the live sampled allocations by call stack, or None if sampling is disabled
+    pub(crate) fn profile(&self) -> Option<ProfileReport> {
+        self.profiler.as_ref().map(Profiler::report)
+    }
+
+    /// Returns the peak bytes of huge pages wanted by live allocations
+    pub fn peak_huge_demand(&self) -> usize {
+        self.lock_stats().peak_huge_demand
+    }
+
+    /// Returns the allocator configuration
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Returns the allocator's byte budget
+    pub(crate) fn quota(&self) -> &Arc<Quota> {
+        &self.quota
+    }
+
+    /// Returns the default mapping options
+    pub fn default_options(&self) -> AllocOptions {
+        self.config.default_options
+    }
+
+    /// Returns the huge page threshold percentage
+    pub fn threshold_pct(&self) -> usize {
+        self.config.threshold_pct
+    }
+
+    /// Returns the allocator instance name
+    pub fn name(&self) -> Option<&str> {
+        self.config.name.as_deref()
+    }
+
+    /// Returns a description of the allocator instance for diagnostics
+    pub fn ident(&self) -> String {
+        match self.name() {
+            Some(name) => format!("HugeAllocator[{}]", name),
+            None => "HugeAllocator".to_string(),
+        }
+    }
+
+    /// Names a mapping with the configured label and its page size
+    fn name_mmap(&self, mmap: &MMap) {
+        if !self.config.name_vmas {
+            return;
+        }
+
+        let label = match (&self.config.vma_label, self.name()) {
+            (Some(label), _) => label.clone(),
+            (None, Some(name)) => format!("huge_allocator:{}", name),
+            (None, None) => "huge_allocator".to_string(),
+        };
+
+        if let Ok(name) = CString::new(format!("{}:{}", label, mmap.page_size().short_name())) {
+            // Naming is best effort as older kernels don't support it
+            let _ = mmap.set_name(&name);
+        }
+    }
+
+    /// Re-checks the allocator's invariants after an operation when the `validate` feature is enabled,
+    /// panicking if any is violated
+    fn validate(&self, op: &str, addr: usize, layout: Layout) {
+        if !cfg!(feature = "validate") {
+            return;
+        }
+
+        if let Err(e) = self.check_integrity() {
+            panic!(
+                "{}: invariant violated after {} of {:#x} with {:?}: {}\n{:#?}",
+                self.ident(),
+                op,
+                addr,
+                layout,
+                e,
+                self.stats()
+            );
+        }
+    }
+
+    /// Checks (in debug builds) that the layout passed by the caller fits the segment being freed or
+    /// reallocated, panicking with a description of the mismatch if not
+    fn check_layout(&self, ptr: NonNull<u8>, layout: Layout, op: &str) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+
+        // Look up the segment
+        let ptr_map = self.lock_map();
+
+        let mismatch = match ptr_map.get(&Self::key(ptr)) {
+            Some(mmap) if !mmap.fits(layout) => Some((mmap.layout(), mmap.alloc_size())),
+            _ => None,
+        };
+
+        drop(ptr_map);
+
+        if let Some((allocated, alloc_size)) = mismatch {
+            panic!(
+                "{}::{}: layout mismatch for {:?} (passed {:?}, allocated {:?} with {} bytes mapped)",
+                self.ident(),
+                op,
+                ptr,
+                layout,
+                allocated,
+                alloc_size
+            );
+        }
+    }
+
+    /// Returns a freed segment to the cache if there is room for it, otherwise unmaps it
+    fn retire(&self, mut mmap: MMap) {
+        let limit = self.config.segment_cache;
+
+        if limit == 0 || mmap.options().pinned || mmap.is_sealed() || mmap.is_executable() {
+            self.release(mmap);
+            return;
+        }
+
+        let cached = |cache: &Vec<MMap>| cache.iter().map(|mmap| mmap.alloc_size()).sum::<usize>();
+
+        if cached(&self.lock_cache()) + mmap.alloc_size() > limit {
+            self.release(mmap);
+            return;
+        }
+
+        // The hook may allocate, so runs without the cache locked
+        self.run_hook(&self.config.on_unmap, &mmap);
+
+        // Cached segments belong to no one
+        mmap.set_userdata(None);
+
+        if self.config.zero_on_free {
+            // Wipe the memory before it is cached
+            mmap.wipe(0);
+        }
+
+        let mut cache = self.lock_cache();
+
+        if cached(&cache) + mmap.alloc_size() > limit {
+            // Filled up while the hook ran
+            drop(cache);
+            self.unmap(mmap);
+            return;
+        }
+
+        // Merge with cached neighbours so larger allocations can reuse the memory
+        while let Some(pos) = cache.iter().position(|cached| cached.adjoins(&mmap)) {
+            mmap = mmap.merge(cache.swap_remove(pos));
+        }
+
+        // Retag so pointers to the freed segment fault
+        mmap.retag();
+
+        cache.push(mmap);
+    }
+
+    /// Takes a cached segment with the given page size and options which maps at least the number of pages
+    /// needed for the layout, preferring one on the target page size over one which fell back to the default
+    /// page size. Larger segments are split, caching the remainder. The segment is zeroed before it is returned
+    fn cache_take(&self, layout: Layout, page_size: &PageSize, options: &AllocOptions) -> Option<MMap> {
+        if self.config.segment_cache == 0 || layout.align() > page_size.bytes() {
+            return None;
+        }
+
+        let mut cache = self.lock_cache();
+
+        let find = |page_size: &PageSize| {
+            let alloc_size = MMap::calc_alloc_size(layout.size(), page_size);
+
+            // Prefer an exact fit over splitting the smallest larger segment
+            cache
+                .iter()
+                .enumerate()
+                .filter(|(_, mmap)| {
+                    mmap.page_size() == *page_size
+                        && mmap.alloc_size() >= alloc_size
+                        && mmap.options() == options
+                        && (mmap.as_ptr() as usize).is_multiple_of(layout.align())
+                })
+                .min_by_key(|(_, mmap)| mmap.alloc_size())
+                .map(|(pos, _)| pos)
+        };
+
+        let pos = match self.fallback_allowed(layout.size()) {
+            true => find(page_size).or_else(|| find(&PageSize::SizeDefault))?,
+            false => find(page_size)?,
+        };
+
+        let mut mmap = cache.swap_remove(pos);
+
+        let alloc_size = if mmap.page_size() != *page_size && self.config.align_fallback {
+            // Keep fallback segments in whole 2mb units
+            MMap::calc_alloc_size(layout.size(), &PageSize::Size2m).min(mmap.alloc_size())
+        } else {
+            MMap::calc_alloc_size(layout.size(), &mmap.page_size())
+        };
+
+        if mmap.alloc_size() > alloc_size {
+            cache.push(mmap.split_off(alloc_size));
+        }
+
+        drop(cache);
+
+        if !self.config.zero_on_free {
+            // New allocations must read as zero
+            mmap.wipe(0);
+        }
+
+        mmap.set_layout(layout);
+        mmap.reset_grown_at();
+
+        Some(mmap)
+    }
+
+    /// Unmaps a segment which has been removed from the pointer map. Failures are counted and logged, or
+    /// cause a panic in strict mode
+    fn release(&self, mmap: MMap) {
+        self.run_hook(&self.config.on_unmap, &mmap);
+        self.discard(mmap);
+    }
+
+    /// Unmaps a segment like [`release`](Self::release) without running the unmap hook, for pages the hook
+    /// has already been run on
+    fn discard(&self, mut mmap: MMap) {
+        if self.config.zero_on_free {
+            if !mmap.protection().contains(Protection::PROT_WRITE) {
+                // Make the memory writable again so it can be wiped
+                let _ = mmap.protect(Protection::PROT_READ | Protection::PROT_WRITE);
+            }
+
+            // Wipe the memory before returning it to the system
+            mmap.wipe(0);
+        }
+
+        self.unmap(mmap);
+    }
+
+    /// Unmaps a segment, or adds it to the current epoch if epochs are enabled. The epoch is unmapped once it
+    /// is full
+    fn unmap(&self, mut mmap: MMap) {
+        let mut held = self.lock_held();
+
+        if held.readers > 0 {
+            // Still being read
+            held.segments.push(mmap);
+            return;
+        }
+
+        drop(held);
+
+        let limit = self.config.unmap_epoch;
+
+        if limit == 0 {
+            self.unmap_now(mmap);
+            return;
+        }
+
+        // Drop the user data now as merged segments lose it
+        mmap.set_userdata(None);
+
+        let mut epoch = self.lock_epoch();
+
+        epoch.push(mmap);
+
+        if epoch.len() < limit {
+            return;
+        }
+
+        let batch = std::mem::take(&mut *epoch);
+
+        drop(epoch);
+
+        self.unmap_batch(batch);
+    }
+
+    /// Unmaps a batch of segments, merging adjacent segments so each run is released with one munmap
+    fn unmap_batch(&self, mut batch: Vec<MMap>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        batch.sort_unstable_by_key(|mmap| mmap.as_ptr() as usize);
+
+        let mut runs: Vec<MMap> = Vec::with_capacity(batch.len());
+
+        for mmap in batch {
+            match runs.pop() {
+                Some(run) if run.adjoins(&mmap) => runs.push(run.merge(mmap)),
+                Some(run) => {
+                    runs.push(run);
+                    runs.push(mmap);
+                }
+                None => runs.push(mmap),
+            }
+        }
+
+        match &self.deferred {
+            Some(deferred) => deferred.push_all(runs),
+            None => runs.into_iter().for_each(|run| self.unmap_now(run)),
+        }
+    }
+
+    /// Unmaps a segment, or queues it for the background thread if deferred unmapping is enabled. Failures
+    /// are counted and logged, or cause a panic in strict mode
+    fn unmap_now(&self, mmap: MMap) {
+        if let Some(deferred) = &self.deferred {
+            deferred.push(mmap);
+            return;
+        }
+
+        let ptr = mmap.as_ptr();
+        let alloc_size = mmap.alloc_size();
+        let page_size = mmap.page_size();
+
+        if let Err(e) = self.latency.time(Syscall::Munmap, page_size, || mmap.unmap()) {
+            if self.config.strict_unmap {
+                panic!("{}: failed to unmap {:?} ({} bytes) ({})", self.ident(), ptr, alloc_size, e);
+            }
+
+            self.lock_stats().unmaps_failed += 1;
+
+            self.log(format_args!("failed to unmap {:?} ({} bytes) ({})", ptr, alloc_size, e));
+        }
+    }
+
+    /// Runs a segment hook if one is set
+    fn run_hook(&self, hook: &Option<SegmentHook>, mmap: &MMap) {
+        if let Some(hook) = hook {
+            if mmap.alloc_size() > 0 {
+                hook(mmap.as_ptr(), mmap.alloc_size());
+            }
+        }
+    }
+
+    /// Records an operation in the trace, if one is being recorded. `addr` is the segment address (0 if a map
+    /// failed), `new_addr` the new address of a remapped segment (0 if the remap failed) and `unmapped` the
+    /// page size of a freed segment (None if it wasn't found)
+    fn trace(&self, op: TraceOp, addr: usize, new_addr: usize, layout: Layout, unmapped: Option<PageSize>) {
+        // The profiler follows sampled allocations through the same operations
+        if let Some(profiler) = &self.profiler {
+            profiler.observe(op, addr, new_addr, layout.size());
+        }
+
+        let Some(recorder) = &self.config.trace_recorder else {
+            return;
+        };
+
+        let mapped_at = |addr: usize| self.lock_map().get(&mte::untag(addr)).map(|mmap| mmap.page_size());
+
+        let page_size = match op {
+            TraceOp::Map => mapped_at(addr),
+            TraceOp::Unmap => unmapped,
+            TraceOp::Remap => mapped_at(new_addr),
+        };
+
+        recorder.record(TraceEvent {
+            op,
+            nanos: 0,
+            addr,
+            new_addr,
+            size: layout.size(),
+            align: layout.align(),
+            page_size,
+            ok: page_size.is_some(),
+        });
+    }
+
+    /// Sends a diagnostic message to the configured log sink
+    pub(crate) fn log(&self, args: fmt::Arguments) {
+        if let Some(sink) = &self.config.log_sink {
+            sink(&format!("{}: {}", self.ident(), args));
+        }
+    }
+
+    /// Sends a warning to the log sink unless the same warning was sent within the warning interval.
+    /// Suppressed warnings are counted and reported with the next one sent
+    pub(crate) fn warn(&self, warning: Warning, args: fmt::Arguments) {
+        if self.config.log_sink.is_none() {
+            return;
+        }
+
+        let mut warnings = self.warnings.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut warnings[warning as usize];
+
+        if state.last.is_some_and(|last| last.elapsed() < self.config.warn_interval) {
+            state.suppressed += 1;
+            return;
+        }
+
+        let suppressed = std::mem::take(&mut state.suppressed);
+        state.last = Some(Instant::now());
+
+        drop(warnings);
+
+        if suppressed > 0 {
+            self.log(format_args!("{} ({} similar warnings suppressed)", args, suppressed));
+        } else {
+            self.log(args);
+        }
+    }
+
+    /// Locks the ptr_map. A poisoned lock is recovered as the map is never left inconsistent by a panic
+    fn lock_map(&self) -> MutexGuard<'_, HashMap<usize, MMap>> {
+        // Lock the ptr_map
+        self.ptr_map.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the segment cache. A poisoned lock is recovered as the cache only holds whole segments
+    fn lock_cache(&self) -> MutexGuard<'_, Vec<MMap>> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the current unmap epoch
+    fn lock_epoch(&self) -> MutexGuard<'_, Vec<MMap>> {
+        self.epoch.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the held segments
+    fn lock_held(&self) -> MutexGuard<'_, Held> {
+        self.held.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks statistics. A poisoned lock is recovered as the counters are always valid
+    fn lock_stats(&self) -> MutexGuard<'_, MMapperStats> {
+        // Lock stats
+        self.stats.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Add statistics about huge allocations served from surplus huge pages
+    fn add_surplus(&self, bytes: usize) {
+        let mut stats = self.lock_stats();
+
+        stats.surplus_allocs += 1;
+        stats.surplus_bytes += bytes;
+    }
+
+    /// Counts a finished request arena and the bytes it allocated
+    pub(crate) fn record_request(&self, bytes: usize) {
+        let mut stats = self.lock_stats();
+
+        stats.requests += 1;
+        stats.request_bytes += bytes;
+        stats.peak_request_bytes = stats.peak_request_bytes.max(bytes);
+    }
+
+    /// Add statistics about missed huge allocations
+    fn add_missed(&self, bytes: usize) {
+        let mut stats = self.lock_stats();
+
+        stats.missed_allocs += 1;
+
+        stats.missed_bytes += bytes;
+
+        if stats.missed_bytes > (1024 * 1024) {
+            let mb = stats.missed_bytes / (1024 * 1024);
+            stats.missed_bytes -= mb * (1024 * 1024);
+            stats.missed_mb += mb;
+        }
+    }
+}
+
+/// Conditions warned about through the log sink at a limited rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Warning {
+    /// A huge page allocation fell back to the default page size
+    Fallback,
+    /// A segment could not be resized in place
+    RemapFailed,
+    /// An object pool could not map a new segment
+    PoolExhausted,
+    /// The number of mappings is approaching vm.max_map_count
+    MapCount,
+}
+
+/// Keeps segments mapped while alive. See [`MMapper::hold`]
+pub(crate) struct HoldGuard<'a> {
+    mapper: &'a MMapper,
+}
+
+impl Drop for HoldGuard<'_> {
+    fn drop(&mut self) {
+        let mut held = self.mapper.lock_held();
+
+        held.readers -= 1;
+
+        if held.readers > 0 {
+            return;
+        }
+
+        let segments = std::mem::take(&mut held.segments);
+
+        drop(held);
+
+        for mmap in segments {
+            self.mapper.unmap(mmap);
+        }
+    }
+}
+
+/// Segments kept mapped for readers outside the registry lock
+#[derive(Default)]
+struct Held {
+    /// Number of live hold guards
+    readers: usize,
+    /// Segments freed while held
+    segments: Vec<MMap>,
+}
+
+impl Warning {
+    /// Number of warnings
+    const COUNT: usize = 4;
+}
+
+/// When a warning was last sent and how many have been suppressed since
+#[derive(Default)]
+struct WarningState {
+    last: Option<Instant>,
+    suppressed: usize,
+}
+
+#[derive(Default)]
 struct MMapperStats {
     missed_allocs: usize,
     missed_bytes: usize,
     missed_mb: usize,
+    thp_eligible: usize,
+    demoted_segments: usize,
+    demoted_bytes: usize,
     remaps_failed: usize,
+    unmaps_failed: usize,
+    surplus_allocs: usize,
+    surplus_bytes: usize,
+    mergeable_ignored: usize,
+    requests: usize,
+    request_bytes: usize,
+    peak_request_bytes: usize,
+    /// Bytes of huge pages wanted by live allocations at or above the threshold, whether or not they got them
+    huge_demand: usize,
+    peak_huge_demand: usize,
+}
+
+impl Drop for MMapper {
+    /// Writes the exit checkpoint, then unmaps any segments still allocated, cached or waiting for the current
+    /// epoch
+    fn drop(&mut self) {
+        self.checkpoint("drop");
+        self.reset();
+        self.trim();
+        self.flush();
+    }
 }
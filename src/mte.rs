@@ -0,0 +1,139 @@
+//! ARM memory tagging (MTE) for allocations made with [`AllocOptions::mte`](crate::AllocOptions::mte)
+//!
+//! On aarch64 CPUs with MTE, tagged segments are mapped with PROT_MTE. Every 16 byte granule of a segment is
+//! given a random tag when it is allocated, and the returned pointer carries the same tag in its top byte.
+//! Freed segments are tagged again, so accesses through stale pointers and accesses which run off the end of
+//! one segment in to another fault. Tag checking must first be enabled for the process with [`enable`]
+//!
+//! ```rust,ignore
+//! #![feature(allocator_api)]
+//! use huge_allocator::{mte, AllocOptions, HugeAllocator};
+//!
+//! if mte::supported() {
+//!     mte::enable(mte::MteMode::Sync).unwrap();
+//!
+//!     let allocator = HugeAllocator::builder()
+//!         .default_options(AllocOptions { mte: true, ..Default::default() })
+//!         .build();
+//! }
+//! ```
+//!
+//! Other architectures don't support MTE and fail tagged allocations
+
+use std::io;
+
+/// How tag check faults are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MteMode {
+    /// Fault on the offending access (PR_MTE_TCF_SYNC)
+    Sync,
+    /// Fault some time after the offending access, at lower cost (PR_MTE_TCF_ASYNC)
+    Async,
+}
+
+/// Protection flag mapping memory with tag storage
+#[cfg(target_arch = "aarch64")]
+pub(crate) const PROT_MTE: libc::c_int = 0x20;
+
+/// Size of a tag granule in bytes
+#[cfg(target_arch = "aarch64")]
+const GRANULE: usize = 16;
+
+/// Mask selecting the address bits of a pointer, below the tag in the top byte
+#[cfg(target_arch = "aarch64")]
+const ADDR_MASK: usize = (1 << 56) - 1;
+
+/// Returns true if the CPU and kernel support MTE
+pub fn supported() -> bool {
+    #[cfg(target_arch = "aarch64")]
+    {
+        const HWCAP2_MTE: libc::c_ulong = 1 << 18;
+
+        unsafe { libc::getauxval(libc::AT_HWCAP2) & HWCAP2_MTE != 0 }
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    false
+}
+
+/// Enables the tagged address ABI and tag checking for the calling thread, allowing any tag but 0 to be
+/// generated. Threads created afterwards inherit the setting
+pub fn enable(mode: MteMode) -> io::Result<()> {
+    #[cfg(target_arch = "aarch64")]
+    {
+        const PR_SET_TAGGED_ADDR_CTRL: libc::c_int = 55;
+        const PR_TAGGED_ADDR_ENABLE: libc::c_ulong = 1;
+        const PR_MTE_TCF_SYNC: libc::c_ulong = 1 << 1;
+        const PR_MTE_TCF_ASYNC: libc::c_ulong = 1 << 2;
+        const PR_MTE_TAG_SHIFT: libc::c_ulong = 3;
+
+        let tcf = match mode {
+            MteMode::Sync => PR_MTE_TCF_SYNC,
+            MteMode::Async => PR_MTE_TCF_ASYNC,
+        };
+
+        let ctrl = PR_TAGGED_ADDR_ENABLE | tcf | (0xfffe << PR_MTE_TAG_SHIFT);
+
+        if unsafe { libc::prctl(PR_SET_TAGGED_ADDR_CTRL, ctrl, 0, 0, 0) } != 0 {
+            Err(io::Error::last_os_error())?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = mode;
+
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+/// Gives every granule of a region a new random tag, returning the pointer carrying the tag
+///
+/// # Safety
+///
+/// The region must be mapped with PROT_MTE and `len` must be a multiple of 16
+pub(crate) unsafe fn retag(ptr: *mut u8, len: usize) -> *mut u8 {
+    #[cfg(target_arch = "aarch64")]
+    {
+        let tagged: *mut u8;
+
+        std::arch::asm!(
+            ".arch_extension memtag",
+            "irg {tagged}, {ptr}",
+            tagged = out(reg) tagged,
+            ptr = in(reg) untag(ptr as usize),
+            options(nomem, nostack, preserves_flags)
+        );
+
+        for offset in (0..len).step_by(GRANULE) {
+            std::arch::asm!(
+                ".arch_extension memtag",
+                "stg {granule}, [{granule}]",
+                granule = in(reg) tagged.add(offset),
+                options(nostack, preserves_flags)
+            );
+        }
+
+        tagged
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        let _ = len;
+
+        ptr
+    }
+}
+
+/// Strips any tag from an address
+pub(crate) fn untag(addr: usize) -> usize {
+    #[cfg(target_arch = "aarch64")]
+    {
+        addr & ADDR_MASK
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    addr
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+/// Per-segment mapping options. These can be set as allocator wide defaults with
+/// [`HugeAllocatorBuilder::default_options`](crate::HugeAllocatorBuilder::default_options) or per
+/// allocation with [`HugeAllocator::allocate_with`](crate::HugeAllocator::allocate_with)
+///
+/// ```rust
+/// use huge_allocator::AllocOptions;
+///
+/// let options = AllocOptions {
+///     wipe_on_fork: true,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocOptions {
+    /// Mark the segment MADV_WIPEONFORK so forked children see zeroed memory in its place
+    pub wipe_on_fork: bool,
+    /// Mark the segment MADV_DONTDUMP so it is excluded from core dumps
+    pub dont_dump: bool,
+    /// Mark the segment MADV_DONTFORK so it is not mapped in to forked children at all
+    pub dont_fork: bool,
+    /// Lock the segment in memory with mlock so it is never swapped out
+    pub lock: bool,
+    /// Never move or resize the segment. Reallocations fail, so the address stays valid for registration
+    /// with hardware (e.g. RDMA memory regions) until the memory is freed
+    pub pinned: bool,
+    /// Bind the segment to the given NUMA node with mbind and populate it
+    pub node: Option<usize>,
+    /// Interleave the segment's pages across the NUMA nodes in the mask (bit n for node n) and populate it.
+    /// Ignored if `node` is set
+    pub interleave: Option<u64>,
+    /// Mark the segment MADV_MERGEABLE so KSM can share identical pages with other processes. Only applies to
+    /// default page size segments - huge page segments are never merged, and are counted in
+    /// [`HugeAllocatorStats::mergeable_ignored`](crate::HugeAllocatorStats::mergeable_ignored)
+    pub mergeable: bool,
+    /// Map the segment with ARM memory tagging (PROT_MTE), tagging it on allocation and free so stray and
+    /// stale accesses fault. Fails where MTE is not supported. See [`mte`](crate::mte)
+    pub mte: bool,
+    /// Map the segment with MAP_32BIT so it lies in the first 2GB of the address space, for buffers which
+    /// must be reachable with 32-bit offsets. Huge page segments still fall back to the default page size,
+    /// and segments are copied rather than moved when they can't grow in place. Fails where MAP_32BIT is not
+    /// supported (anything but x86_64)
+    pub map_32bit: bool,
+}
+
+/// What happens to a huge page segment when a reallocation takes it below the huge page threshold
+///
+/// ```rust
+/// use std::time::Duration;
+/// use huge_allocator::{HugeAllocator, ShrinkPolicy};
+///
+/// // Buffers which shrink and regrow within a second stay on huge pages
+/// let allocator = HugeAllocator::builder()
+///     .shrink_policy(ShrinkPolicy::Lazy(Duration::from_secs(1)))
+///     .build();
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ShrinkPolicy {
+    /// Move the allocation to default size pages straight away
+    #[default]
+    Demote,
+    /// Keep the huge pages, wasting the unused part of the last huge page but avoiding a copy
+    Keep,
+    /// Keep the huge pages until the allocation has been below the threshold for the grace period. The
+    /// allocation is demoted by the first reallocation after the grace period has passed
+    Lazy(Duration),
+}
+
+/// NUMA placement of new segments which don't have a node or interleave mask set in their [`AllocOptions`]
+///
+/// ```rust
+/// use huge_allocator::{HugeAllocator, NumaPolicy};
+///
+/// // Spread a shared lookup table across nodes 0 and 1
+/// let allocator = HugeAllocator::builder().numa_policy(NumaPolicy::Interleave(0b11)).build();
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NumaPolicy {
+    /// Pages are placed by the kernel's default policy, normally on the node of the CPU which first touches
+    /// them
+    #[default]
+    FirstTouch,
+    /// Segments are bound to the NUMA node of the CPU making the allocation and populated, so thread per core
+    /// services keep their memory local even if another thread touches it first
+    BindLocal,
+    /// Segments are bound to the given NUMA node and populated
+    BindNode(usize),
+    /// Segment pages are interleaved across the NUMA nodes in the mask (bit n for node n) and populated
+    Interleave(u64),
+}
@@ -0,0 +1,168 @@
+//! OpenTelemetry metrics for an allocator, enabled with the `opentelemetry` feature
+//!
+//! [`register_metrics`] publishes the allocator's statistics as observable instruments on a [`Meter`]. The
+//! statistics are read when the meter provider collects, and the instruments stay registered for as long
+//! as the meter provider lives. They don't keep the allocator alive, and stop reporting once it is dropped
+//!
+//! | Instrument | Kind | Unit | Attributes |
+//! |---|---|---|---|
+//! | `huge_allocator.memory.allocated` | gauge | `By` | `page_size` |
+//! | `huge_allocator.memory.mapped` | gauge | `By` | `page_size` |
+//! | `huge_allocator.segments` | gauge | `{segment}` | `page_size` |
+//! | `huge_allocator.cache.memory` | gauge | `By` | |
+//! | `huge_allocator.cache.segments` | gauge | `{segment}` | |
+//! | `huge_allocator.surplus.memory` | gauge | `By` | |
+//! | `huge_allocator.efficiency` | gauge | `%` | |
+//! | `huge_allocator.huge_page.misses` | counter | `{allocation}` | |
+//! | `huge_allocator.surplus.allocations` | counter | `{allocation}` | |
+//! | `huge_allocator.remap.failures` | counter | `{remap}` | |
+//! | `huge_allocator.unmap.failures` | counter | `{unmap}` | |
+//!
+//! `page_size` is `default` or `huge`. Every measurement of a named allocator also carries
+//! `huge_allocator.name`
+//!
+//! ```rust
+//! use huge_allocator::HugeAllocator;
+//!
+//! let allocator = HugeAllocator::builder().name("requests").build();
+//! let meter = opentelemetry::global::meter("myservice");
+//!
+//! huge_allocator::otel::register_metrics(&allocator, &meter);
+//! ```
+
+use std::sync::{Arc, Weak};
+
+use opentelemetry::metrics::Meter;
+use opentelemetry::KeyValue;
+
+use crate::mmapper::MMapper;
+use crate::{HugeAllocator, HugeAllocatorStats};
+
+/// Registers observable instruments publishing the allocator's statistics on the meter
+pub fn register_metrics(allocator: &HugeAllocator, meter: &Meter) {
+    let metrics = Metrics {
+        allocator,
+        meter,
+        attributes: allocator
+            .name()
+            .map(|name| KeyValue::new("huge_allocator.name", name.to_string()))
+            .into_iter()
+            .collect(),
+    };
+
+    metrics.by_page_size("huge_allocator.memory.allocated", "By", "Memory allocated", |stats| {
+        [stats.default_alloc, stats.huge_alloc]
+    });
+    metrics.by_page_size("huge_allocator.memory.mapped", "By", "Memory mapped", |stats| {
+        [stats.default_mapped, stats.huge_mapped]
+    });
+    metrics.by_page_size("huge_allocator.segments", "{segment}", "Segments mapped", |stats| {
+        [stats.default_segments, stats.huge_segments]
+    });
+
+    metrics.gauge("huge_allocator.cache.memory", "By", "Memory held in the segment cache", |stats| {
+        stats.cached_bytes
+    });
+    metrics.gauge("huge_allocator.cache.segments", "{segment}", "Freed segments held in the segment cache", |stats| {
+        stats.cached_segments
+    });
+    metrics.gauge("huge_allocator.surplus.memory", "By", "Memory mapped from surplus huge pages", |stats| {
+        stats.surplus_bytes
+    });
+    metrics.gauge("huge_allocator.efficiency", "%", "Percentage of mapped memory used by allocations", |stats| {
+        stats.efficiency
+    });
+
+    metrics.counter(
+        "huge_allocator.huge_page.misses",
+        "{allocation}",
+        "Allocations which fell back to the default page size for lack of huge pages",
+        |stats| stats.missed_allocs,
+    );
+    metrics.counter(
+        "huge_allocator.surplus.allocations",
+        "{allocation}",
+        "Huge page allocations served at least partly from surplus huge pages",
+        |stats| stats.surplus_allocs,
+    );
+    metrics.counter("huge_allocator.remap.failures", "{remap}", "Failed remaps", |stats| stats.remaps_failed);
+    metrics.counter("huge_allocator.unmap.failures", "{unmap}", "Failed unmaps", |stats| stats.unmaps_failed);
+}
+
+/// Builds instruments for an allocator
+struct Metrics<'a> {
+    allocator: &'a HugeAllocator,
+    meter: &'a Meter,
+    /// Attributes added to every measurement
+    attributes: Vec<KeyValue>,
+}
+
+impl Metrics<'_> {
+    /// Registers a gauge
+    fn gauge(&self, name: &'static str, unit: &'static str, description: &'static str, value: fn(&HugeAllocatorStats) -> usize) {
+        let mapper = Arc::downgrade(&self.allocator.mapper);
+        let attributes = self.attributes.clone();
+
+        self.meter
+            .u64_observable_gauge(name)
+            .with_unit(unit)
+            .with_description(description)
+            .with_callback(move |observer| {
+                if let Some(stats) = stats(&mapper) {
+                    observer.observe(value(&stats) as u64, &attributes);
+                }
+            })
+            .build();
+    }
+
+    /// Registers a gauge with a measurement for each page size
+    fn by_page_size(
+        &self,
+        name: &'static str,
+        unit: &'static str,
+        description: &'static str,
+        values: fn(&HugeAllocatorStats) -> [usize; 2],
+    ) {
+        let mapper = Arc::downgrade(&self.allocator.mapper);
+        let attributes = ["default", "huge"].map(|page_size| {
+            let mut attributes = self.attributes.clone();
+            attributes.push(KeyValue::new("page_size", page_size));
+            attributes
+        });
+
+        self.meter
+            .u64_observable_gauge(name)
+            .with_unit(unit)
+            .with_description(description)
+            .with_callback(move |observer| {
+                if let Some(stats) = stats(&mapper) {
+                    for (value, attributes) in values(&stats).into_iter().zip(&attributes) {
+                        observer.observe(value as u64, attributes);
+                    }
+                }
+            })
+            .build();
+    }
+
+    /// Registers a monotonic counter
+    fn counter(&self, name: &'static str, unit: &'static str, description: &'static str, value: fn(&HugeAllocatorStats) -> usize) {
+        let mapper = Arc::downgrade(&self.allocator.mapper);
+        let attributes = self.attributes.clone();
+
+        self.meter
+            .u64_observable_counter(name)
+            .with_unit(unit)
+            .with_description(description)
+            .with_callback(move |observer| {
+                if let Some(stats) = stats(&mapper) {
+                    observer.observe(value(&stats) as u64, &attributes);
+                }
+            })
+            .build();
+    }
+}
+
+/// Reads the statistics of an allocator if it still exists
+fn stats(mapper: &Weak<MMapper>) -> Option<HugeAllocatorStats> {
+    mapper.upgrade().map(|mapper| mapper.stats())
+}
@@ -0,0 +1,75 @@
+use std::alloc::Layout;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use crate::{AllocError, AllocOptions, HugeAllocator, HugeBuf};
+
+/// A zero-initialised byte buffer which is locked in memory, excluded from forked children and never
+/// moved or resized by the allocator. The address is stable until the buffer is dropped, so it can be
+/// registered with hardware such as an RDMA NIC (e.g. with `ibv_reg_mr`). Any attempt to reallocate the
+/// memory through the allocator fails
+///
+/// ```rust
+/// use huge_allocator::HugeAllocator;
+///
+/// let allocator = HugeAllocator::new(50);
+///
+/// let mut buf = allocator.alloc_pinned(2 * 1024 * 1024).unwrap();
+/// buf[0] = 1;
+///
+/// // Register buf.as_mut_ptr() and buf.len() with the device here
+/// ```
+pub struct PinnedBuf<'a> {
+    buf: HugeBuf<'a>,
+}
+
+impl<'a> PinnedBuf<'a> {
+    /// Allocates a new pinned buffer of the given size
+    pub(crate) fn new(allocator: &'a HugeAllocator, size: usize) -> Result<Self, AllocError> {
+        let layout = Layout::from_size_align(size, 1).map_err(|_| AllocError)?;
+
+        let options = AllocOptions {
+            lock: true,
+            dont_fork: true,
+            pinned: true,
+            ..allocator.mapper.default_options()
+        };
+
+        Ok(Self {
+            buf: HugeBuf::with_options(allocator, layout, &options)?,
+        })
+    }
+
+    /// Returns a raw pointer to the buffer
+    pub fn as_ptr(&self) -> *const u8 {
+        self.buf.as_ptr()
+    }
+
+    /// Returns a raw mutable pointer to the buffer
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.buf.as_mut_ptr()
+    }
+}
+
+impl Deref for PinnedBuf<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PinnedBuf<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl fmt::Debug for PinnedBuf<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PinnedBuf")
+            .field("ptr", &self.buf.as_ptr())
+            .field("len", &self.buf.len())
+            .finish()
+    }
+}
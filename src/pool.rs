@@ -0,0 +1,246 @@
+use std::alloc::Layout;
+use std::fmt;
+use std::mem::{size_of, ManuallyDrop};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::mmapper::Warning;
+use crate::{AllocError, HugeAllocator};
+
+/// Default segment size in bytes
+const SEGMENT_BYTES: usize = 2 * 1024 * 1024;
+
+/// A slot holding either a live object or a link to the next free slot
+union Slot<T> {
+    value: ManuallyDrop<T>,
+    next: Option<NonNull<Slot<T>>>,
+}
+
+/// Mutable pool state
+struct PoolInner<T> {
+    /// Head of the free slot list
+    free: Option<NonNull<Slot<T>>>,
+    /// Mapped segments
+    segments: Vec<NonNull<Slot<T>>>,
+    /// Number of free slots
+    available: usize,
+}
+
+/// A pool of fixed size object slots carved out of huge page segments. Slots are handed out and recycled
+/// through a free list in constant time. When the pool is exhausted another segment is mapped
+///
+/// ```rust
+/// use huge_allocator::{HugeAllocator, ObjectPool};
+///
+/// let allocator = HugeAllocator::new(50);
+/// let pool = ObjectPool::with_capacity(&allocator, 1024).unwrap();
+///
+/// let mut packet = pool.get([0u8; 1500]).unwrap();
+/// packet[0] = 0x45;
+///
+/// assert_eq!(1, pool.in_use());
+/// ```
+pub struct ObjectPool<'a, T> {
+    allocator: &'a HugeAllocator,
+    /// Number of slots in each segment
+    slots_per_segment: usize,
+    inner: Mutex<PoolInner<T>>,
+}
+
+unsafe impl<T: Send> Send for ObjectPool<'_, T> {}
+unsafe impl<T: Send> Sync for ObjectPool<'_, T> {}
+
+impl<'a, T> ObjectPool<'a, T> {
+    /// Creates an empty pool. Segments of 2mb are mapped as slots are required
+    pub fn new(allocator: &'a HugeAllocator) -> Self {
+        Self::with_segment_slots(allocator, (SEGMENT_BYTES / size_of::<Slot<T>>()).max(1))
+    }
+
+    /// Creates an empty pool with the given number of slots in each segment
+    pub fn with_segment_slots(allocator: &'a HugeAllocator, slots_per_segment: usize) -> Self {
+        Self {
+            allocator,
+            slots_per_segment: slots_per_segment.max(1),
+            inner: Mutex::new(PoolInner {
+                free: None,
+                segments: Vec::new(),
+                available: 0,
+            }),
+        }
+    }
+
+    /// Creates a pool with at least `capacity` slots mapped up front
+    pub fn with_capacity(allocator: &'a HugeAllocator, capacity: usize) -> Result<Self, AllocError> {
+        let pool = Self::new(allocator);
+
+        pool.reserve(capacity)?;
+
+        Ok(pool)
+    }
+
+    /// Maps segments until at least `additional` slots are free
+    pub fn reserve(&self, additional: usize) -> Result<(), AllocError> {
+        let mut inner = self.lock();
+
+        while inner.available < additional {
+            self.add_segment(&mut inner)?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves a value in to a free slot, mapping a new segment if none are free. The value is returned if
+    /// a segment can't be mapped
+    pub fn get(&self, value: T) -> Result<Pooled<'_, 'a, T>, T> {
+        let mut inner = self.lock();
+
+        if inner.free.is_none() && self.add_segment(&mut inner).is_err() {
+            let capacity = inner.segments.len() * self.slots_per_segment;
+
+            self.allocator.mapper.warn(
+                Warning::PoolExhausted,
+                format_args!("object pool of {} slots exhausted and a new segment could not be mapped", capacity),
+            );
+
+            return Err(value);
+        }
+
+        // Pop the head of the free list
+        let slot = inner.free.unwrap();
+
+        unsafe {
+            inner.free = (*slot.as_ptr()).next;
+            inner.available -= 1;
+
+            slot.as_ptr().write(Slot {
+                value: ManuallyDrop::new(value),
+            });
+        }
+
+        Ok(Pooled { pool: self, slot })
+    }
+
+    /// Returns the total number of slots
+    pub fn capacity(&self) -> usize {
+        self.lock().segments.len() * self.slots_per_segment
+    }
+
+    /// Returns the number of free slots
+    pub fn available(&self) -> usize {
+        self.lock().available
+    }
+
+    /// Returns the number of slots in use
+    pub fn in_use(&self) -> usize {
+        let inner = self.lock();
+
+        inner.segments.len() * self.slots_per_segment - inner.available
+    }
+
+    /// Returns a slot to the free list
+    fn put(&self, slot: NonNull<Slot<T>>) {
+        let mut inner = self.lock();
+
+        unsafe { slot.as_ptr().write(Slot { next: inner.free }) };
+
+        inner.free = Some(slot);
+        inner.available += 1;
+    }
+
+    /// Maps a new segment and adds its slots to the free list
+    fn add_segment(&self, inner: &mut PoolInner<T>) -> Result<(), AllocError> {
+        let ptr = self.allocator.mapper.alloc(self.segment_layout()?)?;
+        let base = ptr.cast::<Slot<T>>();
+
+        // Thread the new slots on to the free list
+        for i in (0..self.slots_per_segment).rev() {
+            let slot = unsafe { NonNull::new_unchecked(base.as_ptr().add(i)) };
+
+            unsafe { slot.as_ptr().write(Slot { next: inner.free }) };
+
+            inner.free = Some(slot);
+        }
+
+        inner.segments.push(base);
+        inner.available += self.slots_per_segment;
+
+        Ok(())
+    }
+
+    /// Returns the layout of a segment
+    fn segment_layout(&self) -> Result<Layout, AllocError> {
+        Layout::array::<Slot<T>>(self.slots_per_segment).map_err(|_| AllocError)
+    }
+
+    /// Locks the pool state
+    fn lock(&self) -> MutexGuard<'_, PoolInner<T>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Drop for ObjectPool<'_, T> {
+    /// Releases all segments. All pooled objects have been returned as they borrow the pool
+    fn drop(&mut self) {
+        let layout = match self.segment_layout() {
+            Ok(layout) => layout,
+            Err(_) => return,
+        };
+
+        let inner = self.inner.get_mut().unwrap_or_else(PoisonError::into_inner);
+
+        for segment in inner.segments.drain(..) {
+            let _ = self.allocator.mapper.dealloc(segment.cast(), layout);
+        }
+    }
+}
+
+/// An object held in an [`ObjectPool`] slot. The slot is returned to the pool when dropped
+pub struct Pooled<'p, 'a, T> {
+    pool: &'p ObjectPool<'a, T>,
+    slot: NonNull<Slot<T>>,
+}
+
+unsafe impl<T: Send> Send for Pooled<'_, '_, T> {}
+unsafe impl<T: Sync> Sync for Pooled<'_, '_, T> {}
+
+impl<T> Pooled<'_, '_, T> {
+    /// Moves the object out of the pool, returning the slot
+    pub fn into_inner(self) -> T {
+        let this = ManuallyDrop::new(self);
+
+        let value = unsafe { ManuallyDrop::take(&mut (*this.slot.as_ptr()).value) };
+
+        this.pool.put(this.slot);
+
+        value
+    }
+}
+
+impl<T> Deref for Pooled<'_, '_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &(*self.slot.as_ptr()).value }
+    }
+}
+
+impl<T> DerefMut for Pooled<'_, '_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut (*self.slot.as_ptr()).value }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Pooled<'_, '_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T> Drop for Pooled<'_, '_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut (*self.slot.as_ptr()).value) };
+
+        self.pool.put(self.slot);
+    }
+}
@@ -0,0 +1,9 @@
+//! Commonly used types and collection helpers
+//!
+//! ```rust
+//! #![feature(allocator_api)]
+//! use huge_allocator::prelude::*;
+//! ```
+
+pub use crate::collections::*;
+pub use crate::{global_allocator, AllocOptions, HugeAllocator, HugeBox, HugeBoxExt, HugeVec, HugeVecExt};
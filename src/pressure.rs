@@ -0,0 +1,100 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::lazy::open_eventfd;
+use crate::HugeAllocator;
+
+/// Trims an allocator's segment cache whenever the host comes under memory pressure, using a Linux pressure
+/// stall information (PSI) trigger on /proc/pressure/memory. The trigger fires when tasks stall waiting for
+/// memory for longer than the stall time in any window. Monitoring stops when dropped
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use huge_allocator::HugeAllocator;
+///
+/// let allocator = HugeAllocator::builder().segment_cache(256 * 1024 * 1024).build();
+///
+/// // Trim when tasks stall for 150ms in any 2s window
+/// let _monitor = allocator
+///     .watch_pressure(Duration::from_millis(150), Duration::from_secs(2))
+///     .unwrap();
+/// ```
+pub struct PressureMonitor {
+    /// Wakes the monitor thread to shut it down
+    event: OwnedFd,
+    monitor: Option<JoinHandle<()>>,
+}
+
+impl PressureMonitor {
+    /// Registers a memory pressure trigger and starts the monitor thread. The window must be between 500ms
+    /// and 10s, and a multiple of 2s for unprivileged processes
+    pub(crate) fn new(allocator: &HugeAllocator, stall: Duration, window: Duration) -> io::Result<Self> {
+        let mut psi = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/proc/pressure/memory")?;
+
+        psi.write_all(format!("some {} {}\0", stall.as_micros(), window.as_micros()).as_bytes())?;
+
+        let event = open_eventfd()?;
+        let event_fd = event.as_raw_fd();
+        let allocator = allocator.clone();
+
+        let monitor = std::thread::Builder::new()
+            .name("huge_allocator-psi".to_string())
+            .spawn(move || {
+                loop {
+                    let mut fds = [
+                        libc::pollfd {
+                            fd: psi.as_raw_fd(),
+                            events: libc::POLLPRI,
+                            revents: 0,
+                        },
+                        libc::pollfd {
+                            fd: event_fd,
+                            events: libc::POLLIN,
+                            revents: 0,
+                        },
+                    ];
+
+                    if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+                        if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+
+                        return;
+                    }
+
+                    if fds[1].revents != 0 || fds[0].revents & libc::POLLERR != 0 {
+                        // Shut down, or the trigger has gone away
+                        return;
+                    }
+
+                    if fds[0].revents & libc::POLLPRI != 0 {
+                        allocator.trim();
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            event,
+            monitor: Some(monitor),
+        })
+    }
+}
+
+impl Drop for PressureMonitor {
+    fn drop(&mut self) {
+        if let Some(monitor) = self.monitor.take() {
+            // Wake the monitor thread and wait for it to exit
+            let one: u64 = 1;
+            let _ = unsafe { libc::write(self.event.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+            let _ = monitor.join();
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use std::fmt;
+use std::fs;
+
+use crate::cgroup::{self, HugetlbLimit};
+use crate::mmap::PageSize;
+
+/// Sysfs directory describing the 2mb huge page pool
+pub(crate) const HUGEPAGES_2M: &str = "/sys/kernel/mm/hugepages/hugepages-2048kB";
+
+/// Transparent huge page setting from /sys/kernel/mm/transparent_hugepage/enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThpMode {
+    /// THP is used for all anonymous mappings
+    Always,
+    /// THP is only used for mappings advised with MADV_HUGEPAGE
+    Madvise,
+    /// THP is disabled
+    Never,
+    /// The setting could not be read
+    Unknown,
+}
+
+/// Transparent huge page defrag setting from /sys/kernel/mm/transparent_hugepage/defrag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThpDefrag {
+    /// Faults on THP eligible memory stall for direct reclaim and compaction
+    Always,
+    /// Compaction is left to kswapd and kcompactd
+    Defer,
+    /// Faults on memory advised with MADV_HUGEPAGE stall, other memory is compacted in the background
+    DeferMadvise,
+    /// Faults on memory advised with MADV_HUGEPAGE stall for direct reclaim and compaction
+    Madvise,
+    /// Faults never stall, falling back to default pages
+    Never,
+    /// The setting could not be read
+    Unknown,
+}
+
+/// How segments which fall back to the default page size are offered to transparent huge pages, decided
+/// from the THP settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThpFallback {
+    /// THP is only used for advised memory, so fallback segments are advised with MADV_HUGEPAGE
+    Advise,
+    /// THP is used for all memory, so fallback segments are eligible without advice. They aren't advised
+    /// as that would opt them in to synchronous defrag
+    AlreadyEligible,
+    /// Advising would make every fault on the segment stall for compaction (defrag is `always`), so
+    /// fallback segments are not advised
+    AvoidDefragStall,
+    /// THP is disabled, or the settings could not be read
+    Disabled,
+}
+
+impl ThpFallback {
+    /// Decides how to treat fallback segments under the given THP settings
+    pub fn decide(thp: ThpMode, defrag: ThpDefrag) -> Self {
+        match (thp, defrag) {
+            (ThpMode::Never | ThpMode::Unknown, _) => ThpFallback::Disabled,
+            (ThpMode::Always, _) => ThpFallback::AlreadyEligible,
+            (ThpMode::Madvise, ThpDefrag::Always) => ThpFallback::AvoidDefragStall,
+            (ThpMode::Madvise, _) => ThpFallback::Advise,
+        }
+    }
+
+    /// Returns true if fallback segments can be backed by transparent huge pages
+    pub fn is_eligible(&self) -> bool {
+        matches!(self, ThpFallback::Advise | ThpFallback::AlreadyEligible)
+    }
+}
+
+/// Huge page capabilities of the host and process, returned by [`HugeAllocator::probe`](crate::HugeAllocator::probe)
+///
+/// ```rust
+/// use huge_allocator::HugeAllocator;
+///
+/// let report = HugeAllocator::probe();
+///
+/// if !report.huge_pages_available() {
+///     eprintln!("warning: {}", report);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapabilityReport {
+    /// The default page size in bytes
+    pub default_page_size: usize,
+    /// Number of 2mb huge pages in the pool
+    pub huge_pages_total: usize,
+    /// Number of 2mb huge pages not yet faulted in
+    pub huge_pages_free: usize,
+    /// Number of free 2mb huge pages reserved by mappings but not yet faulted in
+    pub huge_pages_reserved: usize,
+    /// Number of 2mb huge pages the pool may grow by on demand
+    pub huge_pages_overcommit: usize,
+    /// Number of 2mb huge pages currently allocated beyond the persistent pool through overcommit
+    pub huge_pages_surplus: usize,
+    /// Transparent huge page setting
+    pub thp: ThpMode,
+    /// Transparent huge page defrag setting
+    pub thp_defrag: ThpDefrag,
+    /// Maximum bytes the process may lock in memory, or None if unlimited
+    pub memlock_limit: Option<u64>,
+    /// Tightest hugetlb cgroup limit on 2mb huge pages, if one applies
+    pub cgroup_limit: Option<HugetlbLimit>,
+    /// Maximum number of mappings a process may have (vm.max_map_count), or 0 if it can't be read
+    pub max_map_count: usize,
+}
+
+impl CapabilityReport {
+    /// Inspects the host and process
+    pub(crate) fn probe() -> Self {
+        Self {
+            default_page_size: PageSize::SizeDefault.bytes(),
+            huge_pages_total: read_hugepages("nr_hugepages"),
+            huge_pages_free: read_hugepages("free_hugepages"),
+            huge_pages_reserved: read_hugepages("resv_hugepages"),
+            huge_pages_overcommit: read_hugepages("nr_overcommit_hugepages"),
+            huge_pages_surplus: read_hugepages("surplus_hugepages"),
+            thp: read_thp(),
+            thp_defrag: read_thp_defrag(),
+            memlock_limit: read_memlock_limit(),
+            cgroup_limit: cgroup::hugetlb_limit(PageSize::Size2m),
+            max_map_count: read_max_map_count(),
+        }
+    }
+
+    /// Returns the number of 2mb huge pages which new mappings can currently reserve, taking the cgroup
+    /// limit in to account
+    pub fn huge_pages_usable(&self) -> usize {
+        let pool = self.huge_pages_free.saturating_sub(self.huge_pages_reserved) + self.surplus_headroom();
+
+        match self.cgroup_limit {
+            Some(limit) => pool.min(limit.headroom() / PageSize::Size2m.bytes()),
+            None => pool,
+        }
+    }
+
+    /// Returns the number of 2mb huge pages which can still be allocated on demand beyond the persistent pool
+    pub fn surplus_headroom(&self) -> usize {
+        self.huge_pages_overcommit.saturating_sub(self.huge_pages_surplus)
+    }
+
+    /// Returns true if at least one 2mb huge page can be mapped
+    pub fn huge_pages_available(&self) -> bool {
+        self.huge_pages_usable() > 0
+    }
+
+    /// Returns how allocations which fall back to the default page size are offered to transparent huge
+    /// pages on this host
+    pub fn thp_fallback(&self) -> ThpFallback {
+        ThpFallback::decide(self.thp, self.thp_defrag)
+    }
+}
+
+impl fmt::Display for CapabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "2mb huge pages: {} usable ({} total, {} free, {} reserved, {} overcommit, {} surplus), THP: {:?} \
+            (defrag: {:?}, fallback: {:?}), memlock limit: ",
+            self.huge_pages_usable(),
+            self.huge_pages_total,
+            self.huge_pages_free,
+            self.huge_pages_reserved,
+            self.huge_pages_overcommit,
+            self.huge_pages_surplus,
+            self.thp,
+            self.thp_defrag,
+            self.thp_fallback()
+        )?;
+
+        match self.memlock_limit {
+            Some(limit) => write!(f, "{} bytes", limit)?,
+            None => f.write_str("unlimited")?,
+        }
+
+        if let Some(limit) = self.cgroup_limit {
+            write!(f, ", cgroup hugetlb headroom: {} bytes", limit.headroom())?;
+        }
+
+        if self.max_map_count > 0 {
+            write!(f, ", max map count: {}", self.max_map_count)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Huge page pool sizing advice for an allocator, returned by
+/// [`HugeAllocator::recommend_pool_size`](crate::HugeAllocator::recommend_pool_size). Only 2mb huge pages are
+/// recommended as they are the only huge page size the allocator maps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolRecommendation {
+    /// Peak bytes of huge pages wanted by live allocations at or above the threshold
+    pub peak_demand: usize,
+    /// Number of allocations which fell back to the default page size
+    pub missed_allocs: usize,
+    /// Number of 2mb huge pages to reserve for the allocator, including 10% headroom
+    pub huge_pages: usize,
+    /// Number of 2mb huge pages currently in the persistent pool (vm.nr_hugepages)
+    pub current_pool: usize,
+}
+
+impl PoolRecommendation {
+    /// Builds a recommendation from the peak demand in bytes
+    pub(crate) fn new(peak_demand: usize, missed_allocs: usize) -> Self {
+        let pages = peak_demand.div_ceil(PageSize::Size2m.bytes());
+
+        Self {
+            peak_demand,
+            missed_allocs,
+            huge_pages: (pages * 11).div_ceil(10),
+            current_pool: read_hugepages("nr_hugepages"),
+        }
+    }
+
+    /// Returns the number of 2mb huge pages the pool is short of the recommendation
+    pub fn shortfall(&self) -> usize {
+        self.huge_pages.saturating_sub(self.current_pool)
+    }
+}
+
+impl fmt::Display for PoolRecommendation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "peak huge page demand {} bytes ({} missed allocations): reserve {} 2mb huge pages (vm.nr_hugepages is {})",
+            self.peak_demand, self.missed_allocs, self.huge_pages, self.current_pool
+        )
+    }
+}
+
+/// Reads a counter for the 2mb huge page pool, giving 0 if it can't be read
+pub(crate) fn read_hugepages(name: &str) -> usize {
+    fs::read_to_string(format!("{}/{}", HUGEPAGES_2M, name))
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Reads vm.max_map_count, giving 0 if it can't be read
+pub(crate) fn read_max_map_count() -> usize {
+    fs::read_to_string("/proc/sys/vm/max_map_count")
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Reads the transparent huge page setting. The active setting is in brackets, e.g. "always [madvise] never"
+pub(crate) fn read_thp() -> ThpMode {
+    let setting = fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled").unwrap_or_default();
+
+    match setting.split_whitespace().find(|word| word.starts_with('[')) {
+        Some("[always]") => ThpMode::Always,
+        Some("[madvise]") => ThpMode::Madvise,
+        Some("[never]") => ThpMode::Never,
+        _ => ThpMode::Unknown,
+    }
+}
+
+/// Reads the transparent huge page defrag setting, e.g. "always defer defer+madvise [madvise] never"
+pub(crate) fn read_thp_defrag() -> ThpDefrag {
+    let setting = fs::read_to_string("/sys/kernel/mm/transparent_hugepage/defrag").unwrap_or_default();
+
+    match setting.split_whitespace().find(|word| word.starts_with('[')) {
+        Some("[always]") => ThpDefrag::Always,
+        Some("[defer]") => ThpDefrag::Defer,
+        Some("[defer+madvise]") => ThpDefrag::DeferMadvise,
+        Some("[madvise]") => ThpDefrag::Madvise,
+        Some("[never]") => ThpDefrag::Never,
+        _ => ThpDefrag::Unknown,
+    }
+}
+
+/// Reads the soft RLIMIT_MEMLOCK limit
+fn read_memlock_limit() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+
+    Some(limit.rlim_cur)
+}
@@ -0,0 +1,245 @@
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::mte;
+use crate::trace::TraceOp;
+
+/// Most return addresses recorded for a sampled allocation
+#[cfg(target_env = "gnu")]
+const MAX_FRAMES: usize = 32;
+
+thread_local! {
+    /// Set while the profiler runs on this thread, so allocations it makes itself are ignored
+    static ACTIVE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Live sampled allocations made from one call stack
+#[derive(Debug, Clone)]
+pub struct SampledCallsite {
+    /// The call stack which made the allocations
+    pub backtrace: String,
+    /// Number of sampled allocations still live
+    pub live_allocs: usize,
+    /// Bytes of sampled allocations still live
+    pub live_bytes: usize,
+    /// Estimated bytes of all live allocations from the call stack, scaling the sampled bytes by the
+    /// sampling rate
+    pub estimated_bytes: usize,
+    /// Number of allocations sampled from the call stack, including those since freed
+    pub samples: usize,
+}
+
+/// Live allocations attributed to the call stacks which made them, returned by
+/// [`HugeAllocator::profile`](crate::HugeAllocator::profile)
+#[derive(Debug, Clone)]
+pub struct ProfileReport {
+    /// One in this many allocations is sampled
+    pub sample_every: usize,
+    /// Call stacks with live sampled allocations, largest first
+    pub callsites: Vec<SampledCallsite>,
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} call stacks, sampling 1 in {} allocations", self.callsites.len(), self.sample_every)?;
+
+        for callsite in &self.callsites {
+            writeln!(
+                f,
+                "\n~{} bytes live ({} sampled allocations, {} bytes):\n{}",
+                callsite.estimated_bytes, callsite.live_allocs, callsite.live_bytes, callsite.backtrace
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Call stack key
+type Stack = Vec<usize>;
+
+/// Sampled allocations made from one call stack
+struct Callsite {
+    /// Backtrace captured with the first sample, resolved when reported
+    backtrace: Backtrace,
+    live_allocs: usize,
+    live_bytes: usize,
+    samples: usize,
+}
+
+#[derive(Default)]
+struct State {
+    callsites: Vec<Callsite>,
+    /// Index of each call stack's entry in callsites
+    index: HashMap<Stack, usize>,
+    /// Callsite index and size of each live sampled allocation by address
+    live: HashMap<usize, (usize, usize)>,
+}
+
+/// Captures the call stack of one in every N allocations and tracks the bytes they hold until freed
+pub(crate) struct Profiler {
+    every: usize,
+    /// Allocations seen
+    count: AtomicUsize,
+    state: Mutex<State>,
+}
+
+impl Profiler {
+    /// Creates a profiler sampling one in `every` allocations
+    pub(crate) fn new(every: usize) -> Self {
+        Self {
+            every,
+            count: AtomicUsize::new(0),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Observes an allocator operation. `addr` is the segment address (0 if a map failed) and `new_addr` the
+    /// new address of a remapped segment (0 if the remap failed)
+    pub(crate) fn observe(&self, op: TraceOp, addr: usize, new_addr: usize, size: usize) {
+        if addr == 0 || ACTIVE.get() {
+            return;
+        }
+
+        ACTIVE.set(true);
+
+        match op {
+            TraceOp::Map => {
+                if self.count.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.every) {
+                    self.sample(mte::untag(addr), size);
+                }
+            }
+            TraceOp::Unmap => self.free(mte::untag(addr)),
+            TraceOp::Remap if new_addr != 0 => self.resize(mte::untag(addr), mte::untag(new_addr), size),
+            TraceOp::Remap => (),
+        }
+
+        ACTIVE.set(false);
+    }
+
+    /// Forgets all live sampled allocations
+    pub(crate) fn clear(&self) {
+        let mut state = self.lock();
+
+        state.live.clear();
+
+        for callsite in &mut state.callsites {
+            callsite.live_allocs = 0;
+            callsite.live_bytes = 0;
+        }
+    }
+
+    /// Builds a report of the live sampled allocations
+    pub(crate) fn report(&self) -> ProfileReport {
+        let state = self.lock();
+
+        let mut callsites: Vec<SampledCallsite> = state
+            .callsites
+            .iter()
+            .filter(|callsite| callsite.live_allocs > 0)
+            .map(|callsite| SampledCallsite {
+                backtrace: callsite.backtrace.to_string(),
+                live_allocs: callsite.live_allocs,
+                live_bytes: callsite.live_bytes,
+                estimated_bytes: callsite.live_bytes.saturating_mul(self.every),
+                samples: callsite.samples,
+            })
+            .collect();
+
+        drop(state);
+
+        callsites.sort_by_key(|callsite| std::cmp::Reverse(callsite.live_bytes));
+
+        ProfileReport {
+            sample_every: self.every,
+            callsites,
+        }
+    }
+
+    /// Records a sampled allocation against the calling stack
+    fn sample(&self, addr: usize, size: usize) {
+        let stack = stack_key();
+
+        let mut state = self.lock();
+
+        let index = match state.index.get(&stack) {
+            Some(&index) => index,
+            None => {
+                let index = state.callsites.len();
+
+                state.callsites.push(Callsite {
+                    backtrace: Backtrace::force_capture(),
+                    live_allocs: 0,
+                    live_bytes: 0,
+                    samples: 0,
+                });
+                state.index.insert(stack, index);
+
+                index
+            }
+        };
+
+        let callsite = &mut state.callsites[index];
+
+        callsite.live_allocs += 1;
+        callsite.live_bytes += size;
+        callsite.samples += 1;
+
+        state.live.insert(addr, (index, size));
+    }
+
+    /// Removes a sampled allocation when it is freed
+    fn free(&self, addr: usize) {
+        let mut state = self.lock();
+
+        if let Some((index, size)) = state.live.remove(&addr) {
+            let callsite = &mut state.callsites[index];
+
+            callsite.live_allocs -= 1;
+            callsite.live_bytes -= size;
+        }
+    }
+
+    /// Follows a sampled allocation when it is resized, keeping the call stack which allocated it
+    fn resize(&self, addr: usize, new_addr: usize, new_size: usize) {
+        let mut state = self.lock();
+
+        if let Some((index, size)) = state.live.remove(&addr) {
+            let callsite = &mut state.callsites[index];
+
+            callsite.live_bytes = callsite.live_bytes - size + new_size;
+
+            state.live.insert(new_addr, (index, new_size));
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Identifies the calling stack by its return addresses
+#[cfg(target_env = "gnu")]
+fn stack_key() -> Stack {
+    let mut frames = [std::ptr::null_mut(); MAX_FRAMES];
+
+    let depth = unsafe { libc::backtrace(frames.as_mut_ptr(), MAX_FRAMES as libc::c_int) };
+
+    frames[..depth.max(0) as usize].iter().map(|&ip| ip as usize).collect()
+}
+
+/// Identifies the calling stack by its resolved frames, as return addresses can't be collected cheaply
+#[cfg(not(target_env = "gnu"))]
+fn stack_key() -> Stack {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+
+    Backtrace::force_capture().to_string().hash(&mut hasher);
+
+    vec![hasher.finish() as usize]
+}
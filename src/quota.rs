@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Byte budget of an allocator, charged with the size of its live allocations and those of its children.
+/// Charges are passed up the tree so every ancestor's budget covers its descendants
+#[derive(Debug)]
+pub(crate) struct Quota {
+    /// Maximum bytes which may be allocated, or None if unlimited
+    limit: Option<usize>,
+    /// Bytes currently allocated by the allocator and its descendants
+    used: AtomicUsize,
+    /// Budget of the parent allocator
+    parent: Option<Arc<Quota>>,
+}
+
+impl Quota {
+    /// Creates a budget, optionally drawing from a parent budget
+    pub(crate) fn new(limit: Option<usize>, parent: Option<Arc<Quota>>) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            parent,
+        }
+    }
+
+    /// Charges bytes to this budget and every ancestor. Nothing is charged if any of them would be exceeded
+    pub(crate) fn try_charge(&self, bytes: usize) -> bool {
+        if bytes == 0 {
+            return true;
+        }
+
+        let charged = self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            let used = used.checked_add(bytes)?;
+
+            self.limit.is_none_or(|limit| used <= limit).then_some(used)
+        });
+
+        if charged.is_err() {
+            return false;
+        }
+
+        if let Some(parent) = &self.parent {
+            if !parent.try_charge(bytes) {
+                self.used.fetch_sub(bytes, Ordering::AcqRel);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Returns bytes to this budget and every ancestor
+    pub(crate) fn release(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+
+        // Saturate rather than wrap, as a wrapped budget would refuse every later charge
+        let released = self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            Some(used.saturating_sub(bytes))
+        });
+
+        debug_assert!(released.is_ok_and(|used| used >= bytes), "released {} bytes more than charged", bytes);
+
+        if let Some(parent) = &self.parent {
+            parent.release(bytes);
+        }
+    }
+
+    /// Returns the maximum bytes which may be allocated, or None if unlimited
+    pub(crate) fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Returns the bytes allocated by the allocator and its descendants
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+}
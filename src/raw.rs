@@ -0,0 +1,137 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{copy_nonoverlapping, null_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Size of a huge page in bytes
+pub(crate) const HUGE_PAGE: usize = 2 * 1024 * 1024;
+
+/// Cached default page size
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// A global allocator which maps huge pages directly with no registry, locking or statistics, so it needs
+/// nothing from std and is available without the `std` feature. Each allocation is its own mapping.
+/// Allocations at or above the threshold are mapped in whole huge pages, falling back to default pages
+/// covering the same length, so the mapped length can always be worked out again from the layout
+///
+/// ```rust
+/// use huge_allocator::RawHugeAlloc;
+///
+/// #[global_allocator]
+/// static GLOBAL: RawHugeAlloc = RawHugeAlloc::new(50);
+///
+/// let vec: Vec<u8> = Vec::with_capacity(2 * 1024 * 1024);
+/// ```
+#[derive(Debug)]
+pub struct RawHugeAlloc {
+    /// Threshold percentage of a huge page at which huge pages are tried
+    threshold_pct: usize,
+}
+
+impl RawHugeAlloc {
+    /// Creates a new raw allocator with a given threshold percentage
+    pub const fn new(threshold_pct: usize) -> Self {
+        Self { threshold_pct }
+    }
+
+    /// Returns true if an allocation of the given size is mapped in whole huge pages
+    fn is_huge(&self, size: usize) -> bool {
+        (size * 100) / HUGE_PAGE >= self.threshold_pct
+    }
+
+    /// Returns the mapped length for an allocation of the given size
+    pub(crate) fn map_len(&self, size: usize) -> usize {
+        let unit = if self.is_huge(size) { HUGE_PAGE } else { page_size() };
+
+        size.max(1).div_ceil(unit) * unit
+    }
+
+    /// Maps an anonymous read write region, returning null on failure
+    pub(crate) unsafe fn map(len: usize, flags: libc::c_int) -> *mut u8 {
+        let ptr = libc::mmap(
+            null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | flags,
+            -1,
+            0,
+        );
+
+        if ptr == libc::MAP_FAILED {
+            null_mut()
+        } else {
+            ptr as *mut u8
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for RawHugeAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let len = self.map_len(layout.size());
+
+        // Huge page mappings are only aligned to the huge page size
+        if self.is_huge(layout.size()) && layout.align() <= HUGE_PAGE {
+            let ptr = Self::map(len, libc::MAP_HUGETLB | libc::MAP_HUGE_2MB);
+
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+
+        if layout.align() > page_size() {
+            // Only huge page mappings are aligned beyond the default page size
+            return null_mut();
+        }
+
+        Self::map(len, 0)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        libc::munmap(ptr as *mut libc::c_void, self.map_len(layout.size()));
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // Anonymous mappings are always zeroed
+        self.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_len = self.map_len(layout.size());
+        let new_len = self.map_len(new_size);
+
+        if self.is_huge(layout.size()) == self.is_huge(new_size) {
+            if old_len == new_len {
+                return ptr;
+            }
+
+            if layout.align() <= page_size() {
+                let new_ptr = libc::mremap(ptr as *mut libc::c_void, old_len, new_len, libc::MREMAP_MAYMOVE);
+
+                if new_ptr != libc::MAP_FAILED {
+                    return new_ptr as *mut u8;
+                }
+            }
+        }
+
+        // Move to a new mapping
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+
+        if !new_ptr.is_null() {
+            copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+
+        new_ptr
+    }
+}
+
+/// Returns the default page size
+pub(crate) fn page_size() -> usize {
+    let mut size = PAGE_SIZE.load(Ordering::Relaxed);
+
+    if size == 0 {
+        size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        PAGE_SIZE.store(size, Ordering::Relaxed);
+    }
+
+    size
+}
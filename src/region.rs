@@ -0,0 +1,202 @@
+use std::alloc::Layout;
+use std::cell::RefCell;
+use std::ptr::{copy_nonoverlapping, NonNull};
+
+use crate::{AllocError, HugeAllocator};
+
+/// Minimum size of a region segment in bytes
+const MIN_SEGMENT_BYTES: usize = 2 * 1024 * 1024;
+
+/// A scoped region allocating from a small number of huge page segments. Individual deallocations are
+/// ignored (apart from the most recent allocation which is rolled back) and all memory is released in one
+/// go when the region is dropped
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::{HugeAllocator, Region};
+///
+/// let allocator = HugeAllocator::new(50);
+///
+/// {
+///     let region = Region::new(&allocator);
+///
+///     let mut a: Vec<u32, _> = Vec::with_capacity_in(1000, &region);
+///     let mut b: Vec<u64, _> = Vec::with_capacity_in(1000, &region);
+///     a.push(1);
+///     b.push(2);
+///
+///     assert_eq!(1, allocator.stats().unwrap().segments);
+/// }
+///
+/// assert_eq!(0, allocator.stats().unwrap().segments);
+/// ```
+pub struct Region<'a> {
+    allocator: &'a HugeAllocator,
+    inner: RefCell<RegionInner>,
+}
+
+/// Mutable region state
+#[derive(Default)]
+struct RegionInner {
+    /// Mapped segments and their layouts
+    segments: Vec<(NonNull<u8>, Layout)>,
+    /// Next free address in the current segment
+    next: usize,
+    /// End of the current segment
+    end: usize,
+    /// Address of the most recent allocation
+    last: usize,
+    /// Bytes handed out
+    allocated: usize,
+}
+
+impl<'a> Region<'a> {
+    /// Creates a new empty region. Segments are mapped as required
+    pub fn new(allocator: &'a HugeAllocator) -> Self {
+        Self {
+            allocator,
+            inner: RefCell::new(RegionInner::default()),
+        }
+    }
+
+    /// Allocates memory from the region
+    pub fn alloc(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut inner = self.inner.borrow_mut();
+
+        let ptr = match Self::bump(&mut inner, layout) {
+            Some(ptr) => ptr,
+            None => {
+                // Map a new segment big enough for the allocation
+                let last_size = inner.segments.last().map(|(_, l)| l.size()).unwrap_or(0);
+                let size = (last_size * 2).max(MIN_SEGMENT_BYTES).max(layout.size() + layout.align());
+
+                let seg_layout = Layout::from_size_align(size, 1).map_err(|_| AllocError)?;
+                let seg = self.allocator.mapper.alloc(seg_layout)?.cast::<u8>();
+
+                inner.segments.push((seg, seg_layout));
+                inner.next = seg.as_ptr() as usize;
+                inner.end = inner.next + size;
+
+                Self::bump(&mut inner, layout).ok_or(AllocError)?
+            }
+        };
+
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    /// Releases memory. Only the most recent allocation is reclaimed
+    pub fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        let mut inner = self.inner.borrow_mut();
+
+        if ptr.as_ptr() as usize == inner.last {
+            inner.next = inner.last;
+            inner.allocated -= layout.size();
+        }
+    }
+
+    /// Resizes an allocation, in place if it is the most recent allocation and there is room
+    pub fn realloc(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        {
+            let mut inner = self.inner.borrow_mut();
+            let addr = ptr.as_ptr() as usize;
+
+            let in_place = new_layout.size() <= old_layout.size()
+                || (addr == inner.last && addr + new_layout.size() <= inner.end);
+
+            if in_place && addr.is_multiple_of(new_layout.align()) {
+                if addr == inner.last {
+                    inner.next = addr + new_layout.size();
+                    inner.allocated = inner.allocated - old_layout.size() + new_layout.size();
+                }
+
+                return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+            }
+        }
+
+        // Copy to a new allocation
+        let new_ptr = self.alloc(new_layout)?;
+
+        unsafe {
+            copy_nonoverlapping(
+                ptr.as_ptr(),
+                new_ptr.cast::<u8>().as_ptr(),
+                old_layout.size().min(new_layout.size()),
+            )
+        };
+
+        Ok(new_ptr)
+    }
+
+    /// Returns the number of bytes allocated from the region
+    pub fn allocated(&self) -> usize {
+        self.inner.borrow().allocated
+    }
+
+    /// Returns the number of segments mapped by the region
+    pub fn segments(&self) -> usize {
+        self.inner.borrow().segments.len()
+    }
+
+    /// Returns the address of a segment mapped by the region
+    pub(crate) fn segment(&self, index: usize) -> NonNull<u8> {
+        self.inner.borrow().segments[index].0
+    }
+
+    /// Bump allocates from the current segment
+    fn bump(inner: &mut RegionInner, layout: Layout) -> Option<NonNull<u8>> {
+        if inner.segments.is_empty() {
+            return None;
+        }
+
+        let start = inner.next.checked_next_multiple_of(layout.align())?;
+        let end = start.checked_add(layout.size())?;
+
+        if end > inner.end {
+            return None;
+        }
+
+        inner.next = end;
+        inner.last = start;
+        inner.allocated += layout.size();
+
+        NonNull::new(start as *mut u8)
+    }
+}
+
+impl Drop for Region<'_> {
+    /// Releases all of the region's segments
+    fn drop(&mut self) {
+        for (seg, layout) in self.inner.get_mut().segments.drain(..) {
+            let _ = self.allocator.mapper.dealloc(seg, layout);
+        }
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl std::alloc::Allocator for Region<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.dealloc(ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout)
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+
+use crate::mmapper::MMapper;
+use crate::HugeAllocatorStats;
+
+/// Set once the registry is enabled
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Allocators built since the registry was enabled
+static REGISTRY: Mutex<Vec<Weak<MMapper>>> = Mutex::new(Vec::new());
+
+/// Statistics of every registered allocator, returned by [`all_stats`]
+#[derive(Debug, Clone, Default)]
+pub struct AllStats {
+    /// Statistics of each live registered allocator, in the order they were built
+    pub instances: Vec<HugeAllocatorStats>,
+    /// Sum of the statistics of all live registered allocators
+    pub total: HugeAllocatorStats,
+}
+
+/// Turns on the global allocator registry. Every [`HugeAllocator`](crate::HugeAllocator) built from now on
+/// registers itself, and is reported by [`all_stats`] until it (and all of its clones) are dropped. Call
+/// this early at startup so allocators created by libraries are included too
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::HugeAllocator;
+///
+/// huge_allocator::enable_registry();
+///
+/// let requests = HugeAllocator::builder().name("requests").build();
+/// let vec: Vec<u8, _> = Vec::with_capacity_in(4096, &requests);
+///
+/// let stats = huge_allocator::all_stats();
+///
+/// for instance in &stats.instances {
+///     println!("{}: {} bytes", instance.name.as_deref().unwrap_or("unnamed"), instance.alloc);
+/// }
+///
+/// assert!(stats.total.alloc >= 4096);
+/// ```
+pub fn enable_registry() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Returns the statistics of every live allocator built since [`enable_registry`] was called, along with
+/// their sum. Returns empty statistics if the registry is not enabled
+pub fn all_stats() -> AllStats {
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+
+    registry.retain(|mapper| mapper.strong_count() > 0);
+
+    let instances: Vec<HugeAllocatorStats> =
+        registry.iter().filter_map(Weak::upgrade).map(|mapper| mapper.stats()).collect();
+
+    AllStats {
+        total: instances.iter().cloned().sum(),
+        instances,
+    }
+}
+
+/// Registers a newly built allocator if the registry is enabled
+pub(crate) fn register(mapper: &Arc<MMapper>) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+
+    registry.retain(|mapper| mapper.strong_count() > 0);
+    registry.push(Arc::downgrade(mapper));
+}
@@ -0,0 +1,145 @@
+use std::alloc::Layout;
+use std::cell::Cell;
+use std::ptr::NonNull;
+
+use crate::region::Region;
+use crate::{AllocError, HugeAllocator};
+
+/// Identifier of the request a [`RequestArena`] segment belongs to, attached to each of the arena's
+/// segments as user data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId(pub u64);
+
+/// Arena serving every allocation made while handling one request from a small number of huge page
+/// segments, releasing them all when the request ends. Each segment is tagged with the [`RequestId`], and
+/// the bytes the request allocated are added to the allocator's request statistics when the arena is
+/// dropped
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::{HugeAllocator, RequestArena};
+///
+/// let allocator = HugeAllocator::new(50);
+///
+/// for id in 0..3 {
+///     let arena = RequestArena::new(&allocator, id);
+///
+///     let mut body: Vec<u8, _> = Vec::with_capacity_in(64 * 1024, &arena);
+///     body.extend_from_slice(b"response");
+/// }
+///
+/// let stats = allocator.stats().unwrap();
+///
+/// assert_eq!(3, stats.requests);
+/// assert_eq!(3 * 64 * 1024, stats.request_bytes);
+/// assert_eq!(0, stats.segments);
+/// ```
+pub struct RequestArena<'a> {
+    allocator: &'a HugeAllocator,
+    region: Region<'a>,
+    id: RequestId,
+    /// Number of segments tagged so far
+    tagged: Cell<usize>,
+    /// Bytes allocated, including those since freed
+    bytes: Cell<usize>,
+}
+
+impl<'a> RequestArena<'a> {
+    /// Creates an arena for a request. Segments are mapped as required
+    pub fn new(allocator: &'a HugeAllocator, id: u64) -> Self {
+        Self {
+            allocator,
+            region: Region::new(allocator),
+            id: RequestId(id),
+            tagged: Cell::new(0),
+            bytes: Cell::new(0),
+        }
+    }
+
+    /// Returns the request identifier
+    pub fn id(&self) -> u64 {
+        self.id.0
+    }
+
+    /// Returns the number of bytes allocated for the request, including any since freed or shrunk
+    pub fn allocated(&self) -> usize {
+        self.bytes.get()
+    }
+
+    /// Returns the number of segments mapped for the request
+    pub fn segments(&self) -> usize {
+        self.region.segments()
+    }
+
+    /// Allocates memory for the request
+    pub fn alloc(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.region.alloc(layout)?;
+
+        self.bytes.set(self.bytes.get() + layout.size());
+        self.tag();
+
+        Ok(ptr)
+    }
+
+    /// Releases memory. Only the most recent allocation is reclaimed before the request ends
+    pub fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.region.dealloc(ptr, layout)
+    }
+
+    /// Resizes an allocation, in place if it is the most recent allocation and there is room
+    pub fn realloc(&self, ptr: NonNull<u8>, old_layout: Layout, new_layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.region.realloc(ptr, old_layout, new_layout)?;
+
+        self.bytes.set(self.bytes.get() + new_layout.size().saturating_sub(old_layout.size()));
+        self.tag();
+
+        Ok(ptr)
+    }
+
+    /// Tags any segments mapped since the last call with the request identifier
+    fn tag(&self) {
+        let segments = self.region.segments();
+
+        for index in self.tagged.get()..segments {
+            let _ = self.allocator.mapper.set_userdata(self.region.segment(index), Box::new(self.id));
+        }
+
+        self.tagged.set(segments);
+    }
+}
+
+impl Drop for RequestArena<'_> {
+    /// Records the request in the allocator's statistics. The segments are released when the region drops
+    fn drop(&mut self) {
+        self.allocator.mapper.record_request(self.bytes.get());
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl std::alloc::Allocator for RequestArena<'_> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.dealloc(ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.realloc(ptr, old_layout, new_layout)
+    }
+}
@@ -0,0 +1,98 @@
+#[cfg(feature = "nightly")]
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ops::Deref;
+#[cfg(feature = "nightly")]
+use std::ptr::NonNull;
+
+use crate::{AllocOptions, HugeAllocator, HugeAllocatorBuilder};
+
+/// A huge page allocator preconfigured for sensitive data such as key material or PII. All segments are
+/// locked in memory, excluded from core dumps, wiped in forked children and zeroed before being unmapped.
+///
+/// ```rust
+/// #![feature(allocator_api)]
+/// use huge_allocator::SecureHugeAllocator;
+///
+/// let allocator = SecureHugeAllocator::new(50);
+///
+/// let mut key: Vec<u8, _> = Vec::with_capacity_in(32, &allocator);
+/// key.extend_from_slice(&[0x55; 32]);
+/// ```
+#[derive(Debug)]
+pub struct SecureHugeAllocator {
+    allocator: HugeAllocator,
+}
+
+impl SecureHugeAllocator {
+    /// Creates a new secure allocator with a given threshold percentage
+    pub fn new(threshold_pct: usize) -> Self {
+        Self::from_builder(HugeAllocator::builder().threshold_pct(threshold_pct))
+    }
+
+    /// Creates a new secure allocator from a builder. The secure options override any set on the builder
+    pub fn from_builder(builder: HugeAllocatorBuilder) -> Self {
+        Self {
+            allocator: builder.zero_on_free(true).default_options(Self::options()).build(),
+        }
+    }
+
+    /// Returns the mapping options used for every allocation
+    pub fn options() -> AllocOptions {
+        AllocOptions {
+            wipe_on_fork: true,
+            dont_dump: true,
+            lock: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl Deref for SecureHugeAllocator {
+    type Target = HugeAllocator;
+
+    fn deref(&self) -> &Self::Target {
+        &self.allocator
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl Allocator for SecureHugeAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.allocator.deallocate(ptr, layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator.allocate_zeroed(layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator.grow(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator.grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocator.shrink(ptr, old_layout, new_layout)
+    }
+}
@@ -0,0 +1,121 @@
+//! Standalone memory mapped segments
+//!
+//! These use the same page size fallback as the allocators but aren't tracked by any allocator, so the
+//! caller manages their lifetime. A [`Segment`] is unmapped when dropped, or explicitly with [`unmap`]
+//! where the failure needs to be reported
+//!
+//! ```rust
+//! use huge_allocator::mmap::PageSize;
+//! use huge_allocator::segment;
+//!
+//! let mut seg = segment::map(4 * 1024 * 1024, PageSize::Size2m).unwrap();
+//!
+//! seg[0] = 1;
+//!
+//! assert_eq!(4 * 1024 * 1024, seg.len());
+//!
+//! segment::unmap(seg).unwrap();
+//! ```
+
+use std::alloc::Layout;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+
+use nix::errno::Errno;
+
+use crate::mmap::{MMap, PageSize};
+use crate::options::AllocOptions;
+
+/// An owned anonymous memory mapped segment, unmapped when dropped
+pub struct Segment {
+    mmap: MMap,
+}
+
+impl Segment {
+    /// Returns a raw pointer to the segment
+    pub fn as_ptr(&self) -> *const u8 {
+        self.mmap.as_ptr()
+    }
+
+    /// Returns a raw mutable pointer to the segment
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.mmap.as_ptr()
+    }
+
+    /// Returns the page size backing the segment. This is the default page size if the requested page
+    /// size could not be mapped
+    pub fn page_size(&self) -> PageSize {
+        self.mmap.page_size()
+    }
+
+    /// Returns the total mapped size of the segment
+    pub fn mapped_len(&self) -> usize {
+        self.mmap.alloc_size()
+    }
+
+    /// Returns the underlying mapping
+    pub fn mmap(&self) -> &MMap {
+        &self.mmap
+    }
+
+    /// Consumes the segment, returning the underlying mapping
+    pub fn into_mmap(self) -> MMap {
+        self.mmap
+    }
+}
+
+impl Deref for Segment {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.mmap.as_ptr(), self.mmap.size()) }
+    }
+}
+
+impl DerefMut for Segment {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.mmap.as_ptr(), self.mmap.size()) }
+    }
+}
+
+impl fmt::Debug for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Segment")
+            .field("ptr", &self.mmap.as_ptr())
+            .field("len", &self.mmap.size())
+            .field("mapped_len", &self.mmap.alloc_size())
+            .field("page_size", &self.mmap.page_size())
+            .finish()
+    }
+}
+
+/// Maps a zeroed read write segment of at least `len` bytes, trying the given page size first and falling
+/// back to the default page size
+pub fn map(len: usize, page_size: PageSize) -> nix::Result<Segment> {
+    let layout = Layout::from_size_align(len, 1).map_err(|_| Errno::EINVAL)?;
+
+    let mmap = map_fallback(layout, &page_size, &AllocOptions::default())?;
+
+    Ok(Segment { mmap })
+}
+
+/// Unmaps a segment, returning any error from munmap
+pub fn unmap(seg: Segment) -> nix::Result<()> {
+    seg.mmap.unmap()
+}
+
+/// Maps a segment with the given page size, falling back to the default page size on failure
+pub(crate) fn map_fallback(layout: Layout, page_size: &PageSize, options: &AllocOptions) -> nix::Result<MMap> {
+    match MMap::new(layout, page_size, options) {
+        Ok(m) => Ok(m),
+        Err(e) => {
+            // Failed - try default page size
+            if *page_size == PageSize::SizeDefault {
+                Err(e)
+            } else {
+                MMap::new(layout, &PageSize::SizeDefault, options)
+            }
+        }
+    }
+}
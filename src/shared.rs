@@ -0,0 +1,372 @@
+//! Huge page backed memory shared between cooperating processes
+//!
+//! The creating process maps a memfd (backed by huge pages where available) and passes a
+//! [`SharedHandle`] to other processes as a string. The region starts with a header holding a bump
+//! allocation cursor and a registry of named allocations, so readers can find published data. Addresses
+//! differ between processes so allocations are identified by their offset in the region
+//!
+//! ```rust
+//! use std::alloc::Layout;
+//! use huge_allocator::shared::{SharedHandle, SharedHugeAllocator};
+//!
+//! // Writer
+//! let writer = SharedHugeAllocator::create("cache", 4 * 1024 * 1024).unwrap();
+//!
+//! let offset = writer.allocate(Layout::from_size_align(1024, 8).unwrap()).unwrap();
+//! unsafe { writer.ptr(offset).write_bytes(0x55, 1024) };
+//! writer.publish("table", offset, 1024).unwrap();
+//!
+//! let handle = writer.handle().to_string();
+//!
+//! // Reader (normally in another process)
+//! let reader = SharedHugeAllocator::open(&handle.parse::<SharedHandle>().unwrap()).unwrap();
+//!
+//! let (offset, len) = reader.lookup("table").unwrap();
+//! let data = unsafe { std::slice::from_raw_parts(reader.ptr(offset), len) };
+//!
+//! assert!(data.iter().all(|&b| b == 0x55));
+//! ```
+
+use std::alloc::Layout;
+use std::ffi::{c_void, CString};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::ptr::{addr_of, addr_of_mut, null_mut, NonNull};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+
+use crate::mmap::PageSize;
+use crate::sys;
+use crate::AllocError;
+
+/// Region header magic number ("HUGESHM1")
+const MAGIC: u64 = 0x4855_4745_5348_4d31;
+/// Number of registry entries
+const REGISTRY_ENTRIES: usize = 64;
+/// Maximum registry key length in bytes
+pub const MAX_KEY_LEN: usize = 48;
+
+/// Registry entry states
+const ENTRY_EMPTY: u32 = 0;
+const ENTRY_WRITING: u32 = 1;
+const ENTRY_PUBLISHED: u32 = 2;
+
+/// Shared region header
+#[repr(C)]
+struct Header {
+    magic: u64,
+    page_size: u64,
+    capacity: u64,
+    /// Offset of the next free byte
+    next: AtomicU64,
+    entries: [Entry; REGISTRY_ENTRIES],
+}
+
+/// Shared registry entry
+#[repr(C)]
+struct Entry {
+    state: AtomicU32,
+    key_len: u32,
+    key: [u8; MAX_KEY_LEN],
+    offset: u64,
+    len: u64,
+}
+
+/// Offset of the first allocatable byte in the region
+const DATA_START: usize = size_of::<Header>().next_multiple_of(4096);
+
+/// Identifies a shared region so another process can open it. Converts to and from a string of the form
+/// "<pid>:<fd>" for passing between processes. The creating process must keep the region open and the
+/// opening process needs permission to access the creator's file descriptors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedHandle {
+    /// Process ID of the creating process
+    pub pid: u32,
+    /// File descriptor of the memfd in the creating process
+    pub fd: i32,
+}
+
+impl fmt::Display for SharedHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.pid, self.fd)
+    }
+}
+
+impl FromStr for SharedHandle {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid shared handle");
+
+        let (pid, fd) = s.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Self {
+            pid: pid.parse().map_err(|_| invalid())?,
+            fd: fd.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// A bump allocator over a huge page backed memory region shared between processes. Allocations live
+/// until the region is destroyed. The mapping is released when dropped and the memory when the last
+/// process using it drops its mapping
+pub struct SharedHugeAllocator {
+    /// Keeps the memfd open while the region is in use
+    _file: File,
+    ptr: NonNull<u8>,
+    len: usize,
+    page_size: PageSize,
+    handle: SharedHandle,
+}
+
+impl SharedHugeAllocator {
+    /// Creates a new shared region with room for at least `capacity` bytes of allocations. Huge pages are
+    /// tried first, falling back to the default page size
+    pub fn create(name: &str, capacity: usize) -> io::Result<Self> {
+        let len = DATA_START.checked_add(capacity).ok_or(io::ErrorKind::InvalidInput)?;
+
+        let shared = match Self::map_new(name, len, PageSize::Size2m) {
+            Ok(shared) => shared,
+            Err(_) => Self::map_new(name, len, PageSize::SizeDefault)?,
+        };
+
+        // Initialise the header. The memfd is zero filled so the registry starts empty
+        let header = shared.header();
+
+        unsafe {
+            addr_of_mut!((*header).page_size).write(shared.page_size.bytes() as u64);
+            addr_of_mut!((*header).capacity).write(shared.len as u64);
+            (*header).next.store(DATA_START as u64, Ordering::Relaxed);
+            addr_of_mut!((*header).magic).write(MAGIC);
+        }
+
+        Ok(shared)
+    }
+
+    /// Opens a shared region created by another process
+    pub fn open(handle: &SharedHandle) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/proc/{}/fd/{}", handle.pid, handle.fd))?;
+
+        let len = file.metadata()?.len() as usize;
+
+        if len < DATA_START {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "shared region too small"))?;
+        }
+
+        // Map with the default page size first to read the header
+        let mut shared = Self::map_file(file, len, PageSize::SizeDefault, *handle)?;
+
+        let header = shared.header();
+
+        if unsafe { addr_of!((*header).magic).read() } != MAGIC {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "not a shared huge page region"))?;
+        }
+
+        if unsafe { addr_of!((*header).page_size).read() } == PageSize::Size2m.bytes() as u64 {
+            shared.page_size = PageSize::Size2m;
+        }
+
+        Ok(shared)
+    }
+
+    /// Creates a memfd with the given page size and maps it
+    fn map_new(name: &str, len: usize, page_size: PageSize) -> io::Result<Self> {
+        let name = CString::new(name).map_err(|_| io::ErrorKind::InvalidInput)?;
+
+        let flags = match page_size {
+            PageSize::SizeDefault => libc::MFD_CLOEXEC,
+            PageSize::Size2m => libc::MFD_CLOEXEC | libc::MFD_HUGETLB | libc::MFD_HUGE_2MB,
+        };
+
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), flags) };
+
+        if fd < 0 {
+            Err(io::Error::last_os_error())?;
+        }
+
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let len = len.next_multiple_of(page_size.bytes());
+        file.set_len(len as u64)?;
+
+        let handle = SharedHandle {
+            pid: std::process::id(),
+            fd,
+        };
+
+        Self::map_file(file, len, page_size, handle)
+    }
+
+    /// Maps a file shared read write
+    fn map_file(file: File, len: usize, page_size: PageSize, handle: SharedHandle) -> io::Result<Self> {
+        // Huge page reservations are made here so this fails if there aren't enough huge pages
+        let ptr = unsafe {
+            sys::mmap(
+                null_mut::<c_void>(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        }?;
+
+        Ok(Self {
+            _file: file,
+            ptr: NonNull::new(ptr as *mut u8).ok_or(io::ErrorKind::Other)?,
+            len,
+            page_size,
+            handle,
+        })
+    }
+
+    /// Returns the handle other processes use to open the region
+    pub fn handle(&self) -> &SharedHandle {
+        &self.handle
+    }
+
+    /// Returns the page size backing the region
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    /// Returns the total mapped size of the region
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the region has no room for allocations
+    pub fn is_empty(&self) -> bool {
+        self.len <= DATA_START
+    }
+
+    /// Returns the number of bytes allocated from the region by all processes
+    pub fn allocated(&self) -> usize {
+        unsafe { (*self.header()).next.load(Ordering::Relaxed) as usize - DATA_START }
+    }
+
+    /// Allocates from the region, returning the offset of the allocation
+    pub fn allocate(&self, layout: Layout) -> Result<usize, AllocError> {
+        let next = unsafe { &(*self.header()).next };
+
+        let mut current = next.load(Ordering::Relaxed);
+
+        loop {
+            let start = (current as usize).checked_next_multiple_of(layout.align()).ok_or(AllocError)?;
+            let end = start.checked_add(layout.size()).ok_or(AllocError)?;
+
+            if end > self.len {
+                Err(AllocError)?;
+            }
+
+            match next.compare_exchange_weak(current, end as u64, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return Ok(start),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Returns a pointer to the given offset in this process's mapping of the region
+    pub fn ptr(&self, offset: usize) -> *mut u8 {
+        assert!(offset < self.len, "offset {} outside shared region of {} bytes", offset, self.len);
+
+        unsafe { self.ptr.as_ptr().add(offset) }
+    }
+
+    /// Publishes an allocation in the registry under a key so other processes can find it
+    pub fn publish(&self, key: &str, offset: usize, len: usize) -> io::Result<()> {
+        if key.len() > MAX_KEY_LEN {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "registry key too long"))?;
+        }
+
+        if offset.checked_add(len).is_none_or(|end| end > self.len) {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "allocation outside shared region"))?;
+        }
+
+        for i in 0..REGISTRY_ENTRIES {
+            let entry = self.entry(i);
+
+            // Claim an empty entry
+            let claimed = unsafe { &(*entry).state }
+                .compare_exchange(ENTRY_EMPTY, ENTRY_WRITING, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok();
+
+            if claimed {
+                unsafe {
+                    let key_ptr = addr_of_mut!((*entry).key) as *mut u8;
+                    key_ptr.copy_from_nonoverlapping(key.as_ptr(), key.len());
+
+                    addr_of_mut!((*entry).key_len).write(key.len() as u32);
+                    addr_of_mut!((*entry).offset).write(offset as u64);
+                    addr_of_mut!((*entry).len).write(len as u64);
+
+                    (*entry).state.store(ENTRY_PUBLISHED, Ordering::Release);
+                }
+
+                return Ok(());
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::OutOfMemory, "shared registry full"))
+    }
+
+    /// Looks up a published allocation by key, returning its offset and length
+    pub fn lookup(&self, key: &str) -> Option<(usize, usize)> {
+        (0..REGISTRY_ENTRIES).find_map(|i| {
+            let entry = self.entry(i);
+
+            unsafe {
+                if (*entry).state.load(Ordering::Acquire) != ENTRY_PUBLISHED {
+                    return None;
+                }
+
+                let key_len = (addr_of!((*entry).key_len).read() as usize).min(MAX_KEY_LEN);
+                let entry_key = std::slice::from_raw_parts(addr_of!((*entry).key) as *const u8, key_len);
+
+                if entry_key != key.as_bytes() {
+                    return None;
+                }
+
+                Some((addr_of!((*entry).offset).read() as usize, addr_of!((*entry).len).read() as usize))
+            }
+        })
+    }
+
+    /// Returns the region header
+    fn header(&self) -> *mut Header {
+        self.ptr.as_ptr() as *mut Header
+    }
+
+    /// Returns a registry entry
+    fn entry(&self, index: usize) -> *mut Entry {
+        unsafe { addr_of_mut!((*self.header()).entries[index]) }
+    }
+}
+
+unsafe impl Send for SharedHugeAllocator {}
+unsafe impl Sync for SharedHugeAllocator {}
+
+impl fmt::Debug for SharedHugeAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedHugeAllocator")
+            .field("handle", &self.handle)
+            .field("ptr", &self.ptr)
+            .field("len", &self.len)
+            .field("page_size", &self.page_size)
+            .finish()
+    }
+}
+
+impl Drop for SharedHugeAllocator {
+    /// Unmaps the region. The memfd is closed when the file is dropped
+    fn drop(&mut self) {
+        let _ = unsafe { sys::munmap(self.ptr.as_ptr() as *mut c_void, self.len) };
+    }
+}
@@ -0,0 +1,207 @@
+use std::alloc::Layout;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::Deref;
+use std::ptr::{copy_nonoverlapping, NonNull};
+use std::slice;
+
+use crate::mmap::{MMap, Protection};
+use crate::{segment, AllocError, AllocOptions, HugeAllocator};
+
+/// Snapshot file magic number
+const MAGIC: &[u8; 8] = b"HUGESNP1";
+
+/// A segment restored from a snapshot. The memory belongs to the allocator it was restored in to and is
+/// released with [`Allocator::deallocate`](std::alloc::Allocator::deallocate) and `layout` as normal
+#[derive(Debug, Clone, Copy)]
+pub struct RestoredSegment {
+    /// Address of the segment when the snapshot was taken
+    pub original: usize,
+    /// The restored segment
+    pub ptr: NonNull<[u8]>,
+    /// Layout of the restored segment
+    pub layout: Layout,
+}
+
+/// A read-only full copy of an allocation. The copy lives in its own mapping with the same page size as the
+/// original and is independent of the allocator, which can keep mutating or free the original
+pub struct SegmentSnapshot {
+    mmap: MMap,
+    original: usize,
+}
+
+impl SegmentSnapshot {
+    /// Returns the address of the allocation the snapshot was taken from
+    pub fn original(&self) -> usize {
+        self.original
+    }
+
+    /// Returns the underlying mapping
+    pub fn mmap(&self) -> &MMap {
+        &self.mmap
+    }
+}
+
+impl Deref for SegmentSnapshot {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.mmap.as_ptr(), self.mmap.size()) }
+    }
+}
+
+impl fmt::Debug for SegmentSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SegmentSnapshot")
+            .field("original", &(self.original as *const u8))
+            .field("ptr", &self.mmap.as_ptr())
+            .field("len", &self.mmap.size())
+            .field("page_size", &self.mmap.page_size())
+            .finish()
+    }
+}
+
+unsafe impl Send for SegmentSnapshot {}
+unsafe impl Sync for SegmentSnapshot {}
+
+/// Copies a live allocation in to a new read-only mapping
+pub(crate) fn snapshot(allocator: &HugeAllocator, ptr: NonNull<u8>) -> Result<SegmentSnapshot, AllocError> {
+    // Keep the allocation mapped so it can be copied without blocking the allocator
+    let _hold = allocator.mapper.hold();
+
+    let (layout, page_size) = allocator
+        .mapper
+        .with_map(|ptr_map| ptr_map.get(&(ptr.as_ptr() as usize)).map(|mmap| (mmap.layout(), mmap.page_size())))
+        .ok_or(AllocError)?;
+
+    let mut copy = segment::map_fallback(layout, &page_size, &AllocOptions::default()).map_err(|_| AllocError)?;
+
+    unsafe { copy_nonoverlapping(ptr.as_ptr(), copy.as_ptr(), layout.size()) };
+
+    copy.protect(Protection::PROT_READ).map_err(|_| AllocError)?;
+
+    Ok(SegmentSnapshot {
+        mmap: copy,
+        original: ptr.as_ptr() as usize,
+    })
+}
+
+/// Writes a snapshot of one segment
+pub(crate) fn save_segment(allocator: &HugeAllocator, ptr: NonNull<u8>, out: &mut impl Write) -> io::Result<()> {
+    // Keep the allocation mapped so the writer can use the allocator
+    let _hold = allocator.mapper.hold();
+
+    let layout = allocator
+        .mapper
+        .with_map(|ptr_map| ptr_map.get(&(ptr.as_ptr() as usize)).map(MMap::layout))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not a live allocation"))?;
+
+    out.write_all(MAGIC)?;
+    out.write_all(&1u64.to_le_bytes())?;
+
+    write_segment(ptr.as_ptr(), layout, out)
+}
+
+/// Writes a snapshot of every segment, returning the number of segments written
+pub(crate) fn save_all(allocator: &HugeAllocator, out: &mut impl Write) -> io::Result<usize> {
+    // Keep the segments mapped so the writer can use the allocator
+    let _hold = allocator.mapper.hold();
+
+    let segments: Vec<(usize, Layout)> = allocator
+        .mapper
+        .with_map(|ptr_map| ptr_map.iter().map(|(&addr, mmap)| (addr, mmap.layout())).collect());
+
+    out.write_all(MAGIC)?;
+    out.write_all(&(segments.len() as u64).to_le_bytes())?;
+
+    for &(addr, layout) in &segments {
+        write_segment(addr as *const u8, layout, out)?;
+    }
+
+    Ok(segments.len())
+}
+
+/// Restores all segments from a snapshot
+pub(crate) fn restore(
+    allocator: &HugeAllocator,
+    input: &mut impl Read,
+    preserve_addresses: bool,
+) -> io::Result<Vec<RestoredSegment>> {
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "not a segment snapshot"))?;
+    }
+
+    let count = read_u64(input)?;
+
+    let mut restored = Vec::new();
+
+    for _ in 0..count {
+        match restore_segment(allocator, input, preserve_addresses) {
+            Ok(segment) => restored.push(segment),
+            Err(e) => {
+                // Release the segments restored so far
+                for segment in restored {
+                    let _ = allocator.mapper.dealloc(segment.ptr.cast(), segment.layout);
+                }
+
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(restored)
+}
+
+/// Writes a segment descriptor and contents. The segment must be held
+fn write_segment(ptr: *const u8, layout: Layout, out: &mut impl Write) -> io::Result<()> {
+    out.write_all(&(ptr as u64).to_le_bytes())?;
+    out.write_all(&(layout.size() as u64).to_le_bytes())?;
+    out.write_all(&(layout.align() as u64).to_le_bytes())?;
+
+    out.write_all(unsafe { slice::from_raw_parts(ptr, layout.size()) })
+}
+
+/// Reads a segment descriptor and restores its contents in to a new allocation
+fn restore_segment(
+    allocator: &HugeAllocator,
+    input: &mut impl Read,
+    preserve_addresses: bool,
+) -> io::Result<RestoredSegment> {
+    let original = read_u64(input)? as usize;
+    let size = read_u64(input)? as usize;
+    let align = read_u64(input)? as usize;
+
+    let layout = Layout::from_size_align(size, align)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid segment layout"))?;
+
+    let options = allocator.mapper.default_options();
+
+    let ptr = if preserve_addresses {
+        allocator.mapper.alloc_at(original, layout, &options)?
+    } else {
+        allocator
+            .mapper
+            .alloc_with(layout, &options)
+            .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?
+    };
+
+    let data = unsafe { slice::from_raw_parts_mut(ptr.cast::<u8>().as_ptr(), size) };
+
+    if let Err(e) = input.read_exact(data) {
+        let _ = allocator.mapper.dealloc(ptr.cast(), layout);
+        Err(e)?;
+    }
+
+    Ok(RestoredSegment { original, ptr, layout })
+}
+
+/// Reads a little endian u64
+fn read_u64(input: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
@@ -0,0 +1,186 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{HugeAllocator, HugeAllocatorStats};
+
+/// Column names written as the first line of each file
+const HEADER: &str = "timestamp_ms,alloc,mapped,segments,default_alloc,default_mapped,default_segments,huge_alloc,\
+    huge_mapped,huge_segments,missed_allocs,missed_mb,remaps_failed,unmaps_failed,surplus_allocs,surplus_bytes,\
+    cached_segments,cached_bytes,efficiency";
+
+/// Rotation of the files written by a [`StatsCsvWriter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvRotation {
+    /// Size in bytes at which the file is rotated
+    pub max_bytes: u64,
+    /// Number of rotated files to keep, named with the suffixes .1 (newest) to .keep (oldest)
+    pub keep: usize,
+}
+
+impl Default for CsvRotation {
+    /// Rotates at 10mb, keeping 5 old files
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+/// Appends a snapshot of an allocator's statistics to a CSV file at a fixed interval, giving a local record
+/// of allocator behaviour where there is no metrics stack. A final sample is written when dropped
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use huge_allocator::{CsvRotation, HugeAllocator};
+///
+/// let allocator = HugeAllocator::new(50);
+///
+/// let _writer = allocator
+///     .write_stats_csv("/var/log/myservice/allocator.csv", Duration::from_secs(60), CsvRotation::default())
+///     .unwrap();
+/// ```
+pub struct StatsCsvWriter {
+    /// Dropped to shut the sampler thread down
+    stop: Option<Sender<()>>,
+    sampler: Option<JoinHandle<()>>,
+}
+
+impl StatsCsvWriter {
+    /// Opens the file, writing the header if it is new, and starts the sampler thread
+    pub(crate) fn new(allocator: &HugeAllocator, path: &Path, interval: Duration, rotation: CsvRotation) -> io::Result<Self> {
+        let mut file = CsvFile::open(path.to_path_buf(), rotation)?;
+
+        let allocator = allocator.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let sampler = std::thread::Builder::new()
+            .name("huge_allocator-csv".to_string())
+            .spawn(move || loop {
+                let shutdown = !matches!(stopped.recv_timeout(interval), Err(RecvTimeoutError::Timeout));
+
+                if let Err(e) = file.append(&allocator.mapper.stats()) {
+                    allocator.mapper.log(format_args!("unable to write stats to {}: {}", file.path.display(), e));
+                }
+
+                if shutdown {
+                    return;
+                }
+            })?;
+
+        Ok(Self {
+            stop: Some(stop),
+            sampler: Some(sampler),
+        })
+    }
+}
+
+impl Drop for StatsCsvWriter {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+
+        if let Some(sampler) = self.sampler.take() {
+            let _ = sampler.join();
+        }
+    }
+}
+
+/// CSV file being appended to
+struct CsvFile {
+    path: PathBuf,
+    rotation: CsvRotation,
+    file: File,
+    len: u64,
+}
+
+impl CsvFile {
+    /// Opens the file for appending, writing the header if it is empty
+    fn open(path: PathBuf, rotation: CsvRotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+
+        let mut csv = Self {
+            path,
+            rotation,
+            file,
+            len,
+        };
+
+        if len == 0 {
+            csv.write_line(HEADER)?;
+        }
+
+        Ok(csv)
+    }
+
+    /// Appends a row, rotating first if the row would take the file past the maximum size
+    fn append(&mut self, stats: &HugeAllocatorStats) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+
+        let row = format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{:.3},{},{},{},{},{},{},{}",
+            timestamp,
+            stats.alloc,
+            stats.mapped,
+            stats.segments,
+            stats.default_alloc,
+            stats.default_mapped,
+            stats.default_segments,
+            stats.huge_alloc,
+            stats.huge_mapped,
+            stats.huge_segments,
+            stats.missed_allocs,
+            stats.missed_mb,
+            stats.remaps_failed,
+            stats.unmaps_failed,
+            stats.surplus_allocs,
+            stats.surplus_bytes,
+            stats.cached_segments,
+            stats.cached_bytes,
+            stats.efficiency
+        );
+
+        if self.len + row.len() as u64 + 1 > self.rotation.max_bytes {
+            self.rotate()?;
+        }
+
+        self.write_line(&row)
+    }
+
+    /// Shifts the rotated files up one suffix, dropping the oldest, and starts a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.rotation.keep).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        *self = Self::open(self.path.clone(), self.rotation)?;
+
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.file, "{}", line)?;
+        self.len += line.len() as u64 + 1;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+//! Randomised stress testing of an allocator configuration
+//!
+//! ```rust
+//! use huge_allocator::stress::{self, StressConfig};
+//! use huge_allocator::HugeAllocator;
+//!
+//! let allocator = HugeAllocator::new(50);
+//!
+//! let config = StressConfig {
+//!     iterations: 200,
+//!     ..Default::default()
+//! };
+//!
+//! let report = stress::run(&allocator, &config).unwrap();
+//!
+//! assert_eq!(200, report.allocs + report.frees + report.reallocs);
+//! ```
+
+use std::alloc::Layout;
+use std::fmt;
+use std::ptr::NonNull;
+
+use crate::HugeAllocator;
+
+/// Stress test workload configuration
+#[derive(Debug, Clone)]
+pub struct StressConfig {
+    /// Number of operations to perform
+    pub iterations: usize,
+    /// Maximum number of live allocations
+    pub max_live: usize,
+    /// Minimum allocation size in bytes
+    pub min_size: usize,
+    /// Maximum allocation size in bytes
+    pub max_size: usize,
+    /// Random number generator seed
+    pub seed: u64,
+    /// Check allocator integrity after every operation rather than only at the end
+    pub check_every_op: bool,
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        Self {
+            iterations: 1000,
+            max_live: 32,
+            min_size: 1,
+            max_size: 8 * 1024 * 1024,
+            seed: 0x5eed,
+            check_every_op: false,
+        }
+    }
+}
+
+/// Stress test results
+#[derive(Debug, Default, Clone)]
+pub struct StressReport {
+    /// Number of allocations performed
+    pub allocs: usize,
+    /// Number of deallocations performed
+    pub frees: usize,
+    /// Number of reallocations performed
+    pub reallocs: usize,
+    /// Number of allocations which failed with AllocError
+    pub alloc_failures: usize,
+    /// Highest number of bytes allocated at once
+    pub peak_bytes: usize,
+}
+
+/// Stress test failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StressError(pub String);
+
+impl fmt::Display for StressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stress test failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for StressError {}
+
+/// A live allocation and the fill byte written to it
+struct Live {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    fill: u8,
+}
+
+/// Runs a randomised alloc/free/realloc workload against the allocator, validating allocation contents
+/// and allocator invariants. All allocations are freed before returning
+pub fn run(allocator: &HugeAllocator, config: &StressConfig) -> Result<StressReport, StressError> {
+    if config.min_size == 0 || config.min_size > config.max_size || config.max_live == 0 {
+        Err(StressError("invalid configuration".to_string()))?;
+    }
+
+    let mut rng = XorShift::new(config.seed);
+    let mut report = StressReport::default();
+    let mut live: Vec<Live> = Vec::with_capacity(config.max_live);
+
+    let base_segments = allocator.stats().map_err(|_| StressError("stats failed".to_string()))?.segments;
+
+    let result = (|| {
+        for _ in 0..config.iterations {
+            let op = rng.next() % 3;
+
+            if live.is_empty() || (op == 0 && live.len() < config.max_live) {
+                // Allocate
+                let layout = random_layout(&mut rng, config);
+                report.allocs += 1;
+
+                match allocator.mapper.alloc(layout) {
+                    Ok(ptr) => {
+                        let fill = rng.next() as u8;
+                        let ptr = ptr.cast::<u8>();
+
+                        unsafe { ptr.as_ptr().write_bytes(fill, layout.size()) };
+
+                        live.push(Live { ptr, layout, fill });
+                    }
+                    Err(_) => report.alloc_failures += 1,
+                }
+            } else if op == 1 {
+                // Free
+                let entry = live.swap_remove(rng.next() as usize % live.len());
+                report.frees += 1;
+
+                verify(&entry, entry.layout.size())?;
+
+                allocator.mapper.dealloc(entry.ptr, entry.layout).map_err(|e| StressError(e.to_string()))?;
+            } else {
+                // Reallocate
+                let idx = rng.next() as usize % live.len();
+                let new_layout = random_layout(&mut rng, config);
+                report.reallocs += 1;
+
+                let entry = &mut live[idx];
+
+                verify(entry, entry.layout.size())?;
+
+                let res = allocator.mapper.realloc(entry.ptr, entry.layout, new_layout);
+
+                match res {
+                    Ok(ptr) => {
+                        let old_size = entry.layout.size();
+
+                        entry.ptr = ptr.cast();
+                        entry.layout = new_layout;
+
+                        // Retained contents must survive the move
+                        verify(entry, old_size.min(new_layout.size()))?;
+
+                        unsafe { entry.ptr.as_ptr().write_bytes(entry.fill, new_layout.size()) };
+                    }
+                    Err(_) => report.alloc_failures += 1,
+                }
+            }
+
+            report.peak_bytes = report.peak_bytes.max(live.iter().map(|l| l.layout.size()).sum());
+
+            if config.check_every_op {
+                check(allocator, base_segments + live.len())?;
+            }
+        }
+
+        check(allocator, base_segments + live.len())
+    })();
+
+    // Free everything still live
+    for entry in live.drain(..) {
+        let _ = allocator.mapper.dealloc(entry.ptr, entry.layout);
+    }
+
+    result?;
+
+    check(allocator, base_segments)?;
+
+    Ok(report)
+}
+
+/// Checks the first bytes of an allocation hold its fill byte
+fn verify(entry: &Live, len: usize) -> Result<(), StressError> {
+    let bytes = unsafe { std::slice::from_raw_parts(entry.ptr.as_ptr(), len) };
+
+    match bytes.iter().position(|&b| b != entry.fill) {
+        Some(pos) => Err(StressError(format!(
+            "allocation at {:?} corrupt at offset {} (expected {:#x}, found {:#x})",
+            entry.ptr, pos, entry.fill, bytes[pos]
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Checks allocator integrity and the live segment count
+fn check(allocator: &HugeAllocator, expected_segments: usize) -> Result<(), StressError> {
+    allocator.check_integrity().map_err(|e| StressError(e.to_string()))?;
+
+    let stats = allocator.stats().map_err(|_| StressError("stats failed".to_string()))?;
+
+    if stats.segments != expected_segments {
+        Err(StressError(format!(
+            "expected {} segments, allocator has {}",
+            expected_segments, stats.segments
+        )))?;
+    }
+
+    Ok(())
+}
+
+/// Returns a random layout within the configured size range
+fn random_layout(rng: &mut XorShift, config: &StressConfig) -> Layout {
+    let size = config.min_size + (rng.next() as usize % (config.max_size - config.min_size + 1));
+    let align = 1 << (rng.next() % 7);
+
+    Layout::from_size_align(size, align).unwrap()
+}
+
+/// Small xorshift random number generator
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
@@ -0,0 +1,97 @@
+//! A mutex which works with or without std. With std this is [`std::sync::Mutex`], recovering from poisoning
+//! as the data it protects is always left consistent. Without std it is a spin lock
+
+#[cfg(not(feature = "std"))]
+use core::cell::UnsafeCell;
+#[cfg(not(feature = "std"))]
+use core::ops::{Deref, DerefMut};
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Mutual exclusion lock
+#[cfg(feature = "std")]
+pub(crate) struct Mutex<T>(std::sync::Mutex<T>);
+
+/// Held lock, unlocked when dropped
+#[cfg(feature = "std")]
+pub(crate) type MutexGuard<'a, T> = std::sync::MutexGuard<'a, T>;
+
+#[cfg(feature = "std")]
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex
+    pub(crate) const fn new(value: T) -> Self {
+        Self(std::sync::Mutex::new(value))
+    }
+
+    /// Locks the mutex, blocking until it is available
+    pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+        self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Mutual exclusion lock
+#[cfg(not(feature = "std"))]
+pub(crate) struct Mutex<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+#[cfg(not(feature = "std"))]
+unsafe impl<T: Send> Send for Mutex<T> {}
+#[cfg(not(feature = "std"))]
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+#[cfg(not(feature = "std"))]
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex
+    pub(crate) const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Locks the mutex, spinning until it is available
+    pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+
+        MutexGuard { mutex: self }
+    }
+}
+
+/// Held lock, unlocked when dropped
+#[cfg(not(feature = "std"))]
+pub(crate) struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,160 @@
+//! Memory mapping system calls. These are issued through nix by default, or directly through libc with
+//! the `libc-backend` feature. Flags are passed as raw libc values so flags not wrapped by nix can be used
+
+use std::ffi::c_void;
+
+use libc::c_int;
+
+#[cfg(not(feature = "libc-backend"))]
+mod backend {
+    use std::ffi::c_void;
+
+    use libc::c_int;
+    use nix::sys::mman::{MRemapFlags, MapFlags, ProtFlags};
+    use nix::unistd::{sysconf, SysconfVar};
+
+    /// Maps memory with mmap
+    pub(crate) unsafe fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: libc::off_t,
+    ) -> nix::Result<*mut c_void> {
+        nix::sys::mman::mmap(
+            addr,
+            len,
+            ProtFlags::from_bits_unchecked(prot),
+            MapFlags::from_bits_unchecked(flags),
+            fd,
+            offset,
+        )
+    }
+
+    /// Unmaps memory with munmap
+    pub(crate) unsafe fn munmap(addr: *mut c_void, len: usize) -> nix::Result<()> {
+        nix::sys::mman::munmap(addr, len)
+    }
+
+    /// Resizes a mapping with mremap
+    pub(crate) unsafe fn mremap(
+        addr: *mut c_void,
+        old_len: usize,
+        new_len: usize,
+        flags: c_int,
+    ) -> nix::Result<*mut c_void> {
+        nix::sys::mman::mremap(addr, old_len, new_len, MRemapFlags::from_bits_unchecked(flags), None)
+    }
+
+    /// Moves a mapping on top of another address with mremap, replacing anything mapped there
+    pub(crate) unsafe fn mremap_fixed(addr: *mut c_void, len: usize, new_addr: *mut c_void) -> nix::Result<()> {
+        nix::sys::mman::mremap(
+            addr,
+            len,
+            len,
+            MRemapFlags::MREMAP_MAYMOVE | MRemapFlags::MREMAP_FIXED,
+            Some(new_addr),
+        )
+        .map(drop)
+    }
+
+    /// Changes the access protection of a mapping with mprotect
+    pub(crate) unsafe fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> nix::Result<()> {
+        nix::sys::mman::mprotect(addr, len, ProtFlags::from_bits_unchecked(prot))
+    }
+
+    /// Locks a mapping in memory with mlock
+    pub(crate) unsafe fn mlock(addr: *const c_void, len: usize) -> nix::Result<()> {
+        nix::sys::mman::mlock(addr, len)
+    }
+
+    /// Returns the default page size
+    pub(crate) fn page_size() -> nix::Result<usize> {
+        match sysconf(SysconfVar::PAGE_SIZE)? {
+            Some(size) => Ok(size as usize),
+            None => Err(nix::errno::Errno::EINVAL),
+        }
+    }
+}
+
+#[cfg(feature = "libc-backend")]
+mod backend {
+    use std::ffi::c_void;
+
+    use libc::c_int;
+    use nix::errno::Errno;
+
+    /// Maps memory with mmap
+    pub(crate) unsafe fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: libc::off_t,
+    ) -> nix::Result<*mut c_void> {
+        let ptr = libc::mmap(addr, len, prot, flags, fd, offset);
+
+        if ptr == libc::MAP_FAILED {
+            Err(Errno::last())
+        } else {
+            Ok(ptr)
+        }
+    }
+
+    /// Unmaps memory with munmap
+    pub(crate) unsafe fn munmap(addr: *mut c_void, len: usize) -> nix::Result<()> {
+        Errno::result(libc::munmap(addr, len)).map(drop)
+    }
+
+    /// Resizes a mapping with mremap
+    pub(crate) unsafe fn mremap(
+        addr: *mut c_void,
+        old_len: usize,
+        new_len: usize,
+        flags: c_int,
+    ) -> nix::Result<*mut c_void> {
+        let ptr = libc::mremap(addr, old_len, new_len, flags);
+
+        if ptr == libc::MAP_FAILED {
+            Err(Errno::last())
+        } else {
+            Ok(ptr)
+        }
+    }
+
+    /// Moves a mapping on top of another address with mremap, replacing anything mapped there
+    pub(crate) unsafe fn mremap_fixed(addr: *mut c_void, len: usize, new_addr: *mut c_void) -> nix::Result<()> {
+        let ptr = libc::mremap(addr, len, len, libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED, new_addr);
+
+        if ptr == libc::MAP_FAILED {
+            Err(Errno::last())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Changes the access protection of a mapping with mprotect
+    pub(crate) unsafe fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> nix::Result<()> {
+        Errno::result(libc::mprotect(addr, len, prot)).map(drop)
+    }
+
+    /// Locks a mapping in memory with mlock
+    pub(crate) unsafe fn mlock(addr: *const c_void, len: usize) -> nix::Result<()> {
+        Errno::result(libc::mlock(addr, len)).map(drop)
+    }
+
+    /// Returns the default page size
+    pub(crate) fn page_size() -> nix::Result<usize> {
+        Errno::result(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).map(|size| size as usize)
+    }
+}
+
+pub(crate) use backend::*;
+
+/// Gives the kernel advice about a mapping with madvise. Always issued through libc as nix doesn't wrap
+/// the newer advice values
+pub(crate) unsafe fn madvise(addr: *mut c_void, len: usize, advice: c_int) -> nix::Result<()> {
+    nix::errno::Errno::result(libc::madvise(addr, len, advice)).map(drop)
+}
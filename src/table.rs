@@ -0,0 +1,184 @@
+//! Table of mapped segments keyed by address, used in place of a `HashMap` by [`CoreHugeAlloc`]. It needs
+//! nothing from std and maps its own slots, so it can be used inside a global allocator
+//!
+//! [`CoreHugeAlloc`]: crate::CoreHugeAlloc
+
+use core::mem::size_of;
+use core::ptr::null_mut;
+
+use crate::raw::{page_size, RawHugeAlloc};
+
+/// A mapped segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Segment {
+    /// Mapped length in bytes
+    pub(crate) len: usize,
+    /// Mapped with huge pages
+    pub(crate) huge: bool,
+}
+
+/// A table slot. Empty slots have an address of zero
+#[derive(Clone, Copy)]
+struct Slot {
+    addr: usize,
+    segment: Segment,
+}
+
+/// Open addressing hash table with linear probing, kept at most half full
+pub(crate) struct SegmentTable {
+    slots: *mut Slot,
+    /// Number of slots, a power of two or zero
+    capacity: usize,
+    len: usize,
+}
+
+unsafe impl Send for SegmentTable {}
+
+impl SegmentTable {
+    /// Creates an empty table. Nothing is mapped until the first insert
+    pub(crate) const fn new() -> Self {
+        Self {
+            slots: null_mut(),
+            capacity: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the segment at the given address
+    pub(crate) fn get(&self, addr: usize) -> Option<Segment> {
+        self.find(addr).map(|index| self.slot(index).segment)
+    }
+
+    /// Adds a segment, returning false if the table couldn't grow. Inserting straight after a remove never
+    /// grows the table
+    pub(crate) fn insert(&mut self, addr: usize, segment: Segment) -> bool {
+        debug_assert!(addr != 0, "segment at address zero");
+
+        if (self.len + 1) * 2 > self.capacity && !self.grow() {
+            return false;
+        }
+
+        let mut index = self.home(addr);
+
+        while self.slot(index).addr != 0 {
+            index = (index + 1) & (self.capacity - 1);
+        }
+
+        *self.slot_mut(index) = Slot { addr, segment };
+        self.len += 1;
+
+        true
+    }
+
+    /// Removes and returns the segment at the given address
+    pub(crate) fn remove(&mut self, addr: usize) -> Option<Segment> {
+        let mut hole = self.find(addr)?;
+        let segment = self.slot(hole).segment;
+
+        let mask = self.capacity - 1;
+        let mut next = hole;
+
+        // Shift later entries in the probe sequence back over the hole
+        loop {
+            next = (next + 1) & mask;
+
+            let slot = *self.slot(next);
+
+            if slot.addr == 0 {
+                break;
+            }
+
+            let home = self.home(slot.addr);
+
+            // Entries whose home lies cyclically in (hole, next] are already as close as they can be
+            let in_place = if hole <= next {
+                hole < home && home <= next
+            } else {
+                hole < home || home <= next
+            };
+
+            if !in_place {
+                *self.slot_mut(hole) = slot;
+                hole = next;
+            }
+        }
+
+        self.slot_mut(hole).addr = 0;
+        self.len -= 1;
+
+        Some(segment)
+    }
+
+    /// Returns the slot index holding the given address
+    fn find(&self, addr: usize) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mut index = self.home(addr);
+
+        loop {
+            match self.slot(index).addr {
+                0 => return None,
+                found if found == addr => return Some(index),
+                _ => index = (index + 1) & (self.capacity - 1),
+            }
+        }
+    }
+
+    /// Returns the first slot to probe for an address
+    fn home(&self, addr: usize) -> usize {
+        // Fibonacci hashing of the page number
+        let hash = (addr >> 12).wrapping_mul(0x9e37_79b9_7f4a_7c15_u64 as usize);
+
+        hash >> (usize::BITS - self.capacity.trailing_zeros())
+    }
+
+    /// Doubles the number of slots, rehashing every entry. Returns false if the new slots can't be mapped
+    fn grow(&mut self) -> bool {
+        let capacity = (self.capacity * 2).max(page_size() / size_of::<Slot>()).next_power_of_two();
+
+        // Anonymous mappings are zeroed, so every slot starts empty
+        let slots = unsafe { RawHugeAlloc::map(capacity * size_of::<Slot>(), 0) } as *mut Slot;
+
+        if slots.is_null() {
+            return false;
+        }
+
+        let old = Self {
+            slots: self.slots,
+            capacity: self.capacity,
+            len: self.len,
+        };
+
+        self.slots = slots;
+        self.capacity = capacity;
+        self.len = 0;
+
+        for index in 0..old.capacity {
+            let slot = *old.slot(index);
+
+            if slot.addr != 0 {
+                self.insert(slot.addr, slot.segment);
+            }
+        }
+
+        true
+    }
+
+    fn slot(&self, index: usize) -> &Slot {
+        unsafe { &*self.slots.add(index) }
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut Slot {
+        unsafe { &mut *self.slots.add(index) }
+    }
+}
+
+impl Drop for SegmentTable {
+    fn drop(&mut self) {
+        if !self.slots.is_null() {
+            unsafe { libc::munmap(self.slots as *mut libc::c_void, self.capacity * size_of::<Slot>()) };
+        }
+    }
+}
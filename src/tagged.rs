@@ -0,0 +1,42 @@
+use std::fmt;
+use std::ptr::NonNull;
+
+/// An allocation tagged with its generation. Each allocation is given a unique generation, so a stale
+/// handle to memory which has since been freed and reallocated at the same address can be detected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaggedPtr {
+    /// Pointer to the allocated memory
+    pub ptr: NonNull<[u8]>,
+    /// Generation of the allocation
+    pub generation: u64,
+}
+
+/// Error returned when freeing through a stale [`TaggedPtr`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaleHandle {
+    /// Address of the allocation
+    pub ptr: usize,
+    /// Generation held by the handle
+    pub generation: u64,
+    /// Generation of the live allocation at the address, if any
+    pub current: Option<u64>,
+}
+
+impl fmt::Display for StaleHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.current {
+            Some(current) => write!(
+                f,
+                "stale handle for {:#x} (generation {}, current generation {})",
+                self.ptr, self.generation, current
+            ),
+            None => write!(
+                f,
+                "stale handle for {:#x} (generation {}, no live allocation)",
+                self.ptr, self.generation
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StaleHandle {}
@@ -0,0 +1,163 @@
+//! Helpers for tests of code using a [`HugeAllocator`]
+//!
+//! ```rust
+//! #![feature(allocator_api)]
+//! use huge_allocator::testing::{self, LeakGuard};
+//! use huge_allocator::HugeAllocator;
+//!
+//! let allocator = HugeAllocator::new(50);
+//!
+//! {
+//!     // Fails the test when it goes out of scope if anything is still allocated
+//!     let _guard = LeakGuard::new(&allocator);
+//!
+//!     let vec: Vec<u8, _> = Vec::with_capacity_in(4 * 1024 * 1024, &allocator);
+//!     drop(vec);
+//! }
+//!
+//! testing::assert_no_leaks(&allocator);
+//! ```
+//!
+//! Tests which depend on the size of the huge page pool can set it for their duration with a
+//! [`HugePageTestGuard`] when running with sufficient privileges. The pool is shared with every other test
+//! in the process (and the rest of the host), so such tests should be `#[ignore]`d and run on their own with
+//! `--test-threads=1`:
+//!
+//! ```rust,no_run
+//! use huge_allocator::testing::HugePageTestGuard;
+//!
+//! match HugePageTestGuard::reserve(4) {
+//!     Ok(guard) => assert_eq!(4, guard.pages()),
+//!     Err(e) => eprintln!("testing with the current huge page pool: {}", e),
+//! }
+//! ```
+
+use std::cell::Cell;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::{fs, thread, time};
+
+use crate::probe::{read_hugepages, HUGEPAGES_2M};
+use crate::HugeAllocator;
+
+/// Serialises guards so only one test resizes the pool at a time
+static POOL_LOCK: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    /// Pool size set by a guard held on this thread
+    static RESERVED: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Panics with a list of the live allocations if the allocator has any
+#[track_caller]
+pub fn assert_no_leaks(allocator: &HugeAllocator) {
+    if let Some(report) = leak_report(allocator) {
+        panic!("{}", report);
+    }
+}
+
+/// Describes the live allocations, or returns None if there are none
+fn leak_report(allocator: &HugeAllocator) -> Option<String> {
+    allocator.mapper.leak_report()
+}
+
+/// Guard which checks an allocator has no live allocations when dropped, failing the test with a list of
+/// the leaked allocations if it does. The check is skipped if the thread is already panicking
+#[derive(Debug)]
+#[must_use = "the check is made when the guard is dropped"]
+pub struct LeakGuard<'a> {
+    allocator: &'a HugeAllocator,
+}
+
+impl<'a> LeakGuard<'a> {
+    /// Creates a guard checking the given allocator
+    pub fn new(allocator: &'a HugeAllocator) -> Self {
+        Self { allocator }
+    }
+}
+
+impl Drop for LeakGuard<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+
+        if let Some(report) = leak_report(self.allocator) {
+            panic!("{}", report);
+        }
+    }
+}
+
+/// Guard which sets the size of the persistent 2mb huge page pool for the duration of a test, restoring the
+/// previous size when dropped. Only one guard can exist at a time in a process; further calls to
+/// [`reserve`](Self::reserve) wait for the current guard to be dropped. Other tests using huge pages are not
+/// serialised, so tests holding a guard must not run in parallel with them
+#[derive(Debug)]
+#[must_use = "the previous pool size is restored when the guard is dropped"]
+pub struct HugePageTestGuard {
+    pages: usize,
+    previous: usize,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl HugePageTestGuard {
+    /// Sets the huge page pool to `pages` 2mb huge pages, waiting up to a few seconds for them all to become
+    /// free. Fails if the pool can't be written (usually because the process isn't privileged) or the kernel
+    /// can't allocate enough pages, leaving the pool as it was
+    pub fn reserve(pages: usize) -> io::Result<Self> {
+        let lock = POOL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let path = format!("{}/nr_hugepages", HUGEPAGES_2M);
+
+        let previous = fs::read_to_string(&path)?
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let guard = Self {
+            pages,
+            previous,
+            _lock: lock,
+        };
+
+        fs::write(&path, pages.to_string())?;
+
+        for _ in 0..30 {
+            if read_hugepages("nr_hugepages") != pages {
+                break;
+            }
+
+            if read_hugepages("free_hugepages") >= pages {
+                RESERVED.with(|reserved| reserved.set(Some(pages)));
+                return Ok(guard);
+            }
+
+            thread::sleep(time::Duration::from_millis(100));
+        }
+
+        Err(io::Error::other(format!(
+            "unable to reserve {} huge pages ({} in pool, {} free)",
+            pages,
+            read_hugepages("nr_hugepages"),
+            read_hugepages("free_hugepages")
+        )))
+    }
+
+    /// Returns the number of 2mb huge pages in the pool
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+}
+
+impl Drop for HugePageTestGuard {
+    fn drop(&mut self) {
+        RESERVED.with(|reserved| reserved.set(None));
+
+        let _ = fs::write(format!("{}/nr_hugepages", HUGEPAGES_2M), self.previous.to_string());
+    }
+}
+
+/// Returns the size of the huge page pool set by a [`HugePageTestGuard`] held on the calling thread
+pub fn reserved_huge_pages() -> Option<usize> {
+    RESERVED.with(|reserved| reserved.get())
+}
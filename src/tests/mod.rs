@@ -11,12 +11,7 @@ fn check_stats(allocator: &HugeAllocator, desc: &str, expected_segs: usize, expe
 
     assert_eq!(expected_segs, stats.segments, "{} segments", desc);
 
-    let avail_bytes = if let Ok(env) = std::env::var("TEST_NR_PAGES") {
-        let avail_pages = env.parse::<usize>().expect("TEST_NR_PAGES not numeric");
-        avail_pages * mb(2)
-    } else {
-        0
-    };
+    let avail_bytes = crate::testing::reserved_huge_pages().unwrap_or(0) * mb(2);
 
     if avail_bytes >= mb(6) {
         // Enough huge pages to satisfy
@@ -69,6 +64,31 @@ fn check_stats_lt(allocator: &HugeAllocator, desc: &str, expected_alloc: usize,
 
 #[test]
 fn huge_alloc() {
+    huge_alloc_vec();
+}
+
+/// Resizes the host huge page pool, which other tests are using, so must be run on its own with
+/// `cargo test huge_alloc_pool_sizes -- --ignored --test-threads=1`
+#[test]
+#[ignore = "resizes the host huge page pool, run alone with --test-threads=1"]
+fn huge_alloc_pool_sizes() {
+    // Run with a varying number of huge pages in the pool if it can be resized
+    for pages in [0, 1, 2, 3, 4] {
+        match crate::testing::HugePageTestGuard::reserve(pages) {
+            Ok(_guard) => {
+                println!("================ Testing with {} huge pages ================", pages);
+                huge_alloc_vec();
+            }
+            Err(e) => {
+                println!("Testing with the current huge page pool: {}", e);
+                huge_alloc_vec();
+                break;
+            }
+        }
+    }
+}
+
+fn huge_alloc_vec() {
     let allocator = HugeAllocator::new(50);
     let mut vec = Vec::new_in(&allocator);
 
@@ -136,3 +156,3325 @@ fn huge_alloc() {
         }
     }
 }
+
+#[test]
+fn integrity() {
+    let allocator = HugeAllocator::new(50);
+
+    allocator.check_integrity().unwrap();
+
+    let mut vecs: Vec<Vec<u64, &HugeAllocator>> = Vec::new();
+
+    for i in 0..16 {
+        let mut vec = Vec::with_capacity_in(i * 64 * 1024, &allocator);
+        vec.push(i as u64);
+        vecs.push(vec);
+
+        allocator.check_integrity().unwrap();
+    }
+
+    for vec in vecs.iter_mut() {
+        vec.reserve(mb(1));
+
+        allocator.check_integrity().unwrap();
+    }
+
+    drop(vecs);
+
+    allocator.check_integrity().unwrap();
+    check_stats_eq(&allocator, "integrity freed", 0, 0, 0);
+}
+
+#[test]
+fn builder_threshold() {
+    let allocator = HugeAllocator::builder().threshold_pct(100).build();
+
+    // 1.5mb is below the threshold so should never be attempted in huge pages
+    let vec: Vec<u8, _> = Vec::with_capacity_in(mb(3) / 2, &allocator);
+
+    let stats = allocator.stats().unwrap();
+
+    assert_eq!(1, stats.default_segments, "default segments");
+    assert_eq!(0, stats.unmaps_failed, "unmaps failed");
+
+    drop(vec);
+
+    check_stats_eq(&allocator, "builder freed", 0, 0, 0);
+}
+
+#[test]
+fn unmap_failure() {
+    let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = messages.clone();
+
+    let allocator = HugeAllocator::builder().log_sink(move |msg| sink.lock().unwrap().push(msg.to_string())).build();
+
+    // munmap fails with EINVAL above the user address space, so adopt ranges which were never mapped there
+    let len = 4096;
+    let bogus = |addr: usize| NonNull::new(addr as *mut u8).unwrap();
+
+    let freed = bogus(usize::MAX - mb(4) + 1);
+    let leaked = bogus(usize::MAX - mb(2) + 1);
+
+    unsafe {
+        allocator.adopt(freed, len, PageSize::SizeDefault).unwrap();
+        allocator.adopt(leaked, len, PageSize::SizeDefault).unwrap();
+    }
+
+    // Freeing counts and logs the failure rather than panicking
+    unsafe { allocator.deallocate(freed, Layout::from_size_align(len, 4096).unwrap()) };
+
+    assert_eq!(1, allocator.stats().unwrap().unmaps_failed, "unmaps failed");
+    assert_eq!(1, messages.lock().unwrap().len(), "failure logged");
+    assert!(messages.lock().unwrap()[0].contains("failed to unmap"), "{:?}", messages.lock().unwrap());
+
+    // Dropping the allocator with the other still allocated doesn't panic either
+    drop(allocator);
+
+    assert_eq!(2, messages.lock().unwrap().len(), "drop failure logged");
+}
+
+#[test]
+fn mmap_drop_failure() {
+    use crate::mmap::MMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static HOOKED: AtomicUsize = AtomicUsize::new(0);
+
+    // munmap fails with EINVAL above the user address space
+    let bogus = || unsafe { MMap::from_raw(usize::MAX - mb(6) + 1, 4096, &PageSize::SizeDefault) }.unwrap();
+
+    let before = MMap::drop_failures();
+
+    MMap::set_drop_failure_hook(Some(|_, len, e| {
+        assert_eq!((4096, nix::errno::Errno::EINVAL), (len, e));
+        HOOKED.fetch_add(1, Ordering::Relaxed);
+    }));
+
+    drop(bogus());
+
+    assert!(MMap::drop_failures() > before, "failure counted");
+    assert_eq!(1, HOOKED.load(Ordering::Relaxed), "hook called");
+
+    MMap::set_drop_failure_hook(None);
+
+    // Strict mode panics
+    let mmap = bogus();
+
+    MMap::set_strict_drop(true);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || drop(mmap)));
+    MMap::set_strict_drop(false);
+
+    assert!(result.is_err(), "strict drop panics");
+}
+
+#[test]
+fn layout_fits() {
+    let allocator = HugeAllocator::new(50);
+
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    let ptr = allocator.allocate(layout).unwrap();
+
+    // Any size between the requested size and the returned size fits
+    let fitting = Layout::from_size_align(ptr.len(), 8).unwrap();
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), fitting) };
+
+    check_stats_eq(&allocator, "layout fits", 0, 0, 0);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "layout mismatch")]
+fn layout_mismatch() {
+    let allocator = HugeAllocator::new(50);
+
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    let ptr = allocator.allocate(layout).unwrap();
+
+    let wrong = Layout::from_size_align(500, 8).unwrap();
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), wrong) };
+}
+
+#[test]
+fn zero_on_free() {
+    let allocator = HugeAllocator::builder().zero_on_free(true).build();
+
+    let layout = Layout::from_size_align(8192, 8).unwrap();
+    let ptr = allocator.allocate(layout).unwrap();
+
+    unsafe { ptr.as_mut_ptr().write_bytes(0xff, layout.size()) };
+
+    // Shrink - the tail of the retained mapping must be wiped
+    let new_layout = Layout::from_size_align(100, 8).unwrap();
+    let new_ptr = unsafe { allocator.shrink(ptr.as_non_null_ptr(), layout, new_layout) }.unwrap();
+
+    let bytes = unsafe { new_ptr.as_ref() };
+
+    assert!(bytes[..100].iter().all(|&b| b == 0xff), "retained bytes preserved");
+    assert!(bytes[100..].iter().all(|&b| b == 0), "released bytes wiped");
+
+    unsafe { allocator.deallocate(new_ptr.as_non_null_ptr(), new_layout) };
+
+    check_stats_eq(&allocator, "zero on free", 0, 0, 0);
+}
+
+#[test]
+fn realign() {
+    let allocator = HugeAllocator::new(50);
+
+    // Allocations aligned beyond the page size
+    let aligned = Layout::from_size_align(4096, 1024 * 1024).unwrap();
+    let ptr = allocator.allocate(aligned).unwrap();
+
+    assert!((ptr.as_mut_ptr() as usize).is_multiple_of(aligned.align()), "allocation aligned");
+    allocator.check_integrity().unwrap();
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), aligned) };
+
+    let layout = Layout::from_size_align(8192, 8).unwrap();
+    let ptr = allocator.allocate(layout).unwrap();
+
+    unsafe { ptr.as_mut_ptr().write_bytes(0x5a, layout.size()) };
+
+    // Shrinking to a stricter alignment than the segment has moves it
+    let stricter = Layout::from_size_align(4096, 64 * 1024).unwrap();
+    let ptr = unsafe { allocator.shrink(ptr.as_non_null_ptr(), layout, stricter) }.unwrap();
+
+    assert!((ptr.as_mut_ptr() as usize).is_multiple_of(stricter.align()), "shrunk allocation aligned");
+    assert!(unsafe { &ptr.as_ref()[..stricter.size()] }.iter().all(|&b| b == 0x5a), "contents preserved");
+
+    // Shrinking to a looser alignment stays in place
+    let looser = Layout::from_size_align(100, 16).unwrap();
+    let new_ptr = unsafe { allocator.shrink(ptr.as_non_null_ptr(), stricter, looser) }.unwrap();
+
+    assert_eq!(ptr.as_mut_ptr(), new_ptr.as_mut_ptr(), "shrunk in place");
+    assert!(unsafe { &new_ptr.as_ref()[..looser.size()] }.iter().all(|&b| b == 0x5a), "contents preserved");
+    allocator.check_integrity().unwrap();
+
+    unsafe { allocator.deallocate(new_ptr.as_non_null_ptr(), looser) };
+
+    check_stats_eq(&allocator, "realigned", 0, 0, 0);
+}
+
+#[test]
+fn sampled_backtraces() {
+    assert!(HugeAllocator::new(50).profile().is_none(), "sampling disabled");
+
+    let allocator = HugeAllocator::builder().sample_backtraces(2).build();
+    let small = Layout::from_size_align(4096, 8).unwrap();
+    let large = Layout::from_size_align(8192, 8).unwrap();
+
+    let smalls: Vec<_> = (0..10).map(|_| allocator.allocate(small).unwrap()).collect();
+    let larges: Vec<_> = (0..10).map(|_| allocator.allocate(large).unwrap()).collect();
+
+    let report = allocator.profile().unwrap();
+
+    assert_eq!(2, report.callsites.len(), "call stacks");
+    assert_eq!(5, report.callsites[0].live_allocs, "sampled allocations");
+    assert_eq!(5 * 8192, report.callsites[0].live_bytes, "largest first");
+    assert_eq!(2 * 5 * 8192, report.callsites[0].estimated_bytes, "estimated bytes");
+    assert_eq!(5 * 4096, report.callsites[1].live_bytes, "smaller call stack");
+    assert!(!report.callsites[0].backtrace.is_empty(), "backtrace captured");
+
+    for ptr in smalls {
+        unsafe { allocator.deallocate(ptr.as_non_null_ptr(), small) };
+    }
+
+    // Resized allocations stay attributed to the call stack which made them
+    let grown = Layout::from_size_align(16384, 8).unwrap();
+    let ptr = unsafe { allocator.grow(larges[0].as_non_null_ptr(), large, grown) }.unwrap();
+
+    let report = allocator.profile().unwrap();
+
+    assert_eq!(1, report.callsites.len(), "freed call stack dropped");
+    assert_eq!(4 * 8192 + 16384, report.callsites[0].live_bytes, "resize followed");
+    assert_eq!(5, report.callsites[0].samples, "samples");
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), grown) };
+
+    for ptr in &larges[1..] {
+        unsafe { allocator.deallocate(ptr.as_non_null_ptr(), large) };
+    }
+
+    assert!(allocator.profile().unwrap().callsites.is_empty(), "nothing live");
+}
+
+/// Returns the VmFlags from /proc/self/smaps for the mapping containing ptr
+fn vm_flags(ptr: *const u8) -> Vec<String> {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+    let addr = ptr as usize;
+    let mut in_mapping = false;
+
+    for line in smaps.lines() {
+        if let Some((range, _)) = line.split_once(' ') {
+            if let Some((start, end)) = range.split_once('-') {
+                if let (Ok(start), Ok(end)) = (usize::from_str_radix(start, 16), usize::from_str_radix(end, 16)) {
+                    in_mapping = addr >= start && addr < end;
+                    continue;
+                }
+            }
+        }
+
+        if in_mapping {
+            if let Some(flags) = line.strip_prefix("VmFlags:") {
+                return flags.split_whitespace().map(String::from).collect();
+            }
+        }
+    }
+
+    panic!("mapping for {:?} not found", ptr);
+}
+
+#[test]
+fn wipe_on_fork() {
+    let options = AllocOptions {
+        wipe_on_fork: true,
+        ..Default::default()
+    };
+
+    // Per allocation
+    let allocator = HugeAllocator::new(50);
+
+    let layout = Layout::from_size_align(mb(1), 8).unwrap();
+    let ptr = allocator.allocate_with(layout, &options).unwrap();
+
+    assert!(vm_flags(ptr.as_mut_ptr()).iter().any(|f| f == "wf"), "wipe on fork set");
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    // Allocator default
+    let allocator = HugeAllocator::builder().default_options(options).build();
+
+    let vec: Vec<u8, _> = Vec::with_capacity_in(mb(1), &allocator);
+
+    assert!(vm_flags(vec.as_ptr()).iter().any(|f| f == "wf"), "wipe on fork set by default");
+}
+
+#[test]
+fn dont_dump() {
+    let options = AllocOptions {
+        dont_dump: true,
+        ..Default::default()
+    };
+
+    let allocator = HugeAllocator::builder().default_options(options).build();
+
+    let mut vec: Vec<u8, _> = Vec::with_capacity_in(mb(1), &allocator);
+
+    assert!(vm_flags(vec.as_ptr()).iter().any(|f| f == "dd"), "dont dump set");
+
+    // Option is retained through reallocation
+    vec.reserve(mb(4));
+
+    assert!(vm_flags(vec.as_ptr()).iter().any(|f| f == "dd"), "dont dump retained");
+}
+
+#[test]
+fn dont_fork() {
+    let allocator = HugeAllocator::new(50);
+
+    let options = AllocOptions {
+        dont_fork: true,
+        ..Default::default()
+    };
+
+    let layout = Layout::from_size_align(mb(1), 8).unwrap();
+    let ptr = allocator.allocate_with(layout, &options).unwrap();
+
+    let flags = vm_flags(ptr.as_mut_ptr());
+
+    assert!(flags.iter().any(|f| f == "dc"), "dont fork set");
+    assert!(!flags.iter().any(|f| f == "wf"), "wipe on fork not set");
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+}
+
+#[test]
+fn map_32bit() {
+    let allocator = HugeAllocator::new(50);
+
+    let options = AllocOptions {
+        map_32bit: true,
+        ..Default::default()
+    };
+
+    let low = |ptr: *const u8, len: usize| ptr as usize + len <= 1 << 31;
+
+    // Huge page requests fall back to the default page size if the pool is empty
+    for size in [mb(1), mb(4)] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = allocator.allocate_with(layout, &options).unwrap();
+
+        assert!(low(ptr.as_mut_ptr(), ptr.len()), "{} byte segment in the first 2gb", size);
+
+        let mut info = Vec::new();
+        allocator.for_each_allocation(|alloc| info.push(*alloc));
+        assert!(info[0].options.map_32bit, "reported in allocation info");
+
+        // Growing copies the segment rather than letting mremap move it anywhere
+        let new_layout = Layout::from_size_align(size + mb(64), 8).unwrap();
+        let grown = unsafe { allocator.grow(ptr.as_non_null_ptr(), layout, new_layout) }.unwrap();
+
+        assert!(low(grown.as_mut_ptr(), grown.len()), "grown segment in the first 2gb");
+
+        unsafe { allocator.deallocate(grown.as_non_null_ptr(), new_layout) };
+    }
+
+    allocator.check_integrity().unwrap();
+}
+
+#[test]
+fn secure_preset() {
+    let allocator = SecureHugeAllocator::new(50);
+
+    let mut vec: Vec<u8, _> = Vec::with_capacity_in(mb(1), &allocator);
+    vec.extend_from_slice(&[0x55; 64]);
+
+    let flags = vm_flags(vec.as_ptr());
+
+    for flag in ["wf", "dd", "lo"] {
+        assert!(flags.iter().any(|f| f == flag), "{} set", flag);
+    }
+
+    drop(vec);
+
+    assert_eq!(0, allocator.stats().unwrap().segments, "segments freed");
+}
+
+/// Returns the /proc/self/maps line for the mapping containing ptr
+fn maps_line(ptr: *const u8) -> String {
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    let addr = ptr as usize;
+
+    for line in maps.lines() {
+        let range = line.split(' ').next().unwrap();
+        let (start, end) = range.split_once('-').unwrap();
+
+        if addr >= usize::from_str_radix(start, 16).unwrap() && addr < usize::from_str_radix(end, 16).unwrap() {
+            return line.to_string();
+        }
+    }
+
+    panic!("mapping for {:?} not found", ptr);
+}
+
+#[test]
+fn vma_names() {
+    let allocator = HugeAllocator::builder().vma_label("vma_test").build();
+
+    let vec: Vec<u8, _> = Vec::with_capacity_in(mb(1), &allocator);
+
+    let line = maps_line(vec.as_ptr());
+    let stats = allocator.stats().unwrap();
+
+    if line.contains("[anon:") {
+        let page_size = if stats.huge_segments > 0 { mmap::PageSize::Size2m } else { mmap::PageSize::SizeDefault };
+        let expected = format!("[anon:vma_test:{}]", page_size.short_name());
+
+        assert!(line.ends_with(&expected), "{} named {}", line, expected);
+    } else {
+        println!("Kernel does not support anonymous VMA names");
+    }
+}
+
+#[test]
+fn named_instance() {
+    let allocator = HugeAllocator::builder().name("cache").build();
+
+    assert_eq!(Some("cache"), allocator.name());
+    assert_eq!(Some("cache"), allocator.stats().unwrap().name.as_deref());
+    assert_eq!(None, HugeAllocator::new(50).name());
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "HugeAllocator[cache]::deallocate: layout mismatch")]
+fn named_panic() {
+    let allocator = HugeAllocator::builder().name("cache").build();
+
+    let layout = Layout::from_size_align(1000, 8).unwrap();
+    let ptr = allocator.allocate(layout).unwrap();
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), Layout::from_size_align(10, 8).unwrap()) };
+}
+
+#[test]
+fn stale_handle() {
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(mb(1), 8).unwrap();
+
+    let first = allocator.allocate_tagged(layout).unwrap();
+    unsafe { allocator.deallocate_tagged(first, layout) }.unwrap();
+
+    // Allocate until the same address is reused
+    let mut live = Vec::new();
+
+    let second = loop {
+        let tagged = allocator.allocate_tagged(layout).unwrap();
+
+        if tagged.ptr.as_mut_ptr() == first.ptr.as_mut_ptr() || live.len() == 16 {
+            break tagged;
+        }
+
+        live.push(tagged);
+    };
+
+    assert_ne!(first.generation, second.generation, "generations differ");
+
+    // Freeing through the stale handle must fail and leave the live allocation alone
+    let err = unsafe { allocator.deallocate_tagged(first, layout) }.unwrap_err();
+
+    if second.ptr.as_mut_ptr() == first.ptr.as_mut_ptr() {
+        assert_eq!(Some(second.generation), err.current, "current generation");
+    }
+
+    assert_eq!(Some(second.generation), allocator.generation_of(second.ptr.as_non_null_ptr()));
+
+    for tagged in live.into_iter().chain(std::iter::once(second)) {
+        unsafe { allocator.deallocate_tagged(tagged, layout) }.unwrap();
+    }
+
+    check_stats_eq(&allocator, "stale handle", 0, 0, 0);
+}
+
+#[test]
+fn hybrid_routing() {
+    use std::alloc::GlobalAlloc;
+
+    let hybrid = HybridGlobalAlloc::new(50);
+
+    let small = Layout::from_size_align(1024, 8).unwrap();
+    let large = Layout::from_size_align(mb(1), 8).unwrap();
+
+    unsafe {
+        // Small allocations go to the system allocator
+        let ptr = hybrid.alloc(small);
+        assert!(!ptr.is_null());
+        assert_eq!(0, hybrid.allocator().stats().unwrap().segments, "small not huge");
+
+        // Growing past the threshold moves to the huge allocator
+        let ptr = hybrid.realloc(ptr, small, mb(1));
+        assert!(!ptr.is_null());
+        assert_eq!(1, hybrid.allocator().stats().unwrap().segments, "grown to huge");
+
+        // Shrinking below the threshold moves back
+        let ptr = hybrid.realloc(ptr, large, 1024);
+        assert!(!ptr.is_null());
+        assert_eq!(0, hybrid.allocator().stats().unwrap().segments, "shrunk to small");
+
+        hybrid.dealloc(ptr, small);
+
+        // Large allocations go to the huge allocator
+        let ptr = hybrid.alloc(large);
+        assert_eq!(1, hybrid.allocator().stats().unwrap().segments, "large is huge");
+
+        hybrid.dealloc(ptr, large);
+        assert_eq!(0, hybrid.allocator().stats().unwrap().segments, "large freed");
+    }
+}
+
+#[test]
+#[cfg(feature = "allocator-api2")]
+fn allocator_api2_vec() {
+    let allocator = HugeAllocator::new(50);
+
+    let mut vec = allocator_api2::vec::Vec::with_capacity_in(mb(1), &allocator);
+    vec.extend_from_slice(&[1u8; 1024]);
+
+    assert_eq!(1, allocator.stats().unwrap().segments, "segments");
+
+    drop(vec);
+
+    check_stats_eq(&allocator, "allocator-api2 freed", 0, 0, 0);
+}
+
+#[test]
+fn raw_buffer() {
+    let allocator = HugeAllocator::new(50);
+
+    let mut buf = allocator.alloc_raw(mb(3)).unwrap();
+
+    assert_eq!(mb(3), buf.len());
+    assert!(buf.iter().all(|&b| b == 0), "zeroed");
+
+    buf.fill(0xaa);
+
+    check_stats_eq(&allocator, "raw buffer", mb(3), 1, mb(4));
+
+    drop(buf);
+
+    check_stats_eq(&allocator, "raw buffer freed", 0, 0, 0);
+}
+
+#[test]
+fn global_collections() {
+    struct Holder {
+        vec: HugeVec<u64>,
+        boxed: HugeBox<[u8; 4096]>,
+    }
+
+    let mut holder = Holder {
+        vec: HugeVec::with_capacity(mb(1)),
+        boxed: HugeBox::new_zeroed_huge(),
+    };
+
+    holder.vec.push(1);
+    holder.boxed[0] = 1;
+
+    assert!(holder.vec.capacity() >= mb(1));
+    assert!(global_allocator().generation_of(NonNull::new(holder.vec.as_mut_ptr()).unwrap().cast()).is_some());
+
+    let boxed: HugeBox<u64> = HugeBox::new(5);
+    assert_eq!(5, *boxed);
+}
+
+#[test]
+fn typed_arena() {
+    use std::rc::Rc;
+
+    let allocator = HugeAllocator::new(50);
+    let counter = Rc::new(());
+
+    {
+        let arena = TypedHugeArena::new(&allocator);
+
+        // Enough objects to need several chunks
+        for i in 0..200_000u64 {
+            let item = arena.alloc((i, counter.clone()));
+            assert_eq!(i, item.0);
+        }
+
+        assert_eq!(200_000, arena.len());
+        assert_eq!(200_001, Rc::strong_count(&counter));
+        assert!(allocator.stats().unwrap().segments > 1, "multiple chunks");
+    }
+
+    assert_eq!(1, Rc::strong_count(&counter), "all dropped");
+    check_stats_eq(&allocator, "arena dropped", 0, 0, 0);
+}
+
+#[test]
+fn object_pool() {
+    let allocator = HugeAllocator::new(50);
+
+    {
+        let pool = ObjectPool::with_segment_slots(&allocator, 4);
+
+        let items: Vec<_> = (0..6u64).map(|i| pool.get(i).unwrap()).collect();
+
+        assert_eq!(8, pool.capacity(), "two segments");
+        assert_eq!(6, pool.in_use());
+        assert_eq!(2, allocator.stats().unwrap().segments);
+
+        let first_addr = &*items[0] as *const u64;
+
+        drop(items);
+
+        assert_eq!(8, pool.available(), "all recycled");
+
+        // Most recently freed slot is reused first
+        let item = pool.get(10).unwrap();
+        assert_ne!(first_addr, &*item as *const u64);
+        assert_eq!(10, item.into_inner());
+
+        assert_eq!(0, pool.in_use());
+    }
+
+    check_stats_eq(&allocator, "pool dropped", 0, 0, 0);
+}
+
+#[test]
+fn region_free_all() {
+    let allocator = HugeAllocator::new(50);
+
+    {
+        let region = Region::new(&allocator);
+
+        let mut vecs: Vec<Vec<u64, &Region>> = Vec::new();
+
+        for i in 0..100 {
+            let mut vec = Vec::with_capacity_in(1024, &region);
+            vec.extend(0..i);
+            vecs.push(vec);
+        }
+
+        // Growing the most recent allocation happens in place
+        let last = vecs.last_mut().unwrap();
+        let ptr = last.as_ptr();
+        last.reserve_exact(4096);
+        assert_eq!(ptr, last.as_ptr(), "grown in place");
+
+        assert_eq!(1, region.segments());
+        assert_eq!(1, allocator.stats().unwrap().segments);
+
+        // Contents are intact
+        for (i, vec) in vecs.iter().enumerate() {
+            assert!(vec.iter().copied().eq(0..i as u64));
+        }
+    }
+
+    check_stats_eq(&allocator, "region dropped", 0, 0, 0);
+}
+
+#[test]
+fn arc_allocator() {
+    let allocator = ArcHugeAllocator::new(50);
+
+    let handle = {
+        let worker = allocator.clone();
+
+        std::thread::spawn(move || {
+            let mut vec: Vec<u64, _> = Vec::with_capacity_in(mb(1), worker);
+            vec.push(1);
+            vec
+        })
+        .join()
+        .unwrap()
+    };
+
+    assert_eq!(1, allocator.stats().unwrap().segments);
+
+    drop(handle);
+
+    assert_eq!(0, allocator.stats().unwrap().segments);
+}
+
+#[test]
+fn cloned_handles() {
+    let allocator = HugeAllocator::builder().name("shared").build();
+
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            let handle = allocator.clone();
+
+            std::thread::spawn(move || {
+                let mut vec: Vec<u8, HugeAllocator> = Vec::with_capacity_in(mb(1), handle.clone());
+                vec.push(1);
+
+                assert_eq!(Some("shared"), handle.name());
+
+                vec
+            })
+        })
+        .collect();
+
+    let vecs: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+
+    assert_eq!(4, allocator.stats().unwrap().segments, "segments shared");
+
+    drop(vecs);
+
+    check_stats_eq(&allocator, "cloned handles", 0, 0, 0);
+}
+
+/// Allocator which fails allocations above a size limit
+struct LimitedAllocator(usize);
+
+unsafe impl Allocator for LimitedAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > self.0 {
+            Err(AllocError)
+        } else {
+            std::alloc::System.allocate(layout)
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        std::alloc::System.deallocate(ptr, layout)
+    }
+}
+
+#[test]
+fn fallback_ownership() {
+    let huge = HugeAllocator::new(50);
+    let allocator = FallbackAllocator::new(LimitedAllocator(1024), huge.clone());
+
+    let mut vec: Vec<u8, _> = Vec::with_capacity_in(512, &allocator);
+    vec.extend(0..=255u8);
+
+    assert_eq!(0, allocator.secondary_allocations(), "primary serves small");
+
+    // Growing past the primary's limit moves to the secondary
+    vec.reserve(mb(1));
+
+    assert!(vec.iter().copied().eq(0..=255u8), "contents moved");
+    assert_eq!(1, allocator.secondary_allocations(), "secondary owns grown");
+    assert_eq!(1, huge.stats().unwrap().segments);
+
+    drop(vec);
+
+    assert_eq!(0, allocator.secondary_allocations(), "secondary freed");
+    check_stats_eq(&huge, "fallback freed", 0, 0, 0);
+}
+
+#[test]
+fn dispatch_routing() {
+    let huge = HugeAllocator::new(50);
+    let allocator = DispatchAllocator::new(|layout: Layout| layout.size() >= mb(1), huge.clone(), std::alloc::System);
+
+    let mut vec: Vec<u8, _> = Vec::with_capacity_in(1024, &allocator);
+    vec.extend(0..=255u8);
+
+    assert_eq!(0, allocator.matched_allocations(), "small routed to system");
+    check_stats_eq(&huge, "dispatch small", 0, 0, 0);
+
+    // Growing across the predicate moves to the huge allocator
+    vec.reserve(mb(2));
+
+    assert!(vec.iter().copied().eq(0..=255u8), "contents moved up");
+    assert_eq!(1, allocator.matched_allocations(), "large routed to huge");
+    assert_eq!(1, huge.stats().unwrap().segments);
+
+    // Shrinking back moves to the system allocator
+    vec.shrink_to_fit();
+
+    assert!(vec.iter().copied().eq(0..=255u8), "contents moved down");
+    assert_eq!(0, allocator.matched_allocations(), "shrunk routed to system");
+    check_stats_eq(&huge, "dispatch shrunk", 0, 0, 0);
+}
+
+#[test]
+fn io_buffers() {
+    let allocator = HugeAllocator::new(50);
+
+    let mut buffers = allocator.io_buffers(3, mb(2)).unwrap();
+
+    check_stats_eq(&allocator, "io buffers", 3 * mb(2), 3, 3 * mb(2));
+
+    for i in 0..buffers.len() {
+        buffers.buffer_mut(i).fill(i as u8 + 1);
+    }
+
+    for (i, iovec) in buffers.iovecs().iter().enumerate() {
+        assert_eq!(mb(2), iovec.iov_len);
+        assert_eq!(0, iovec.iov_base as usize % 4096, "page aligned");
+        assert!(buffers.buffer(i).iter().all(|&b| b == i as u8 + 1));
+    }
+
+    drop(buffers);
+
+    check_stats_eq(&allocator, "io buffers freed", 0, 0, 0);
+}
+
+#[test]
+fn pinned_buffer() {
+    let allocator = HugeAllocator::new(50);
+
+    let mut buf = allocator.alloc_pinned(mb(2)).unwrap();
+    buf.fill(0xaa);
+
+    // Huge pages are never swapped so the kernel doesn't mark them locked
+    let flags = vm_flags(buf.as_ptr());
+    assert!(flags.contains(&"lo".to_string()) || flags.contains(&"ht".to_string()), "locked: {:?}", flags);
+    assert!(flags.contains(&"dc".to_string()), "dont fork: {:?}", flags);
+
+    // Reallocation is rejected and the buffer is untouched
+    let ptr = NonNull::new(buf.as_mut_ptr()).unwrap();
+    let layout = Layout::from_size_align(mb(2), 1).unwrap();
+    let bigger = Layout::from_size_align(mb(4), 1).unwrap();
+
+    assert!(unsafe { allocator.grow(ptr, layout, bigger) }.is_err(), "grow rejected");
+    assert!(unsafe { allocator.shrink(ptr, layout, Layout::from_size_align(1, 1).unwrap()) }.is_err());
+
+    assert!(buf.iter().all(|&b| b == 0xaa));
+    check_stats_eq(&allocator, "pinned", mb(2), 1, mb(2));
+
+    drop(buf);
+
+    check_stats_eq(&allocator, "pinned freed", 0, 0, 0);
+}
+
+#[test]
+fn segment_hooks() {
+    use std::sync::Mutex;
+
+    let events: Arc<Mutex<Vec<(bool, usize, usize)>>> = Arc::new(Mutex::new(Vec::new()));
+    let map_events = events.clone();
+    let unmap_events = events.clone();
+
+    let allocator = HugeAllocator::builder()
+        .on_map(move |ptr, len| map_events.lock().unwrap().push((true, ptr as usize, len)))
+        .on_unmap(move |ptr, len| unmap_events.lock().unwrap().push((false, ptr as usize, len)))
+        .build();
+
+    let mut vec: Vec<u8, _> = Vec::with_capacity_in(mb(2), &allocator);
+    let first = vec.as_ptr() as usize;
+
+    vec.reserve(mb(4));
+    let second = vec.as_ptr() as usize;
+
+    drop(vec);
+
+    let events = events.lock().unwrap();
+
+    assert_eq!(Some(&(true, first, mb(2))), events.first(), "first mapped");
+    assert_eq!(Some(&(false, second, events.last().unwrap().2)), events.last(), "last unmapped");
+
+    // Every map is balanced by an unmap of the same range
+    let mut live = std::collections::HashSet::new();
+
+    for &(mapped, ptr, len) in events.iter() {
+        if mapped {
+            assert!(live.insert((ptr, len)), "double map");
+        } else {
+            assert!(live.remove(&(ptr, len)), "unmap of unmapped range");
+        }
+    }
+
+    assert!(live.is_empty(), "all unmapped");
+}
+
+#[test]
+fn segment_hooks_reentrant() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Mutex, OnceLock};
+
+    let events: Arc<Mutex<Vec<(bool, usize, usize)>>> = Arc::new(Mutex::new(Vec::new()));
+    let map_events = events.clone();
+    let unmap_events = events.clone();
+
+    let slot: Arc<OnceLock<HugeAllocator>> = Arc::new(OnceLock::new());
+    let hook_slot = slot.clone();
+    let busy = AtomicBool::new(false);
+
+    let allocator = HugeAllocator::builder()
+        .segment_cache(mb(16))
+        .zero_on_free(true)
+        .on_map(move |ptr, len| map_events.lock().unwrap().push((true, ptr as usize, len)))
+        .on_unmap(move |ptr, len| {
+            unmap_events.lock().unwrap().push((false, ptr as usize, len));
+
+            // Allocate from the allocator being unmapped from
+            if !busy.swap(true, Ordering::Relaxed) {
+                let allocator = hook_slot.get().unwrap();
+                let layout = Layout::from_size_align(4096, 8).unwrap();
+                let ptr = allocator.allocate(layout).unwrap();
+                unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+                busy.store(false, Ordering::Relaxed);
+            }
+        })
+        .build();
+
+    assert!(slot.set(allocator.clone()).is_ok());
+
+    // Shrink in place, splitting off the released pages, then cache the segment
+    let mut vec: Vec<u8, _> = Vec::with_capacity_in(400 * 1024, &allocator);
+    vec.shrink_to(100 * 1024);
+    drop(vec);
+
+    allocator.trim();
+
+    // Every map is balanced by one unmap of the same range
+    let mut live = std::collections::HashSet::new();
+
+    for &(mapped, ptr, len) in events.lock().unwrap().iter() {
+        if mapped {
+            assert!(live.insert((ptr, len)), "double map");
+        } else {
+            assert!(live.remove(&(ptr, len)), "unmap of unmapped range");
+        }
+    }
+
+    assert!(live.is_empty(), "all unmapped");
+}
+
+#[test]
+fn shared_region() {
+    use crate::shared::{SharedHandle, SharedHugeAllocator};
+
+    let writer = SharedHugeAllocator::create("shared_test", mb(4)).unwrap();
+    let layout = Layout::from_size_align(mb(1), 64).unwrap();
+
+    let offset = writer.allocate(layout).unwrap();
+    assert_eq!(0, offset % 64, "aligned");
+
+    unsafe { writer.ptr(offset).write_bytes(0x5a, mb(1)) };
+    writer.publish("table", offset, mb(1)).unwrap();
+
+    let handle: SharedHandle = writer.handle().to_string().parse().unwrap();
+    let reader = SharedHugeAllocator::open(&handle).unwrap();
+
+    assert_eq!(writer.page_size(), reader.page_size());
+    assert_eq!(None, reader.lookup("missing"));
+
+    let (found, len) = reader.lookup("table").unwrap();
+    assert_eq!((offset, mb(1)), (found, len));
+
+    let data = unsafe { std::slice::from_raw_parts(reader.ptr(found), len) };
+    assert!(data.iter().all(|&b| b == 0x5a), "reader sees writer data");
+
+    // Allocations from either process share the cursor
+    let second = reader.allocate(layout).unwrap();
+    assert!(second >= offset + mb(1), "no overlap");
+    assert_eq!(writer.allocated(), reader.allocated());
+
+    // Running out of space fails cleanly
+    assert!(writer.allocate(Layout::from_size_align(mb(64), 1).unwrap()).is_err());
+}
+
+#[test]
+fn snapshot_restore() {
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(mb(3), 64).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    let addr = ptr.as_mut_ptr() as usize;
+
+    unsafe { ptr.as_mut_ptr().write_bytes(0x3c, mb(3)) };
+
+    let mut file = Vec::new();
+    allocator.save_segment(ptr.as_non_null_ptr(), &mut file).unwrap();
+
+    // Restoring at the original address fails while it is still mapped
+    assert!(allocator.restore(&mut file.as_slice(), true).is_err());
+    check_stats(&allocator, "restore in use", 1, mb(4));
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    // Restore at the original address
+    let restored = allocator.restore(&mut file.as_slice(), true).unwrap();
+
+    assert_eq!(1, restored.len());
+    assert_eq!(addr, restored[0].original);
+    assert_eq!(addr, restored[0].ptr.as_mut_ptr() as usize, "address preserved");
+    assert_eq!(layout, restored[0].layout);
+    assert!(unsafe { restored[0].ptr.as_ref() }[..mb(3)].iter().all(|&b| b == 0x3c));
+
+    allocator.check_integrity().unwrap();
+
+    unsafe { allocator.deallocate(restored[0].ptr.as_non_null_ptr(), layout) };
+
+    // Truncated snapshots are rejected without leaking
+    assert!(allocator.restore(&mut &file[..file.len() - 1], false).is_err());
+    check_stats_eq(&allocator, "restore truncated", 0, 0, 0);
+}
+
+#[test]
+fn save_all_writer_allocates() {
+    /// Buffers in to the allocator being saved and frees an allocation mid-save
+    struct Writer<'a> {
+        allocator: &'a HugeAllocator,
+        buf: Vec<u8, &'a HugeAllocator>,
+        victim: Option<(NonNull<u8>, Layout)>,
+    }
+
+    impl std::io::Write for Writer<'_> {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            if let Some((ptr, layout)) = self.victim.take() {
+                unsafe { self.allocator.deallocate(ptr, layout) };
+            }
+
+            self.buf.extend_from_slice(data);
+
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(mb(3), 1).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    unsafe { ptr.as_mut_ptr().write_bytes(0x5a, mb(3)) };
+
+    let mut writer = Writer {
+        allocator: &allocator,
+        buf: Vec::new_in(&allocator),
+        victim: Some((ptr.as_non_null_ptr(), layout)),
+    };
+
+    assert_eq!(1, allocator.save_all(&mut writer).unwrap());
+
+    // The freed allocation was still written in full
+    let restored = HugeAllocator::new(50);
+    let segments = restored.restore(&mut writer.buf.as_slice(), false).unwrap();
+
+    assert_eq!(layout, segments[0].layout);
+    assert!(unsafe { segments[0].ptr.as_ref() }[..mb(3)].iter().all(|&b| b == 0x5a));
+
+    drop(writer);
+
+    // The freed allocation was unmapped once the save finished
+    check_stats_eq(&allocator, "save with allocating writer", 0, 0, 0);
+    allocator.check_integrity().unwrap();
+}
+
+#[test]
+fn segment_snapshot() {
+    let allocator = HugeAllocator::new(50);
+
+    let mut vec: Vec<u8, _> = Vec::with_capacity_in(mb(2), &allocator);
+    vec.resize(mb(2), 0x11);
+
+    let snapshot = allocator.snapshot(NonNull::new(vec.as_mut_ptr()).unwrap()).unwrap();
+
+    vec.fill(0x22);
+    drop(vec);
+
+    assert_eq!(mb(2), snapshot.len());
+    assert!(snapshot.iter().all(|&b| b == 0x11), "point in time copy");
+
+    let flags = vm_flags(snapshot.as_ptr());
+    assert!(!flags.contains(&"wr".to_string()), "read only: {:?}", flags);
+
+    check_stats_eq(&allocator, "snapshot independent", 0, 0, 0);
+}
+
+#[test]
+fn lazy_fill() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(mb(8), 1).unwrap();
+
+    let fills = Arc::new(AtomicUsize::new(0));
+    let counter = fills.clone();
+
+    let segment = match allocator.allocate_lazy(layout, move |offset, page| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        page.fill((offset / 4096) as u8);
+    }) {
+        Ok(segment) => segment,
+        Err(e) => {
+            // userfaultfd may be blocked in containers
+            eprintln!("userfaultfd unavailable ({})", e);
+            check_stats_eq(&allocator, "lazy failed", 0, 0, 0);
+            return;
+        }
+    };
+
+    assert_eq!(0, fills.load(Ordering::Relaxed), "nothing filled up front");
+
+    let page_bytes = if allocator.stats().unwrap().huge_segments == 1 { mb(2) } else { 4096 };
+
+    assert_eq!(((3 * page_bytes) / 4096) as u8, segment[3 * page_bytes + 10]);
+    assert_eq!(0, segment[5]);
+    assert_eq!(2, fills.load(Ordering::Relaxed), "only touched pages filled");
+
+    // The segment is pinned
+    let ptr = NonNull::new(segment.as_ptr() as *mut u8).unwrap();
+    assert!(unsafe { allocator.grow(ptr, layout, Layout::from_size_align(mb(16), 1).unwrap()) }.is_err());
+
+    drop(segment);
+
+    check_stats_eq(&allocator, "lazy freed", 0, 0, 0);
+}
+
+#[test]
+fn sealed_segment() {
+    let allocator = HugeAllocator::builder().zero_on_free(true).build();
+    let layout = Layout::from_size_align(mb(2), 8).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    unsafe { ptr.as_mut_ptr().write_bytes(0x77, mb(2)) };
+
+    allocator.seal(ptr.as_non_null_ptr()).unwrap();
+
+    let flags = vm_flags(ptr.as_mut_ptr());
+    assert!(!flags.contains(&"wr".to_string()), "write protected: {:?}", flags);
+
+    // Resizing is rejected
+    assert!(unsafe { allocator.grow(ptr.as_non_null_ptr(), layout, Layout::from_size_align(mb(4), 8).unwrap()) }
+        .is_err());
+    assert!(unsafe { ptr.as_ref() }[..mb(2)].iter().all(|&b| b == 0x77));
+
+    // Freeing still works (including the wipe)
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    check_stats_eq(&allocator, "sealed freed", 0, 0, 0);
+    assert!(allocator.seal(ptr.as_non_null_ptr()).is_err(), "seal of freed");
+}
+
+#[test]
+fn executable_segment() {
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(mb(2), 4096).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    let code = ptr.as_mut_ptr();
+
+    // mov eax, 42; ret
+    let bytes: &[u8] = &[0xb8, 0x2a, 0x00, 0x00, 0x00, 0xc3];
+
+    unsafe { code.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len()) };
+
+    allocator.make_executable(ptr.as_non_null_ptr()).unwrap();
+
+    let flags = vm_flags(code);
+    assert!(flags.contains(&"ex".to_string()), "executable: {:?}", flags);
+    assert!(!flags.contains(&"wr".to_string()), "W^X: {:?}", flags);
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        let f: extern "C" fn() -> u32 = unsafe { std::mem::transmute(code) };
+        assert_eq!(42, f());
+    }
+
+    // Resizing is rejected while executable
+    assert!(unsafe { allocator.grow(ptr.as_non_null_ptr(), layout, Layout::from_size_align(mb(4), 4096).unwrap()) }
+        .is_err());
+
+    allocator.make_writable(ptr.as_non_null_ptr()).unwrap();
+
+    let flags = vm_flags(code);
+    assert!(flags.contains(&"wr".to_string()) && !flags.contains(&"ex".to_string()), "writable: {:?}", flags);
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    check_stats_eq(&allocator, "executable freed", 0, 0, 0);
+}
+
+#[test]
+fn fixed_address() {
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(mb(4), 8).unwrap();
+
+    let first = allocator.allocate(layout).unwrap();
+    let addr = first.as_mut_ptr() as usize;
+
+    // The range is in use
+    assert!(allocator.allocate_at(addr, layout).is_err(), "overlap rejected");
+    assert!(allocator.allocate_at(addr + 4096, layout).is_err(), "partial overlap rejected");
+
+    unsafe { allocator.deallocate(first.as_non_null_ptr(), layout) };
+
+    let fixed = allocator.allocate_at(addr, layout).unwrap();
+
+    assert_eq!(addr, fixed.as_mut_ptr() as usize);
+    assert!(allocator.generation_of(fixed.as_non_null_ptr()).is_some(), "tracked");
+    check_stats(&allocator, "fixed", 1, mb(4));
+    allocator.check_integrity().unwrap();
+
+    unsafe { allocator.deallocate(fixed.as_non_null_ptr(), layout) };
+
+    check_stats_eq(&allocator, "fixed freed", 0, 0, 0);
+}
+
+#[test]
+fn collection_helpers() {
+    use crate::collections::*;
+
+    let allocator = HugeAllocator::new(50);
+
+    let mut vec = huge_vec_with_capacity::<u64, _>(mb(1), &allocator);
+    vec.push(1);
+
+    let mut deque = huge_vec_deque_with_capacity::<u8, _>(mb(2), &allocator);
+    deque.push_front(1);
+
+    // Both above the threshold
+    check_stats(&allocator, "collections", 2, mb(8) + mb(2));
+
+    drop((vec, deque));
+
+    // Below the threshold, checked on its own so page sizes aren't mixed
+    let boxed = huge_box([0u8; 4096], &allocator);
+
+    check_stats(&allocator, "boxed", 1, 4096);
+
+    drop(boxed);
+
+    #[cfg(feature = "hashbrown")]
+    {
+        let mut map = huge_hashmap_with_capacity::<u64, u64, _>(1 << 20, &allocator);
+
+        for i in 0..1000 {
+            map.insert(i, i * 2);
+        }
+
+        assert_eq!(Some(&200), map.get(&100));
+        assert_eq!(1, allocator.stats().unwrap().segments);
+    }
+}
+
+#[test]
+fn try_constructors() {
+    use crate::collections::*;
+
+    let allocator = HugeAllocator::new(50);
+
+    // Larger than the address space
+    assert!(try_huge_vec_with_capacity::<u64, _>(1 << 45, &allocator).is_err());
+    assert!(try_huge_vec_deque_with_capacity::<u64, _>(1 << 45, &allocator).is_err());
+    assert!(HugeVec::<u64>::try_with_capacity(1 << 45).is_err());
+
+    check_stats_eq(&allocator, "try failed", 0, 0, 0);
+
+    let vec = try_huge_vec_with_capacity::<u64, _>(1 << 18, &allocator).unwrap();
+    assert!(vec.capacity() >= 1 << 18);
+
+    let boxed = try_huge_box([1u8; 4096], &allocator).unwrap();
+    assert_eq!(1, boxed[4095]);
+
+    let zeroed: HugeBox<[u64; 1 << 18]> = HugeBox::try_new_zeroed_huge().unwrap();
+    assert_eq!(0, zeroed[1000]);
+
+    #[cfg(feature = "hashbrown")]
+    assert!(try_huge_hashmap_with_capacity::<u64, u64, _>(1 << 58, &allocator).is_err());
+}
+
+#[test]
+fn file_loading() {
+    use crate::load::{load_file, load_file_mmap};
+
+    let allocator = HugeAllocator::new(50);
+
+    let path = std::env::temp_dir().join(format!("huge_allocator_load_{}", std::process::id()));
+    let contents: Vec<u8> = (0..mb(3) + 123).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, &contents).unwrap();
+
+    let read = load_file(&path, &allocator).unwrap();
+    assert!(read[..] == contents[..], "read contents");
+    assert_eq!(1, allocator.stats().unwrap().segments);
+
+    let mapped = load_file_mmap(&path, &allocator).unwrap();
+    assert!(mapped[..] == contents[..], "mapped contents");
+
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(load_file(&path, &allocator).is_err(), "missing file");
+}
+
+#[test]
+fn read_to_huge_vec() {
+    use crate::load::ReadHugeExt;
+
+    let allocator = HugeAllocator::new(50);
+
+    let contents: Vec<u8> = (0..mb(5) + 7).map(|i| (i % 253) as u8).collect();
+
+    // Small reads to exercise growth
+    let mut reader = std::io::BufReader::with_capacity(65536, &contents[..]);
+    let vec = reader.read_to_huge_vec(&allocator).unwrap();
+
+    assert!(vec[..] == contents[..]);
+    assert_eq!(0, vec.capacity() % mb(2), "grown in whole huge pages");
+    assert_eq!(mb(8), vec.capacity(), "capacity doubled");
+
+    let mut vec = vec;
+    let added = (&b"tail"[..]).read_to_huge_end(&mut vec).unwrap();
+
+    assert_eq!(4, added);
+    assert_eq!(b"tail", &vec[vec.len() - 4..]);
+}
+
+#[test]
+fn page_size_query() {
+    let allocator = HugeAllocator::new(50);
+
+    let small = allocator.allocate(Layout::from_size_align(4096, 8).unwrap()).unwrap();
+    let large = allocator.allocate(Layout::from_size_align(mb(2), 8).unwrap()).unwrap();
+
+    assert_eq!(Some(PageSize::SizeDefault), allocator.page_size_of(small.as_non_null_ptr()));
+
+    let expected = if allocator.stats().unwrap().huge_segments == 1 { PageSize::Size2m } else { PageSize::SizeDefault };
+    assert_eq!(Some(expected), allocator.page_size_of(large.as_non_null_ptr()));
+
+    // Interior pointers aren't allocations
+    assert_eq!(None, allocator.page_size_of(NonNull::new(unsafe { large.as_mut_ptr().add(8) }).unwrap()));
+
+    unsafe {
+        allocator.deallocate(small.as_non_null_ptr(), Layout::from_size_align(4096, 8).unwrap());
+        allocator.deallocate(large.as_non_null_ptr(), Layout::from_size_align(mb(2), 8).unwrap());
+    }
+
+    assert_eq!(None, allocator.page_size_of(small.as_non_null_ptr()));
+}
+
+#[test]
+fn promote_segment() {
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(mb(3), 8).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    unsafe { ptr.as_mut_ptr().write_bytes(0x42, mb(3)) };
+
+    let was_huge = allocator.page_size_of(ptr.as_non_null_ptr()) == Some(PageSize::Size2m);
+
+    let ptr = match unsafe { allocator.promote(ptr.as_non_null_ptr()) } {
+        Ok(new_ptr) => {
+            assert_eq!(Some(PageSize::Size2m), allocator.page_size_of(new_ptr.as_non_null_ptr()));
+            new_ptr
+        }
+        Err(_) => {
+            // No huge pages available - the original is untouched
+            assert!(!was_huge);
+            assert_eq!(Some(PageSize::SizeDefault), allocator.page_size_of(ptr.as_non_null_ptr()));
+            ptr
+        }
+    };
+
+    assert!(unsafe { ptr.as_ref() }[..mb(3)].iter().all(|&b| b == 0x42), "contents kept");
+    check_stats(&allocator, "promote", 1, mb(4));
+    allocator.check_integrity().unwrap();
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    assert!(unsafe { allocator.promote(ptr.as_non_null_ptr()) }.is_err(), "promote freed");
+}
+
+#[test]
+fn demote_segments() {
+    let allocator = HugeAllocator::builder().segment_cache(mb(8)).build();
+    let layout = Layout::from_size_align(mb(3), 8).unwrap();
+
+    let old = allocator.allocate(layout).unwrap();
+    let new = allocator.allocate(layout).unwrap();
+    let cached = allocator.allocate(layout).unwrap();
+
+    let is_huge = |ptr: NonNull<[u8]>| allocator.page_size_of(ptr.as_non_null_ptr()) == Some(PageSize::Size2m);
+    let (old_huge, new_huge, cached_huge) = (is_huge(old), is_huge(new), is_huge(cached));
+
+    unsafe { old.as_mut_ptr().write_bytes(0x42, mb(3)) };
+    unsafe { allocator.deallocate(cached.as_non_null_ptr(), layout) };
+
+    // Demoted in place
+    let released = unsafe { allocator.demote(old.as_non_null_ptr()) }.unwrap();
+
+    assert_eq!(if old_huge { mb(4) } else { 0 }, released, "released bytes");
+    assert!(!is_huge(old), "demoted");
+    assert!(unsafe { old.as_ref() }[..mb(3)].iter().all(|&b| b == 0x42), "contents kept");
+    allocator.check_integrity().unwrap();
+
+    // Cached segments are released before live segments are demoted
+    let expected = [cached_huge, new_huge].iter().filter(|&&huge| huge).count() * mb(4);
+
+    assert_eq!(expected, unsafe { allocator.demote_coldest(mb(8)) }, "coldest released bytes");
+    assert!(!is_huge(new), "live segment demoted");
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(0, stats.huge_segments, "huge segments");
+    assert_eq!(
+        [old_huge, new_huge].iter().filter(|&&huge| huge).count(),
+        stats.demoted_segments,
+        "demoted segments"
+    );
+    assert_eq!(mb(8), stats.default_mapped, "default mapped");
+    allocator.check_integrity().unwrap();
+
+    unsafe { allocator.deallocate(old.as_non_null_ptr(), layout) };
+    unsafe { allocator.deallocate(new.as_non_null_ptr(), layout) };
+
+    assert!(unsafe { allocator.demote(old.as_non_null_ptr()) }.is_err(), "demote freed");
+}
+
+#[test]
+fn watermark_demotion() {
+    let Ok(_pages) = crate::testing::HugePageTestGuard::reserve(4) else {
+        eprintln!("huge page pool unavailable");
+        return;
+    };
+
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(mb(4), 8).unwrap();
+
+    let old = allocator.allocate(layout).unwrap();
+    let new = allocator.allocate(layout).unwrap();
+
+    if allocator.stats().unwrap().huge_segments != 2 {
+        // Pages taken by another test
+        eprintln!("huge pages unavailable");
+        return;
+    }
+
+    // One page short of the watermark once the pool is used up
+    let monitor = unsafe { allocator.watch_watermark(1, Duration::from_millis(10)) }.unwrap();
+
+    for _ in 0..200 {
+        if allocator.stats().unwrap().demoted_segments > 0 {
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    drop(monitor);
+
+    assert_eq!(Some(PageSize::SizeDefault), allocator.page_size_of(old.as_non_null_ptr()), "oldest demoted");
+    assert_eq!(Some(PageSize::Size2m), allocator.page_size_of(new.as_non_null_ptr()), "newest kept");
+    assert_eq!(mb(4), allocator.stats().unwrap().demoted_bytes, "demoted bytes");
+
+    unsafe { allocator.deallocate(old.as_non_null_ptr(), layout) };
+    unsafe { allocator.deallocate(new.as_non_null_ptr(), layout) };
+}
+
+#[test]
+fn advise_segment() {
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(256 * 1024, 8).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+
+    allocator.advise(ptr.as_non_null_ptr(), Advice::Random).unwrap();
+    assert!(vm_flags(ptr.as_mut_ptr()).contains(&"rr".to_string()), "random read");
+
+    allocator.advise(ptr.as_non_null_ptr(), Advice::Sequential).unwrap();
+    assert!(vm_flags(ptr.as_mut_ptr()).contains(&"sr".to_string()), "sequential read");
+
+    allocator.advise(ptr.as_non_null_ptr(), Advice::Normal).unwrap();
+
+    // Default page segments can be demoted without losing their contents
+    unsafe { ptr.as_mut_ptr().write_bytes(0x99, layout.size()) };
+
+    allocator.advise(ptr.as_non_null_ptr(), Advice::Cold).unwrap();
+    allocator.advise(ptr.as_non_null_ptr(), Advice::PageOut).unwrap();
+
+    assert!(unsafe { ptr.as_ref() }[..layout.size()].iter().all(|&b| b == 0x99), "contents kept");
+
+    // Only owned allocations can be advised
+    let interior = NonNull::new(unsafe { ptr.as_mut_ptr().add(4096) }).unwrap();
+    assert!(allocator.advise(interior, Advice::WillNeed).is_err());
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+}
+
+#[test]
+fn segment_cache() {
+    let allocator = HugeAllocator::builder().segment_cache(mb(6)).build();
+    let layout = Layout::from_size_align(mb(3), 8).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    unsafe { ptr.as_mut_ptr().write_bytes(0x5a, mb(3)) };
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(0, stats.segments, "segments after free");
+    assert_eq!(1, stats.cached_segments, "cached segments");
+    assert_eq!(ptr.len(), stats.cached_bytes, "cached bytes");
+
+    // A request needing the same number of pages reuses the segment zeroed
+    let reused = allocator.allocate(Layout::from_size_align(mb(3) - 100, 8).unwrap()).unwrap();
+
+    assert_eq!(ptr.as_mut_ptr(), reused.as_mut_ptr(), "segment reused");
+    assert!(unsafe { reused.as_ref() }.iter().all(|&b| b == 0), "reused segment zeroed");
+    assert_eq!(0, allocator.stats().unwrap().cached_segments, "cache emptied");
+    allocator.check_integrity().unwrap();
+
+    unsafe { allocator.deallocate(reused.as_non_null_ptr(), Layout::from_size_align(mb(3) - 100, 8).unwrap()) };
+
+    // Segments which don't fit in the cache are unmapped
+    let big = Layout::from_size_align(mb(8), 8).unwrap();
+    let ptr = allocator.allocate(big).unwrap();
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), big) };
+
+    assert_eq!(1, allocator.stats().unwrap().cached_segments, "oversized segment not cached");
+
+    assert_eq!(reused.len(), allocator.trim(), "trimmed bytes");
+    assert_eq!(0, allocator.stats().unwrap().cached_bytes, "cache trimmed");
+    assert_eq!(0, allocator.trim(), "nothing left to trim");
+
+    // Pressure monitoring is only available with PSI enabled
+    match allocator.watch_pressure(Duration::from_millis(150), Duration::from_secs(2)) {
+        Ok(monitor) => drop(monitor),
+        Err(e) => eprintln!("pressure monitoring unavailable: {}", e),
+    }
+}
+
+#[test]
+fn segment_coalescing() {
+    // Place the segments next to each other
+    let allocator = HugeAllocator::builder()
+        .segment_cache(mb(16))
+        .address_hint(0x5200_0000_0000)
+        .build();
+
+    let layout = Layout::from_size_align(mb(2), 8).unwrap();
+    let big = Layout::from_size_align(mb(4), 8).unwrap();
+
+    let ptr1 = allocator.allocate(layout).unwrap();
+    let ptr2 = allocator.allocate(layout).unwrap();
+    assert_eq!(ptr1.as_mut_ptr().wrapping_add(mb(2)), ptr2.as_mut_ptr(), "adjacent");
+
+    unsafe {
+        allocator.deallocate(ptr2.as_non_null_ptr(), layout);
+        allocator.deallocate(ptr1.as_non_null_ptr(), layout);
+    }
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(1, stats.cached_segments, "segments merged");
+    assert_eq!(mb(4), stats.cached_bytes, "merged bytes");
+
+    // The merged segment satisfies a larger allocation
+    let merged = allocator.allocate(big).unwrap();
+    assert_eq!(ptr1.as_mut_ptr(), merged.as_mut_ptr(), "merged segment reused");
+    assert_eq!(mb(4), merged.len());
+    unsafe { merged.as_mut_ptr().write_bytes(0x6b, mb(4)) };
+    assert_eq!(0, allocator.stats().unwrap().cached_segments, "cache emptied");
+
+    unsafe { allocator.deallocate(merged.as_non_null_ptr(), big) };
+
+    // A smaller allocation splits it, leaving the rest cached
+    let split = allocator.allocate(layout).unwrap();
+    assert_eq!(ptr1.as_mut_ptr(), split.as_mut_ptr(), "split segment reused");
+    assert!(unsafe { split.as_ref() }.iter().all(|&b| b == 0), "split segment zeroed");
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(1, stats.cached_segments, "remainder cached");
+    assert_eq!(mb(2), stats.cached_bytes, "remainder bytes");
+    allocator.check_integrity().unwrap();
+
+    unsafe { allocator.deallocate(split.as_non_null_ptr(), layout) };
+
+    assert_eq!(mb(4), allocator.trim(), "trimmed bytes");
+}
+
+#[test]
+fn deferred_unmap() {
+    let allocator = HugeAllocator::builder().deferred_unmap(true).build();
+    let layout = Layout::from_size_align(mb(3), 8).unwrap();
+
+    let ptrs: Vec<_> = (0..4).map(|_| allocator.allocate(layout).unwrap()).collect();
+
+    for ptr in &ptrs {
+        unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+    }
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(0, stats.segments, "segments after free");
+    assert!(stats.deferred_segments <= 4, "deferred segments");
+    assert!(stats.deferred_bytes <= 4 * ptrs[0].len(), "deferred bytes");
+
+    // The background thread drains the queue
+    for _ in 0..500 {
+        if allocator.stats().unwrap().deferred_segments == 0 {
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(0, stats.deferred_segments, "queue drained");
+    assert_eq!(0, stats.deferred_bytes, "deferred bytes drained");
+    assert_eq!(0, stats.unmaps_failed, "unmaps failed");
+
+    // Segments still queued are unmapped when the allocator is dropped
+    let ptr = allocator.allocate(layout).unwrap();
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    drop(allocator);
+}
+
+#[test]
+fn deferred_unmap_strict() {
+    let allocator = HugeAllocator::builder().deferred_unmap(true).strict_unmap(true).build();
+
+    // munmap fails with EINVAL above the user address space
+    let bogus = NonNull::new((usize::MAX - mb(8) + 1) as *mut u8).unwrap();
+    unsafe { allocator.adopt(bogus, 4096, PageSize::SizeDefault).unwrap() };
+
+    // Freeing doesn't wait for the unmap, so the failure panics when the queue is waited for
+    unsafe { allocator.deallocate(bogus, Layout::from_size_align(4096, 4096).unwrap()) };
+
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| allocator.flush()));
+    assert!(result.is_err(), "strict failure reported");
+
+    assert_eq!(1, allocator.stats().unwrap().unmaps_failed, "unmaps failed");
+
+    // Reported once
+    drop(allocator);
+}
+
+#[test]
+fn unmap_epoch() {
+    for deferred in [false, true] {
+        let allocator = HugeAllocator::builder().unmap_epoch(8).deferred_unmap(deferred).build();
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+
+        let ptrs: Vec<_> = (0..20).map(|_| allocator.allocate(layout).unwrap()).collect();
+
+        for ptr in &ptrs {
+            unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+        }
+
+        // Two full epochs have been unmapped
+        let stats = allocator.stats().unwrap();
+        assert_eq!(0, stats.segments, "segments after free");
+        assert_eq!(4, stats.epoch_segments, "epoch segments");
+        assert_eq!(4 * 4096, stats.epoch_bytes, "epoch bytes");
+
+        assert_eq!(4 * 4096, allocator.flush(), "flushed bytes");
+
+        let stats = allocator.stats().unwrap();
+        assert_eq!(0, stats.epoch_segments, "epoch flushed");
+        assert_eq!(0, stats.deferred_segments, "deferred unmaps finished");
+        assert_eq!(0, stats.unmaps_failed, "unmaps failed");
+
+        assert_eq!(0, allocator.flush(), "nothing left to flush");
+    }
+}
+
+#[test]
+fn layout_dump() {
+    let allocator = HugeAllocator::builder().segment_cache(mb(4)).build();
+    let layout = Layout::from_size_align(mb(1), 8).unwrap();
+
+    let live = allocator.allocate(layout).unwrap();
+    let freed = allocator.allocate(layout).unwrap();
+    unsafe { allocator.deallocate(freed.as_non_null_ptr(), layout) };
+
+    let mut text = Vec::new();
+    allocator.dump_layout(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(5, lines.len(), "summary, header, two segments and gaps:\n{}", text);
+    assert!(lines[0].starts_with("2 segments mapping"), "summary line");
+
+    let line = |ptr: NonNull<[u8]>| {
+        let start = format!("{:#018x}", ptr.as_mut_ptr() as usize);
+        *lines.iter().find(|line| line.starts_with(&start)).unwrap()
+    };
+
+    assert!(line(live).contains(" live "), "live segment shown");
+    assert!(line(freed).contains(" cached "), "cached segment shown");
+
+    // Segments are sorted by address
+    let (first, second) = if live.as_mut_ptr() < freed.as_mut_ptr() { (live, freed) } else { (freed, live) };
+    assert_eq!([line(first), line(second)], lines[2..4], "sorted by address");
+
+    let mut svg = Vec::new();
+    allocator.dump_layout_svg(&mut svg).unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+
+    assert!(svg.starts_with("<svg") && svg.trim_end().ends_with("</svg>"), "svg document");
+    assert!(svg.contains(&format!("<title>{:#x}-", live.as_mut_ptr() as usize)), "live segment drawn");
+
+    unsafe { allocator.deallocate(live.as_non_null_ptr(), layout) };
+    allocator.trim();
+
+    let mut text = Vec::new();
+    allocator.dump_layout(&mut text).unwrap();
+    assert_eq!(b"0 segments mapped\n", text.as_slice(), "empty layout");
+}
+
+#[test]
+fn syscall_latency() {
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(8192, 8).unwrap();
+    let grown = Layout::from_size_align(64 * 1024, 8).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    let ptr = unsafe { allocator.grow(ptr.as_non_null_ptr(), layout, grown) }.unwrap();
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), grown) };
+
+    let stats = allocator.stats().unwrap();
+
+    for (name, latency) in [
+        ("mmap", stats.default_mmap_latency),
+        ("mremap", stats.default_mremap_latency),
+        ("munmap", stats.default_munmap_latency),
+    ] {
+        assert_eq!(1, latency.calls, "{} calls", name);
+        assert!(latency.min <= latency.mean && latency.mean <= latency.max, "{} min <= mean <= max", name);
+    }
+
+    assert_eq!(0, stats.huge_mremap_latency.calls, "no huge page remaps");
+
+    // Merging weights the mean by the number of calls
+    let mut total = stats.clone();
+    total.merge(&stats);
+
+    assert_eq!(2, total.default_mmap_latency.calls, "merged calls");
+    assert_eq!(stats.default_mmap_latency.mean, total.default_mmap_latency.mean, "merged mean");
+}
+
+#[test]
+fn cgroup_hugetlb_limit() {
+    let root = std::env::temp_dir().join(format!("huge_allocator_cgroup_{}", std::process::id()));
+    let child = root.join("service");
+
+    std::fs::create_dir_all(&child).unwrap();
+
+    // Unlimited child inside a limited parent
+    std::fs::write(child.join("hugetlb.2MB.max"), "max\n").unwrap();
+    std::fs::write(child.join("hugetlb.2MB.current"), "0\n").unwrap();
+    std::fs::write(root.join("hugetlb.2MB.max"), format!("{}\n", mb(8))).unwrap();
+    std::fs::write(root.join("hugetlb.2MB.current"), format!("{}\n", mb(6))).unwrap();
+
+    let limit = cgroup::hugetlb_limit_in(&child, PageSize::Size2m).unwrap();
+
+    assert_eq!(mb(8), limit.max, "parent max");
+    assert_eq!(mb(2), limit.headroom(), "parent headroom");
+    assert!(cgroup::hugetlb_limit_in(&child, PageSize::SizeDefault).is_none(), "default pages unlimited");
+
+    // Tighter child limit wins
+    std::fs::write(child.join("hugetlb.2MB.max"), format!("{}\n", mb(4))).unwrap();
+    std::fs::write(child.join("hugetlb.2MB.current"), format!("{}\n", mb(4))).unwrap();
+
+    assert_eq!(0, cgroup::hugetlb_limit_in(&child, PageSize::Size2m).unwrap().headroom(), "child headroom");
+
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let allocator = HugeAllocator::new(50);
+    assert_eq!(
+        cgroup::hugetlb_limit(PageSize::Size2m).map(|limit| limit.max),
+        allocator.stats().unwrap().cgroup_limit.map(|limit| limit.max)
+    );
+}
+
+#[test]
+fn cgroup_limit_cache() {
+    let cache = cgroup::LimitCache::new(Duration::from_secs(60));
+    let reads = std::cell::Cell::new(0);
+
+    let read = || {
+        reads.set(reads.get() + 1);
+        Some(HugetlbLimit { max: mb(8), current: mb(2) })
+    };
+
+    assert!(cache.try_reserve(mb(4), read), "within headroom");
+    assert!(!cache.try_reserve(mb(4), read), "reservations deducted");
+    assert!(cache.try_reserve(mb(2), read), "rest of headroom");
+    assert_eq!(Some(HugetlbLimit { max: mb(8), current: mb(8) }), cache.get(read), "usage includes reservations");
+    assert_eq!(1, reads.get(), "limit read once within the TTL");
+
+    // Expired values are read again
+    let cache = cgroup::LimitCache::new(Duration::ZERO);
+    reads.set(0);
+
+    assert!(cache.try_reserve(mb(6), read), "within headroom");
+    assert!(cache.try_reserve(mb(6), read), "headroom read again");
+    assert_eq!(2, reads.get(), "limit read each time");
+
+    // No limit
+    let cache = cgroup::LimitCache::new(Duration::from_secs(60));
+    assert!(cache.try_reserve(usize::MAX, || None), "unlimited");
+}
+
+#[test]
+fn numa_node_binding() {
+    let allocator = HugeAllocator::new(50);
+    let layout = Layout::from_size_align(mb(1), 8).unwrap();
+
+    let ptr = allocator.allocate_on_node(layout, 0).unwrap();
+
+    assert_eq!(Some(0), allocator.node_of(ptr.as_non_null_ptr()), "node recorded");
+    assert_eq!(Some(&ptr.len()), allocator.stats().unwrap().node_mapped.get(&0), "node stats");
+
+    let numa_maps = std::fs::read_to_string("/proc/self/numa_maps").unwrap();
+    let line = numa_maps
+        .lines()
+        .find(|line| line.starts_with(&format!("{:x} ", ptr.as_mut_ptr() as usize)))
+        .unwrap();
+    assert!(line.contains("bind:0"), "bound policy: {}", line);
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    // Unbound allocations have no node
+    let ptr = allocator.allocate(layout).unwrap();
+    assert_eq!(None, allocator.node_of(ptr.as_non_null_ptr()), "unbound");
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    // Nodes which don't exist are rejected
+    assert!(allocator.allocate_on_node(layout, 1000).is_err(), "missing node");
+    assert!(allocator.stats().unwrap().node_mapped.is_empty());
+}
+
+#[test]
+fn numa_bind_local() {
+    let allocator = HugeAllocator::builder().numa_policy(NumaPolicy::BindLocal).build();
+    let layout = Layout::from_size_align(mb(1), 8).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+
+    assert!(allocator.node_of(ptr.as_non_null_ptr()).is_some(), "bound to local node");
+    allocator.check_integrity().unwrap();
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    // An explicit node takes priority
+    let ptr = allocator.allocate_on_node(layout, 0).unwrap();
+    assert_eq!(Some(0), allocator.node_of(ptr.as_non_null_ptr()), "explicit node");
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+}
+
+#[test]
+fn numa_policies() {
+    let layout = Layout::from_size_align(mb(1), 8).unwrap();
+
+    let policy_of = |ptr: *mut u8| {
+        let numa_maps = std::fs::read_to_string("/proc/self/numa_maps").unwrap();
+        let prefix = format!("{:x} ", ptr as usize);
+
+        numa_maps
+            .lines()
+            .find_map(|line| line.strip_prefix(&prefix).map(|rest| rest.split(' ').next().unwrap().to_string()))
+            .unwrap()
+    };
+
+    for (policy, expected) in [
+        (NumaPolicy::FirstTouch, "default"),
+        (NumaPolicy::BindNode(0), "bind:0"),
+        (NumaPolicy::Interleave(0b1), "interleave:0"),
+    ] {
+        let allocator = HugeAllocator::builder().numa_policy(policy).build();
+
+        let ptr = allocator.allocate(layout).unwrap();
+
+        assert_eq!(expected, policy_of(ptr.as_mut_ptr()), "{:?} policy", policy);
+        allocator.check_integrity().unwrap();
+
+        unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+    }
+}
+
+#[test]
+fn reset_allocator() {
+    let allocator = HugeAllocator::builder().segment_cache(mb(4)).build();
+
+    let small: Vec<u8, _> = Vec::with_capacity_in(mb(1), &allocator);
+    let big: Vec<u8, _> = Vec::with_capacity_in(mb(8), &allocator);
+
+    std::mem::forget(small);
+    std::mem::forget(big);
+
+    check_stats(&allocator, "before reset", 2, mb(10));
+
+    assert_eq!(2, unsafe { allocator.reset() }, "segments released");
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(0, stats.segments, "segments after reset");
+    assert_eq!(1, stats.cached_segments, "small segment cached");
+    allocator.check_integrity().unwrap();
+
+    assert_eq!(0, unsafe { allocator.reset() }, "nothing left");
+}
+
+#[test]
+fn allocation_visitor() {
+    let allocator = HugeAllocator::new(50);
+
+    let small = Layout::from_size_align(4096, 8).unwrap();
+    let big = Layout::from_size_align(mb(3), 8).unwrap();
+
+    let small_ptr = allocator.allocate(small).unwrap();
+    let big_ptr = allocator.allocate(big).unwrap();
+
+    allocator.seal(big_ptr.as_non_null_ptr()).unwrap();
+
+    let mut infos = Vec::new();
+    allocator.for_each_allocation(|info| infos.push(*info));
+    infos.sort_by_key(|info| info.layout.size());
+
+    assert_eq!(2, infos.len(), "allocations visited");
+
+    assert_eq!(small_ptr.as_mut_ptr() as usize, infos[0].ptr, "small address");
+    assert_eq!(small, infos[0].layout, "small layout");
+    assert_eq!(PageSize::SizeDefault, infos[0].page_size, "small page size");
+    assert!(!infos[0].sealed, "small not sealed");
+
+    assert_eq!(big_ptr.as_mut_ptr() as usize, infos[1].ptr, "big address");
+    assert_eq!(big_ptr.len(), infos[1].mapped, "big mapped");
+    assert_eq!(allocator.generation_of(big_ptr.as_non_null_ptr()), Some(infos[1].generation), "big generation");
+    assert!(infos[1].sealed, "big sealed");
+
+    unsafe {
+        allocator.deallocate(small_ptr.as_non_null_ptr(), small);
+        allocator.deallocate(big_ptr.as_non_null_ptr(), big);
+    }
+
+    let mut count = 0;
+    allocator.for_each_allocation(|_| count += 1);
+    assert_eq!(0, count, "nothing live");
+}
+
+#[test]
+fn shrink_policies() {
+    let big = Layout::from_size_align(mb(4), 8).unwrap();
+    let small = Layout::from_size_align(64 * 1024, 8).unwrap();
+
+    for (policy, keeps) in [
+        (ShrinkPolicy::Demote, false),
+        (ShrinkPolicy::Keep, true),
+        (ShrinkPolicy::Lazy(Duration::ZERO), false),
+        (ShrinkPolicy::Lazy(Duration::from_secs(3600)), true),
+    ] {
+        let allocator = HugeAllocator::builder().shrink_policy(policy).build();
+
+        let ptr = allocator.allocate(big).unwrap();
+
+        if allocator.page_size_of(ptr.as_non_null_ptr()) != Some(PageSize::Size2m) {
+            println!("{:?}: no huge pages available, skipping", policy);
+            unsafe { allocator.deallocate(ptr.as_non_null_ptr(), big) };
+            continue;
+        }
+
+        unsafe { ptr.as_mut_ptr().write_bytes(0x17, small.size()) };
+
+        let shrunk = unsafe { allocator.shrink(ptr.as_non_null_ptr(), big, small) }.unwrap();
+
+        let expected = if keeps { PageSize::Size2m } else { PageSize::SizeDefault };
+        assert_eq!(Some(expected), allocator.page_size_of(shrunk.as_non_null_ptr()), "{:?} page size", policy);
+        assert!(unsafe { shrunk.as_ref() }[..small.size()].iter().all(|&b| b == 0x17), "{:?} contents", policy);
+        allocator.check_integrity().unwrap();
+
+        // Regrowing a kept segment stays on huge pages
+        let grown = unsafe { allocator.grow(shrunk.as_non_null_ptr(), small, big) }.unwrap();
+        assert_eq!(Some(PageSize::Size2m), allocator.page_size_of(grown.as_non_null_ptr()), "{:?} regrown", policy);
+
+        unsafe { allocator.deallocate(grown.as_non_null_ptr(), big) };
+    }
+}
+
+#[test]
+fn capability_probe() {
+    let report = HugeAllocator::probe();
+
+    println!("{}", report);
+
+    assert_eq!(PageSize::SizeDefault.bytes(), report.default_page_size, "default page size");
+    assert!(report.huge_pages_free <= report.huge_pages_total, "free within pool");
+
+    // The report agrees with what the allocator manages to map
+    if report.huge_pages_usable() >= 16 {
+        let allocator = HugeAllocator::new(50);
+        let vec: Vec<u8, _> = Vec::with_capacity_in(mb(2), &allocator);
+
+        assert_eq!(Some(PageSize::Size2m), allocator.page_size_of(NonNull::new(vec.as_ptr() as *mut u8).unwrap()));
+    }
+
+    if let Ok(_guard) = crate::testing::HugePageTestGuard::reserve(report.huge_pages_total + 1) {
+        assert!(HugeAllocator::probe().huge_pages_available(), "huge pages available after reserving");
+    }
+}
+
+#[test]
+fn system_report() {
+    let report = crate::diagnostics::system_report();
+
+    println!("{}", report);
+
+    let probe = HugeAllocator::probe();
+
+    assert_eq!(probe.default_page_size, report.default_page_size, "default page size");
+    assert_eq!(probe.thp, report.thp, "thp");
+    assert_eq!(probe.thp_fallback(), report.thp_fallback, "thp fallback");
+    assert_eq!(probe.memlock_limit, report.memlock_soft, "memlock limit");
+    assert!(!report.kernel.is_empty(), "kernel release");
+    assert!(report.pools.windows(2).all(|pools| pools[0].page_size < pools[1].page_size), "pools sorted");
+
+    if let Some(pool) = report.pool(mb(2)) {
+        assert!(pool.free <= pool.total, "free within pool");
+        assert_eq!(probe.cgroup_limit.map(|limit| limit.max), pool.cgroup_limit.map(|limit| limit.max), "cgroup limit");
+        assert!(report.to_string().contains("huge page pool 2m: "), "2m pool listed");
+    }
+}
+
+#[test]
+fn map_count_limit() {
+    // Mappings fall back to the default page size with a log message without huge pages
+    let _pages = crate::testing::HugePageTestGuard::reserve(4);
+
+    let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = messages.clone();
+
+    let allocator = HugeAllocator::builder()
+        .segment_cache(mb(1))
+        .map_count_limit(4)
+        .log_sink(move |msg| sink.lock().unwrap().push(msg.to_string()))
+        .build();
+
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+
+    // Cache a segment
+    let cached = allocator.allocate(layout).unwrap();
+    unsafe { allocator.deallocate(cached.as_non_null_ptr(), layout) };
+
+    let ptrs: Vec<_> = (0..2).map(|_| allocator.allocate(Layout::from_size_align(mb(1), 8).unwrap())).collect();
+    assert!(messages.lock().unwrap().is_empty(), "no warning below the limit");
+
+    let ptr = allocator.allocate(Layout::from_size_align(mb(2), 8).unwrap()).unwrap();
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(4, stats.map_count_limit);
+    assert_eq!(0, stats.cached_segments, "cache trimmed at the limit");
+
+    let messages = messages.lock().unwrap();
+    assert_eq!(1, messages.len(), "warned");
+    assert!(messages[0].contains("vm.max_map_count"), "{}", messages[0]);
+
+    unsafe {
+        allocator.deallocate(ptr.as_non_null_ptr(), Layout::from_size_align(mb(2), 8).unwrap());
+
+        for ptr in ptrs {
+            allocator.deallocate(ptr.unwrap().as_non_null_ptr(), Layout::from_size_align(mb(1), 8).unwrap());
+        }
+    }
+
+    // Defaults to 90% of the system limit
+    let report = HugeAllocator::probe();
+    assert_eq!(report.max_map_count / 10 * 9, HugeAllocator::new(50).stats().unwrap().map_count_limit);
+}
+
+#[test]
+fn surplus_accounting() {
+    let report = HugeAllocator::probe();
+
+    assert_eq!(report.huge_pages_overcommit.saturating_sub(report.huge_pages_surplus), report.surplus_headroom());
+    assert!(report.huge_pages_usable() >= report.surplus_headroom().min(report.huge_pages_usable()));
+
+    let layout = Layout::from_size_align(mb(4), 8).unwrap();
+
+    // Not counted unless enabled
+    let allocator = HugeAllocator::new(50);
+    let ptr = allocator.allocate(layout).unwrap();
+    assert_eq!(0, allocator.stats().unwrap().surplus_allocs, "not tracked by default");
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    let allocator = HugeAllocator::builder().track_surplus(true).build();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    let stats = allocator.stats().unwrap();
+
+    if report.huge_pages_overcommit == 0 {
+        assert_eq!(0, stats.surplus_allocs, "no surplus without overcommit");
+    }
+
+    assert!(stats.surplus_bytes <= stats.huge_mapped, "surplus bytes within huge mappings");
+
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+}
+
+#[test]
+fn thp_fallback() {
+    // Larger than any huge page pool in the test environment
+    let layout = Layout::from_size_align(mb(4096) + mb(2), 8).unwrap();
+
+    let thp_available = crate::probe::read_thp() != ThpMode::Unknown;
+    let eligible = HugeAllocator::probe().thp_fallback().is_eligible();
+
+    for advise in [true, false] {
+        let allocator = HugeAllocator::builder().thp_fallback(advise).build();
+
+        let ptr = allocator.allocate(layout).unwrap();
+
+        let stats = allocator.stats().unwrap();
+        assert_eq!(1, stats.missed_allocs, "fell back to default pages");
+        assert_eq!(usize::from(advise && eligible), stats.thp_eligible, "thp eligible");
+
+        unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+    }
+
+    // Allocations below the threshold can be advised directly
+    let allocator = HugeAllocator::new(50);
+    let small = Layout::from_size_align(mb(1) - 4096, 8).unwrap();
+
+    let ptr = allocator.allocate(small).unwrap();
+    if thp_available {
+        allocator.advise(ptr.as_non_null_ptr(), Advice::HugePage).unwrap();
+    }
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), small) };
+
+    // Fallback segments are only advised where THP needs advice and won't stall faults
+    assert_eq!(ThpFallback::Disabled, ThpFallback::decide(ThpMode::Never, ThpDefrag::Madvise));
+    assert_eq!(ThpFallback::Disabled, ThpFallback::decide(ThpMode::Unknown, ThpDefrag::Unknown));
+    assert_eq!(ThpFallback::AlreadyEligible, ThpFallback::decide(ThpMode::Always, ThpDefrag::Madvise));
+    assert_eq!(ThpFallback::AvoidDefragStall, ThpFallback::decide(ThpMode::Madvise, ThpDefrag::Always));
+    assert_eq!(ThpFallback::Advise, ThpFallback::decide(ThpMode::Madvise, ThpDefrag::DeferMadvise));
+}
+
+#[test]
+fn align_fallback() {
+    // Larger than any huge page pool in the test environment
+    let layout = Layout::from_size_align(mb(4096) + mb(2) + 4096, 8).unwrap();
+
+    for align in [true, false] {
+        let allocator = HugeAllocator::builder().align_fallback(align).build();
+
+        let ptr = allocator.allocate(layout).unwrap();
+
+        let stats = allocator.stats().unwrap();
+        assert_eq!(1, stats.missed_allocs, "fell back to default pages");
+
+        if align {
+            assert!((ptr.as_mut_ptr() as usize).is_multiple_of(mb(2)), "2mb aligned start");
+            assert_eq!(mb(4096) + mb(4), stats.default_mapped, "mapped in 2mb units");
+        } else {
+            assert_eq!(layout.size(), stats.default_mapped, "mapped in default pages");
+        }
+
+        allocator.check_integrity().unwrap();
+
+        unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+    }
+}
+
+#[test]
+fn no_fallback() {
+    let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = messages.clone();
+
+    let allocator = HugeAllocator::builder()
+        .no_fallback(true)
+        .log_sink(move |msg| sink.lock().unwrap().push(msg.to_string()))
+        .build();
+
+    // Larger than any huge page pool in the test environment
+    let huge = Layout::from_size_align(mb(4096) + mb(2), 8).unwrap();
+    let small = Layout::from_size_align(mb(1) - 4096, 8).unwrap();
+
+    assert!(allocator.allocate(huge).is_err(), "huge allocation fails");
+    assert_eq!(1, messages.lock().unwrap().len(), "failure logged");
+    assert_eq!(0, allocator.stats().unwrap().missed_allocs, "nothing missed");
+
+    // Allocations below the threshold are unaffected
+    let ptr = allocator.allocate(small).unwrap();
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), small) };
+
+    // The same allocation falls back without the option
+    let fallback = HugeAllocator::new(50);
+    let ptr = fallback.allocate(huge).unwrap();
+    assert_eq!(Some(PageSize::SizeDefault), fallback.page_size_of(ptr.as_non_null_ptr()), "fell back");
+    unsafe { fallback.deallocate(ptr.as_non_null_ptr(), huge) };
+}
+
+#[test]
+fn rate_limited_warnings() {
+    let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = messages.clone();
+
+    let allocator = HugeAllocator::builder()
+        .warn_interval(Duration::from_secs(3600))
+        .log_sink(move |msg| sink.lock().unwrap().push(msg.to_string()))
+        .build();
+
+    // Larger than any huge page pool in the test environment so always falls back
+    let layout = Layout::from_size_align(mb(4096) + mb(2), 8).unwrap();
+
+    for _ in 0..5 {
+        let ptr = allocator.allocate(layout).unwrap();
+        unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+    }
+
+    assert_eq!(1, messages.lock().unwrap().len(), "one fallback warning: {:?}", messages.lock().unwrap());
+
+    // Without an interval every occurrence is reported
+    let allocator = HugeAllocator::builder()
+        .warn_interval(Duration::ZERO)
+        .log_sink({
+            let messages = messages.clone();
+            move |msg| messages.lock().unwrap().push(msg.to_string())
+        })
+        .build();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    assert_eq!(2, messages.lock().unwrap().len(), "warning with no interval");
+    assert!(messages.lock().unwrap()[1].contains("default page size"), "{:?}", messages.lock().unwrap());
+}
+
+#[test]
+fn raw_global_alloc() {
+    use std::alloc::GlobalAlloc;
+
+    let raw = RawHugeAlloc::new(50);
+
+    let small = Layout::from_size_align(100, 8).unwrap();
+    let big = Layout::from_size_align(mb(3), 8).unwrap();
+
+    unsafe {
+        let ptr = raw.alloc_zeroed(small);
+        assert!(!ptr.is_null(), "small allocation");
+        assert!(std::slice::from_raw_parts(ptr, 100).iter().all(|&b| b == 0), "zeroed");
+        ptr.write_bytes(0x11, 100);
+
+        // Grow within the page, then across the huge threshold
+        let ptr = raw.realloc(ptr, small, 4000);
+        let ptr = raw.realloc(ptr, Layout::from_size_align(4000, 8).unwrap(), mb(3));
+        assert!(!ptr.is_null(), "grown");
+        assert!(std::slice::from_raw_parts(ptr, 100).iter().all(|&b| b == 0x11), "contents kept");
+
+        ptr.add(mb(3) - 1).write(0x22);
+
+        // Grow within huge sizes
+        let ptr = raw.realloc(ptr, big, mb(5));
+        assert_eq!(0x22, ptr.add(mb(3) - 1).read(), "tail kept");
+
+        raw.dealloc(ptr, Layout::from_size_align(mb(5), 8).unwrap());
+
+        // Huge page alignment is only available when huge pages are
+        let aligned = Layout::from_size_align(mb(2), mb(2)).unwrap();
+        let ptr = raw.alloc(aligned);
+
+        if !ptr.is_null() {
+            assert_eq!(0, ptr as usize % mb(2), "huge page aligned");
+            raw.dealloc(ptr, aligned);
+        }
+
+        // Nothing is aligned beyond a huge page
+        assert!(raw.alloc(Layout::from_size_align(mb(4), mb(4)).unwrap()).is_null(), "over aligned");
+    }
+}
+
+#[test]
+fn core_global_alloc() {
+    use crate::{CoreHugeAlloc, CoreHugeStats};
+    use std::alloc::GlobalAlloc;
+
+    let core = CoreHugeAlloc::new(50);
+    let page = PageSize::SizeDefault.bytes();
+
+    let small = Layout::from_size_align(100, 8).unwrap();
+    let big = Layout::from_size_align(mb(3), 8).unwrap();
+
+    unsafe {
+        let ptr = core.alloc_zeroed(small);
+        assert!(!ptr.is_null(), "small allocation");
+        assert!(std::slice::from_raw_parts(ptr, 100).iter().all(|&b| b == 0), "zeroed");
+        ptr.write_bytes(0x11, 100);
+
+        assert_eq!(CoreHugeStats { segments: 1, mapped: page, ..Default::default() }, core.stats());
+
+        // Grow within the page, then across the huge threshold
+        let ptr = core.realloc(ptr, small, 4000);
+        let ptr = core.realloc(ptr, Layout::from_size_align(4000, 8).unwrap(), mb(3));
+        assert!(!ptr.is_null(), "grown");
+        assert!(std::slice::from_raw_parts(ptr, 100).iter().all(|&b| b == 0x11), "contents kept");
+
+        // Huge pages or a recorded fallback to default pages
+        let stats = core.stats();
+        assert_eq!(1, stats.segments, "segments");
+
+        if stats.huge_segments == 1 {
+            assert_eq!(mb(4), stats.huge_mapped, "huge mapped");
+        } else {
+            assert_eq!(1, stats.fallbacks, "fallbacks");
+            assert_eq!(mb(3), stats.mapped, "default mapped");
+        }
+
+        core.dealloc(ptr, big);
+        assert_eq!(0, core.stats().segments, "freed");
+
+        // Alignment beyond a page
+        let aligned = Layout::from_size_align(8192, mb(1)).unwrap();
+        let ptr = core.alloc(aligned);
+        assert_eq!(0, ptr as usize % mb(1), "aligned");
+        assert_eq!(8192, core.stats().mapped, "only the aligned pages kept");
+        core.dealloc(ptr, aligned);
+
+        // Enough segments to grow the table several times, freed out of order
+        let ptrs: Vec<*mut u8> = (0..2000).map(|_| core.alloc(small)).collect();
+        assert!(ptrs.iter().all(|ptr| !ptr.is_null()), "all allocated");
+        assert_eq!(2000, core.stats().segments);
+
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            *ptr = i as u8;
+        }
+
+        for &ptr in ptrs.iter().step_by(2).chain(ptrs.iter().skip(1).step_by(2)) {
+            core.dealloc(ptr, small);
+        }
+
+        assert_eq!(CoreHugeStats { fallbacks: stats.fallbacks, ..Default::default() }, core.stats(), "all freed");
+    }
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn c_interface() {
+    use crate::ffi::*;
+
+    unsafe {
+        let allocator = huge_allocator_new(50);
+
+        let ptr = huge_alloc(allocator, mb(1), 64) as *mut u8;
+        assert!(!ptr.is_null(), "allocated");
+        assert_eq!(0, ptr as usize % 64, "aligned");
+        ptr.write_bytes(0x33, mb(1));
+
+        let ptr = huge_realloc(allocator, ptr as *mut _, mb(3)) as *mut u8;
+        assert!(!ptr.is_null(), "reallocated");
+        assert_eq!(0x33, ptr.add(mb(1) - 1).read(), "contents kept");
+
+        let mut stats = HugeStats::default();
+        assert_eq!(0, huge_stats(allocator, &mut stats));
+        assert_eq!(1, stats.segments, "segments");
+        assert_eq!(mb(3), stats.alloc, "allocated bytes");
+
+        huge_free(allocator, ptr as *mut _);
+        huge_free(allocator, std::ptr::null_mut());
+
+        assert_eq!(0, huge_stats(allocator, &mut stats));
+        assert_eq!(0, stats.segments, "freed");
+
+        assert!(huge_alloc(allocator, 10, 3).is_null(), "bad alignment");
+        assert_eq!(-1, huge_stats(std::ptr::null(), &mut stats));
+
+        huge_allocator_free(allocator);
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+#[test]
+fn jemalloc_extent_hooks() {
+    use crate::jemalloc::JemallocExtentHooks;
+
+    let hooks = JemallocExtentHooks::new(50);
+    let ptr = hooks.as_ptr();
+    let extent_hooks = unsafe { &*ptr };
+
+    let mut zero = false;
+    let mut commit = false;
+
+    let alloc = extent_hooks.alloc.unwrap();
+    let addr = unsafe { alloc(ptr, std::ptr::null_mut(), mb(2), 4096, &mut zero, &mut commit, 0) };
+    assert!(!addr.is_null(), "extent mapped");
+    assert!(zero && commit, "zeroed and committed");
+    assert_eq!(mb(2), hooks.mapped());
+    unsafe { (addr as *mut u8).write_bytes(0x44, mb(2)) };
+
+    let small = unsafe { alloc(ptr, std::ptr::null_mut(), 64 * 1024, 4096, &mut zero, &mut commit, 0) };
+    assert!(!small.is_null(), "small extent mapped");
+    assert_eq!(mb(2) + 64 * 1024, hooks.mapped());
+    assert!(hooks.huge_mapped() <= mb(2), "small extent on default pages");
+
+    // Above the threshold but not whole huge pages, so mapped on default pages
+    let odd = unsafe { alloc(ptr, std::ptr::null_mut(), mb(3), 4096, &mut zero, &mut commit, 0) };
+    assert!(!odd.is_null(), "odd sized extent mapped");
+
+    // Aligned beyond the default page size
+    let aligned = unsafe { alloc(ptr, std::ptr::null_mut(), 64 * 1024, mb(1), &mut zero, &mut commit, 0) };
+    assert!(!aligned.is_null(), "aligned extent mapped");
+    assert_eq!(0, aligned as usize % mb(1), "aligned");
+    assert_eq!(mb(5) + 128 * 1024, hooks.mapped(), "only the extents counted");
+
+    assert!(unsafe { (extent_hooks.dalloc.unwrap())(ptr, addr, mb(2), true, 0) }, "dalloc opts out");
+    let split = extent_hooks.split.unwrap();
+    assert!(unsafe { split(ptr, addr, mb(2), mb(1), mb(1), true, 0) }, "split inside a huge page refused");
+    assert!(!unsafe { split(ptr, odd, mb(3), mb(1), mb(2), true, 0) }, "default page split");
+    assert!(extent_hooks.purge_forced.is_none(), "purge opted out");
+
+    let destroy = extent_hooks.destroy.unwrap();
+    unsafe {
+        destroy(ptr, addr, mb(2), true, 0);
+        destroy(ptr, small, 64 * 1024, true, 0);
+        destroy(ptr, odd, mb(3), true, 0);
+        destroy(ptr, aligned, 64 * 1024, true, 0);
+    }
+    assert_eq!(0, hooks.mapped());
+}
+
+#[cfg(feature = "mimalloc")]
+#[test]
+fn mimalloc_arena() {
+    use crate::mimalloc::MimallocArena;
+    use std::ffi::{c_int, c_void};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static MANAGED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn manage(start: *mut c_void, size: usize, commit: bool, _: bool, zero: bool, _: c_int) -> bool {
+        assert!(commit && zero, "committed and zeroed");
+        assert_eq!(0, start as usize % mb(2), "aligned");
+        MANAGED.fetch_add(size, Ordering::Relaxed);
+        true
+    }
+
+    unsafe extern "C" fn reject(_: *mut c_void, _: usize, _: bool, _: bool, _: bool, _: c_int) -> bool {
+        false
+    }
+
+    let allocator: &'static HugeAllocator = Box::leak(Box::new(HugeAllocator::new(50)));
+
+    let arena = MimallocArena::register(allocator, mb(3), manage).unwrap();
+    assert_eq!(mb(4), arena.len(), "rounded to huge pages");
+    assert_eq!(mb(4), MANAGED.load(Ordering::Relaxed), "registered");
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(1, stats.segments, "arena accounted");
+    assert_eq!(mb(4), stats.alloc, "arena bytes accounted");
+    allocator.check_integrity().unwrap();
+
+    assert!(MimallocArena::register(allocator, mb(2), reject).is_err(), "rejected");
+    assert_eq!(1, allocator.stats().unwrap().segments, "rejected arena unmapped");
+}
+
+#[test]
+fn address_hint_region() {
+    let base = 0x5100_0000_0000;
+    let allocator = HugeAllocator::builder().address_hint(base + 1).build();
+
+    let hint = base + mb(2);
+    assert_eq!(Some(hint..hint), allocator.hint_region(), "base rounded to a huge page");
+
+    let layout1 = Layout::from_size_align(mb(3), 8).unwrap();
+    let layout2 = Layout::from_size_align(10, 8).unwrap();
+
+    let ptr1 = allocator.allocate(layout1).unwrap();
+    let ptr2 = allocator.allocate(layout2).unwrap();
+
+    assert_eq!(hint, ptr1.as_ptr() as *mut u8 as usize, "placed at the base");
+    assert_eq!(hint + mb(4), ptr2.as_ptr() as *mut u8 as usize, "placed contiguously");
+
+    // Default page allocations only reserve what they map
+    let region = allocator.hint_region().unwrap();
+    assert_eq!(hint..hint + mb(4) + PageSize::SizeDefault.bytes(), region);
+
+    // Occupy the next slot so the allocator has to place elsewhere
+    let blocker = crate::mmap::MMap::new_at(region.end, layout2, &PageSize::SizeDefault, &AllocOptions::default()).unwrap();
+    let ptr3 = allocator.allocate(layout2).unwrap();
+    assert!(!allocator.hint_region().unwrap().contains(&(ptr3.as_ptr() as *mut u8 as usize)), "placed elsewhere");
+    drop(blocker);
+
+    allocator.check_integrity().unwrap();
+
+    unsafe {
+        allocator.deallocate(ptr1.cast(), layout1);
+        allocator.deallocate(ptr2.cast(), layout2);
+        allocator.deallocate(ptr3.cast(), layout2);
+    }
+
+    assert_eq!(None, HugeAllocator::new(50).hint_region());
+}
+
+#[test]
+fn address_hint_exhausted() {
+    let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = messages.clone();
+
+    // Beyond the top of the user address space
+    let allocator = HugeAllocator::builder()
+        .address_hint(1 << 56)
+        .log_sink(move |msg| sink.lock().unwrap().push(msg.to_string()))
+        .build();
+
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+
+    let ptrs: Vec<_> = (0..3).map(|_| allocator.allocate(layout).expect("placed anywhere")).collect();
+
+    let region = allocator.hint_region().unwrap();
+    assert_eq!(PageSize::SizeDefault.bytes(), region.len(), "hinting stopped after the first failure");
+    assert!(ptrs.iter().all(|ptr| !region.contains(&(ptr.as_ptr() as *mut u8 as usize))), "placed elsewhere");
+    assert_eq!(1, messages.lock().unwrap().iter().filter(|msg| msg.contains("no longer hinting")).count());
+
+    for ptr in ptrs {
+        unsafe { allocator.deallocate(ptr.cast(), layout) };
+    }
+}
+
+#[test]
+fn trace_recording() {
+    let path = std::env::temp_dir().join(format!("huge_allocator_trace_{}", std::process::id()));
+
+    let recorder = Arc::new(TraceRecorder::file(&path).unwrap());
+    let memory = Arc::new(TraceRecorder::memory(2));
+
+    let allocator = HugeAllocator::builder().trace_recorder(recorder.clone()).build();
+    let small = HugeAllocator::builder().trace_recorder(memory.clone()).build();
+
+    let layout = Layout::from_size_align(mb(1), 8).unwrap();
+    let grown = Layout::from_size_align(mb(3), 8).unwrap();
+
+    let ptr = allocator.allocate(layout).unwrap();
+    let new_ptr = unsafe { allocator.grow(ptr.as_non_null_ptr(), layout, grown) }.unwrap();
+    unsafe { allocator.deallocate(new_ptr.as_non_null_ptr(), grown) };
+
+    assert!(allocator.allocate(Layout::from_size_align(1 << 50, 8).unwrap()).is_err());
+
+    recorder.flush().unwrap();
+    let events = read_trace(&mut std::fs::File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let ops: Vec<_> = events.iter().map(|event| event.op).collect();
+    assert_eq!(vec![TraceOp::Map, TraceOp::Remap, TraceOp::Unmap, TraceOp::Map], ops);
+
+    assert_eq!(ptr.as_mut_ptr() as usize, events[0].addr, "mapped address");
+    assert_eq!((mb(1), 8), (events[0].size, events[0].align), "mapped layout");
+    assert_eq!(allocator.stats().unwrap().segments, 0);
+
+    assert_eq!(events[0].addr, events[1].addr, "remapped from");
+    assert_eq!(new_ptr.as_mut_ptr() as usize, events[1].new_addr, "remapped to");
+    assert_eq!(mb(3), events[1].size, "remapped size");
+
+    assert_eq!(events[1].new_addr, events[2].addr, "unmapped address");
+    assert!(events[..3].iter().all(|event| event.ok && event.page_size.is_some()), "succeeded");
+
+    assert!(!events[3].ok, "failed map recorded");
+    assert_eq!(None, events[3].page_size);
+    assert!(events.windows(2).all(|pair| pair[0].nanos <= pair[1].nanos), "timestamps ordered");
+
+    // Memory recorders keep the most recent events
+    for _ in 0..2 {
+        let ptr = small.allocate(layout).unwrap();
+        unsafe { small.deallocate(ptr.as_non_null_ptr(), layout) };
+    }
+
+    let events = memory.events();
+    assert_eq!(vec![TraceOp::Map, TraceOp::Unmap], events.iter().map(|event| event.op).collect::<Vec<_>>());
+
+    let mut out = Vec::new();
+    assert_eq!(2, memory.write_to(&mut out).unwrap());
+    assert_eq!(events, read_trace(&mut out.as_slice()).unwrap(), "round trip");
+    assert!(read_trace(&mut &b"not a trace"[..]).is_err());
+}
+
+#[test]
+fn trace_replay() {
+    let recorder = Arc::new(TraceRecorder::memory(1024));
+    let recorded = HugeAllocator::builder().trace_recorder(recorder.clone()).build();
+
+    let small = Layout::from_size_align(4096, 8).unwrap();
+    let big = Layout::from_size_align(mb(3), 8).unwrap();
+
+    let ptr1 = recorded.allocate(small).unwrap();
+    let ptr2 = recorded.allocate(small).unwrap();
+    let ptr1 = unsafe { recorded.grow(ptr1.as_non_null_ptr(), small, big) }.unwrap();
+    unsafe { recorded.deallocate(ptr2.as_non_null_ptr(), small) };
+
+    let events = recorder.events();
+    assert_eq!(4, events.len());
+
+    let candidate = HugeAllocator::builder().segment_cache(mb(16)).build();
+    let report = candidate.replay(&events);
+
+    assert_eq!((2, 1, 1), (report.maps, report.unmaps, report.remaps), "operations replayed");
+    assert_eq!(0, report.failed, "failures");
+    assert_eq!(0, report.skipped, "skipped");
+    assert_eq!(1, report.live, "live at the end");
+    assert_eq!(1, report.stats.segments, "segments at the end");
+    assert_eq!(mb(3), report.stats.alloc, "allocated at the end");
+
+    assert_eq!(0, candidate.stats().unwrap().segments, "live allocations freed");
+    candidate.check_integrity().unwrap();
+
+    // Events for allocations the replay doesn't hold are skipped
+    let report = candidate.replay(&events[2..]);
+    assert_eq!(2, report.skipped);
+
+    unsafe { recorded.deallocate(ptr1.as_non_null_ptr(), big) };
+}
+
+#[test]
+fn seeded_address_hints() {
+    let layouts = [mb(3), 4096, mb(1), 100].map(|size| Layout::from_size_align(size, 8).unwrap());
+
+    let run = |seed| {
+        let allocator = HugeAllocator::builder().address_seed(seed).build();
+
+        let ptrs: Vec<_> = layouts.iter().map(|&layout| allocator.allocate(layout).unwrap()).collect();
+        let addrs: Vec<_> = ptrs.iter().map(|ptr| ptr.as_mut_ptr() as usize).collect();
+
+        assert!(addrs.iter().all(|addr| allocator.hint_region().unwrap().contains(addr)), "in the region");
+
+        for (ptr, layout) in ptrs.into_iter().zip(layouts) {
+            unsafe { allocator.deallocate(ptr.as_non_null_ptr(), layout) };
+        }
+
+        addrs
+    };
+
+    let first = run(42);
+
+    assert_eq!(first, run(42), "same seed, same addresses");
+    assert_ne!(first, run(43), "different seed, different addresses");
+}
+
+#[test]
+fn pool_recommendation() {
+    let allocator = HugeAllocator::new(50);
+
+    assert_eq!(0, allocator.recommend_pool_size().huge_pages, "nothing wanted");
+
+    let big = Layout::from_size_align(mb(7), 8).unwrap();
+    let small = Layout::from_size_align(4096, 8).unwrap();
+
+    let ptr1 = allocator.allocate(big).unwrap();
+    let ptr2 = allocator.allocate(big).unwrap();
+    let ptr3 = allocator.allocate(small).unwrap();
+
+    // Resizing moves demand between sizes
+    let ptr2 = unsafe { allocator.shrink(ptr2.as_non_null_ptr(), big, small) }.unwrap();
+
+    unsafe { allocator.deallocate(ptr1.as_non_null_ptr(), big) };
+
+    let recommendation = allocator.recommend_pool_size();
+
+    assert_eq!(mb(16), recommendation.peak_demand, "peak demand");
+    assert_eq!(9, recommendation.huge_pages, "pages with headroom");
+    assert_eq!(recommendation.huge_pages.saturating_sub(recommendation.current_pool), recommendation.shortfall());
+
+    unsafe {
+        allocator.deallocate(ptr2.as_non_null_ptr(), small);
+        allocator.deallocate(ptr3.as_non_null_ptr(), small);
+    }
+
+    // The peak is kept after everything is freed
+    assert_eq!(mb(16), allocator.recommend_pool_size().peak_demand);
+}
+
+#[test]
+fn untracked_mode() {
+    let allocator = HugeAllocator::builder().untracked(true).build();
+
+    let small = Layout::from_size_align(100, 8).unwrap();
+    let big = Layout::from_size_align(mb(3), 8).unwrap();
+
+    let ptr = allocator.allocate(small).unwrap();
+    assert_eq!(100, ptr.len(), "exact length");
+    unsafe { ptr.as_mut_ptr().write_bytes(0x77, 100) };
+
+    // Growing past the threshold moves the allocation to a whole number of huge pages
+    let ptr = unsafe { allocator.grow(ptr.as_non_null_ptr(), small, big) }.unwrap();
+    assert_eq!(mb(3), ptr.len(), "exact length");
+    assert!(unsafe { &ptr.as_ref()[..100] }.iter().all(|&b| b == 0x77), "contents kept");
+
+    // Nothing is registered
+    let stats = allocator.stats().unwrap();
+    assert_eq!(0, stats.segments, "untracked");
+    assert_eq!(None, allocator.page_size_of(ptr.as_non_null_ptr()));
+
+    let mid = Layout::from_size_align(mb(2), 8).unwrap();
+    let aligned = Layout::from_size_align(mb(3), mb(2)).unwrap();
+
+    let ptr = unsafe { allocator.shrink(ptr.as_non_null_ptr(), big, mid) }.unwrap();
+
+    // Changing the alignment moves the allocation. Only huge page mappings are aligned to 2mb
+    match unsafe { allocator.grow(ptr.as_non_null_ptr(), mid, aligned) } {
+        Ok(moved) => {
+            assert_eq!(0, moved.as_mut_ptr() as usize % mb(2), "realigned");
+            unsafe { allocator.deallocate(moved.as_non_null_ptr(), aligned) };
+        }
+        Err(_) => unsafe { allocator.deallocate(ptr.as_non_null_ptr(), mid) },
+    }
+
+    // Rounding up to the default page size would cross the threshold, so freeing with the returned length
+    // must not unmap a whole huge page
+    let below = Layout::from_size_align(1_046_000, 8).unwrap();
+    let ptr = allocator.allocate(below).unwrap();
+    assert_eq!(below.size(), ptr.len(), "not rounded across the threshold");
+    unsafe { allocator.deallocate(ptr.as_non_null_ptr(), Layout::from_size_align(ptr.len(), 8).unwrap()) };
+
+    // Stale handles can't be detected
+    assert!(allocator.allocate_tagged(small).is_err(), "tagged refused");
+
+    allocator.check_integrity().unwrap();
+}
+
+#[test]
+fn allocation_userdata() {
+    let allocator = HugeAllocator::builder().segment_cache(mb(8)).build();
+
+    let small = Layout::from_size_align(4096, 8).unwrap();
+    let big = Layout::from_size_align(mb(3), 8).unwrap();
+
+    let ptr = allocator.allocate(small).unwrap().as_non_null_ptr();
+
+    assert_eq!(None, allocator.get_userdata::<u64>(ptr), "nothing attached");
+    assert!(allocator.set_userdata(ptr, 7u64).unwrap().is_none(), "first value");
+
+    let previous = allocator.set_userdata(ptr, String::from("owner")).unwrap().unwrap();
+    assert_eq!(Some(&7u64), previous.downcast_ref::<u64>(), "previous value returned");
+
+    assert_eq!(None, allocator.get_userdata::<u64>(ptr), "wrong type");
+    assert_eq!(Some(String::from("owner")), allocator.get_userdata(ptr));
+
+    // The value follows the allocation when it moves
+    let moved = unsafe { allocator.grow(ptr, small, big) }.unwrap().as_non_null_ptr();
+    assert_ne!(ptr, moved, "moved");
+    assert_eq!(Some(String::from("owner")), allocator.get_userdata(moved));
+    assert_eq!(None, allocator.get_userdata::<String>(ptr), "old address has none");
+
+    let taken = allocator.take_userdata(moved).unwrap();
+    assert_eq!(Some(&String::from("owner")), taken.downcast_ref::<String>());
+    assert!(allocator.take_userdata(moved).is_none(), "taken");
+
+    allocator.set_userdata(moved, 9u32).unwrap();
+    unsafe { allocator.deallocate(moved, big) };
+
+    // Cached segments carry nothing to the next allocation
+    let reused = allocator.allocate(big).unwrap().as_non_null_ptr();
+    assert_eq!(moved, reused, "segment reused");
+    assert_eq!(None, allocator.get_userdata::<u32>(reused));
+
+    assert!(allocator.set_userdata(NonNull::dangling(), 1u8).is_err(), "not an allocation");
+
+    unsafe { allocator.deallocate(reused, big) };
+}
+
+#[test]
+fn mte_options() {
+    let allocator = HugeAllocator::builder().build();
+
+    let options = AllocOptions {
+        mte: true,
+        ..Default::default()
+    };
+
+    let layout = Layout::from_size_align(mb(2), 16).unwrap();
+
+    if crate::mte::supported() {
+        let ptr = allocator.allocate_with(layout, &options).unwrap().as_non_null_ptr();
+
+        // The tag doesn't stop the allocation being found
+        assert!(allocator.page_size_of(ptr).is_some());
+
+        unsafe {
+            ptr.as_ptr().write_bytes(0x5a, layout.size());
+            allocator.deallocate(ptr, layout);
+        }
+    } else {
+        assert!(allocator.allocate_with(layout, &options).is_err(), "tagged segments unsupported");
+        assert!(crate::mte::enable(crate::mte::MteMode::Sync).is_err());
+    }
+
+    let stats = allocator.stats().unwrap();
+    assert_eq!(0, stats.huge_segments + stats.default_segments, "nothing left");
+}
+
+#[test]
+fn mergeable() {
+    let options = AllocOptions {
+        mergeable: true,
+        ..Default::default()
+    };
+
+    let allocator = HugeAllocator::builder().default_options(options).build();
+
+    let small: Vec<u8, _> = Vec::with_capacity_in(64 * 1024, &allocator);
+
+    if std::path::Path::new("/sys/kernel/mm/ksm").exists() {
+        assert!(vm_flags(small.as_ptr()).iter().any(|f| f == "mg"), "mergeable set");
+    }
+
+    assert_eq!(0, allocator.stats().unwrap().mergeable_ignored, "default pages merged");
+
+    let big: Vec<u8, _> = Vec::with_capacity_in(mb(4), &allocator);
+
+    if allocator.page_size_of(NonNull::new(big.as_ptr() as *mut u8).unwrap()) == Some(PageSize::Size2m) {
+        assert!(!vm_flags(big.as_ptr()).iter().any(|f| f == "mg"), "huge pages not mergeable");
+        assert_eq!(1, allocator.stats().unwrap().mergeable_ignored, "huge page segment ignored");
+    }
+}
+
+#[test]
+fn segment_transfer() {
+    let recorder = Arc::new(TraceRecorder::memory(16));
+
+    let loader = HugeAllocator::builder().name("loader").trace_recorder(recorder.clone()).build();
+    let cache = HugeAllocator::new(50);
+
+    let layout = Layout::from_size_align(mb(3), 8).unwrap();
+    let ptr = loader.allocate(layout).unwrap().as_non_null_ptr();
+
+    unsafe { ptr.as_ptr().write_bytes(0x42, layout.size()) };
+    loader.set_userdata(ptr, 5u32).unwrap();
+
+    let page_size = loader.page_size_of(ptr);
+
+    unsafe { loader.transfer(ptr, &cache) }.unwrap();
+
+    let stats = loader.stats().unwrap();
+    assert_eq!(0, stats.segments, "loader gave up segment");
+    assert_eq!(0, stats.alloc, "loader alloc");
+    assert!(loader.page_size_of(ptr).is_none(), "loader no longer owns it");
+
+    let stats = cache.stats().unwrap();
+    assert_eq!(1, stats.segments, "cache took segment");
+    assert_eq!(mb(3), stats.alloc, "cache alloc");
+    assert_eq!(page_size, cache.page_size_of(ptr), "same backing");
+    assert_eq!(Some(5u32), cache.get_userdata(ptr), "user data moved");
+
+    let bytes = unsafe { std::slice::from_raw_parts(ptr.as_ptr(), layout.size()) };
+    assert!(bytes.iter().all(|&b| b == 0x42), "contents kept");
+
+    let ops: Vec<TraceOp> = recorder.events().iter().map(|e| e.op).collect();
+    assert_eq!(vec![TraceOp::Map, TraceOp::Unmap], ops, "transfer traced as leaving the loader");
+
+    // Only the owner can transfer
+    assert!(unsafe { loader.transfer(ptr, &cache) }.is_err(), "not owned");
+    assert!(unsafe { cache.transfer(ptr, &cache) }.is_ok(), "already owned");
+
+    unsafe { cache.deallocate(ptr, layout) };
+
+    assert_eq!(0, cache.stats().unwrap().segments, "freed by new owner");
+}
+
+#[test]
+fn adopt_mapping() {
+    let allocator = HugeAllocator::new(50);
+
+    let map = |len: usize, flags: libc::c_int| {
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+                -1,
+                0,
+            )
+        };
+
+        (addr != libc::MAP_FAILED).then(|| NonNull::new(addr.cast::<u8>()).unwrap())
+    };
+
+    let len = 64 * 1024;
+    let addr = map(len, 0).unwrap();
+    let misaligned = NonNull::new(addr.as_ptr().wrapping_add(1)).unwrap();
+
+    assert!(unsafe { allocator.adopt(addr, 0, PageSize::SizeDefault) }.is_err(), "empty");
+    assert!(unsafe { allocator.adopt(misaligned, len, PageSize::SizeDefault) }.is_err(), "misaligned");
+
+    let ptr = unsafe { allocator.adopt(addr, len, PageSize::SizeDefault) }.unwrap();
+    assert_eq!(addr, ptr.as_non_null_ptr(), "same address");
+    assert_eq!(len, ptr.len(), "whole mapping");
+
+    assert!(unsafe { allocator.adopt(addr, len, PageSize::SizeDefault) }.is_err(), "already adopted");
+
+    let stats = check_stats(&allocator, "adopted", 1, len);
+    assert_eq!(0, stats.missed_allocs, "not a missed allocation");
+
+    let layout = Layout::from_size_align(len, 4096).unwrap();
+    let bigger = Layout::from_size_align(len * 2, 4096).unwrap();
+
+    assert!(unsafe { allocator.grow(addr, layout, bigger) }.is_err(), "pinned");
+
+    unsafe { allocator.deallocate(addr, layout) };
+
+    check_stats_eq(&allocator, "adopted freed", 0, 0, 0);
+
+    // Huge page mappings count as huge
+    if let Some(addr) = map(mb(2), libc::MAP_HUGETLB) {
+        unsafe { allocator.adopt(addr, mb(2), PageSize::Size2m) }.unwrap();
+
+        let stats = allocator.stats().unwrap();
+        assert_eq!(1, stats.huge_segments, "huge segment");
+        assert_eq!(mb(2), stats.huge_mapped, "huge mapped");
+
+        unsafe { allocator.deallocate(addr, Layout::from_size_align(mb(2), mb(2)).unwrap()) };
+    }
+}
+
+#[test]
+fn leak_checks() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let allocator = HugeAllocator::builder().name("leaky").build();
+
+    crate::testing::assert_no_leaks(&allocator);
+
+    let small = Layout::from_size_align(4096, 8).unwrap();
+    let big = Layout::from_size_align(mb(4), 8).unwrap();
+
+    let a = allocator.allocate(small).unwrap().as_non_null_ptr();
+    let b = allocator.allocate(big).unwrap().as_non_null_ptr();
+
+    let err = catch_unwind(AssertUnwindSafe(|| crate::testing::assert_no_leaks(&allocator))).unwrap_err();
+    let msg = err.downcast_ref::<String>().unwrap();
+
+    assert!(msg.starts_with("leaky: 2 allocations leaked"), "{}", msg);
+    assert!(msg.contains(&format!("{:#x}: 4096 bytes", a.as_ptr() as usize)), "{}", msg);
+    assert!(msg.contains(&format!("{:#x}: {} bytes", b.as_ptr() as usize, mb(4))), "{}", msg);
+
+    // The guard checks on drop
+    let guarded = catch_unwind(AssertUnwindSafe(|| {
+        let _guard = crate::testing::LeakGuard::new(&allocator);
+    }));
+    assert!(guarded.is_err(), "guard caught leaks");
+
+    unsafe {
+        allocator.deallocate(a, small);
+        allocator.deallocate(b, big);
+    }
+
+    drop(crate::testing::LeakGuard::new(&allocator));
+}
+
+#[test]
+fn stats_csv() {
+    let dir = std::env::temp_dir().join(format!("huge_allocator_csv_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join("stats.csv");
+    let rotation = CsvRotation {
+        max_bytes: 400,
+        keep: 2,
+    };
+
+    let allocator = HugeAllocator::new(50);
+    let vec: Vec<u8, _> = Vec::with_capacity_in(mb(1), &allocator);
+
+    let writer = allocator.write_stats_csv(&path, Duration::from_millis(10), rotation).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    drop(writer);
+
+    // Rotated, keeping two old files
+    assert!(dir.join("stats.csv.1").exists(), "rotated");
+    assert!(dir.join("stats.csv.2").exists(), "rotated twice");
+    assert!(!dir.join("stats.csv.3").exists(), "oldest dropped");
+
+    for name in ["stats.csv", "stats.csv.1", "stats.csv.2"] {
+        let csv = std::fs::read_to_string(dir.join(name)).unwrap();
+        let mut lines = csv.lines();
+
+        assert!(csv.len() <= 400, "{} within max size", name);
+        assert!(lines.next().unwrap().starts_with("timestamp_ms,alloc,mapped,segments,"), "{} header", name);
+
+        for line in lines {
+            let fields: Vec<&str> = line.split(',').collect();
+
+            assert_eq!(19, fields.len(), "{} columns", name);
+            assert_eq!(mb(1).to_string(), fields[1], "{} alloc", name);
+        }
+    }
+
+    drop(vec);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(feature = "opentelemetry")]
+#[test]
+fn otel_metrics() {
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+
+    let exporter = InMemoryMetricExporter::default();
+    let provider = SdkMeterProvider::builder().with_reader(PeriodicReader::builder(exporter.clone()).build()).build();
+
+    let allocator = HugeAllocator::builder().name("otel").threshold_pct(100).build();
+    crate::otel::register_metrics(&allocator, &provider.meter("test"));
+
+    let vec: Vec<u8, _> = Vec::with_capacity_in(64 * 1024, &allocator);
+
+    provider.force_flush().unwrap();
+
+    let metrics = exporter.get_finished_metrics().unwrap();
+    let metrics: Vec<_> = metrics.iter().flat_map(|rm| rm.scope_metrics()).flat_map(|sm| sm.metrics()).collect();
+
+    let metric = |name: &str| *metrics.iter().find(|metric| metric.name() == name).unwrap_or_else(|| panic!("{} missing", name));
+
+    let allocated = metric("huge_allocator.memory.allocated");
+    assert_eq!("By", allocated.unit());
+
+    let AggregatedMetrics::U64(MetricData::Gauge(gauge)) = allocated.data() else {
+        panic!("allocated not a u64 gauge");
+    };
+
+    let default = gauge
+        .data_points()
+        .find(|point| point.attributes().any(|kv| kv.key.as_str() == "page_size" && kv.value.as_str() == "default"))
+        .unwrap();
+
+    assert_eq!(64 * 1024, default.value(), "default allocated");
+    assert!(
+        default.attributes().any(|kv| kv.key.as_str() == "huge_allocator.name" && kv.value.as_str() == "otel"),
+        "named"
+    );
+
+    let AggregatedMetrics::U64(MetricData::Sum(sum)) = metric("huge_allocator.huge_page.misses").data() else {
+        panic!("misses not a u64 sum");
+    };
+    assert!(sum.is_monotonic(), "misses monotonic");
+
+    drop(vec);
+}
+
+#[test]
+fn stats_arithmetic() {
+    let a = HugeAllocator::builder().name("a").map_count_limit(100).build();
+    let b = HugeAllocator::builder().name("b").map_count_limit(50).build();
+
+    let small = Layout::from_size_align(4096, 8).unwrap();
+    let big = Layout::from_size_align(mb(2), 8).unwrap();
+
+    let p1 = a.allocate(small).unwrap();
+    let p2 = b.allocate(small).unwrap();
+    let p3 = b.allocate(big).unwrap();
+
+    let stats_a = a.stats().unwrap();
+    let stats_b = b.stats().unwrap();
+
+    let total = stats_a.clone() + stats_b.clone();
+
+    assert_eq!(None, total.name, "names differ");
+    assert_eq!(stats_a.alloc + stats_b.alloc, total.alloc, "alloc");
+    assert_eq!(stats_a.mapped + stats_b.mapped, total.mapped, "mapped");
+    assert_eq!(3, total.segments, "segments");
+    assert_eq!(stats_a.huge_segments + stats_b.huge_segments, total.huge_segments, "huge segments");
+    assert_eq!(50, total.map_count_limit, "tightest map count limit");
+    assert_eq!(total.alloc * 100 / total.mapped, total.efficiency, "efficiency");
+
+    let summed: HugeAllocatorStats = [a.stats().unwrap(), b.stats().unwrap()].into_iter().sum();
+    assert_eq!(total.alloc, summed.alloc, "sum");
+    assert_eq!(total.segments, summed.segments, "sum segments");
+
+    let mut same = a.stats().unwrap();
+    same += &stats_a;
+    assert_eq!(Some("a".to_string()), same.name, "same name kept");
+    assert_eq!(2 * stats_a.alloc, same.alloc, "add assign");
+
+    unsafe {
+        a.deallocate(p1.as_non_null_ptr(), small);
+        b.deallocate(p2.as_non_null_ptr(), small);
+        b.deallocate(p3.as_non_null_ptr(), big);
+    }
+}
+
+#[test]
+fn registry_stats() {
+    crate::enable_registry();
+
+    let a = HugeAllocator::builder().name("registry a").build();
+    let b = HugeAllocator::builder().name("registry b").build();
+
+    let layout = Layout::from_size_align(4096, 8).unwrap();
+    let ptr = a.allocate(layout).unwrap();
+
+    let stats = crate::all_stats();
+    let instance = |name: &str| stats.instances.iter().find(|stats| stats.name.as_deref() == Some(name)).cloned();
+
+    assert_eq!(4096, instance("registry a").unwrap().alloc, "a registered");
+    assert_eq!(0, instance("registry b").unwrap().alloc, "b registered");
+    assert!(stats.total.alloc >= 4096, "total");
+
+    // Dropped allocators are removed
+    drop(b);
+    assert!(crate::all_stats().instances.iter().all(|stats| stats.name.as_deref() != Some("registry b")), "b removed");
+
+    unsafe { a.deallocate(ptr.as_non_null_ptr(), layout) };
+}
+
+#[test]
+fn child_quota() {
+    let parent = HugeAllocator::builder().name("parent").quota(mb(8)).build();
+
+    let a = parent.child("tenant a", mb(6));
+    let b = parent.child("tenant b", mb(6));
+    let grandchild = a.child("tenant a job", mb(2));
+
+    let layout = |size| Layout::from_size_align(size, 8).unwrap();
+
+    let pa = a.allocate(layout(mb(4))).unwrap();
+    assert!(a.allocate(layout(mb(3))).is_err(), "over child quota");
+
+    // Freed when the grandchild is dropped
+    grandchild.allocate(layout(mb(2))).unwrap();
+    assert!(grandchild.allocate(layout(1)).is_err(), "over grandchild quota");
+
+    // Within b's quota but not what is left of the parent's
+    assert!(b.allocate(layout(mb(3))).is_err(), "over parent quota");
+    let pb = b.allocate(layout(mb(2))).unwrap();
+
+    let stats = parent.stats().unwrap();
+    assert_eq!(Some(mb(8)), stats.quota, "parent quota");
+    assert_eq!(mb(8), stats.quota_used, "parent used includes descendants");
+    assert_eq!(0, stats.alloc, "parent allocates nothing itself");
+    assert_eq!(mb(6), a.stats().unwrap().quota_used, "child used includes grandchild");
+    assert_eq!(Some("tenant a".to_string()), a.stats().unwrap().name, "child named");
+
+    // Growth is charged and shrinking returns quota
+    assert!(unsafe { b.grow(pb.as_non_null_ptr(), layout(mb(2)), layout(mb(3))) }.is_err(), "growth over quota");
+    let pb = unsafe { b.shrink(pb.as_non_null_ptr(), layout(mb(2)), layout(mb(1))) }.unwrap();
+    assert_eq!(mb(7), parent.stats().unwrap().quota_used, "shrink released");
+
+    // Dropping a child releases its allocations
+    drop(grandchild);
+    assert_eq!(mb(4), a.stats().unwrap().quota_used, "grandchild released");
+
+    unsafe {
+        a.deallocate(pa.as_non_null_ptr(), layout(mb(4)));
+        b.deallocate(pb.as_non_null_ptr(), layout(mb(1)));
+    }
+
+    assert_eq!(0, parent.stats().unwrap().quota_used, "all released");
+}
+
+#[test]
+fn child_instance_options() {
+    let recorder = Arc::new(TraceRecorder::memory(16));
+
+    let parent = HugeAllocator::builder()
+        .address_hint(0x5200_0000_0000)
+        .trace_recorder(recorder.clone())
+        .sample_backtraces(1)
+        .deferred_unmap(true)
+        .build();
+
+    let child = parent.child("child", mb(8));
+
+    // The child doesn't place its mappings in the parent's hint region or share its recorders
+    assert_eq!(None, child.hint_region(), "address hint");
+    assert!(child.profile().is_none(), "profiler");
+    assert!(!child.mapper.config().deferred_unmap, "unmap thread");
+
+    let layout = Layout::from_size_align(mb(3), 1).unwrap();
+    let ptr = child.allocate(layout).unwrap();
+    unsafe { child.deallocate(ptr.as_non_null_ptr(), layout) };
+
+    assert!(recorder.events().is_empty(), "trace recorder");
+}
+
+#[test]
+fn shrink_quota_mismatched_layout() {
+    let allocator = HugeAllocator::new(50);
+
+    let layout = Layout::from_size_align(mb(1) + 1, 8).unwrap();
+    let ptr = allocator.allocate(layout).unwrap();
+    assert!(ptr.len() > layout.size(), "slice covers the whole mapping");
+
+    // Shrink passing the returned slice length rather than the allocated size
+    let old_layout = Layout::from_size_align(ptr.len(), 8).unwrap();
+    let new_layout = Layout::from_size_align(mb(1), 8).unwrap();
+    let ptr = unsafe { allocator.shrink(ptr.as_non_null_ptr(), old_layout, new_layout) }.unwrap();
+    assert_eq!(mb(1), allocator.stats().unwrap().quota_used, "only the recorded size released");
+
+    // Later allocations must still be charged successfully
+    let ptr2 = allocator.allocate(new_layout).unwrap();
+    assert_eq!(mb(2), allocator.stats().unwrap().quota_used, "allocation charged");
+
+    unsafe {
+        allocator.deallocate(ptr.as_non_null_ptr(), new_layout);
+        allocator.deallocate(ptr2.as_non_null_ptr(), new_layout);
+    }
+
+    assert_eq!(0, allocator.stats().unwrap().quota_used, "all released");
+}
+
+#[test]
+fn request_arena() {
+    let allocator = HugeAllocator::new(50);
+
+    {
+        let arena = RequestArena::new(&allocator, 42);
+
+        let mut a: Vec<u64, _> = Vec::with_capacity_in(1024, &arena);
+        let b: Vec<u8, _> = Vec::with_capacity_in(mb(3), &arena);
+        a.extend(0..2048);
+
+        assert_eq!(42, arena.id());
+        assert_eq!(2048 * 8 + mb(3), arena.allocated(), "bytes including growth");
+        assert_eq!(2, arena.segments(), "second segment for the big allocation");
+
+        // Every segment is tagged with the request
+        let mut segments = Vec::new();
+        allocator.for_each_allocation(|info| segments.push(info.ptr));
+
+        for seg in segments {
+            assert_eq!(Some(RequestId(42)), allocator.get_userdata(NonNull::new(seg as *mut u8).unwrap()), "tagged");
+        }
+
+        drop(b);
+        drop(a);
+    }
+
+    let stats = allocator.stats().unwrap();
+
+    assert_eq!(0, stats.segments, "released when the request ends");
+    assert_eq!(1, stats.requests, "request counted");
+    assert_eq!(2048 * 8 + mb(3), stats.request_bytes, "request bytes");
+    assert_eq!(2048 * 8 + mb(3), stats.peak_request_bytes, "peak request bytes");
+}
+
+#[test]
+fn file_arena() {
+    // Files which aren't on hugetlbfs are refused
+    let path = std::env::temp_dir().join(format!("huge_allocator_arena_{}", std::process::id()));
+    std::fs::write(&path, [0; 4096]).unwrap();
+
+    let err = HugeFileArena::open(&path).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind(), "{}", err);
+
+    std::fs::remove_file(&path).unwrap();
+
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap();
+
+    let Some(mount) = mounts
+        .lines()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .find(|fields| fields[2] == "hugetlbfs" && fields[3].contains("pagesize=2M"))
+        .map(|fields| fields[1].to_string())
+    else {
+        eprintln!("no 2mb hugetlbfs mount");
+        return;
+    };
+
+    let Ok(_pages) = crate::testing::HugePageTestGuard::reserve(4) else {
+        eprintln!("huge page pool unavailable");
+        return;
+    };
+
+    let path = Path::new(&mount).join(format!("huge_allocator_arena_{}", std::process::id()));
+    std::fs::File::create(&path).unwrap().set_len(mb(8) as u64).unwrap();
+
+    let arena = HugeFileArena::open(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    let arena = match arena {
+        Ok(arena) => arena,
+        Err(e) => {
+            // Pages taken by another test
+            eprintln!("unable to map arena: {}", e);
+            return;
+        }
+    };
+
+    assert_eq!(mb(8), arena.capacity());
+
+    let mut vec: Vec<u8, _> = Vec::with_capacity_in(mb(1), &arena);
+    vec.extend_from_slice(&[0x5a; 1000]);
+
+    // Grows in place in to the free space after it
+    let before = vec.as_ptr();
+    vec.reserve_exact(mb(4));
+    assert_eq!(before, vec.as_ptr(), "grown in place");
+    assert!(vec.iter().all(|&b| b == 0x5a), "contents kept");
+
+    // The file bounds the arena
+    assert!(arena.alloc(Layout::from_size_align(mb(4), 8).unwrap()).is_err(), "allocation beyond capacity");
+
+    let aligned = arena.alloc(Layout::from_size_align(4096, mb(2)).unwrap()).unwrap();
+    assert!((aligned.as_mut_ptr() as usize).is_multiple_of(mb(2)), "aligned");
+
+    drop(vec);
+    unsafe { arena.deallocate(aligned.as_non_null_ptr(), Layout::from_size_align(4096, mb(2)).unwrap()) };
+
+    assert_eq!(0, arena.used(), "all returned");
+    assert_eq!(mb(8), arena.largest_free(), "free ranges merged");
+}
+
+#[test]
+fn exit_checkpoint() {
+    let path = std::env::temp_dir().join(format!("huge_allocator_checkpoint_{}", std::process::id()));
+
+    let allocator = HugeAllocator::builder().name("batch").exit_stats(&path).exit_leak_report(true).build();
+
+    // Children don't overwrite the parent's checkpoint
+    drop(allocator.child("worker", mb(8)));
+    assert!(!path.exists(), "child checkpoint");
+
+    let layout = Layout::from_size_align(mb(3), 1).unwrap();
+    allocator.mapper.alloc(layout).unwrap();
+
+    drop(allocator);
+
+    let checkpoint = std::fs::read_to_string(&path).unwrap();
+
+    assert!(checkpoint.starts_with("HugeAllocator[batch] checkpoint at drop"), "{}", checkpoint);
+    assert!(checkpoint.contains("segments: 1,"), "{}", checkpoint);
+    assert!(checkpoint.contains(&format!("batch: 1 allocation leaked ({} bytes)", mb(3))), "{}", checkpoint);
+
+    // Written once, at exit if still alive then
+    let allocator = HugeAllocator::builder().exit_stats(&path).build();
+
+    allocator.mapper.checkpoint("process exit");
+    let vec: Vec<u8, _> = Vec::with_capacity_in(4096, &allocator);
+    drop(vec);
+    drop(allocator);
+
+    let checkpoint = std::fs::read_to_string(&path).unwrap();
+
+    assert!(checkpoint.starts_with("HugeAllocator checkpoint at process exit"), "{}", checkpoint);
+    assert!(!checkpoint.contains("leaked"), "{}", checkpoint);
+
+    std::fs::remove_file(&path).unwrap();
+}
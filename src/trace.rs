@@ -0,0 +1,362 @@
+use std::alloc::Layout;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::mmap::PageSize;
+use crate::{HugeAllocator, HugeAllocatorStats};
+
+/// Trace file magic number
+const MAGIC: &[u8; 8] = b"HUGETRC1";
+
+/// Size of an encoded event in bytes
+const EVENT_SIZE: usize = 43;
+
+/// Operation recorded in a trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+    /// A segment was allocated
+    Map,
+    /// A segment was freed
+    Unmap,
+    /// A segment was resized, possibly moving
+    Remap,
+}
+
+/// A recorded allocator operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// The operation
+    pub op: TraceOp,
+    /// Nanoseconds since the recorder was created
+    pub nanos: u64,
+    /// Address of the segment (the old address for a remap), or 0 if a map failed
+    pub addr: usize,
+    /// New address of a remapped segment, or 0 if the remap failed or for other operations
+    pub new_addr: usize,
+    /// Requested size in bytes (the new size for a remap)
+    pub size: usize,
+    /// Requested alignment in bytes
+    pub align: usize,
+    /// Page size of the segment, or None if the operation failed
+    pub page_size: Option<PageSize>,
+    /// True if the operation succeeded
+    pub ok: bool,
+}
+
+impl TraceEvent {
+    /// Encodes the event in its little endian binary form
+    fn encode(&self) -> [u8; EVENT_SIZE] {
+        let mut bytes = [0u8; EVENT_SIZE];
+
+        bytes[0] = match self.op {
+            TraceOp::Map => 0,
+            TraceOp::Unmap => 1,
+            TraceOp::Remap => 2,
+        };
+
+        bytes[1] = match self.page_size {
+            None => 0,
+            Some(PageSize::SizeDefault) => 1,
+            Some(PageSize::Size2m) => 2,
+        };
+
+        bytes[2] = self.ok as u8;
+
+        let words = [self.nanos, self.addr as u64, self.new_addr as u64, self.size as u64, self.align as u64];
+
+        for (i, word) in words.iter().enumerate() {
+            bytes[3 + i * 8..11 + i * 8].copy_from_slice(&word.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    /// Decodes an event from its binary form
+    fn decode(bytes: &[u8; EVENT_SIZE]) -> io::Result<Self> {
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+
+        let op = match bytes[0] {
+            0 => TraceOp::Map,
+            1 => TraceOp::Unmap,
+            2 => TraceOp::Remap,
+            _ => Err(invalid("invalid trace operation"))?,
+        };
+
+        let page_size = match bytes[1] {
+            0 => None,
+            1 => Some(PageSize::SizeDefault),
+            2 => Some(PageSize::Size2m),
+            _ => Err(invalid("invalid trace page size"))?,
+        };
+
+        let word = |i: usize| u64::from_le_bytes(bytes[3 + i * 8..11 + i * 8].try_into().unwrap());
+
+        Ok(Self {
+            op,
+            nanos: word(0),
+            addr: word(1) as usize,
+            new_addr: word(2) as usize,
+            size: word(3) as usize,
+            align: word(4) as usize,
+            page_size,
+            ok: bytes[2] != 0,
+        })
+    }
+}
+
+/// Where recorded events go
+enum TraceSink {
+    /// The most recent events, up to the capacity
+    Memory { events: VecDeque<TraceEvent>, capacity: usize },
+    /// Every event, appended to a file
+    File(BufWriter<File>),
+}
+
+/// Records every allocation, free and resize an allocator makes, for reconstructing what it did before a
+/// failure or replaying with [`HugeAllocator::replay`]. Attach one with
+/// [`HugeAllocatorBuilder::trace_recorder`](crate::HugeAllocatorBuilder::trace_recorder)
+///
+/// Events are kept in memory, where only the most recent are retained, or written to a file in a compact
+/// binary format (an 8 byte header then 43 bytes per event) which [`read_trace`] reads back
+/// ```rust
+/// #![feature(allocator_api)]
+/// use std::sync::Arc;
+/// use huge_allocator::{HugeAllocator, TraceOp, TraceRecorder};
+///
+/// let recorder = Arc::new(TraceRecorder::memory(1024));
+/// let allocator = HugeAllocator::builder().trace_recorder(recorder.clone()).build();
+///
+/// drop(Vec::<u8, _>::with_capacity_in(4 * 1024 * 1024, &allocator));
+///
+/// let events = recorder.events();
+/// assert_eq!(TraceOp::Map, events[0].op);
+/// assert_eq!(TraceOp::Unmap, events[1].op);
+/// ```
+pub struct TraceRecorder {
+    start: Instant,
+    sink: Mutex<TraceSink>,
+}
+
+impl TraceRecorder {
+    /// Creates a recorder keeping the most recent `capacity` events in memory
+    pub fn memory(capacity: usize) -> Self {
+        Self::with_sink(TraceSink::Memory {
+            events: VecDeque::with_capacity(capacity.min(4096)),
+            capacity,
+        })
+    }
+
+    /// Creates a recorder writing every event to a new file at the given path
+    pub fn file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+
+        Ok(Self::with_sink(TraceSink::File(out)))
+    }
+
+    fn with_sink(sink: TraceSink) -> Self {
+        Self {
+            start: Instant::now(),
+            sink: Mutex::new(sink),
+        }
+    }
+
+    /// Returns the events held in memory, oldest first. File recorders hold none
+    pub fn events(&self) -> Vec<TraceEvent> {
+        match &*self.lock_sink() {
+            TraceSink::Memory { events, .. } => events.iter().copied().collect(),
+            TraceSink::File(_) => Vec::new(),
+        }
+    }
+
+    /// Writes the events held in memory in the binary trace format, returning the number written
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<usize> {
+        let events = self.events();
+
+        out.write_all(MAGIC)?;
+
+        for event in &events {
+            out.write_all(&event.encode())?;
+        }
+
+        Ok(events.len())
+    }
+
+    /// Flushes buffered events to the trace file
+    pub fn flush(&self) -> io::Result<()> {
+        match &mut *self.lock_sink() {
+            TraceSink::Memory { .. } => Ok(()),
+            TraceSink::File(out) => out.flush(),
+        }
+    }
+
+    /// Records an event, timestamping it
+    pub(crate) fn record(&self, mut event: TraceEvent) {
+        event.nanos = self.start.elapsed().as_nanos() as u64;
+
+        match &mut *self.lock_sink() {
+            TraceSink::Memory { events, capacity } => {
+                if *capacity == 0 {
+                    return;
+                }
+
+                if events.len() == *capacity {
+                    events.pop_front();
+                }
+
+                events.push_back(event);
+            }
+            TraceSink::File(out) => {
+                // Tracing must never fail an allocation
+                let _ = out.write_all(&event.encode());
+            }
+        }
+    }
+
+    fn lock_sink(&self) -> MutexGuard<'_, TraceSink> {
+        self.sink.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for TraceRecorder {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl fmt::Debug for TraceRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match &*self.lock_sink() {
+            TraceSink::Memory { .. } => "memory",
+            TraceSink::File(_) => "file",
+        };
+
+        f.debug_struct("TraceRecorder").field("sink", &kind).finish()
+    }
+}
+
+/// Reads a trace written by a file [`TraceRecorder`] or [`TraceRecorder::write_to`]
+pub fn read_trace(input: &mut impl Read) -> io::Result<Vec<TraceEvent>> {
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "not an allocator trace"))?;
+    }
+
+    let mut events = Vec::new();
+    let mut bytes = [0u8; EVENT_SIZE];
+
+    loop {
+        match input.read_exact(&mut bytes) {
+            Ok(()) => events.push(TraceEvent::decode(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => Err(e)?,
+        }
+    }
+
+    Ok(events)
+}
+
+/// Outcome of replaying a trace with [`HugeAllocator::replay`]
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// Number of allocations replayed
+    pub maps: usize,
+    /// Number of frees replayed
+    pub unmaps: usize,
+    /// Number of resizes replayed
+    pub remaps: usize,
+    /// Number of replayed operations which failed
+    pub failed: usize,
+    /// Number of events skipped, either resizes which failed when recorded or events for allocations the
+    /// replay doesn't hold
+    pub skipped: usize,
+    /// Number of allocations still live at the end of the trace. These are freed after the replay
+    pub live: usize,
+    /// Time taken to replay the events
+    pub elapsed: Duration,
+    /// Allocator statistics at the end of the trace, before live allocations are freed
+    pub stats: HugeAllocatorStats,
+}
+
+/// Replays a trace against an allocator as fast as possible
+pub(crate) fn replay(allocator: &HugeAllocator, events: &[TraceEvent]) -> ReplayReport {
+    let mapper = &allocator.mapper;
+    let options = mapper.default_options();
+
+    let mut report = ReplayReport::default();
+
+    // Recorded address to replayed allocation
+    let mut live: HashMap<usize, (NonNull<u8>, Layout)> = HashMap::new();
+
+    // Allocations which failed when recorded have no address, so are held here
+    let mut unaddressed = Vec::new();
+
+    let start = Instant::now();
+
+    for event in events {
+        let Ok(layout) = Layout::from_size_align(event.size, event.align) else {
+            report.skipped += 1;
+            continue;
+        };
+
+        match event.op {
+            TraceOp::Map => {
+                report.maps += 1;
+
+                match mapper.alloc_with(layout, &options) {
+                    Ok(ptr) if event.ok => {
+                        live.insert(event.addr, (ptr.cast(), layout));
+                    }
+                    Ok(ptr) => unaddressed.push((ptr.cast(), layout)),
+                    Err(_) => report.failed += 1,
+                }
+            }
+            TraceOp::Unmap => match live.remove(&event.addr) {
+                Some((ptr, layout)) => {
+                    report.unmaps += 1;
+
+                    let _ = mapper.dealloc(ptr, layout);
+                }
+                None => report.skipped += 1,
+            },
+            TraceOp::Remap => match live.get(&event.addr) {
+                Some(&(ptr, old_layout)) if event.ok => {
+                    report.remaps += 1;
+
+                    match mapper.realloc(ptr, old_layout, layout) {
+                        Ok(new_ptr) => {
+                            live.remove(&event.addr);
+                            live.insert(event.new_addr, (new_ptr.cast(), layout));
+                        }
+                        Err(_) => {
+                            // Later events expect the new layout, so the allocation can't be matched up again
+                            report.failed += 1;
+
+                            live.remove(&event.addr);
+                            unaddressed.push((ptr, old_layout));
+                        }
+                    }
+                }
+                _ => report.skipped += 1,
+            },
+        }
+    }
+
+    report.elapsed = start.elapsed();
+    report.live = live.len() + unaddressed.len();
+    report.stats = mapper.stats();
+
+    for (ptr, layout) in live.into_values().chain(unaddressed) {
+        let _ = mapper.dealloc(ptr, layout);
+    }
+
+    report
+}
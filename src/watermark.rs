@@ -0,0 +1,78 @@
+use std::fs;
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::mmap::PageSize;
+use crate::probe::{read_hugepages, HUGEPAGES_2M};
+use crate::HugeAllocator;
+
+/// Gives huge pages back to the host whenever the number of free 2mb huge pages drops below a watermark,
+/// keeping a shared pool healthy without manual intervention. The pool is checked every interval, and when it
+/// is short the allocator's cached huge page segments are released and its coldest live segments (those
+/// allocated or last grown longest ago) are demoted with
+/// [`HugeAllocator::demote_coldest`] until the shortfall is covered. Monitoring stops when dropped
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use huge_allocator::HugeAllocator;
+///
+/// let allocator = HugeAllocator::new(50);
+///
+/// // Keep at least 64 huge pages free for other processes
+/// let _monitor = unsafe { allocator.watch_watermark(64, Duration::from_secs(1)) }.unwrap();
+/// ```
+pub struct WatermarkMonitor {
+    /// Dropped to shut the monitor thread down
+    stop: Option<Sender<()>>,
+    monitor: Option<JoinHandle<()>>,
+}
+
+impl WatermarkMonitor {
+    /// Starts the monitor thread. Fails if the 2mb huge page pool can't be read
+    pub(crate) fn new(allocator: &HugeAllocator, watermark: usize, interval: Duration) -> io::Result<Self> {
+        fs::metadata(format!("{}/free_hugepages", HUGEPAGES_2M))?;
+
+        let allocator = allocator.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let monitor = std::thread::Builder::new()
+            .name("huge_allocator-watermark".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // Reserved pages are promised to existing mappings so aren't available to anyone else
+                    let free = read_hugepages("free_hugepages").saturating_sub(read_hugepages("resv_hugepages"));
+
+                    if free >= watermark {
+                        continue;
+                    }
+
+                    let wanted = (watermark - free) * PageSize::Size2m.bytes();
+                    let released = allocator.mapper.demote_coldest(wanted);
+
+                    if released > 0 {
+                        allocator.mapper.log(format_args!(
+                            "{} free huge pages is below the watermark of {}, released {} bytes",
+                            free, watermark, released
+                        ));
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            stop: Some(stop),
+            monitor: Some(monitor),
+        })
+    }
+}
+
+impl Drop for WatermarkMonitor {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+
+        if let Some(monitor) = self.monitor.take() {
+            let _ = monitor.join();
+        }
+    }
+}
@@ -0,0 +1,21 @@
+//! The hybrid allocator installed as the global allocator. This needs its own test binary
+
+#![cfg(feature = "std")]
+
+use huge_allocator::HybridGlobalAlloc;
+
+// A low threshold so the registry's own table reaches it after a few hundred allocations
+#[global_allocator]
+static GLOBAL: HybridGlobalAlloc = HybridGlobalAlloc::new(1);
+
+#[test]
+fn registry_past_threshold() {
+    // Each of these is huge, so the registry grows its table (freeing the old one) past the threshold
+    let vecs: Vec<Vec<u8>> = (0..1000).map(|_| Vec::with_capacity(32 * 1024)).collect();
+
+    assert!(GLOBAL.allocator().stats().unwrap().segments >= 1000, "allocations are huge");
+
+    drop(vecs);
+
+    GLOBAL.allocator().check_integrity().unwrap();
+}